@@ -0,0 +1,146 @@
+pub const MEASUREMENT_SIZE: usize = 32;
+pub const REPORT_DATA_SIZE: usize = 64;
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+pub const KEY_ID_SIZE: usize = 32;
+pub const SEALING_KEY_SIZE: usize = 32;
+pub const MAC_SIZE: usize = 32;
+/// The most random bytes returned by one `SbiSMRandom` call.
+pub const RANDOM_MAX: usize = 0x1000;
+
+/// Number of runtime measurement registers of an enclave.
+pub const NUM_RTMRS: usize = 4;
+
+/// The most events in the boot log of the security monitor.
+pub const MAX_BOOT_EVENTS: usize = 8;
+
+/// What a [`BootEvent`] measures.
+#[repr(u64)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BootEventKind {
+    /// Text and read-only data of the firmware image.
+    Image = 1,
+    /// Platform configuration taken from the fdt.
+    Config = 2,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BootEvent {
+    pub kind: u64,
+    pub digest: [u8; MEASUREMENT_SIZE],
+}
+
+/// The boot measurement log of the security monitor.
+///
+/// `measurement` is the chain of the first `num` events, by
+/// `value = SHA256(value || kind || digest)` starting from zeros.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BootLog {
+    pub measurement: [u8; MEASUREMENT_SIZE],
+    pub num: u64,
+    pub events: [BootEvent; MAX_BOOT_EVENTS],
+}
+
+impl BootLog {
+    pub const fn empty() -> Self {
+        Self {
+            measurement: [0; MEASUREMENT_SIZE],
+            num: 0,
+            events: [BootEvent {
+                kind: 0,
+                digest: [0; MEASUREMENT_SIZE],
+            }; MAX_BOOT_EVENTS],
+        }
+    }
+
+    pub fn events(&self) -> &[BootEvent] {
+        &self.events[..self.num as usize]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the log is `repr(C)` and has no padding.
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// The attestation report of an enclave.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct EnclaveReport {
+    /// `measurement` of the [`BootLog`] of the security monitor.
+    pub sm_measurement: [u8; MEASUREMENT_SIZE],
+    pub measurement: [u8; MEASUREMENT_SIZE],
+    pub eid: u64,
+    pub enc_type: u64,
+    /// Non-zero if the enclave is a debug enclave, or the report is produced by
+    /// a debug build of the security monitor.
+    pub debug: u64,
+    /// Runtime measurement registers, extended by the enclave after launch.
+    pub rtmrs: [[u8; MEASUREMENT_SIZE]; NUM_RTMRS],
+    /// Data supplied by the enclave, usually the hash of a nonce or a public key.
+    pub report_data: [u8; REPORT_DATA_SIZE],
+}
+
+impl EnclaveReport {
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the report is `repr(C)` and has no padding.
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// An [`EnclaveReport`] signed by the device key.
+///
+/// The signature is Ed25519 over the bytes of `report`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SignedReport {
+    pub report: EnclaveReport,
+    pub public_key: [u8; PUBLIC_KEY_SIZE],
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+/// An [`EnclaveReport`] for another enclave on the same machine.
+///
+/// `mac` is keyed by a secret of the security monitor and the identity of
+/// the `target` enclave, so only the target can have it verified.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LocalReport {
+    pub report: EnclaveReport,
+    pub target: u64,
+    pub mac: [u8; MAC_SIZE],
+}
+
+/// What a sealing key is bound to, besides the platform and the key id.
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SealPolicy {
+    /// Only the enclave with the same measurement gets the same key.
+    Measurement = 0,
+    /// Every enclave signed by the same signer gets the same key.
+    Signer = 1,
+}
+
+impl TryFrom<usize> for SealPolicy {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Measurement),
+            1 => Ok(Self::Signer),
+            _ => Err(()),
+        }
+    }
+}
@@ -1,9 +1,19 @@
+/// The most channels open at the same time.
+pub const MAX_CHANNELS: usize = 16;
+/// The most pages a channel lends, the [`Head`](crate::op::Head) page
+/// included.
+pub const MAX_CHANNEL_PAGES: usize = 64;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChannelStatus {
     Free,
     Using,
 }
 
+/// A channel the security monitor keeps between two enclaves.
+///
+/// `lue` opens the channel, naming it by `arg0` and lending the page at
+/// `arg1`, and `lde` connects to it.
 pub struct Channel<T> {
     pub lue: Option<T>,
     pub lde: Option<T>,
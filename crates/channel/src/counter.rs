@@ -0,0 +1,23 @@
+use crate::attest::MAC_SIZE;
+
+/// The most monotonic counters the security monitor keeps.
+pub const MAX_COUNTERS: usize = 32;
+/// Size of the id an enclave names a counter with.
+pub const COUNTER_ID_SIZE: usize = 32;
+/// Size of one counter in [`CounterBlob::entries`].
+pub const COUNTER_ENTRY_SIZE: usize = 80;
+
+/// The counter table of the security monitor, sealed for the host to keep
+/// across restarts.
+///
+/// `entries` is encrypted and `mac` covers the whole table. The monitor only
+/// takes a blob back if `version` matches the version it holds, so a blob
+/// older than the latest one is refused.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CounterBlob {
+    pub version: u64,
+    pub num: u64,
+    pub entries: [u8; MAX_COUNTERS * COUNTER_ENTRY_SIZE],
+    pub mac: [u8; MAC_SIZE],
+}
@@ -1,13 +1,25 @@
 pub mod client {
     use core::arch::asm;
+
+    /// Why a user enclave thread returned to the host, besides the requests
+    /// of the runtime.
+    pub use sbi::ecall::{STOP_EXIT_ENCLAVE, STOP_TIMER_INTERRUPT};
+
     #[inline(always)]
     pub fn resume_enclave(eidx: usize) -> (usize, usize) {
+        resume_thread(eidx, 0)
+    }
+
+    /// Resume the paused thread `tid` of a user enclave.
+    #[inline(always)]
+    pub fn resume_thread(eidx: usize, tid: usize) -> (usize, usize) {
         let rc0;
         let rc1;
         unsafe {
             asm!(
                 "unimp",
                 in("a0") eidx,
+                in("a1") tid,
                 in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMResumeEnclave as usize,
                 in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
                 lateout("a0") rc0,
@@ -69,8 +81,9 @@ pub mod client {
 
     use crate::info::LdeInfo;
     #[inline(never)]
-    pub fn create_lde(info: *const LdeInfo) -> usize {
+    pub fn create_lde(info: *const LdeInfo) -> (usize, usize) {
         let rc;
+        let eidx;
         unsafe {
             asm!(
                 "unimp",
@@ -79,23 +92,32 @@ pub mod client {
                 in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMCreateEnclave as usize,
                 in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
                 lateout("a0") rc,
+                lateout("a1") eidx,
                 lateout("a6") _,
                 lateout("a7") _,
                 options(nostack)
             )
         }
 
-        rc
+        (rc, eidx)
     }
 
     #[inline(always)]
     pub fn launch_enclave(eidx: usize) -> (usize, usize) {
+        launch_thread(eidx, 0)
+    }
+
+    /// Launch the thread `tid` of a user enclave on the calling hart, after
+    /// the main thread 0 is launched.
+    #[inline(always)]
+    pub fn launch_thread(eidx: usize, tid: usize) -> (usize, usize) {
         let a0: usize;
         let a1: usize;
         unsafe {
             asm!(
                 "unimp",
                 in("a0") eidx,
+                in("a1") tid,
                 in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMRunEnclave as usize,
                 in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
                 lateout("a0") a0,
@@ -109,6 +131,92 @@ pub mod client {
         (a0, a1)
     }
 
+    /// Destroy an enclave that is not running, and get its memory back.
+    #[inline(never)]
+    pub fn destroy_enclave(eidx: usize) -> usize {
+        let rc;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") eidx,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMDestroyEnclave as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") _,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        rc
+    }
+
+    use crate::attest::BootLog;
+    #[inline(never)]
+    pub fn get_boot_log(log: *mut BootLog) -> usize {
+        let rc;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") log,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMGetBootLog as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") _,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        rc
+    }
+
+    use crate::counter::CounterBlob;
+    /// Seal the counter table of the security monitor into `blob`.
+    #[inline(never)]
+    pub fn export_counters(blob: *mut CounterBlob) -> usize {
+        let rc;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") blob,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMExportCounters as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") _,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        rc
+    }
+
+    /// Restore the counter table of the security monitor from `blob`, which
+    /// must be the latest one exported.
+    #[inline(never)]
+    pub fn import_counters(blob: *const CounterBlob) -> usize {
+        let rc;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") blob,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMImportCounters as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") _,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        rc
+    }
+
     // pub fn request_ctl(head: *const usize) {
     //     unsafe {
     //         asm!(
@@ -204,6 +312,32 @@ driver start: {:#x}, size: {:#x}
         }
     }
 
+    /// Most frames of each index of a [`LueRestoreArgs`].
+    pub const RESTORE_INDEX_FRAMES: usize = 64;
+    /// Frames a [`LueRestoreArgs`] gives for the tables the trampoline needs
+    /// at its new address.
+    pub const RESTORE_SPARE_FRAMES: usize = 2;
+
+    /// What the monitor gives the restore hook of the runtime, in a page of
+    /// the enclave at a physical address.
+    ///
+    /// The pages of the enclave moved on restore. The old address of the
+    /// page `i` is entry `i` of the frames `old`, in ascending order, and its
+    /// new address entry `i` of the frames `new`. The hook takes the index,
+    /// the spare frames and the page of the arguments once it is done.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct LueRestoreArgs {
+        /// New physical address of the trampoline.
+        pub tp: usize,
+        /// Where the host restored the shared memory.
+        pub shared: SharedArg,
+        pub pages: usize,
+        pub old: [usize; RESTORE_INDEX_FRAMES],
+        pub new: [usize; RESTORE_INDEX_FRAMES],
+        pub spare: [usize; RESTORE_SPARE_FRAMES],
+    }
+
     pub struct MemArg {
         pub total_size: usize,
     }
@@ -2,10 +2,26 @@ use core::fmt::Display;
 
 pub use elf::Sections;
 
-#[repr(C, align(0x1000))]
+use crate::manifest::Manifest;
+
+#[repr(C)]
 pub struct LseInfo {
     pub mem: MemInfo,
     pub rt: RtInfo,
+    /// Room for the page table of the runtime, see [`LseInfo::table_pages`],
+    /// followed by the private memory of a callable service.
+    pub unused: UnusedInfo,
+    /// Offset of the service entry in the runtime, 0 if it is not callable.
+    pub entry: usize,
+}
+
+impl LseInfo {
+    /// Pages at the start of `unused` the monitor keeps for the page table
+    /// it records the runtime in, which maps `rt_size` bytes from a 1 GiB
+    /// boundary.
+    pub const fn table_pages(rt_size: usize) -> usize {
+        1 + rt_size.div_ceil(0x4000_0000) + rt_size.div_ceil(0x20_0000)
+    }
 }
 
 impl Display for LseInfo {
@@ -15,17 +31,21 @@ impl Display for LseInfo {
 mem_start:\t{:#x}
 mem_size:\t{:#x}
 rt_start:\t{:#x}
+unused_start:\t{:#x}
+entry:\t\t{:#x}
 ",
             self.mem.start as usize,
             self.mem.page_num * 0x1000 as usize,
             self.rt.ptr as usize,
+            self.unused.start as usize,
+            self.entry,
         ))?;
 
         Ok(())
     }
 }
 
-#[repr(C, align(0x1000))]
+#[repr(C)]
 pub struct LueInfo {
     pub mem: MemInfo,
     pub bin: BinInfo,
@@ -33,6 +53,18 @@ pub struct LueInfo {
     // pub mods: &'a [ModInfo],
     pub shared: SharedInfo,
     pub unused: UnusedInfo,
+    /// Null if the enclave is unsigned.
+    pub manifest: *const Manifest,
+    /// Id of the service enclave whose runtime the enclave runs on.
+    pub lse: usize,
+    /// Number of threads, the main one included. 0 is taken as 1.
+    pub threads: usize,
+    /// Ticks of the machine timer a thread runs for before the host gets the
+    /// hart back. 0 is taken as the default of the monitor.
+    pub time_slice: u64,
+    /// Ticks the threads may run for in all before the enclave is stopped.
+    /// 0 is no limit.
+    pub time_budget: u64,
 }
 
 impl Display for LueInfo {
@@ -45,13 +77,21 @@ elf_start:\t{:#x}
 rt_start:\t{:#x}
 shared_start:\t{:#x}
 unused_start:\t{:#x}
+lse:\t\t{}
+threads:\t{}
+time_slice:\t{}
+time_budget:\t{}
 ",
             self.mem.start as usize,
             self.mem.page_num * 0x1000 as usize,
             self.bin.ptr as usize,
             self.rt.ptr as usize,
             self.shared.ptr as usize,
-            self.unused.start as usize
+            self.unused.start as usize,
+            self.lse,
+            self.threads,
+            self.time_slice,
+            self.time_budget
         ))?;
 
         Ok(())
@@ -1,18 +1,32 @@
 #![no_std]
 #![feature(str_from_raw_parts)]
 
+pub mod attest;
 pub mod call;
 pub mod channel;
+pub mod counter;
 pub mod enclave;
 pub mod info;
+pub mod manifest;
+pub mod mem;
 pub mod op;
 pub mod proxy;
+pub mod service;
+pub mod snapshot;
 
 pub mod h2e {
+    pub use crate::counter::*;
     pub use crate::enclave::client::*;
     pub use crate::info::*;
+    pub use crate::manifest::*;
+    pub use crate::mem::client::*;
+    pub use crate::service::client::*;
+    pub use crate::snapshot::client::*;
 }
 
 pub mod e2r {
+    pub use crate::attest::*;
+    pub use crate::counter::*;
     pub use crate::enclave::runtime::*;
+    pub use crate::service::*;
 }
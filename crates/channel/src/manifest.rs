@@ -0,0 +1,43 @@
+use crate::attest::{MEASUREMENT_SIZE, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+
+/// What the signer of an enclave allows it to be.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ManifestBody {
+    /// The measurement the enclave must have once created.
+    pub measurement: [u8; MEASUREMENT_SIZE],
+    /// Non-zero if the enclave is a debug enclave.
+    ///
+    /// Production enclaves are refused by debug builds of the security monitor.
+    pub debug: u64,
+    /// The oldest security monitor the enclave runs on.
+    pub min_sm_version: u64,
+    /// The most memory the enclave can be given, in bytes.
+    pub max_mem_size: u64,
+    /// The largest memory shared with the host, in bytes.
+    pub max_shared_size: u64,
+}
+
+impl ManifestBody {
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the body is `repr(C)` and has no padding.
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// A [`ManifestBody`] signed by the signer of the enclave.
+///
+/// The signature is Ed25519 over the bytes of `body`. Manifest files hold
+/// exactly the bytes of this struct.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Manifest {
+    pub body: ManifestBody,
+    pub signer: [u8; PUBLIC_KEY_SIZE],
+    pub signature: [u8; SIGNATURE_SIZE],
+}
@@ -0,0 +1,72 @@
+/// What a user enclave pauses with to ask the host for more memory.
+///
+/// Other requests are the host address of a proxied system call, or one of
+/// the stop reasons of [`sbi::ecall`], which come first.
+pub const MEM_REQUEST: usize = 3;
+/// What a user enclave pauses with after it gave memory back to the host.
+pub const MEM_RETURN: usize = 4;
+/// The most pages donated or given back at once.
+pub const MAX_DONATED_PAGES: usize = 64;
+
+pub mod client {
+    use core::arch::asm;
+
+    /// Donate `size` bytes of host memory at `addr` to the paused thread `tid`
+    /// of the user enclave `eidx`.
+    ///
+    /// The pages are scrubbed and belong to the enclave until it is destroyed.
+    /// The thread gets them when it is resumed.
+    #[inline(never)]
+    pub fn donate_memory(eidx: usize, tid: usize, addr: *const u8, size: usize) -> usize {
+        let rc;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") eidx,
+                in("a1") tid,
+                in("a2") addr,
+                in("a3") size,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMELock as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") _,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        rc
+    }
+
+    /// Ask for the `size` bytes of host memory at `addr` donated to the
+    /// user enclave `eidx` back.
+    ///
+    /// Returns the error code and how many of the pages the enclave still
+    /// holds. The memory may be freed once none is held. Otherwise the
+    /// paused thread `tid` is asked to give as many pages back when it is
+    /// resumed, which it may do with any of its free pages.
+    #[inline(never)]
+    pub fn reclaim_memory(eidx: usize, tid: usize, addr: *const u8, size: usize) -> (usize, usize) {
+        let rc;
+        let held;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") eidx,
+                in("a1") tid,
+                in("a2") addr,
+                in("a3") size,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMEFree as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") held,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        (rc, held)
+    }
+}
@@ -0,0 +1,82 @@
+/// Where the message page is mapped in a service enclave.
+pub const SERVICE_MSG_VADDR: usize = 0xFFFF_FFFF_7FF0_0000;
+/// The most bytes of a request or a reply.
+pub const SERVICE_MSG_MAX: usize = 0x1000 - 2 * size_of::<u64>();
+/// [`ServiceMsg::caller`] of the first call, made when the host launches the
+/// service enclave.
+pub const SERVICE_LAUNCH: u64 = 0;
+
+/// The page a service enclave takes requests from and puts replies in.
+///
+/// The security monitor enters the service at its entry with `a0` pointing
+/// to the page and `a1` the length of the request, and the service replies
+/// with [`runtime::service_return`]. Calls run to completion, one at a time.
+#[repr(C, align(0x1000))]
+pub struct ServiceMsg {
+    /// Id of the calling enclave, 1 for the host.
+    pub caller: u64,
+    /// Length of the request, then of the reply.
+    pub len: u64,
+    pub data: [u8; SERVICE_MSG_MAX],
+}
+
+const _: () = assert!(size_of::<ServiceMsg>() == 0x1000);
+
+pub mod client {
+    use core::arch::asm;
+
+    /// Call the service enclave `eidx` with `len` bytes of request at `req`.
+    ///
+    /// Returns the error code and the length of the reply, of which at most
+    /// `cap` bytes are written to `reply`.
+    #[inline(never)]
+    pub fn call_service(
+        eidx: usize,
+        req: *const u8,
+        len: usize,
+        reply: *mut u8,
+        cap: usize,
+    ) -> (usize, usize) {
+        let rc;
+        let reply_len;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") eidx,
+                in("a1") req,
+                in("a2") len,
+                in("a3") reply,
+                in("a4") cap,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMCallService as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") reply_len,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+
+        (rc, reply_len)
+    }
+}
+
+pub mod runtime {
+    use core::arch::asm;
+
+    /// Finish the current call with `len` bytes of reply in the message page.
+    ///
+    /// The stack of the service is reset by the next call.
+    #[inline(always)]
+    pub fn service_return(len: usize) -> ! {
+        unsafe {
+            asm!(
+                "ecall",
+                in("a0") len,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMServiceReturn as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                options(noreturn, nostack)
+            )
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    attest::{MAC_SIZE, MEASUREMENT_SIZE},
+    e2r::RESTORE_SPARE_FRAMES,
+    info::{MemInfo, SharedInfo},
+};
+
+/// `magic` of a [`SnapshotHeader`].
+pub const SNAPSHOT_MAGIC: u64 = u64::from_le_bytes(*b"LTCSNAP1");
+
+/// A paused user enclave, sealed by the security monitor for the host to keep
+/// and restore later, possibly after the monitor restarts on the same device.
+///
+/// The header is followed by the encrypted state of the enclave, of its
+/// threads, the `pages`, the physical address each page was at as a `u64` in
+/// ascending order, and the [`SnapshotMapping`]s, in this order. `mac`
+/// covers the header and the ciphertext. The keys are bound to `measurement`,
+/// and the monitor only restores the latest snapshot of `slot`, once.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SnapshotHeader {
+    pub magic: u64,
+    /// Snapshot counter of the enclave the blob is fresh for.
+    pub slot: u64,
+    /// Value of the counter when the snapshot was taken.
+    pub version: u64,
+    pub measurement: [u8; MEASUREMENT_SIZE],
+    pub threads: u64,
+    /// Page tables the enclave is rebuilt with, but for those of the
+    /// trampoline, which depend on where it is restored.
+    pub tables: u64,
+    pub mappings: u64,
+    pub pages: u64,
+    pub mac: [u8; MAC_SIZE],
+}
+
+/// A page of the enclave mapped in its page table, by the index of the page
+/// in the snapshot.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SnapshotMapping {
+    pub vaddr: u64,
+    /// Flags of the page table entry.
+    pub flags: u64,
+    pub page: u64,
+}
+
+impl SnapshotHeader {
+    pub const EMPTY: Self = Self {
+        magic: 0,
+        slot: 0,
+        version: 0,
+        measurement: [0; MEASUREMENT_SIZE],
+        threads: 0,
+        tables: 0,
+        mappings: 0,
+        pages: 0,
+        mac: [0; MAC_SIZE],
+    };
+
+    /// Pages of memory the enclave is restored into: its meta page, threads,
+    /// pages, the indexes of where the pages are and were, the
+    /// [`LueRestoreArgs`](crate::e2r::LueRestoreArgs) and its spare frames,
+    /// and page tables.
+    ///
+    /// The trampoline needs up to two tables of its own at its new address,
+    /// and those it does not take are given back.
+    pub fn restore_pages(&self) -> usize {
+        const PER_INDEX: usize = 0x1000 / size_of::<usize>();
+        let index = (self.pages as usize).div_ceil(PER_INDEX);
+        let args = 1 + RESTORE_SPARE_FRAMES;
+        1 + self.threads as usize
+            + self.pages as usize
+            + 2 * index
+            + args
+            + self.tables as usize
+            + 2
+    }
+}
+
+/// Where the host restores a [`SnapshotHeader`] blob.
+pub struct RestoreInfo {
+    /// At least [`SnapshotHeader::restore_pages`] pages followed by the shared
+    /// memory. The pages in between are given back.
+    pub mem: MemInfo,
+    /// The last pages of `mem`, as many as the enclave shared when it was
+    /// snapshotted.
+    pub shared: SharedInfo,
+    /// Id of the service enclave whose runtime the enclave runs on, which
+    /// must be the one it ran on.
+    pub lse: usize,
+    pub snapshot: *const u8,
+    pub size: usize,
+}
+
+pub mod client {
+    use core::arch::asm;
+
+    use super::RestoreInfo;
+
+    /// Snapshot the paused user enclave `eidx` into `size` bytes at `buf`,
+    /// fresh for the counter `slot`.
+    ///
+    /// Returns the size of the snapshot, which is all that is done if `buf`
+    /// is null. Otherwise the enclave is destroyed once it is written.
+    #[inline(never)]
+    pub fn snapshot_enclave(eidx: usize, slot: u64, buf: *mut u8, size: usize) -> (usize, usize) {
+        let rc;
+        let len;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") eidx,
+                in("a1") slot,
+                in("a2") buf,
+                in("a3") size,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMSnapshotEnclave as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") len,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+        (rc, len)
+    }
+
+    /// Restore a user enclave from a snapshot, returning its new id.
+    ///
+    /// Its threads are paused where they were, and are resumed rather than
+    /// launched.
+    #[inline(never)]
+    pub fn restore_enclave(info: *const RestoreInfo) -> (usize, usize) {
+        let rc;
+        let eidx;
+        unsafe {
+            asm!(
+                "unimp",
+                in("a0") info,
+                in("a6") sbi::ecall::SBISMEnclaveCall::SbiSMRestoreEnclave as usize,
+                in("a7") sbi::ecall::SBI_EXT_TEE_ENCLAVE,
+                lateout("a0") rc,
+                lateout("a1") eidx,
+                lateout("a6") _,
+                lateout("a7") _,
+                options(nostack)
+            )
+        }
+        (rc, eidx)
+    }
+}
@@ -7,6 +7,11 @@ pub struct Config {
     pub runtime: Runtime,
     pub binary: Option<Binary>,
     pub driver: Option<Driver>,
+    pub manifest: Option<Manifest>,
+    pub counters: Option<Counters>,
+    pub service: Option<Service>,
+    pub schedule: Option<Schedule>,
+    pub snapshot: Option<Snapshot>,
 }
 
 impl Default for Config {
@@ -17,6 +22,11 @@ impl Default for Config {
             runtime: Default::default(),
             binary: Default::default(),
             driver: None,
+            manifest: None,
+            counters: None,
+            service: None,
+            schedule: None,
+            snapshot: None,
         }
     }
 }
@@ -25,17 +35,63 @@ impl Default for Config {
 pub struct Memory {
     pub size: Option<String>,
     pub shared_size: Option<String>,
+    /// The most memory donated to the enclave as it runs, none if not given.
+    pub grow_size: Option<String>,
 }
 
 #[derive(Deserialize, Default, Debug)]
 pub struct Binary {
     pub path: String,
+    /// Threads of the enclave, the main one included. 1 if not given.
+    pub threads: Option<usize>,
+}
+
+/// Signed manifest of the enclave, see [`channel::manifest::Manifest`].
+#[derive(Deserialize, Default, Debug)]
+pub struct Manifest {
+    pub path: String,
+}
+
+/// Where the sealed counter table of the SM is kept between restarts.
+#[derive(Deserialize, Default, Debug)]
+pub struct Counters {
+    pub path: String,
+}
+
+/// Makes a service enclave created with `--lse` callable.
+#[derive(Deserialize, Default, Debug)]
+pub struct Service {
+    /// Offset of the service entry in the runtime.
+    pub entry: usize,
+}
+
+/// How long the enclave runs, in ticks of the machine timer.
+#[derive(Deserialize, Default, Debug)]
+pub struct Schedule {
+    /// How long a thread runs before the host gets the hart back, the
+    /// default of the SM if not given.
+    pub time_slice: Option<u64>,
+    /// How long the threads run in all before the enclave is stopped, no
+    /// limit if not given.
+    pub time_budget: Option<u64>,
+}
+
+/// Where the enclave is saved on `SIGUSR2`, and restored from with
+/// `--restore`.
+#[derive(Deserialize, Default, Debug)]
+pub struct Snapshot {
+    pub path: String,
+    /// Counter slot of the SM the snapshot is fresh for, 0 if not given.
+    pub slot: Option<u64>,
 }
 
 #[derive(Deserialize, Default, Debug)]
 pub struct Runtime {
     pub path: String,
-    pub mem_size: Option<String> 
+    pub mem_size: Option<String>,
+    /// Id of the service enclave providing the runtime, printed when it is
+    /// created with `--lse`.
+    pub lse: Option<usize>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,5 +1,5 @@
 use libc::*;
-use std::{fs::File, io, os::fd::AsRawFd, ptr};
+use std::{fs::File, io, os::fd::AsRawFd, ptr, sync::Mutex};
 
 pub type Error = i32;
 
@@ -10,11 +10,11 @@ pub struct UserArg {
 }
 
 const TEECTL_IOCTL_ALLOC: usize = 0x40106b01;
-const TEECTL_IOCTL_FREE: usize = 0x40106b02;
 
 const TEECTL_DEV: &str = "/dev/teectl";
 
-static mut MEM_FD: i32 = 0;
+/// The address of each mapped region and its fd, which frees it once closed.
+static REGIONS: Mutex<Vec<(usize, i32)>> = Mutex::new(Vec::new());
 
 pub fn teectl_open() -> File {
     let file = File::open(TEECTL_DEV).expect("Failed to open device");
@@ -38,7 +38,6 @@ pub fn teectl_alloc(size: usize) -> Result<usize, Error> {
     teectl_ioctl(TEECTL_IOCTL_ALLOC, user_arg.as_mut()).unwrap();
     unsafe {
         let mmap_fd = user_arg.fd;
-        MEM_FD = mmap_fd;
 
         println!("mmap fd: {}", mmap_fd);
         println!("size: {}", user_arg.size);
@@ -56,6 +55,7 @@ pub fn teectl_alloc(size: usize) -> Result<usize, Error> {
         }
 
         println!("mmap addr: {:p}", addr);
+        REGIONS.lock().unwrap().push((addr as usize, mmap_fd));
 
         Ok(addr as usize)
     }
@@ -68,10 +68,13 @@ pub fn teectl_free(addr: usize, size: usize) -> Result<(), io::Error> {
             return Err(io::Error::last_os_error());
         }
     }
-    let mut user_arg = Box::new(UserArg {
-        size: 0,
-        fd: unsafe { MEM_FD },
-    });
-    teectl_ioctl(TEECTL_IOCTL_FREE, user_arg.as_mut()).unwrap();
+    // the region is freed when its last reference, the fd, is closed
+    let mut regions = REGIONS.lock().unwrap();
+    if let Some(i) = regions.iter().position(|&(start, _)| start == addr) {
+        let (_, fd) = regions.swap_remove(i);
+        if unsafe { close(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
     Ok(())
 }
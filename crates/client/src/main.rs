@@ -3,10 +3,21 @@ use clap::Parser;
 use config::{Binary, Config};
 use core::slice;
 use channel::{
-    enclave::client::{create_lde, create_lue, launch_enclave, resume_enclave},
-    h2e::create_lse,
+    counter::CounterBlob,
+    enclave::client::{
+        create_lde, create_lue, destroy_enclave, export_counters, import_counters, launch_enclave,
+        resume_enclave, STOP_EXIT_ENCLAVE, STOP_TIMER_INTERRUPT,
+    },
+    h2e::{call_service, create_lse, restore_enclave, snapshot_enclave},
     info::*,
+    manifest::Manifest,
+    mem::{
+        client::{donate_memory, reclaim_memory},
+        MAX_DONATED_PAGES, MEM_REQUEST, MEM_RETURN,
+    },
     proxy::proxy_system_call,
+    service::SERVICE_MSG_MAX,
+    snapshot::{RestoreInfo, SnapshotHeader},
 };
 use loader::Loader;
 use page::Page;
@@ -14,6 +25,7 @@ use std::{
     fs::File,
     io::{BufRead, BufReader, Read},
     os::unix::fs::MetadataExt,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 mod alloc;
@@ -32,6 +44,16 @@ struct Cli {
     lde: bool,
     #[arg(short, long, default_value_t = false)]
     lse: bool,
+    /// Restore the enclave from the snapshot in the config file instead of
+    /// creating it.
+    #[arg(long, default_value_t = false)]
+    restore: bool,
+    /// Call the service enclave with this id, and print its reply.
+    #[arg(long)]
+    call: Option<usize>,
+    /// Request sent with `--call`.
+    #[arg(long, default_value = "")]
+    request: String,
     // #[arg(short, long, default_value_t = false)]
     // hugepage: bool,
     // #[arg(short, long, action = clap::ArgAction::SetTrue)]
@@ -55,6 +77,10 @@ fn main() {
         cli_create_lde(&path, false);
     } else if cli.lse {
         cli_create_lse(&path);
+    } else if let Some(eidx) = cli.call {
+        cli_call_service(eidx, &cli.request);
+    } else if cli.restore {
+        cli_restore_lue(&path);
     } else {
         cli_create_lue(&cli, &path, false);
     }
@@ -66,8 +92,9 @@ fn cli_create_lse(path: &str) {
     println!("[Client] Load runtime: {}", rt.path);
 
     let file = File::open(&rt.path).unwrap();
-    // one page for meta page
-    let size = file.metadata().unwrap().size() + 0x1000;
+    // one page for meta page, and the page table the runtime is recorded in
+    let rt_size = file.metadata().unwrap().size() as usize;
+    let size = rt_size + 0x1000 + LseInfo::table_pages(rt_size) * 0x1000;
 
     println!("rt size: {}", size);
     // let mem_size = arser_mem_size(&config.memory.size.unwrap_or("8k".to_owned()));;
@@ -75,7 +102,7 @@ fn cli_create_lse(path: &str) {
         .mem_size
         .as_ref()
         .map(|size| parser_mem_size(size))
-        .unwrap_or(size as usize);
+        .unwrap_or(size);
 
     let pages = alloc_pages((mem_size + 0x1000 - 1) as usize & !(0x1000 - 1), false);
 
@@ -95,6 +122,10 @@ fn cli_create_lse(path: &str) {
         .mmap(&config.runtime.path)
         .expect(&format!("{} load failed\n", config.runtime.path));
 
+    // the rest is private memory of the service
+    let unused = loader.get_remain_page();
+    let entry = config.service.as_ref().map_or(0, |service| service.entry);
+
     let load_info = LseInfo {
         mem: MemInfo {
             start: loader.get_start(),
@@ -104,28 +135,71 @@ fn cli_create_lse(path: &str) {
             ptr: rt.as_ptr(),
             size: rt.len(),
         },
+        unused: UnusedInfo {
+            start: unused.as_ptr(),
+            size: unused.len(),
+        },
+        entry,
     };
 
     println!("create enclave");
     let (rc, eidx) = create_lse(&load_info as *const _);
+    if rc != 0 {
+        println!("[Client] create lse failed: {rc}");
+        return;
+    }
     println!("lse created");
     println!("eidx: {eidx:#x}");
+
+    if entry != 0 {
+        let (rc, _) = launch_enclave(eidx);
+        if rc != 0 {
+            println!("[Client] launch service failed: {rc}");
+            return;
+        }
+        println!("[Client] service #{eidx} launched");
+    }
     // never stop
     loop {
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
 }
 
+fn cli_call_service(eidx: usize, request: &str) {
+    let mut reply = vec![0u8; SERVICE_MSG_MAX];
+    let (rc, len) = call_service(
+        eidx,
+        request.as_ptr(),
+        request.len(),
+        reply.as_mut_ptr(),
+        reply.len(),
+    );
+    if rc != 0 {
+        println!("[Client] call service #{eidx} failed: {rc}");
+        return;
+    }
+    println!("{}", String::from_utf8_lossy(&reply[..len]));
+}
+
 fn cli_create_lue(cli: &Cli, path: &str, hugepage: bool) {
     let mut config = load_toml(&path);
     if let Some(binary) = &cli.binary {
+        let threads = config.binary.as_ref().and_then(|binary| binary.threads);
         config.binary = Some(Binary {
             path: binary.clone(),
+            threads,
         });
     }
 
+    let lse = config
+        .runtime
+        .lse
+        .expect("runtime.lse must name the service enclave to run on");
+
     let mem_size = parser_mem_size(&config.memory.size.unwrap_or("8k".to_owned()));
     let shared_size = parser_mem_size(&config.memory.shared_size.unwrap_or("8k".to_owned()));
+    let grow_limit = config.memory.grow_size.as_deref().map_or(0, parser_mem_size);
+    let schedule = config.schedule.as_ref();
 
     // alloc continue memory area
     let pages = alloc_pages(mem_size, hugepage);
@@ -148,13 +222,19 @@ fn cli_create_lue(cli: &Cli, path: &str, hugepage: bool) {
         .mmap(&config.runtime.path)
         .expect(&format!("{} load failed\n", config.runtime.path));
 
-    let elf = loader.mmap(&config.binary.unwrap().path).unwrap();
+    let binary = config.binary.unwrap();
+    let elf = loader.mmap(&binary.path).unwrap();
 
     // let shared = loader.alloc(shared_size, 0x1000);
     let shared = loader.alloc_tail(shared_size);
 
     let unused = loader.get_remain_page();
 
+    let manifest = config.manifest.as_ref().map(|manifest| {
+        println!("[Client] Load manifest: {}", manifest.path);
+        load_manifest(&manifest.path)
+    });
+
     let load_info = LueInfo {
         mem: MemInfo {
             start: loader.get_start(),
@@ -177,8 +257,20 @@ fn cli_create_lue(cli: &Cli, path: &str, hugepage: bool) {
             start: unused.as_ptr(),
             size: unused.len(),
         },
+        manifest: manifest
+            .as_deref()
+            .map_or(core::ptr::null(), |manifest| manifest as *const _),
+        lse,
+        threads: binary.threads.unwrap_or(1),
+        time_slice: schedule.and_then(|s| s.time_slice).unwrap_or(0),
+        time_budget: schedule.and_then(|s| s.time_budget).unwrap_or(0),
     };
 
+    // the SM only accepts the table before any counter is used after boot
+    if let Some(counters) = &config.counters {
+        restore_counters(&counters.path);
+    }
+
     println!("create enclave");
     let (rc, eidx) = create_lue(&load_info as *const _);
     if rc != 0 {
@@ -186,12 +278,102 @@ fn cli_create_lue(cli: &Cli, path: &str, hugepage: bool) {
     }
     println!("lue created");
     println!("eidx: {eidx:#x}");
-    loop_waiting_for_enclave(eidx);
+    let snapshot = config.snapshot.as_ref().map(|snapshot| SnapshotTarget {
+        path: &snapshot.path,
+        slot: snapshot.slot.unwrap_or(0),
+        shared,
+    });
+    loop_waiting_for_enclave(eidx, grow_limit, snapshot);
+    if let Some(counters) = &config.counters {
+        save_counters(&counters.path);
+    }
     // let pages = unsafe { slice::from_raw_parts_mut(page_ptr, page_num) };
     // free_pages(pages);
     println!("enclave finished");
 }
 
+/// Restore the enclave saved on `SIGUSR2`, and run it from where it stopped.
+fn cli_restore_lue(path: &str) {
+    let config = load_toml(&path);
+    let snapshot = config
+        .snapshot
+        .as_ref()
+        .expect("snapshot.path must name the snapshot to restore");
+    let lse = config
+        .runtime
+        .lse
+        .expect("runtime.lse must name the service enclave to run on");
+    let grow_limit = config.memory.grow_size.as_deref().map_or(0, parser_mem_size);
+
+    println!("[Client] Load snapshot: {}", snapshot.path);
+    let bytes = std::fs::read(&snapshot.path).unwrap();
+    let (shared_size, bytes) = bytes.split_at(size_of::<u64>());
+    let shared_size = u64::from_le_bytes(shared_size.try_into().unwrap()) as usize;
+    let (shared_bytes, blob_bytes) = bytes.split_at(shared_size);
+    if blob_bytes.len() < size_of::<SnapshotHeader>() {
+        panic!("{} is not a snapshot", snapshot.path);
+    }
+    // SAFETY: SnapshotHeader is `repr(C)` and valid for any bytes of its size.
+    let header =
+        unsafe { std::ptr::read_unaligned(blob_bytes.as_ptr() as *const SnapshotHeader) };
+
+    // the monitor reads the snapshot from pinned pages
+    let blob = alloc_pages(align_up!(blob_bytes.len(), 0x1000), false);
+    let blob_ptr = blob.as_mut_ptr() as *mut u8;
+    unsafe { slice::from_raw_parts_mut(blob_ptr, blob_bytes.len()) }.copy_from_slice(blob_bytes);
+
+    // the pages restored into, then the shared memory
+    let pages = alloc_pages(header.restore_pages() * 0x1000 + shared_size, false);
+    let page_num = pages.len();
+    let mut loader = Loader::new(pages);
+    let shared = loader.alloc_tail(shared_size);
+    shared.copy_from_slice(shared_bytes);
+
+    // the counter the snapshot is fresh for is in the table
+    if let Some(counters) = &config.counters {
+        restore_counters(&counters.path);
+    }
+
+    let info = RestoreInfo {
+        mem: MemInfo {
+            start: loader.get_start(),
+            page_num,
+        },
+        shared: SharedInfo {
+            ptr: shared.as_ptr(),
+            size: shared.len(),
+        },
+        lse,
+        snapshot: blob_ptr,
+        size: blob_bytes.len(),
+    };
+    let (rc, eidx) = restore_enclave(&info as *const _);
+    free_pages(blob);
+    if rc != 0 {
+        println!("[Client] restore lue failed: {rc}");
+        return;
+    }
+    println!("lue restored");
+    println!("eidx: {eidx:#x}");
+
+    let target = SnapshotTarget {
+        path: &snapshot.path,
+        slot: snapshot.slot.unwrap_or(0),
+        shared,
+    };
+    let (rc, arg_addr) = resume_enclave(eidx);
+    if rc != 0 {
+        println!("[client]: resume enclave failed. Error code: {}", rc);
+        cleanup_enclave(eidx);
+    } else {
+        run_enclave(eidx, arg_addr, grow_limit, Some(target));
+    }
+    if let Some(counters) = &config.counters {
+        save_counters(&counters.path);
+    }
+    println!("enclave finished");
+}
+
 fn cli_create_lde(path: &str, hugepage: bool) {
     let config = load_toml(&path);
     let driver = config.driver.unwrap();
@@ -251,7 +433,11 @@ fn cli_create_lde(path: &str, hugepage: bool) {
     );
 
     println!("create enclave");
-    let eidx = create_lde(&load_info as *const _);
+    let (rc, eidx) = create_lde(&load_info as *const _);
+    if rc != 0 {
+        println!("[Client] create lde failed: {rc}");
+        return;
+    }
     println!("lde created");
     println!("eidx: {eidx:#x}");
     // never stop
@@ -260,32 +446,173 @@ fn cli_create_lde(path: &str, hugepage: bool) {
     }
 }
 
-fn loop_waiting_for_enclave(eidx: usize) {
-    let (mut rc, mut arg_addr) = launch_enclave(eidx);
+/// Set by `SIGUSR1`, which asks the client to take donated memory back.
+static MEM_PRESSURE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_mem_pressure(_: libc::c_int) {
+    MEM_PRESSURE.store(true, Ordering::Relaxed);
+}
+
+/// Set by `SIGUSR2`, which asks the client to save the enclave to its
+/// snapshot and stop.
+static SNAPSHOT: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_snapshot(_: libc::c_int) {
+    SNAPSHOT.store(true, Ordering::Relaxed);
+}
+
+/// Where an enclave is saved, see [`config::Snapshot`].
+struct SnapshotTarget<'a> {
+    path: &'a str,
+    slot: u64,
+    /// Memory shared with the enclave, saved along.
+    shared: &'a [u8],
+}
+
+/// Launch the enclave and run it, see [`run_enclave`].
+fn loop_waiting_for_enclave(eidx: usize, grow_limit: usize, snapshot: Option<SnapshotTarget>) {
+    let (rc, arg_addr) = launch_enclave(eidx);
 
     if rc != 0 {
         println!("[client]: launch enclave failed. Error code: {}", rc);
+        cleanup_enclave(eidx);
         return;
     }
 
-    //
+    run_enclave(eidx, arg_addr, grow_limit, snapshot);
+}
+
+/// Run the enclave, stopped with `arg_addr`, until it exits, donating at most
+/// `grow_limit` bytes of memory as it asks for more.
+///
+/// With a `snapshot`, the enclave is saved and destroyed on `SIGUSR2` instead.
+fn run_enclave(
+    eidx: usize,
+    mut arg_addr: usize,
+    grow_limit: usize,
+    snapshot: Option<SnapshotTarget>,
+) {
+    let mut donations = Vec::new();
+    let mut rc;
+    unsafe { libc::signal(libc::SIGUSR1, on_mem_pressure as libc::sighandler_t) };
+    if snapshot.is_some() {
+        unsafe { libc::signal(libc::SIGUSR2, on_snapshot as libc::sighandler_t) };
+    }
+
     loop {
+        if arg_addr == STOP_EXIT_ENCLAVE {
+            break;
+        }
         if arg_addr > 0x10 {
             unsafe {
                 proxy_system_call(arg_addr);
             }
             // println!("[client]: proxy ecall returns, the eidx is: {}", eidx);
+        } else if arg_addr == MEM_REQUEST {
+            donate_pages(eidx, &mut donations, grow_limit);
+        }
+        // a preempted thread cannot take a request, so pressure waits for a
+        // pause of the enclave
+        if arg_addr == MEM_RETURN
+            || (arg_addr != STOP_TIMER_INTERRUPT && MEM_PRESSURE.swap(false, Ordering::Relaxed))
+        {
+            reclaim_pages(eidx, &mut donations);
+        }
+        // only a preempted thread has nothing pending with the host
+        if let Some(target) = &snapshot {
+            // the monitor destroys the enclave it saves
+            if arg_addr == STOP_TIMER_INTERRUPT
+                && SNAPSHOT.swap(false, Ordering::Relaxed)
+                && save_snapshot(eidx, target)
+            {
+                break;
+            }
         }
 
         // println!("[client]: resume enclave");
         (rc, arg_addr) = resume_enclave(eidx);
         if rc != 0 {
             println!("[client]: resume enclave failed. Error code: {}", rc);
+            cleanup_enclave(eidx);
+            break;
+        }
+    }
+
+    // the monitor gave all the memory of the enclave back
+    donations.into_iter().for_each(free_pages);
+}
+
+/// Donate a batch of pages to the enclave, unless it would get more than
+/// `limit` bytes in all. The enclave learns of a refusal by getting none.
+fn donate_pages(eidx: usize, donations: &mut Vec<&'static mut [Page]>, limit: usize) {
+    let size = MAX_DONATED_PAGES * 0x1000;
+    if (donations.len() + 1) * size > limit {
+        println!("[client]: enclave memory would grow over {limit:#x}");
+        return;
+    }
+
+    let pages = alloc_pages(size, false);
+    let rc = donate_memory(eidx, 0, pages.as_ptr() as *const u8, size);
+    if rc != 0 {
+        println!("[client]: donate memory failed. Error code: {}", rc);
+        free_pages(pages);
+        return;
+    }
+    donations.push(pages);
+}
+
+/// Free the donated batches the enclave gave all pages of back, latest first,
+/// and ask it for the pages of the latest one it still holds.
+///
+/// The enclave tells when it gave pages back, and is asked again until it has
+/// no free page left.
+fn reclaim_pages(eidx: usize, donations: &mut Vec<&'static mut [Page]>) {
+    while let Some(pages) = donations.last() {
+        let size = pages.len() * 0x1000;
+        let (rc, held) = reclaim_memory(eidx, 0, pages.as_ptr() as *const u8, size);
+        if rc != 0 {
+            println!("[client]: reclaim memory failed. Error code: {}", rc);
             return;
         }
-        if arg_addr == 0 {
+        if held != 0 {
+            println!("[client]: enclave holds {held} donated pages");
             return;
         }
+        free_pages(donations.pop().unwrap());
+    }
+}
+
+/// Save the enclave to the file of `target`, as the size of the shared memory,
+/// its bytes and the snapshot. Returns whether it is saved.
+fn save_snapshot(eidx: usize, target: &SnapshotTarget) -> bool {
+    let (rc, len) = snapshot_enclave(eidx, target.slot, core::ptr::null_mut(), 0);
+    if rc != 0 {
+        println!("[client]: snapshot enclave failed. Error code: {}", rc);
+        return false;
+    }
+    // the monitor writes the snapshot to pinned pages
+    let blob = alloc_pages(align_up!(len, 0x1000), false);
+    let blob_ptr = blob.as_mut_ptr() as *mut u8;
+    let (rc, _) = snapshot_enclave(eidx, target.slot, blob_ptr, len);
+    if rc != 0 {
+        println!("[client]: snapshot enclave failed. Error code: {}", rc);
+        free_pages(blob);
+        return false;
+    }
+
+    let mut bytes = (target.shared.len() as u64).to_le_bytes().to_vec();
+    bytes.extend_from_slice(target.shared);
+    bytes.extend_from_slice(unsafe { slice::from_raw_parts(blob_ptr, len) });
+    free_pages(blob);
+    std::fs::write(target.path, bytes).unwrap();
+    println!("[client]: enclave saved to {}", target.path);
+    true
+}
+
+fn cleanup_enclave(eidx: usize) {
+    let rc = destroy_enclave(eidx);
+    if rc != 0 {
+        println!("[client]: destroy enclave failed. Error code: {}", rc);
     }
 }
 
@@ -298,6 +625,44 @@ fn load_toml(path: &str) -> Config {
     cfg
 }
 
+fn load_manifest(path: &str) -> Box<Manifest> {
+    let bytes = std::fs::read(path).unwrap();
+    if bytes.len() != size_of::<Manifest>() {
+        panic!("{path} is not a manifest");
+    }
+    // SAFETY: Manifest is `repr(C)` and valid for any bytes of its size.
+    Box::new(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const Manifest) })
+}
+
+fn restore_counters(path: &str) {
+    let Ok(bytes) = std::fs::read(path) else {
+        return;
+    };
+    if bytes.len() != size_of::<CounterBlob>() {
+        panic!("{path} is not a counter table");
+    }
+    // SAFETY: CounterBlob is `repr(C)` and valid for any bytes of its size.
+    let blob = Box::new(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const CounterBlob) });
+    let rc = import_counters(&*blob as *const _);
+    if rc != 0 {
+        println!("[Client] counters in {path} are not restored. Error code: {rc}");
+    }
+}
+
+fn save_counters(path: &str) {
+    // SAFETY: CounterBlob is valid for any bytes.
+    let mut blob: Box<CounterBlob> = Box::new(unsafe { core::mem::zeroed() });
+    let rc = export_counters(&mut *blob as *mut _);
+    if rc != 0 {
+        println!("[Client] export counters failed. Error code: {rc}");
+        return;
+    }
+    let bytes = unsafe {
+        slice::from_raw_parts(&*blob as *const CounterBlob as *const u8, size_of::<CounterBlob>())
+    };
+    std::fs::write(path, bytes).unwrap();
+}
+
 fn get_driver_info(name: &str) -> DriverInfo {
     println!("get driver info for {name}");
     let line = filter_proc_modules(name).unwrap();
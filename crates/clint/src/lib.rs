@@ -56,10 +56,33 @@ impl ClintClient {
         }
     }
 
+    #[inline]
+    pub fn send_ipi(&self, hartid: usize) {
+        unsafe { &*self.clint.load(Ordering::Relaxed) }.set_msip(hartid);
+    }
+
     #[inline]
     pub fn reset_msip(&self) {
         unsafe { &*self.clint.load(Ordering::Relaxed) }.clear_msip(mhartid::read());
     }
+
+    /// Ticks of the machine timer.
+    #[inline]
+    pub fn mtime(&self) -> u64 {
+        unsafe { &*self.clint.load(Ordering::Relaxed) }.mtime()
+    }
+
+    #[inline]
+    pub fn mtimecmp(&self, hartid: usize) -> u64 {
+        unsafe { &*self.clint.load(Ordering::Relaxed) }.mtimecmp(hartid)
+    }
+
+    /// Raise the timer interrupt of `hartid` once [`mtime`](Self::mtime)
+    /// reaches `ticks`.
+    #[inline]
+    pub fn set_mtimecmp(&self, hartid: usize, ticks: u64) {
+        unsafe { &*self.clint.load(Ordering::Relaxed) }.set_mtimecmp(hartid, ticks)
+    }
 }
 
 #[repr(C)]
@@ -80,4 +103,19 @@ impl Clint {
     pub fn set_msip(&self, hartid: usize) {
         unsafe { self.mswi.0[hartid].0.get().write_volatile(1) }
     }
+
+    #[inline]
+    pub fn mtime(&self) -> u64 {
+        unsafe { self.mtime.0.get().read_volatile() }
+    }
+
+    #[inline]
+    pub fn mtimecmp(&self, hartid: usize) -> u64 {
+        unsafe { self.mtimer.0[hartid].0.get().read_volatile() }
+    }
+
+    #[inline]
+    pub fn set_mtimecmp(&self, hartid: usize, ticks: u64) {
+        unsafe { self.mtimer.0[hartid].0.get().write_volatile(ticks) }
+    }
 }
@@ -24,6 +24,8 @@ pub fn sstatus_read_bit() -> usize {
     sstatus
 }
 
+#[repr(C)]
+#[derive(Clone)]
 pub struct HartContext {
     pub tregs: TrapRegs,
     pub sregs: SupervisorRegs,
@@ -155,6 +157,8 @@ impl HartContext {
 //     }
 // }
 
+#[repr(C)]
+#[derive(Clone)]
 pub struct SupervisorRegs {
     pub stvec: usize,
     // pub satp: usize,
@@ -34,10 +34,15 @@ impl Display for MemRegion {
 pub struct DeviceInfo<'a> {
     pub hart_num: usize,
     pub fdt: Fdt<'a>,
+    /// The start of the fdt blob, which the device info is given to change.
+    base: *mut u8,
 }
 
 impl<'a> DeviceInfo<'a> {
     /// Generate DeviceInfo from fdt by given the @ptr
+    ///
+    /// The blob at @ptr must be writable, and not changed by others while the
+    /// DeviceInfo lives, as methods taking `&mut self` change it.
     pub fn new(ptr: *const u8) -> Option<Self> {
         let total_size = unsafe { slice::from_raw_parts(ptr.offset(4), 4) };
         let total_size = u32::from_be_bytes(total_size.try_into().unwrap());
@@ -47,6 +52,7 @@ impl<'a> DeviceInfo<'a> {
             //data,
             hart_num: 0,
             fdt: Fdt::new(data).ok()?,
+            base: ptr as *mut u8,
         })
     }
 
@@ -103,6 +109,21 @@ impl<'a> DeviceInfo<'a> {
             .next()
     }
 
+    /// Take a secret from the `/chosen` node and wipe it from the fdt.
+    ///
+    /// The fdt is passed to the next stage, which must not see the secret.
+    pub fn take_chosen_secret<const N: usize>(&mut self, name: &str) -> Option<[u8; N]> {
+        let value = self.fdt.find_node("/chosen")?.property(name)?.value;
+        let secret = value.try_into().ok();
+        let offset = value.as_ptr() as usize - self.fdt.raw_data().as_ptr() as usize;
+        // SAFETY: the property lies in the blob at `offset`, and the blob is
+        // writable memory handed over with `self`, borrowed mutably here so no
+        // value read from the fdt is alive while it is wiped.
+        unsafe { self.base.add(offset).write_bytes(0, value.len()) };
+
+        secret
+    }
+
     #[inline(always)]
     pub fn get_cpu(&self) -> Cpu {
         Cpu {
@@ -110,6 +131,19 @@ impl<'a> DeviceInfo<'a> {
         }
     }
 
+    /// Whether every hart implements the multi-letter ISA extension `ext`,
+    /// e.g. `zkr`.
+    pub fn has_isa_ext(&self, ext: &str) -> bool {
+        self.fdt.cpus().all(|cpu| {
+            if let Some(exts) = cpu.property("riscv,isa-extensions") {
+                return exts.iter_str().any(|e| e.eq_ignore_ascii_case(ext));
+            }
+            cpu.property("riscv,isa")
+                .and_then(|isa| isa.as_str())
+                .is_some_and(|isa| isa.split('_').skip(1).any(|e| e.eq_ignore_ascii_case(ext)))
+        })
+    }
+
     /// Change memory region
     pub fn update_mem_region_size(&mut self, start: usize, new_size: usize) -> Option<usize> {
         let node = self.fdt.find_node("/memory")?;
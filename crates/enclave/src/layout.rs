@@ -5,6 +5,8 @@ use vm::{PAGE_SIZE, VirtMemArea, page_table::PTEFlags};
 pub const RT_VADDR_START: usize = 0xFFFF_FFFF_8000_0000;
 pub const BIN_VADDR_START: usize = 0x20_0000_0000;
 pub const DEFAULT_BOOTARG_ADDR: usize = 0xFFFF_FFFF_7FF0_0000;
+/// Where the runtime of a driver enclave keeps its kernel object.
+pub const LDE_KERNEL_VADDR: usize = 0xFFFF_FFFF_7000_0000;
 
 pub struct Layout {
     pub rt: VirtMemArea,
@@ -13,6 +15,19 @@ pub struct Layout {
     pub share: VirtMemArea,
     pub trampoline: VirtMemArea,
     pub bootargs: VirtMemArea,
+    /// Kernel page of a driver enclave, empty for others.
+    pub kernel: VirtMemArea,
+    /// Number of threads of a user enclave, 1 for others.
+    pub threads: usize,
+}
+
+impl Layout {
+    /// Stack of the thread `tid`, below the stack of the previous thread with
+    /// a guard page in between.
+    pub fn thread_stack(&self, tid: usize) -> VirtMemArea {
+        self.stack
+            .start(self.stack.start - tid * (self.stack.size + PAGE_SIZE))
+    }
 }
 
 impl Default for Layout {
@@ -33,6 +48,10 @@ impl Default for Layout {
             bootargs: VirtMemArea::default()
                 .start(DEFAULT_BOOTARG_ADDR)
                 .flags(PTEFlags::rw().dirty().accessed()),
+            kernel: VirtMemArea::default()
+                .start(LDE_KERNEL_VADDR)
+                .flags(PTEFlags::rw().dirty().accessed()),
+            threads: 1,
         }
     }
 }
@@ -46,8 +65,17 @@ binary:     {}
 share:      {}
 trampoline: {}
 bootargs:   {}
+kernel:     {}
+threads:    {}
         ",
-            self.rt, self.stack, self.binary, self.share, self.trampoline, self.bootargs,
+            self.rt,
+            self.stack,
+            self.binary,
+            self.share,
+            self.trampoline,
+            self.bootargs,
+            self.kernel,
+            self.threads,
         ))
     }
 }
@@ -1,6 +1,8 @@
+use console::log;
+use context::HartContext;
 use data_structure::linked_list::LinkedList;
 
-use crate::{Enclave, EnclaveData, EnclaveType};
+use crate::{Enclave, EnclaveData, EnclaveId, EnclaveType};
 
 pub type LinuxDriverEnclave = Enclave<LinuxDriver>;
 pub struct LinuxDriverEnclaveList(LinkedList<EnclaveType>);
@@ -9,9 +11,42 @@ impl LinuxDriverEnclaveList {
     pub fn new() -> Self {
         Self(LinkedList::new())
     }
+
+    pub fn get(&self, eid: EnclaveId) -> Option<&'static mut LinuxDriverEnclave> {
+        self.0.iter().find_map(|ptr| {
+            // Safety: the node is pushed by &mut LinuxDriverEnclave, thus it is valid
+            let lde = unsafe { LinuxDriverEnclave::from_ptr(ptr) };
+            if lde.id == eid { Some(lde) } else { None }
+        })
+    }
+
+    pub fn push(&mut self, lde: &'static mut LinuxDriverEnclave) {
+        debug_assert_eq!(lde.get_type(), EnclaveType::Driver);
+        self.0.push_node(&mut lde.list.lock());
+    }
+
+    pub fn remove(&mut self, eid: EnclaveId) -> Option<&'static mut LinuxDriverEnclave> {
+        let enc = self
+            .get(eid)
+            .and_then(|enc| self.0.rm_node(&mut enc.list.lock()))
+            .map(|node| unsafe { Enclave::from_ptr(node) })?;
+
+        log::debug!("remaining driver enclaves:");
+        for e in self.0.iter() {
+            let eid = unsafe { Enclave::<()>::from_ptr(e).id };
+            log::debug!("{eid}");
+        }
+
+        Some(enc)
+    }
 }
 
-pub struct LinuxDriver {}
+pub struct LinuxDriver {
+    pub enc_ctx: HartContext,
+    pub pmp_cache: pmp::Cache,
+    /// Whether the host has launched the driver, which it resumes after.
+    pub launched: bool,
+}
 
 impl EnclaveData for LinuxDriver {
     const TYPE: EnclaveType = EnclaveType::Driver;
@@ -1,8 +1,11 @@
 #![no_std]
 #![feature(never_type)]
 
+use channel::attest::NUM_RTMRS;
 use context::HartContext;
 use console::log;
+use lde::LinuxDriver;
+use lse::LinuxService;
 use lue::LinuxUser;
 use perf::PmpFaultRecord;
 use pma::Owner;
@@ -10,9 +13,14 @@ use riscv::register::satp;
 use spin::Mutex;
 use vm::{PAGE_SIZE, VirtMemArea, align_down, pm::PhysPageNum, vm::VirtAddr};
 
-use core::{fmt::Display, ptr::NonNull, sync::atomic::AtomicUsize};
+use core::{
+    fmt::Display,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 mod layout;
+mod lde;
 mod lse;
 mod lue;
 mod node;
@@ -21,16 +29,25 @@ pub mod prelude {
     // pub use crate::builder
 }
 
-// pub use lde::LinuxDriverEnclave;
 // pub use builder::{Builder, link_remain_frame};
-pub use layout::Layout;
+pub use layout::{LDE_KERNEL_VADDR, Layout};
+pub use lde::{LinuxDriverEnclave, LinuxDriverEnclaveList};
 pub use lse::{LinuxServiceEnclave, LinuxServiceEnclaveList};
-pub use lue::{LinuxUserEnclave, LinuxUserEnclaveList};
+pub use lue::{LinuxUserEnclave, LinuxUserEnclaveList, MAX_THREADS, Thread};
 pub use node::EncListNode;
 
 pub const DEFAULT_RT_START: usize = 0xFFFF_FFFF_8000_0000;
 pub const DEFAULT_BIN_START: usize = 0x20_0000_0000;
 pub const DEFAULT_BOOTARG_ADDR: usize = 0xFFFF_FFFF_7FF0_0000;
+
+pub const MEASUREMENT_SIZE: usize = 32;
+
+/// SHA-256 measurement of an enclave.
+pub type Measurement = [u8; MEASUREMENT_SIZE];
+
+/// [`Enclave::hart`] of an enclave that is not running.
+pub const NO_HART: usize = usize::MAX;
+
 pub trait EnclaveData {
     const TYPE: EnclaveType;
 }
@@ -42,6 +59,15 @@ impl EnclaveData for () {
 pub fn create_lue_at(addr: usize, eid: EnclaveId) -> &'static mut LinuxUserEnclave {
     let enc = Enclave::create_at(addr);
     enc.list.lock().value = EnclaveType::User;
+    enc.data = LinuxUser::new();
+    enc.nw_vma = enc.nw_vma.satp(satp::read());
+    enc.id = eid;
+    enc
+}
+
+pub fn create_lde_at(addr: usize, eid: EnclaveId) -> &'static mut LinuxDriverEnclave {
+    let enc = Enclave::create_at(addr);
+    enc.list.lock().value = EnclaveType::Driver;
     enc.nw_vma = enc.nw_vma.satp(satp::read());
     enc.id = eid;
     enc
@@ -67,6 +93,19 @@ pub struct Enclave<D: EnclaveData> {
 
     pub tp: usize,
 
+    /// The hart the enclave is running on, [`NO_HART`] if it is not running.
+    hart: AtomicUsize,
+
+    /// Launch measurement, fixed once the enclave is created.
+    pub measurement: Measurement,
+    /// SHA-256 of the signer public key, `None` if the enclave is unsigned.
+    pub signer: Option<Measurement>,
+    /// Whether the signer allows the enclave to be debugged.
+    pub debug: bool,
+    /// Runtime measurement registers, only extended by
+    /// `value = SHA256(value || digest)` after launch.
+    pub rtmrs: [Measurement; NUM_RTMRS],
+
     // Records
     // pub time_record: TimeRecord,
     pub pmp_record: PmpFaultRecord,
@@ -83,6 +122,11 @@ impl<D: EnclaveData> Enclave<D> {
         let enclave: &'static mut Self = unsafe { &mut *(addr as *mut Self) };
         enclave.list = Mutex::new(EncListNode::new(D::TYPE));
         enclave.data_ptr = &mut enclave.data as *mut _;
+        enclave.measurement = [0; MEASUREMENT_SIZE];
+        enclave.signer = None;
+        enclave.debug = false;
+        enclave.rtmrs = [[0; MEASUREMENT_SIZE]; NUM_RTMRS];
+        enclave.hart = AtomicUsize::new(NO_HART);
 
         enclave.pmp_record = PmpFaultRecord::empty();
 
@@ -114,6 +158,22 @@ impl<D: EnclaveData> Enclave<D> {
         self.id
     }
 
+    /// Mark the enclave running on `hart`.
+    ///
+    /// Fails with the hart it is already running on.
+    #[inline]
+    pub fn enter(&self, hart: usize) -> Result<(), usize> {
+        self.hart
+            .compare_exchange(NO_HART, hart, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+    }
+
+    /// Mark the enclave not running.
+    #[inline]
+    pub fn leave(&self) {
+        self.hart.store(NO_HART, Ordering::Release);
+    }
+
     #[inline]
     pub fn print_records(&mut self) {
         // log::info!("time record: {:#x}", self.time_record.end().get_data());
@@ -137,11 +197,48 @@ impl Enclave<()> {
     pub fn as_lue(&self) -> Option<&'static mut Enclave<LinuxUser>> {
         self.as_enc::<LinuxUser>()
     }
+
+    #[inline]
+    pub fn as_lse(&self) -> Option<&'static mut Enclave<LinuxService>> {
+        self.as_enc::<LinuxService>()
+    }
+
+    #[inline]
+    pub fn as_lde(&self) -> Option<&'static mut Enclave<LinuxDriver>> {
+        self.as_enc::<LinuxDriver>()
+    }
 }
 
 pub enum Error {
     InvalidEnclaveType = 1,
     InvalidEnclaveId = 2,
+    InvalidCaller = 3,
+    InvalidAddress = 4,
+    NoDeviceKey = 5,
+    NoRootSecret = 6,
+    InvalidPolicy = 7,
+    NoEntropy = 8,
+    InvalidReport = 9,
+    InvalidManifest = 10,
+    InvalidRegister = 11,
+    CounterExists = 12,
+    NoCounter = 13,
+    NoCounterSpace = 14,
+    InvalidCounters = 15,
+    NoCounterAnchor = 16,
+    EnclaveRunning = 17,
+    NoServiceEnclave = 18,
+    ServiceUnavailable = 19,
+    ChannelExists = 20,
+    NoChannel = 21,
+    NoChannelSpace = 22,
+    ChannelConnected = 23,
+    InvalidThread = 24,
+    EnclaveExited = 25,
+    MemoryLimitExceeded = 26,
+    TimeBudgetExceeded = 27,
+    InvalidSnapshot = 28,
+    EnclaveFault = 29,
 }
 
 impl Display for Error {
@@ -149,6 +246,33 @@ impl Display for Error {
         match self {
             Self::InvalidEnclaveType => write!(f, "Invalid enclave type"),
             Self::InvalidEnclaveId => write!(f, "Invalid enclave id"),
+            Self::InvalidCaller => write!(f, "Invalid caller"),
+            Self::InvalidAddress => write!(f, "Invalid address"),
+            Self::NoDeviceKey => write!(f, "No device key"),
+            Self::NoRootSecret => write!(f, "No root secret"),
+            Self::InvalidPolicy => write!(f, "Invalid policy"),
+            Self::NoEntropy => write!(f, "No entropy"),
+            Self::InvalidReport => write!(f, "Invalid report"),
+            Self::InvalidManifest => write!(f, "Invalid manifest"),
+            Self::InvalidRegister => write!(f, "Invalid measurement register"),
+            Self::CounterExists => write!(f, "Counter exists"),
+            Self::NoCounter => write!(f, "No such counter"),
+            Self::NoCounterSpace => write!(f, "No counter space"),
+            Self::InvalidCounters => write!(f, "Invalid counter table"),
+            Self::NoCounterAnchor => write!(f, "No counter anchor"),
+            Self::EnclaveRunning => write!(f, "Enclave is running"),
+            Self::NoServiceEnclave => write!(f, "No such service enclave"),
+            Self::ServiceUnavailable => write!(f, "Service is not available"),
+            Self::ChannelExists => write!(f, "Channel exists"),
+            Self::NoChannel => write!(f, "No such channel"),
+            Self::NoChannelSpace => write!(f, "No channel space"),
+            Self::ChannelConnected => write!(f, "Channel is connected"),
+            Self::InvalidThread => write!(f, "Invalid thread"),
+            Self::EnclaveExited => write!(f, "Enclave exited"),
+            Self::MemoryLimitExceeded => write!(f, "Memory limit exceeded"),
+            Self::TimeBudgetExceeded => write!(f, "Time budget exceeded"),
+            Self::InvalidSnapshot => write!(f, "Invalid snapshot"),
+            Self::EnclaveFault => write!(f, "Enclave faulted"),
         }
     }
 }
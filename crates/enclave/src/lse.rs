@@ -1,7 +1,8 @@
+use context::HartContext;
 use data_structure::linked_list::LinkedList;
 use vm::VirtMemArea;
 
-use crate::{Enclave, EnclaveData, EnclaveType};
+use crate::{Enclave, EnclaveData, EnclaveId, EnclaveType};
 
 pub type LinuxServiceEnclave = Enclave<LinuxService>;
 
@@ -19,19 +20,61 @@ impl LinuxServiceEnclaveList {
             .map(|ptr| unsafe { LinuxServiceEnclave::from_ptr(ptr) })
     }
 
+    pub fn get(&self, eid: EnclaveId) -> Option<&'static mut LinuxServiceEnclave> {
+        self.0.iter().find_map(|ptr| {
+            // Safety: the node is pushed by &mut LinuxServiceEnclave, thus it is valid
+            let lse = unsafe { LinuxServiceEnclave::from_ptr(ptr) };
+            if lse.id == eid { Some(lse) } else { None }
+        })
+    }
+
     pub fn push(&mut self, lse: &'static mut LinuxServiceEnclave) {
         debug_assert_eq!(lse.get_type(), EnclaveType::Service);
         self.0.push_node(&mut lse.list.lock());
     }
+
+    pub fn remove(&mut self, eid: EnclaveId) -> Option<&'static mut LinuxServiceEnclave> {
+        self.get(eid)
+            .and_then(|enc| self.0.rm_node(&mut enc.list.lock()))
+            .map(|node| unsafe { Enclave::from_ptr(node) })
+    }
 }
 
 pub struct LinuxService {
+    /// The runtime, in a page table of the monitor that maps only it.
     pub rt: VirtMemArea,
+    /// The first page of `rt`, which user enclaves also map at its physical
+    /// address.
     pub trampoline: VirtMemArea,
+
+    /// Offset of the entry in the runtime, 0 if the service is not callable.
+    pub entry: usize,
+    pub satp: usize,
+    pub stack_top: usize,
+    /// Physical address of the message page.
+    pub msg: usize,
+    /// Whether the host has launched the service.
+    pub launched: bool,
+
+    /// The caller of the running call, and its context to return to.
+    pub caller: EnclaveId,
+    pub caller_ctx: HartContext,
+    /// Address and size of the reply buffer of the caller.
+    pub reply: (usize, usize),
+    /// When the running call is cut short, in ticks of the machine timer.
+    pub deadline: u64,
+    /// Timer of the caller, given back as the call returns.
+    pub caller_timer: u64,
+    pub caller_timer_enabled: bool,
 }
 
 impl EnclaveData for LinuxService {
     const TYPE: EnclaveType = EnclaveType::Service;
 }
 
-impl LinuxService {}
+impl LinuxService {
+    #[inline]
+    pub fn is_callable(&self) -> bool {
+        self.entry != 0
+    }
+}
@@ -1,9 +1,13 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
 use data_structure::linked_list::LinkedList;
 use console::log;
 
 use context::HartContext;
+use perf::PmpFaultRecord;
+use vm::{PAGE_SIZE, VirtMemArea};
 
-use crate::{Enclave, EnclaveData, EnclaveType};
+use crate::{Enclave, EnclaveData, EnclaveType, Error, MEASUREMENT_SIZE, Measurement, NO_HART};
 
 use super::EnclaveId;
 
@@ -24,6 +28,13 @@ impl LinuxUserEnclaveList {
         })
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &'static mut LinuxUserEnclave> + '_ {
+        // Safety: the nodes are pushed by &mut LinuxUserEnclave, thus they are valid
+        self.0
+            .iter()
+            .map(|ptr| unsafe { LinuxUserEnclave::from_ptr(ptr) })
+    }
+
     pub fn push(&mut self, lue: &'static mut LinuxUserEnclave) {
         debug_assert_eq!(lue.get_type(), EnclaveType::User);
         self.0.push_node(&mut lue.list.lock());
@@ -45,14 +56,400 @@ impl LinuxUserEnclaveList {
     }
 }
 
-pub struct LinuxUser {
+/// The most threads of a LUE, the main one included.
+pub const MAX_THREADS: usize = 8;
+
+/// A thread of a LUE, which runs on one hart at a time.
+///
+/// Like a TCS of SGX, it is kept in a page of the enclave the enclave itself
+/// cannot access.
+pub struct Thread {
     pub enc_ctx: HartContext,
+    /// Context of the host that entered the thread.
+    pub nw_ctx: HartContext,
+    /// Context the thread goes back to once it relocates a restored runtime.
+    pub restored_ctx: HartContext,
     pub pmp_cache: pmp::Cache,
+    pub pmp_record: PmpFaultRecord,
+    pub switch_cycle: perf::CycleRecord,
+    pub launched: bool,
+    /// Set when the thread is stopped as its time slice is over, rather
+    /// than pausing by itself, so all of its registers are live.
+    pub preempted: bool,
+    /// Timer of the host that entered the thread, given back as it leaves.
+    pub nw_timer: u64,
+    pub nw_timer_enabled: bool,
+    /// When the thread was entered, in ticks of the machine timer.
+    pub entered_at: u64,
+    /// The hart the thread is running on, [`NO_HART`] if it is not running.
+    hart: AtomicUsize,
+}
+
+const _: () = assert!(size_of::<Thread>() <= PAGE_SIZE);
+
+impl Thread {
+    /// Create a thread in the page at `addr`, which is cleared first.
+    pub fn create_at(addr: usize) -> &'static mut Self {
+        debug_assert_eq!(addr & (PAGE_SIZE - 1), 0);
+        let thread = addr as *mut Self;
+        // SAFETY: the page is owned by the enclave being created, and every
+        // field of a thread is valid as zeros
+        let thread = unsafe {
+            thread.write_bytes(0, 1);
+            &mut *thread
+        };
+        thread.hart = AtomicUsize::new(NO_HART);
+        thread
+    }
+
+    #[inline]
+    pub fn is_running_on(&self, hart: usize) -> bool {
+        self.hart.load(Ordering::Acquire) == hart
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        !self.is_running_on(NO_HART)
+    }
+
+    /// The hart the thread is running on.
+    #[inline]
+    pub fn hart(&self) -> Option<usize> {
+        let hart = self.hart.load(Ordering::Acquire);
+        (hart != NO_HART).then_some(hart)
+    }
+}
+
+pub struct LinuxUser {
+    /// Pages of the threads, the main one first.
+    threads: [usize; MAX_THREADS],
+    num_threads: usize,
+    /// How many threads are running.
+    running: AtomicUsize,
+    /// Set when a thread exits, so the others are stopped and the last one
+    /// to leave cleans the enclave up.
+    pub exiting: AtomicBool,
+    /// Ticks of the machine timer a thread runs for before the host gets
+    /// the hart back, 0 for the default of the monitor.
+    pub time_slice: u64,
+    /// Ticks the threads may run for in all, 0 for no limit.
+    pub time_budget: u64,
+    /// Ticks the threads ran for so far.
+    cpu_time: AtomicU64,
+    /// Bytes of memory the manifest lets the enclave hold, 0 for no limit.
+    pub max_mem_size: usize,
+    /// Bytes of memory the enclave holds, the pages the host donated
+    /// included.
+    mem_size: AtomicUsize,
+    /// Pages the host donated in all.
+    donated: AtomicUsize,
+    /// Measurement of the service enclave providing the runtime, which a
+    /// snapshot is only restored on.
+    pub lse: Measurement,
+    /// Id of the service enclave whose frames the runtime is mapped from.
+    pub lse_id: EnclaveId,
+    /// Where the runtime and the shared memory are mapped, where they are
+    /// mapped again when a snapshot is restored.
+    pub rt: VirtMemArea,
+    pub share: VirtMemArea,
+    /// Entry of the runtime to relocate itself once restored, 0 if it cannot
+    /// be restored.
+    pub restore_entry: usize,
+    /// Physical address of the arguments of the relocation the runtime has
+    /// yet to do once restored, 0 if none.
+    relocation: AtomicUsize,
+    /// The thread doing the relocation, 0 if none.
+    relocator: AtomicUsize,
 
     pub pause_num: usize,
-    pub switch_cycle: perf::CycleRecord,
+}
+
+impl LinuxUser {
+    pub fn new() -> Self {
+        Self {
+            threads: [0; MAX_THREADS],
+            num_threads: 0,
+            running: AtomicUsize::new(0),
+            exiting: AtomicBool::new(false),
+            time_slice: 0,
+            time_budget: 0,
+            cpu_time: AtomicU64::new(0),
+            max_mem_size: 0,
+            mem_size: AtomicUsize::new(0),
+            donated: AtomicUsize::new(0),
+            lse: [0; MEASUREMENT_SIZE],
+            lse_id: EnclaveId::HOST,
+            rt: VirtMemArea::default(),
+            share: VirtMemArea::default(),
+            restore_entry: 0,
+            relocation: AtomicUsize::new(0),
+            relocator: AtomicUsize::new(0),
+            pause_num: 0,
+        }
+    }
+
+    /// Add a thread in the page at `addr`.
+    pub fn push_thread(&mut self, addr: usize) -> &'static mut Thread {
+        assert!(self.num_threads < MAX_THREADS);
+        self.threads[self.num_threads] = addr;
+        self.num_threads += 1;
+        Thread::create_at(addr)
+    }
+
+    pub fn thread(&self, tid: usize) -> Option<&'static mut Thread> {
+        self.threads[..self.num_threads]
+            .get(tid)
+            // SAFETY: the page is initialized by `push_thread`
+            .map(|&addr| unsafe { &mut *(addr as *mut Thread) })
+    }
+
+    pub fn threads(&self) -> impl Iterator<Item = &'static mut Thread> + '_ {
+        (0..self.num_threads).filter_map(|tid| self.thread(tid))
+    }
+
+    /// The thread running on `hart`.
+    pub fn thread_on(&self, hart: usize) -> Option<&'static mut Thread> {
+        self.threads().find(|thread| thread.is_running_on(hart))
+    }
+
+    /// Mark `thread` running on `hart`.
+    ///
+    /// Fails with the hart it is already running on.
+    pub fn enter(&self, thread: &Thread, hart: usize) -> Result<(), usize> {
+        thread
+            .hart
+            .compare_exchange(NO_HART, hart, Ordering::AcqRel, Ordering::Acquire)?;
+        self.running.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Mark `thread` not running, returning whether it was the last one
+    /// running.
+    pub fn leave(&self, thread: &Thread) -> bool {
+        thread.hart.store(NO_HART, Ordering::Release);
+        self.running.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst) != 0
+    }
+
+    /// Charge the enclave for `ticks` a thread ran for.
+    pub fn charge(&self, ticks: u64) {
+        self.cpu_time.fetch_add(ticks, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn cpu_time(&self) -> u64 {
+        self.cpu_time.load(Ordering::SeqCst)
+    }
+
+    /// Set the bytes of memory the enclave holds as it is created.
+    pub fn set_mem_size(&self, size: usize) {
+        self.mem_size.store(size, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn mem_size(&self) -> usize {
+        self.mem_size.load(Ordering::SeqCst)
+    }
+
+    /// Count `pages` the host donates, if the enclave may hold them.
+    pub fn donate(&self, pages: usize) -> Result<(), Error> {
+        let size = pages * PAGE_SIZE;
+        self.mem_size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |held| {
+                held.checked_add(size)
+                    .filter(|&held| self.max_mem_size == 0 || held <= self.max_mem_size)
+            })
+            .map_err(|_| Error::MemoryLimitExceeded)?;
+        self.donated.fetch_add(pages, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Take back the count of `pages` donated, which the enclave did not get.
+    pub fn undo_donate(&self, pages: usize) {
+        self.mem_size.fetch_sub(pages * PAGE_SIZE, Ordering::SeqCst);
+        self.donated.fetch_sub(pages, Ordering::SeqCst);
+    }
+
+    /// Count `pages` the enclave gives back to the host.
+    pub fn give_back(&self, pages: usize) {
+        let size = pages * PAGE_SIZE;
+        let _ = self
+            .mem_size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |held| {
+                Some(held.saturating_sub(size))
+            });
+    }
+
+    /// Pages the host donated in all.
+    #[inline]
+    pub fn donated(&self) -> usize {
+        self.donated.load(Ordering::SeqCst)
+    }
+
+    /// Have the runtime relocate itself with the arguments at `args` before
+    /// any thread goes on.
+    pub fn set_relocation(&self, args: usize) {
+        self.relocation.store(args, Ordering::SeqCst);
+    }
+
+    /// The arguments of the relocation `thread` is to do, if there is one no
+    /// thread has started.
+    ///
+    /// Fails if another thread is doing it.
+    pub fn claim_relocation(&self, thread: &Thread) -> Result<Option<usize>, Error> {
+        let args = self.relocation.load(Ordering::SeqCst);
+        if args == 0 {
+            return Ok(None);
+        }
+        let addr = thread as *const Thread as usize;
+        match self
+            .relocator
+            .compare_exchange(0, addr, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(Some(args)),
+            // preempted while relocating, so it goes on from where it was
+            Err(relocator) if relocator == addr => Ok(None),
+            Err(_) => Err(Error::EnclaveRunning),
+        }
+    }
+
+    /// Mark the relocation done, if `thread` is doing it.
+    pub fn finish_relocation(&self, thread: &Thread) -> bool {
+        let addr = thread as *const Thread as usize;
+        if self.relocator.load(Ordering::Acquire) != addr {
+            return false;
+        }
+        self.relocation.store(0, Ordering::SeqCst);
+        self.relocator.store(0, Ordering::Release);
+        true
+    }
+
+    /// Whether the runtime has yet to relocate itself once restored.
+    #[inline]
+    pub fn is_relocating(&self) -> bool {
+        self.relocation.load(Ordering::SeqCst) != 0
+    }
+
+    /// Whether the threads ran for all of the time budget.
+    #[inline]
+    pub fn out_of_budget(&self) -> bool {
+        self.time_budget != 0 && self.cpu_time() >= self.time_budget
+    }
 }
 
 impl EnclaveData for LinuxUser {
     const TYPE: EnclaveType = EnclaveType::User;
 }
+
+#[cfg(test)]
+mod test {
+    use vm::PAGE_SIZE;
+
+    use super::{LinuxUser, Thread};
+    use crate::Error;
+
+    #[repr(C, align(0x1000))]
+    struct Page([u8; PAGE_SIZE]);
+
+    fn lue() -> LinuxUser {
+        // SAFETY: every field is valid as zeros, as in the cleared page an
+        // enclave is created in, while `LinuxUser::new` reads satp
+        unsafe { core::mem::zeroed() }
+    }
+
+    fn push_threads(lue: &mut LinuxUser, pages: &mut [Page; 2]) -> [&'static mut Thread; 2] {
+        pages
+            .each_mut()
+            .map(|page| lue.push_thread(page.0.as_mut_ptr() as usize))
+    }
+
+    #[test]
+    pub fn test_thread_enter_leave() {
+        let mut lue = lue();
+        let mut pages = [Page([0; PAGE_SIZE]), Page([0; PAGE_SIZE])];
+        let [main, other] = push_threads(&mut lue, &mut pages);
+
+        assert!(lue.enter(main, 0).is_ok());
+        assert_eq!(lue.enter(main, 1), Err(0));
+        assert!(lue.enter(other, 1).is_ok());
+        assert!(
+            lue.thread_on(1)
+                .is_some_and(|thread| core::ptr::eq(thread, other))
+        );
+
+        assert!(!lue.leave(main));
+        assert!(lue.is_running());
+        assert!(lue.leave(other));
+        assert!(!lue.is_running());
+        assert_eq!(main.hart(), None);
+        assert!(lue.thread_on(1).is_none());
+    }
+
+    #[test]
+    pub fn test_donate_limit() {
+        let mut lue = lue();
+        lue.max_mem_size = 4 * PAGE_SIZE;
+        lue.set_mem_size(2 * PAGE_SIZE);
+
+        assert!(lue.donate(2).is_ok());
+        assert!(matches!(lue.donate(1), Err(Error::MemoryLimitExceeded)));
+        assert_eq!(lue.mem_size(), 4 * PAGE_SIZE);
+        assert_eq!(lue.donated(), 2);
+
+        lue.give_back(3);
+        assert_eq!(lue.mem_size(), PAGE_SIZE);
+        assert!(lue.donate(3).is_ok());
+        lue.undo_donate(3);
+        assert_eq!((lue.mem_size(), lue.donated()), (PAGE_SIZE, 2));
+
+        // no limit, but what the enclave holds never wraps
+        lue.max_mem_size = 0;
+        assert!(lue.donate(8).is_ok());
+        assert!(matches!(
+            lue.donate(usize::MAX / PAGE_SIZE),
+            Err(Error::MemoryLimitExceeded)
+        ));
+    }
+
+    #[test]
+    pub fn test_budget() {
+        let mut lue = lue();
+        lue.charge(100);
+        // no budget, no limit
+        assert!(!lue.out_of_budget());
+
+        lue.time_budget = 250;
+        lue.charge(100);
+        assert_eq!(lue.cpu_time(), 200);
+        assert!(!lue.out_of_budget());
+        lue.charge(50);
+        assert!(lue.out_of_budget());
+    }
+
+    #[test]
+    pub fn test_relocation() {
+        let mut lue = lue();
+        let mut pages = [Page([0; PAGE_SIZE]), Page([0; PAGE_SIZE])];
+        let [main, other] = push_threads(&mut lue, &mut pages);
+        assert!(matches!(lue.claim_relocation(main), Ok(None)));
+
+        lue.set_relocation(0x1000);
+        assert!(lue.is_relocating());
+        assert!(matches!(lue.claim_relocation(main), Ok(Some(0x1000))));
+        // preempted while relocating, it goes on from where it was
+        assert!(matches!(lue.claim_relocation(main), Ok(None)));
+        assert!(matches!(
+            lue.claim_relocation(other),
+            Err(Error::EnclaveRunning)
+        ));
+
+        assert!(!lue.finish_relocation(other));
+        assert!(lue.finish_relocation(main));
+        assert!(!lue.is_relocating());
+        assert!(matches!(lue.claim_relocation(other), Ok(None)));
+    }
+}
@@ -1,9 +1,9 @@
 #![no_std]
 
-use core::{cell::UnsafeCell, ptr::NonNull};
+use core::{cell::UnsafeCell, ops::Range, ptr::NonNull};
 
 use heapless::Vec;
-use pmp::{MAX_PMP_COUNT, PmpBuf, PmpHelper};
+use pmp::{MAX_PMP_COUNT, PMP_COUNT, PmpBuf, PmpHelper};
 use riscv::{
     asm::sfence_vma_all,
     register::{mhartid, mstatus},
@@ -17,6 +17,7 @@ const DEFAULT_HART: UnsafeHartState = UnsafeHartState::new();
 // static ALL_HARTS: [UnsafeHartState; MAX_HART_NUM] = [DEFAULT_HART; MAX_HART_NUM];
 
 const EMPTY_OP: Mutex<HartStateOps> = Mutex::new(HartStateOps::empty());
+const EMPTY_REGIONS: Mutex<PmpRegions> = Mutex::new(Vec::new());
 // static OPS: [Mutex<HartStateOps>; MAX_HART_NUM] = [EMPTY_OP; MAX_HART_NUM];
 
 // static HART_NUM: Once<usize> = Once::new();
@@ -29,10 +30,16 @@ pub const MSTATUS_SPP: usize = 0x1 << 8;
 pub const MSTATUS_MPP_SHIFT: usize = 11;
 pub const MSTATUS_MPP: usize = 0x2 << MSTATUS_MPP_SHIFT;
 
+/// Regions the pmp entries of a hart cover.
+type PmpRegions = Vec<Range<usize>, PMP_COUNT>;
+
 pub struct Hsm {
     hart_num: usize,
     all_harts: [UnsafeHartState; MAX_HART_NUM],
     ops: [Mutex<HartStateOps>; MAX_HART_NUM],
+    /// What each hart may access through its pmp entries, so that only the
+    /// harts covering a changed area are asked to clean them.
+    pmp_regions: [Mutex<PmpRegions>; MAX_HART_NUM],
 }
 
 impl Hsm {
@@ -41,6 +48,7 @@ impl Hsm {
             hart_num,
             all_harts: [DEFAULT_HART; MAX_HART_NUM],
             ops: [EMPTY_OP; MAX_HART_NUM],
+            pmp_regions: [EMPTY_REGIONS; MAX_HART_NUM],
         }
     }
 
@@ -60,6 +68,15 @@ impl Hsm {
         self.ops[id].lock().clone()
     }
 
+    /// Add `ops` to those pending on hart `id`, under one lock so none sent
+    /// by another hart meanwhile is lost.
+    pub fn or_ops(&self, id: usize, ops: HartStateOps) {
+        let pending = &mut *self.ops[id].lock();
+        pending.clean_pmp |= ops.clean_pmp;
+        pending.revoke_pmp |= ops.revoke_pmp;
+        pending.stop_enclave |= ops.stop_enclave;
+    }
+
     #[inline]
     pub fn take_op(&self) -> HartStateOps {
         let op = &mut *self.ops[mhartid::read()].lock();
@@ -70,6 +87,52 @@ impl Hsm {
         self.hart_num
     }
 
+    /// Write the pmp entries of the current hart by `flush`, and record the
+    /// regions they cover.
+    ///
+    /// The record is locked while the entries are written, so a hart reading
+    /// it in [`Self::harts_covering`] sees the entries that may be in use.
+    #[inline]
+    pub fn update_pmp(&self, flush: impl FnOnce()) {
+        let mut regions = self.pmp_regions[mhartid::read()].lock();
+        flush();
+        regions.clear();
+        for pmp in pmp::iter_hps().filter(|pmp| !pmp.is_off()) {
+            regions.push(pmp.get_region()).unwrap();
+        }
+    }
+
+    /// Clean the pmp entries of the current hart.
+    #[inline]
+    pub fn clean_pmp(&self) {
+        self.update_pmp(pmp::reset_pmp_registers);
+    }
+
+    /// Harts other than the current one whose pmp entries cover part of any
+    /// of `dirty`.
+    pub fn harts_covering<'a>(
+        &'a self,
+        dirty: &'a [Range<usize>],
+    ) -> impl Iterator<Item = usize> + 'a {
+        self.harts_covering_from(mhartid::read(), dirty)
+    }
+
+    /// [`Self::harts_covering`], asked by hart `current`.
+    fn harts_covering_from<'a>(
+        &'a self,
+        current: usize,
+        dirty: &'a [Range<usize>],
+    ) -> impl Iterator<Item = usize> + 'a {
+        (0..self.hart_num).filter(move |&i| {
+            i != current
+                && self.pmp_regions[i].lock().iter().any(|region| {
+                    dirty
+                        .iter()
+                        .any(|d| d.start < region.end && region.start < d.end)
+                })
+        })
+    }
+
     #[inline]
     pub unsafe fn iter_hs_mut(&mut self) -> impl Iterator<Item = &mut HartState> {
         self.all_harts.iter_mut().map(|hs| hs.as_mut())
@@ -170,6 +233,10 @@ impl HartState {
 #[derive(Clone)]
 pub struct HartStateOps {
     pub clean_pmp: bool,
+    /// Clean the pmp in enclaves too.
+    pub revoke_pmp: bool,
+    /// Leave the exiting user enclave running on the hart.
+    pub stop_enclave: bool,
 }
 
 impl Default for HartStateOps {
@@ -180,7 +247,11 @@ impl Default for HartStateOps {
 
 impl HartStateOps {
     pub const fn empty() -> Self {
-        Self { clean_pmp: false }
+        Self {
+            clean_pmp: false,
+            revoke_pmp: false,
+            stop_enclave: false,
+        }
     }
 }
 
@@ -212,3 +283,30 @@ impl Buffer {
         Self { pmp: Vec::new() }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use core::ops::Range;
+
+    use heapless::Vec;
+
+    use super::{Hsm, MAX_HART_NUM};
+
+    fn covering(hsm: &Hsm, current: usize, dirty: &[Range<usize>]) -> Vec<usize, MAX_HART_NUM> {
+        hsm.harts_covering_from(current, dirty).collect()
+    }
+
+    #[test]
+    pub fn test_harts_covering() {
+        let hsm = Hsm::new(4);
+        hsm.pmp_regions[1].lock().push(0x1000..0x2000).unwrap();
+        *hsm.pmp_regions[2].lock() = Vec::from_slice(&[0x3000..0x4000, 0x8000..0x9000]).unwrap();
+
+        assert_eq!(covering(&hsm, 0, &[0x1800..0x1900]), [1]);
+        assert_eq!(covering(&hsm, 0, &[0x8fff..0xa000]), [2]);
+        assert!(covering(&hsm, 0, &[0x0..0x1000, 0x2000..0x3000]).is_empty());
+        assert_eq!(covering(&hsm, 0, &[0x1000..0x9000]), [1, 2]);
+        // the asking hart cleans its own entries
+        assert_eq!(covering(&hsm, 2, &[0x1000..0x9000]), [1]);
+    }
+}
@@ -3,9 +3,24 @@
 use core::ops::Range;
 
 use console::println;
+use device::device::DeviceInfo;
 use macros::usize_env_or;
 use riscv::register::Permission;
 
+/// Monotonic storage of the version of the counter table, which survives a
+/// restart and which the host cannot write.
+///
+/// The monitor stores every new version before the counters change, and only
+/// takes back the counter blob of the version stored last.
+pub trait CounterAnchor: Sync {
+    /// The version stored last, 0 if none is.
+    fn load(&self) -> u64;
+
+    /// Store `version`, which is larger than any stored before. Returns
+    /// whether it is stored.
+    fn store(&self, version: u64) -> bool;
+}
+
 pub trait Platform {
     const PMP_COUNT: usize = usize_env_or!("PMP_COUNT", 16);
     const SBI_START: usize = usize_env_or!("FW_TEXT_START", 0x0);
@@ -24,10 +39,10 @@ pub trait Platform {
         pmp::PMP_COUNT
     }
 
-    fn get_sbi_region(&self) -> Range<usize> {
+    /// Text and read-only data of the firmware, which never change after boot.
+    fn get_sbi_text_region(&self) -> Range<usize> {
         let sbi_start = Self::SBI_START;
         let mut sbi_text_end = 0;
-        let mut sbi_rw_end = 0;
         for pmp in pmp::iter_hps() {
             if pmp.is_off() {
                 continue;
@@ -38,6 +53,14 @@ pub trait Platform {
             }
         }
 
+        sbi_start..sbi_text_end
+    }
+
+    fn get_sbi_region(&self) -> Range<usize> {
+        let sbi_start = Self::SBI_START;
+        let sbi_text_end = self.get_sbi_text_region().end;
+        let mut sbi_rw_end = 0;
+
         for pmp in pmp::iter_hps() {
             if pmp.is_off() {
                 continue;
@@ -66,6 +89,38 @@ pub trait Platform {
         heap_region.end..(heap_region.end + Self::SM_RW_SIZE)
     }
 
+    /// Seed of the Ed25519 device key, which signs attestation reports.
+    ///
+    /// Taken from `/chosen/lattice,device-key` by default. Platforms with a
+    /// key in fuses or a secure element should override it.
+    fn get_device_key(&self, device: &mut DeviceInfo) -> Option<[u8; 32]> {
+        device.take_chosen_secret("lattice,device-key")
+    }
+
+    /// Root secret of the platform, from which sealing keys are derived.
+    ///
+    /// Taken from `/chosen/lattice,root-secret` by default.
+    fn get_root_secret(&self, device: &mut DeviceInfo) -> Option<[u8; 32]> {
+        device.take_chosen_secret("lattice,root-secret")
+    }
+
+    /// Seed of the random number generator on harts without Zkr.
+    ///
+    /// Taken from `/chosen/lattice,rng-seed` by default, which should be
+    /// filled by the previous boot stage on every boot.
+    fn get_rng_seed(&self, device: &mut DeviceInfo) -> Option<[u8; 32]> {
+        device.take_chosen_secret("lattice,rng-seed")
+    }
+
+    /// Storage of the version of the counter table, kept across restarts.
+    ///
+    /// None by default. Platforms with storage the host cannot write, like a
+    /// replay protected memory block, should override it, as the monitor
+    /// refuses the counters without it.
+    fn get_counter_anchor(&self, _device: &mut DeviceInfo) -> Option<&'static dyn CounterAnchor> {
+        None
+    }
+
     #[inline]
     fn get_hart_num(&self) -> usize {
         let mut hart_num = 0;
@@ -6,12 +6,16 @@ mod rbtree_ext;
 use core::{fmt::Display, ops::Range};
 
 use console::log;
+use heapless::Vec;
 use nostd_rbtree::{NodePtr, RBTree, RBTreeAllocator, node_size};
 use rbtree_ext::PmaExt;
 use vm::{BarePtReader, Translate, VirtMemArea};
 
 pub use prop::{Owner, PmaProp};
 
+/// Changed regions a [`PhysMemAreaMgr`] keeps apart, before they are merged.
+pub const MAX_DIRTY_REGIONS: usize = 16;
+
 #[derive(Debug)]
 pub enum Error {
     SizeOverflow,
@@ -60,6 +64,8 @@ impl Display for PhysMemArea {
 
 pub struct PhysMemAreaMgr {
     mtree: RBTree<usize, PmaInfo>,
+    /// Regions whose property changed since [`Self::take_dirty`].
+    dirty: Vec<Range<usize>, MAX_DIRTY_REGIONS>,
 }
 
 impl PhysMemAreaMgr {
@@ -71,6 +77,7 @@ impl PhysMemAreaMgr {
         let allocator = RBTreeAllocator::new(&mut [0_u8]);
         let mgr = Self {
             mtree: RBTree::new(allocator),
+            dirty: Vec::new(),
         };
 
         mgr
@@ -81,6 +88,7 @@ impl PhysMemAreaMgr {
         let allocator = RBTreeAllocator::new(mem_pool);
         let mgr = Self {
             mtree: RBTree::new(allocator),
+            dirty: Vec::new(),
         };
         mgr
     }
@@ -106,6 +114,7 @@ impl PhysMemAreaMgr {
     }
 
     pub fn insert_pma(&mut self, pma: PhysMemArea) -> Result<(), Error> {
+        self.mark_dirty(pma.get_region());
         if self.mtree.is_empty() {
             self.mtree.insert(pma.region.start, PmaInfo {
                 size: pma.region.end - pma.region.start,
@@ -145,6 +154,26 @@ impl PhysMemAreaMgr {
         Ok(())
     }
 
+    /// Take the regions whose property changed since the last call, which
+    /// the pmp entries of any hart may still cover.
+    ///
+    /// Adjacent regions are merged, and so are the last ones when there are
+    /// too many, so more may be returned than what changed, never less.
+    pub fn take_dirty(&mut self) -> Vec<Range<usize>, MAX_DIRTY_REGIONS> {
+        core::mem::take(&mut self.dirty)
+    }
+
+    fn mark_dirty(&mut self, region: Range<usize>) {
+        let full = self.dirty.is_full();
+        match self.dirty.last_mut() {
+            Some(last) if full || (region.start <= last.end && last.start <= region.end) => {
+                last.start = last.start.min(region.start);
+                last.end = last.end.max(region.end);
+            }
+            _ => self.dirty.push(region).unwrap(),
+        }
+    }
+
     pub fn insert_page(&mut self, paddr: impl Into<usize>, prop: PmaProp) {
         let paddr = paddr.into();
         let pma = PhysMemArea {
@@ -292,3 +321,55 @@ fn rbtree_merge_node(
     tree.remove(right_k);
     return Some(left);
 }
+
+#[cfg(test)]
+mod test {
+    use riscv::register::Permission;
+
+    use super::{MAX_DIRTY_REGIONS, Owner, PhysMemArea, PhysMemAreaMgr, PmaProp};
+
+    const PAGE: usize = 0x1000;
+
+    #[test]
+    pub fn test_take_dirty() {
+        let mut pool = [0_u8; 0x4000];
+        let mut mgr = PhysMemAreaMgr::new(&mut pool);
+        mgr.insert_pma(PhysMemArea {
+            region: 0..0x100 * PAGE,
+            prop: PmaProp::default(),
+        })
+        .unwrap();
+        mgr.take_dirty();
+
+        let prop = PmaProp::empty().owner(Owner(2)).permission(Permission::RWX);
+        mgr.insert_page(PAGE, prop);
+        mgr.insert_page(2 * PAGE, prop);
+        mgr.insert_page(8 * PAGE, prop);
+        // adjacent regions are merged, the others kept apart
+        assert_eq!(mgr.take_dirty(), [PAGE..3 * PAGE, 8 * PAGE..9 * PAGE]);
+        assert!(mgr.take_dirty().is_empty());
+    }
+
+    #[test]
+    pub fn test_take_dirty_full() {
+        let mut pool = [0_u8; 0x8000];
+        let mut mgr = PhysMemAreaMgr::new(&mut pool);
+        mgr.insert_pma(PhysMemArea {
+            region: 0..0x100 * PAGE,
+            prop: PmaProp::default(),
+        })
+        .unwrap();
+        mgr.take_dirty();
+
+        let prop = PmaProp::empty().owner(Owner(2)).permission(Permission::RWX);
+        for i in 0..MAX_DIRTY_REGIONS + 2 {
+            mgr.insert_page(2 * i * PAGE, prop);
+        }
+        // the last ones are merged rather than lost
+        let dirty = mgr.take_dirty();
+        assert_eq!(dirty.len(), MAX_DIRTY_REGIONS);
+        assert_eq!(dirty[0], 0..PAGE);
+        let last = 2 * (MAX_DIRTY_REGIONS - 1) * PAGE;
+        assert_eq!(dirty[MAX_DIRTY_REGIONS - 1], last..last + 5 * PAGE);
+    }
+}
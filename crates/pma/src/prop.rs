@@ -36,6 +36,13 @@ impl Display for Owner {
     }
 }
 
+/// Bit of a [`PmaProp`] marking an area pending assignment.
+const PENDING_BIT: usize = 63;
+
+/// Bit of a pending [`PmaProp`] marking an area its owner shares with
+/// everyone once it is created.
+const SHARED_BIT: usize = 62;
+
 /// 描述了一块PMA区域的所有者以及所有者拥有的权限
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PmaProp {
@@ -55,7 +62,7 @@ impl PmaProp {
 
     #[inline(always)]
     pub fn owner(mut self, owner: impl Into<Owner>) -> Self {
-        self.bits.set_bits(3..64, owner.into().0);
+        self.bits.set_bits(3..SHARED_BIT, owner.into().0);
         self
     }
 
@@ -76,9 +83,35 @@ impl PmaProp {
         self
     }
 
+    /// Mark the area taken for its owner, an enclave being created, until it
+    /// is given its property.
+    #[inline(always)]
+    pub fn pending(mut self) -> Self {
+        self.bits.set_bit(PENDING_BIT, true);
+        self
+    }
+
+    #[inline(always)]
+    pub fn is_pending(&self) -> bool {
+        self.bits.get_bit(PENDING_BIT)
+    }
+
+    /// Mark a pending area to be shared with everyone, with its permission,
+    /// once its owner is created.
+    #[inline(always)]
+    pub fn shared(mut self) -> Self {
+        self.bits.set_bit(SHARED_BIT, true);
+        self
+    }
+
+    #[inline(always)]
+    pub fn is_shared(&self) -> bool {
+        self.bits.get_bit(SHARED_BIT)
+    }
+
     #[inline(always)]
     pub fn get_owner(&self) -> Owner {
-        Owner(self.bits.get_bits(3..64))
+        Owner(self.bits.get_bits(3..SHARED_BIT))
     }
 
     #[inline(always)]
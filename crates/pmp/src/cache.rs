@@ -19,6 +19,11 @@ impl Cache {
             unsafe { self.entries.flush() };
         }
     }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entries.0.clear();
+    }
 }
 
 pub struct NwCache {
@@ -65,6 +65,9 @@ pub mod stval;
 // Supervisor Protection and Translation
 pub mod satp;
 
+// Entropy Source (Zkr)
+pub mod seed;
+
 // Machine Information Registers
 pub mod marchid;
 pub mod mhartid;
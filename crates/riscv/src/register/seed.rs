@@ -0,0 +1,65 @@
+//! seed register (Zkr)
+
+/// seed register
+#[derive(Clone, Copy, Debug)]
+pub struct Seed {
+    bits: usize,
+}
+
+/// Status of the entropy source
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opst {
+    /// Built-in self-test is running
+    Bist = 0b00,
+    /// No entropy is available yet
+    Wait = 0b01,
+    /// 16 bits of entropy are in `entropy`
+    Es16 = 0b10,
+    /// Unrecoverable self-test error
+    Dead = 0b11,
+}
+
+impl Seed {
+    /// Returns the contents of the register as raw bits
+    #[inline]
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// Status of the entropy source
+    #[inline]
+    pub fn opst(&self) -> Opst {
+        match (self.bits >> 30) & 0b11 {
+            0b00 => Opst::Bist,
+            0b01 => Opst::Wait,
+            0b10 => Opst::Es16,
+            _ => Opst::Dead,
+        }
+    }
+
+    /// 16 bits of entropy, only valid if `opst` is `Es16`
+    #[inline]
+    pub fn entropy(&self) -> u16 {
+        self.bits as u16
+    }
+}
+
+/// Reads the CSR
+///
+/// The seed CSR must be accessed with a read-write instruction, reading it
+/// with `csrrs` raises an illegal instruction exception.
+/// The hart must implement Zkr.
+#[inline]
+pub unsafe fn read() -> Seed {
+    match () {
+        #[cfg(riscv)]
+        () => {
+            let r: usize;
+            core::arch::asm!("csrrw {0}, 0x015, x0", out(reg) r);
+            Seed { bits: r }
+        }
+
+        #[cfg(not(riscv))]
+        () => unimplemented!(),
+    }
+}
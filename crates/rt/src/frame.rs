@@ -51,6 +51,16 @@ impl PhysMemMgr {
         *size_lock.deref_mut() += 0x1000;
     }
 
+    /// Add the frames linked from `head` like the free list, which the host
+    /// donates through the security monitor.
+    pub fn add_frames(&self, mut head: usize) {
+        while head != 0 {
+            let next = unsafe { self.readp(head) };
+            self.add_frame(PhysPageNum::from_paddr(head));
+            head = next;
+        }
+    }
+
     /// 获取一个空闲帧
     pub fn get_free_frame(&self) -> Option<PhysPageNum> {
         let mut lock = self.head.lock();
@@ -74,6 +84,29 @@ impl PhysMemMgr {
         RtFrameAlloc(NonNull::from(self))
     }
 
+    /// The first frame of the free list.
+    pub fn head(&self) -> usize {
+        *self.head.lock()
+    }
+
+    /// Move the trampoline and the free list to where the enclave was
+    /// restored, by the new address `relocate` gives of each old one.
+    pub fn relocate(&mut self, trampoline: usize, relocate: impl Fn(usize) -> usize) {
+        self.trampoline = Trampoline::create(trampoline);
+        let mut head = self.head.lock();
+        if *head != 0 {
+            *head = relocate(*head);
+        }
+        let mut frame = *head;
+        while frame != 0 {
+            // the frame moved with the old address of the next one in it
+            let next = unsafe { self.readp(frame) };
+            let next = if next == 0 { 0 } else { relocate(next) };
+            unsafe { self.writep(frame, next) };
+            frame = next;
+        }
+    }
+
     pub fn clone_trampoline(&self) -> Trampoline {
         self.trampoline.clone()
     }
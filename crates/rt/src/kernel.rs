@@ -36,7 +36,7 @@ use crate::{
     scratch::switch_scratch,
     scratch::ScratchManager,
     stack::StackEnv,
-    syscall::RandGenerator,
+    syscall::fill_random,
     task::Task,
     trap::trap_to_process,
     Result, Scratch,
@@ -291,9 +291,10 @@ impl LinuxUserKernel {
         // const AT_SYSINFO: usize = 32;
 
         let sp = TASK_STACK_TOP - core::mem::size_of::<StackEnv>();
-        let mut rand = RandGenerator::new(sp);
-        let r1 = rand.next_u64();
-        let r2 = rand.next_u64();
+        let mut rand = [0u8; 16];
+        fill_random(&mut rand).expect("no entropy for AT_RANDOM");
+        let r1 = u64::from_ne_bytes(rand[..8].try_into().unwrap());
+        let r2 = u64::from_ne_bytes(rand[8..].try_into().unwrap());
 
         let usr_env = StackEnv {
             argc: 0,
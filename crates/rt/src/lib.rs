@@ -20,6 +20,7 @@ mod ldesym;
 mod loader;
 pub mod macros;
 pub mod pt;
+pub mod restore;
 mod scratch;
 mod stack;
 pub mod syscall;
@@ -0,0 +1,226 @@
+use core::mem::size_of;
+
+use channel::enclave::runtime::{LueRestoreArgs, RESTORE_INDEX_FRAMES};
+use riscv::register::satp;
+use vm::{
+    mm::SV39,
+    page_table::{PTEFlags, PageTableEntry},
+    pm::{PhysAddr, PhysPageNum},
+    vm::{Sv39VmMgr, VirtMemMgr, VirtPageNum},
+};
+
+use crate::{
+    consts::{LUE_KERNEL_VADDR, MAX_HART_NUM, PAGE_SIZE},
+    frame::{PhysMemMgr, RtFrameAlloc},
+    kernel::LinuxUserKernel,
+    log,
+    pt::RtPtWriter,
+    syscall::sbi_finish_restore,
+    trampoline::Trampoline,
+    Scratch,
+};
+
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+const PTES: usize = PAGE_SIZE / size_of::<PageTableEntry>();
+const PER_INDEX: usize = PAGE_SIZE / size_of::<usize>();
+
+/// Where the pages of a restored enclave moved, by the indexes the security
+/// monitor gives.
+struct Relocation {
+    trampoline: Trampoline,
+    pages: usize,
+    old: [usize; RESTORE_INDEX_FRAMES],
+    new: [usize; RESTORE_INDEX_FRAMES],
+}
+
+impl Relocation {
+    unsafe fn entry(&self, index: &[usize; RESTORE_INDEX_FRAMES], i: usize) -> usize {
+        self.trampoline
+            .readp(index[i / PER_INDEX] + i % PER_INDEX * size_of::<usize>())
+    }
+
+    /// The new physical address of `paddr`, if it was of the enclave.
+    fn get(&self, paddr: usize) -> Option<usize> {
+        let page = paddr & !(PAGE_SIZE - 1);
+        let (mut low, mut high) = (0, self.pages);
+        // the old addresses are in ascending order
+        while low < high {
+            let mid = (low + high) / 2;
+            let old = unsafe { self.entry(&self.old, mid) };
+            if old == page {
+                return Some(unsafe { self.entry(&self.new, mid) } + paddr % PAGE_SIZE);
+            } else if old < page {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        None
+    }
+
+    /// Point the entries of the page table at `table`, which moved with the
+    /// old addresses in it, and of the tables below it to where the pages are
+    /// now. The pages not of the enclave are found where `current` maps the
+    /// same address, or unmapped.
+    unsafe fn relocate_table(
+        &self,
+        table: usize,
+        level: usize,
+        vaddr: usize,
+        current: &Sv39VmMgr<RtPtWriter, RtFrameAlloc>,
+    ) {
+        for idx in 0..PTES {
+            let addr = table + idx * size_of::<PageTableEntry>();
+            let pte = PageTableEntry::from_bits(self.trampoline.readp(addr));
+            if !pte.is_valid() {
+                continue;
+            }
+            let vaddr = vaddr | idx << (12 + 9 * level);
+            let paddr = self.get(pte.get_addr()).or_else(|| {
+                // sign extended like Sv39 wants
+                let vaddr = ((vaddr << 25) as isize >> 25) as usize;
+                pte.is_leaf()
+                    .then(|| current.translate(VirtPageNum::from_vaddr(vaddr)))
+                    .flatten()
+                    .map(|ppn| PhysAddr::from_ppn(ppn).into())
+            });
+            let Some(paddr) = paddr else {
+                self.trampoline.writep(addr, 0usize);
+                continue;
+            };
+            let new = PageTableEntry::new(PhysPageNum::from_paddr(paddr), pte.get_flags());
+            self.trampoline.writep(addr, new);
+            if !pte.is_leaf() && level != 0 {
+                self.relocate_table(paddr, level - 1, vaddr, current);
+            }
+        }
+    }
+
+    /// Give the page table at `table` and the tables below it, which moved
+    /// with the old addresses in them, to `pmm`.
+    unsafe fn release_table(&self, table: usize, level: usize, pmm: &PhysMemMgr) {
+        if level != 0 {
+            for idx in 0..PTES {
+                let addr = table + idx * size_of::<PageTableEntry>();
+                let pte = PageTableEntry::from_bits(self.trampoline.readp(addr));
+                if pte.is_valid() && !pte.is_leaf() {
+                    if let Some(child) = self.get(pte.get_addr()) {
+                        self.release_table(child, level - 1, pmm);
+                    }
+                }
+            }
+        }
+        pmm.add_frame(PhysPageNum::from_paddr(table));
+    }
+}
+
+/// Relocate the runtime of a restored user enclave, and go back to where the
+/// thread was.
+///
+/// The security monitor rebuilt the address space the thread was in, at the
+/// current `satp`, and moved every other page of the enclave. `tp` is where
+/// the trampoline is now, and `args_addr` the physical address of the
+/// [`LueRestoreArgs`].
+pub unsafe fn relocate(tp: usize, args_addr: usize) -> ! {
+    let trampoline = Trampoline::create(tp);
+    let mut words = [0usize; size_of::<LueRestoreArgs>() / size_of::<usize>()];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = trampoline.readp(args_addr + i * size_of::<usize>());
+    }
+    let args: LueRestoreArgs = core::mem::transmute(words);
+    let reloc = Relocation {
+        trampoline: trampoline.clone(),
+        pages: args.pages,
+        old: args.old,
+        new: args.new,
+    };
+
+    // the spare frames take the tables the trampoline needs in the other space
+    let mut head = 0;
+    for &frame in args.spare.iter().rev() {
+        trampoline.writep(frame, head);
+        head = frame;
+    }
+    let spare = PhysMemMgr::new(head, args.spare.len() * PAGE_SIZE, tp);
+    let current = Sv39VmMgr::from_reg(
+        RtPtWriter::new(spare.clone_trampoline()),
+        spare.spawn_allocator(),
+    );
+    let current_root = current.root_ppn.0;
+
+    // the scratch is mapped in both spaces, and keeps the satp of the other
+    let prev_satp = Scratch::from_ssratch().prev_satp;
+    let old_root = prev_satp & SATP_PPN_MASK;
+    let other_root = reloc
+        .get(old_root * PAGE_SIZE)
+        .expect("the other address space was not restored");
+    reloc.relocate_table(other_root, 2, 0, &current);
+    let mut other = VirtMemMgr::new(
+        PhysPageNum::from_paddr(other_root),
+        RtPtWriter::new(spare.clone_trampoline()),
+        spare.spawn_allocator(),
+        (prev_satp >> 44) & 0xffff,
+        SV39,
+    );
+    if current
+        .translate(VirtPageNum::from_vaddr(LUE_KERNEL_VADDR))
+        .is_none()
+    {
+        // the thread was in its task, and the kernel is in the other space
+        other.map_frame(
+            VirtPageNum::from_vaddr(tp),
+            PhysPageNum::from_paddr(tp),
+            PTEFlags::rx().accessed(),
+        );
+        satp::write(other.gen_satp());
+        riscv::asm::sfence_vma_all();
+    }
+
+    let kernel = LinuxUserKernel::uninit(LUE_KERNEL_VADDR);
+    kernel.pmm.relocate(tp, |paddr| {
+        reloc.get(paddr).expect("a free frame was not restored")
+    });
+    // the tables of the space the thread was in are new
+    let root = |old: usize| {
+        if old == old_root {
+            other_root / PAGE_SIZE
+        } else {
+            current_root
+        }
+    };
+    let mut stale = None;
+    for vmm in [kernel.vmm.get_mut(), kernel.task.vmm.get_mut()] {
+        if vmm.root_ppn.0 != old_root {
+            stale = reloc.get(vmm.root_ppn.0 * PAGE_SIZE);
+        }
+        vmm.root_ppn = PhysPageNum(root(vmm.root_ppn.0));
+        vmm.writer = RtPtWriter::new(kernel.pmm.clone_trampoline());
+    }
+    for hartid in 0..MAX_HART_NUM {
+        let scratch = kernel.scratch_manager.get_scratch_mut(hartid);
+        if scratch.prev_satp != 0 {
+            let satp = scratch.prev_satp;
+            scratch.prev_satp = satp & !SATP_PPN_MASK | root(satp & SATP_PPN_MASK);
+        }
+    }
+    let shared = args.shared;
+    kernel
+        .task
+        .set_ht_offset(shared.host_vaddr - shared.enc_vaddr);
+
+    // the runtime takes what the monitor gave for the restore, and the old
+    // tables of the space the monitor rebuilt
+    if let Some(table) = stale {
+        reloc.release_table(table, 2, &kernel.pmm);
+    }
+    kernel.pmm.add_frames(spare.head());
+    kernel.pmm.add_frame(PhysPageNum::from_paddr(args_addr));
+    for i in 0..args.pages.div_ceil(PER_INDEX) {
+        kernel.pmm.add_frame(PhysPageNum::from_paddr(args.old[i]));
+        kernel.pmm.add_frame(PhysPageNum::from_paddr(args.new[i]));
+    }
+    log::debug!("relocated {} pages", args.pages);
+
+    sbi_finish_restore();
+    unreachable!("the thread goes back where it was")
+}
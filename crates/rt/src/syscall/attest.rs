@@ -0,0 +1,167 @@
+use channel::attest::{
+    LocalReport, SignedReport, KEY_ID_SIZE, MEASUREMENT_SIZE, REPORT_DATA_SIZE, SEALING_KEY_SIZE,
+};
+
+use crate::usr::{copy_from_user, copy_to_user, Buf_Policy, UsrBuf};
+
+use super::{
+    sbi_attest_enclave, sbi_extend_rtmr, sbi_get_sealing_key, sbi_local_report,
+    sbi_verify_local_report,
+};
+
+/// Get a signed report of this enclave.
+///
+/// `data` points to [`REPORT_DATA_SIZE`] bytes bound into the report, and the
+/// [`SignedReport`] is written to `report`, whose size is `len`.
+pub fn sys_attest_enclave(report: usize, data: usize, len: usize) -> isize {
+    if len < core::mem::size_of::<SignedReport>() {
+        return -1;
+    }
+
+    let mut report_data = [0u8; REPORT_DATA_SIZE];
+    let mut signed = core::mem::MaybeUninit::<SignedReport>::zeroed();
+    unsafe {
+        copy_from_user(
+            UsrBuf::new(data, REPORT_DATA_SIZE, Buf_Policy::Read, None),
+            report_data.as_mut_ptr() as usize,
+        );
+    }
+
+    let (error, _) =
+        sbi_attest_enclave(signed.as_mut_ptr() as usize, report_data.as_ptr() as usize);
+    if error != 0 {
+        return -1;
+    }
+
+    unsafe {
+        copy_to_user(
+            UsrBuf::new(
+                report,
+                core::mem::size_of::<SignedReport>(),
+                Buf_Policy::Write,
+                None,
+            ),
+            signed.as_ptr() as usize,
+        );
+    }
+
+    0
+}
+
+/// Get the sealing key of this enclave.
+///
+/// `key_id` points to [`KEY_ID_SIZE`] bytes chosen by the enclave, and the
+/// [`SEALING_KEY_SIZE`] bytes of key are written to `key`.
+pub fn sys_get_sealing_key(key: usize, key_id: usize, policy: usize) -> isize {
+    let mut id = [0u8; KEY_ID_SIZE];
+    let mut sealing_key = [0u8; SEALING_KEY_SIZE];
+    unsafe {
+        copy_from_user(
+            UsrBuf::new(key_id, KEY_ID_SIZE, Buf_Policy::Read, None),
+            id.as_mut_ptr() as usize,
+        );
+    }
+
+    let (error, _) = sbi_get_sealing_key(
+        sealing_key.as_mut_ptr() as usize,
+        id.as_ptr() as usize,
+        policy,
+    );
+    if error != 0 {
+        return -1;
+    }
+
+    unsafe {
+        copy_to_user(
+            UsrBuf::new(key, SEALING_KEY_SIZE, Buf_Policy::Write, None),
+            sealing_key.as_ptr() as usize,
+        );
+    }
+    sealing_key.fill(0);
+
+    0
+}
+
+/// Get a report of this enclave for the enclave `target`.
+///
+/// `data` points to [`REPORT_DATA_SIZE`] bytes bound into the report, and the
+/// [`LocalReport`] is written to `report`.
+pub fn sys_local_report(report: usize, data: usize, target: usize) -> isize {
+    let mut report_data = [0u8; REPORT_DATA_SIZE];
+    let mut local = core::mem::MaybeUninit::<LocalReport>::zeroed();
+    unsafe {
+        copy_from_user(
+            UsrBuf::new(data, REPORT_DATA_SIZE, Buf_Policy::Read, None),
+            report_data.as_mut_ptr() as usize,
+        );
+    }
+
+    let (error, _) = sbi_local_report(
+        local.as_mut_ptr() as usize,
+        report_data.as_ptr() as usize,
+        target,
+    );
+    if error != 0 {
+        return -1;
+    }
+
+    unsafe {
+        copy_to_user(
+            UsrBuf::new(
+                report,
+                core::mem::size_of::<LocalReport>(),
+                Buf_Policy::Write,
+                None,
+            ),
+            local.as_ptr() as usize,
+        );
+    }
+
+    0
+}
+
+/// Check that the [`LocalReport`] at `report` was made for this enclave.
+///
+/// Returns 0 if it is valid.
+pub fn sys_verify_local_report(report: usize) -> isize {
+    let mut local = core::mem::MaybeUninit::<LocalReport>::zeroed();
+    unsafe {
+        copy_from_user(
+            UsrBuf::new(
+                report,
+                core::mem::size_of::<LocalReport>(),
+                Buf_Policy::Read,
+                None,
+            ),
+            local.as_mut_ptr() as usize,
+        );
+    }
+
+    let (error, _) = sbi_verify_local_report(local.as_ptr() as usize);
+    if error != 0 {
+        return -1;
+    }
+
+    0
+}
+
+/// Extend the runtime measurement register `index` with the
+/// [`MEASUREMENT_SIZE`] bytes of digest at `digest`.
+///
+/// Usually the digest is the hash of a file loaded after launch.
+pub fn sys_extend_rtmr(index: usize, digest: usize) -> isize {
+    let mut value = [0u8; MEASUREMENT_SIZE];
+    unsafe {
+        copy_from_user(
+            UsrBuf::new(digest, MEASUREMENT_SIZE, Buf_Policy::Read, None),
+            value.as_mut_ptr() as usize,
+        );
+    }
+
+    let (error, _) = sbi_extend_rtmr(index, value.as_ptr() as usize);
+    if error != 0 {
+        return -1;
+    }
+
+    0
+}
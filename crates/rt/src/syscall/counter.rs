@@ -0,0 +1,61 @@
+use channel::counter::COUNTER_ID_SIZE;
+
+use crate::usr::{copy_from_user, copy_to_user, Buf_Policy, UsrBuf};
+
+use super::{sbi_counter_create, sbi_counter_increment, sbi_counter_read};
+
+fn read_id(id: usize) -> [u8; COUNTER_ID_SIZE] {
+    let mut counter_id = [0u8; COUNTER_ID_SIZE];
+    unsafe {
+        copy_from_user(
+            UsrBuf::new(id, COUNTER_ID_SIZE, Buf_Policy::Read, None),
+            counter_id.as_mut_ptr() as usize,
+        );
+    }
+    counter_id
+}
+
+fn write_value(value: usize, counter: u64) {
+    unsafe {
+        copy_to_user(
+            UsrBuf::new(value, size_of::<u64>(), Buf_Policy::Write, None),
+            &counter as *const u64 as usize,
+        );
+    }
+}
+
+/// Create the monotonic counter named by the [`COUNTER_ID_SIZE`] bytes at
+/// `id`, bound to the measurement or the signer as chosen by `policy`.
+pub fn sys_counter_create(id: usize, policy: usize) -> isize {
+    let counter_id = read_id(id);
+    let (error, _) = sbi_counter_create(counter_id.as_ptr() as usize, policy);
+    if error != 0 {
+        return -1;
+    }
+
+    0
+}
+
+/// Increment a monotonic counter, and write its new value to `value`.
+pub fn sys_counter_increment(id: usize, policy: usize, value: usize) -> isize {
+    let counter_id = read_id(id);
+    let (error, counter) = sbi_counter_increment(counter_id.as_ptr() as usize, policy);
+    if error != 0 {
+        return -1;
+    }
+    write_value(value, counter as u64);
+
+    0
+}
+
+/// Write the value of a monotonic counter to `value`.
+pub fn sys_counter_read(id: usize, policy: usize, value: usize) -> isize {
+    let counter_id = read_id(id);
+    let (error, counter) = sbi_counter_read(counter_id.as_ptr() as usize, policy);
+    if error != 0 {
+        return -1;
+    }
+    write_value(value, counter as u64);
+
+    0
+}
@@ -20,7 +20,8 @@ use super::{
         SYS_CLOSE, SYS_EPOLL_CREATE1, SYS_EPOLL_CTL, SYS_EPOLL_PWAIT, SYS_FSTAT, SYS_FSYNC,
         SYS_FTRUNCATE, SYS_GETCWD, SYS_IOCTL, SYS_LSEEK, SYS_PIPE2, SYS_READ, SYS_SYNC, SYS_WRITE,
     },
-    sbi_close_channel, sbi_open_channel, sbi_recv_channel, sbi_stop_enclave,
+    mem::pause_for_host,
+    sbi_close_channel, sbi_open_channel, sbi_recv_channel,
     time::TimeSpec,
 };
 
@@ -713,7 +714,7 @@ pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
     let kernel = unsafe { LinuxUserKernel::from_sscratch() };
 
     // // create ipc channel and get the channel id
-    // let channel_id = ipc_channel_open(arg, kernel.task.vmm.lock().gen_satp());
+    // let channel_id = ipc_channel_open(arg, 0, kernel.task.vmm.lock().gen_satp());
     // log::debug!("channel id: {channel_id:#x}");
 
     let vstack_enc_addr = kernel.task.get_vstack_addr();
@@ -744,8 +745,11 @@ pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
     ret
 }
 
-pub fn ipc_channel_open(arg: usize, task_satp: usize) -> isize {
-    let (error, channel_id) = sbi_open_channel(arg, task_satp);
+/// Open the channel `name`, lending the pages listed by the head page at
+/// `head`, if it is not 0, and letting the driver copy from and to the task
+/// of `task_satp`.
+pub fn ipc_channel_open(name: usize, head: usize, task_satp: usize) -> isize {
+    let (error, channel_id) = sbi_open_channel(name, head, task_satp);
     channel_id
 }
 
@@ -754,13 +758,15 @@ pub fn ipc_channel_close(channel_id: usize) -> isize {
     value
 }
 
+/// Connect to the channel `channel_id`, returning the physical address of its
+/// head page.
 pub fn ipc_channel_recv(channel_id: usize) -> usize {
-    let (error, arg) = sbi_recv_channel(channel_id);
-    arg as usize
+    let (error, head) = sbi_recv_channel(channel_id);
+    head as usize
 }
 
 fn dispatch_proxy_syscall(vstack_host_addr: usize, vstack_enc_addr: usize) -> isize {
-    let (error, value) = sbi_stop_enclave(vstack_host_addr);
+    pause_for_host(vstack_host_addr);
     let vstack = Vstack::from_addr(vstack_enc_addr);
     let a0 = vstack.regs.a0;
     a0 as isize
@@ -1,10 +1,19 @@
 use rand_core::le;
 use sbi::tlb_flush;
-use vm::{consts::PAGE_SIZE, page_table::PTEFlags, pm::PhysPageNum, vm::VirtPageNum};
+use vm::{
+    consts::PAGE_SIZE,
+    page_table::PTEFlags,
+    pm::{PhysAddr, PhysPageNum},
+    vm::VirtPageNum,
+};
+
+use channel::mem::{MAX_DONATED_PAGES, MEM_REQUEST, MEM_RETURN};
 
 use crate::kernel::{self, LinuxUserKernel};
 use crate::log;
 
+use super::{sbi_free_memory, sbi_stop_enclave};
+
 // mmap flags
 const MAP_ANONYMOUS: usize = 0x20;
 const MAP_PRIVATE: usize = 0x2;
@@ -15,6 +24,68 @@ const PROT_WRITE: usize = 0x2; /* Page can be written.  */
 const PROT_EXEC: usize = 0x4; /* Page can be executed.  */
 const PROT_NONE: usize = 0x0; /* Page can not be accessed.  */
 
+/// Pause the enclave with `request`, then take what the host left for it
+/// meanwhile: pages it donated, and how many pages it asks back.
+///
+/// Returns whether any page was donated.
+pub fn pause_for_host(request: usize) -> bool {
+    let kernel = unsafe { LinuxUserKernel::from_sscratch() };
+    let mut request = request;
+    let mut donated = false;
+    loop {
+        let (wanted, head) = sbi_stop_enclave(request);
+        if head != 0 {
+            kernel.pmm.add_frames(head as usize);
+            donated = true;
+        }
+        // the host is told once pages are given back, and may ask for more
+        if wanted <= 0 || release_memory(kernel, wanted as usize) == 0 {
+            return donated;
+        }
+        request = MEM_RETURN;
+    }
+}
+
+/// Ask the host for memory until `size` bytes are free, returning whether
+/// they are.
+fn grow_memory(kernel: &LinuxUserKernel, size: usize) -> bool {
+    while kernel.pmm.get_spa_size() < size {
+        if !pause_for_host(MEM_REQUEST) {
+            log::debug!("host donated no memory");
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Give up to `pages` free frames back to the host, returning how many.
+fn release_memory(kernel: &LinuxUserKernel, pages: usize) -> usize {
+    let count = pages
+        .min(MAX_DONATED_PAGES)
+        .min(kernel.pmm.get_spa_size() / PAGE_SIZE);
+    if count == 0 {
+        return 0;
+    }
+
+    // linked like the free list, which the monitor walks
+    let mut head = 0;
+    for _ in 0..count {
+        let paddr: usize = PhysAddr::from_ppn(kernel.pmm.get_free_frame().unwrap()).into();
+        unsafe { kernel.pmm.writep(paddr, head) };
+        head = paddr;
+    }
+
+    let (error, released) = sbi_free_memory(head, count);
+    if error != 0 {
+        log::debug!("cannot give memory back: {error}");
+        kernel.pmm.add_frames(head);
+        return 0;
+    }
+
+    released as usize
+}
+
 pub fn sys_brk(addr: usize) -> isize {
     let kernel = unsafe { LinuxUserKernel::from_sscratch() };
     let cur_break = kernel.task.get_break();
@@ -40,7 +111,7 @@ pub fn sys_brk(addr: usize) -> isize {
     // check if exists enough space
     let free_size = kernel.pmm.get_spa_size();
     log::debug!("[sys_brk]: free space size: {:#x}", free_size);
-    if free_size < (end_vpn.0 - cur_vpn.0 + 1) * PAGE_SIZE {
+    if !grow_memory(kernel, (end_vpn.0 - cur_vpn.0 + 1) * PAGE_SIZE) {
         log::debug!("[sys_brk]: dearth of free pages to brk expand, return error.");
         return -1;
     }
@@ -108,7 +179,7 @@ pub fn sys_mmap(
     // check if exists enough space
     let free_size = kernel.pmm.get_spa_size();
     log::debug!("[sys_mmap]: free space size: {:#x}", free_size);
-    if free_size < (end_vpn.0 - cur_vpn.0 + 1) * PAGE_SIZE {
+    if !grow_memory(kernel, (end_vpn.0 - cur_vpn.0 + 1) * PAGE_SIZE) {
         log::debug!("[sys_mmap]: dearth of free pages to mmap, return error.");
         return -1;
     }
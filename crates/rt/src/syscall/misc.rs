@@ -1,108 +1,69 @@
-use core::ops::Add;
+use crate::consts::PAGE_SIZE;
+use crate::usr::{copy_to_user, Buf_Policy, UsrBuf, UsrOutPtr, UsrPtr};
 
-use crate::usr::{Buf_Policy, UsrBuf, UsrOutPtr, UsrPtr};
+use channel::attest::RANDOM_MAX;
 
-use rand_core::{RngCore, SeedableRng};
+use super::sbi_random;
 
-/// pedo-random number generator
-/// Xorshift32
-pub struct Xorshift32 {
-    state: u32,
-}
-
-impl SeedableRng for Xorshift32 {
-    type Seed = [u8; 4];
+const EIO: isize = 5;
+const EAGAIN: isize = 11;
+const EINVAL: isize = 22;
 
-    fn from_seed(seed: Self::Seed) -> Xorshift32 {
-        let mut state = 0u32;
-        state |= (seed[0] as u32) << 24;
-        state |= (seed[1] as u32) << 16;
-        state |= (seed[2] as u32) << 8;
-        state |= seed[3] as u32;
-        Xorshift32 { state }        
-    }
-}
+const GRND_NONBLOCK: u32 = 0x1;
+const GRND_RANDOM: u32 = 0x2;
+const GRND_INSECURE: u32 = 0x4;
 
-impl RngCore for Xorshift32 {
-    fn next_u32(&mut self) -> u32 {
-        self.state ^= self.state << 13;
-        self.state ^= self.state >> 17;
-        self.state ^= self.state << 5;
-        self.state
-    }
-
-    fn next_u64(&mut self) -> u64 {
-        let upper = self.next_u32() as u64;
-        let lower = self.next_u32() as u64;
-        (upper << 32) | lower
-    }
+/// The most bytes returned by one read, as `MAX_RW_COUNT` of Linux.
+const MAX_RW_COUNT: usize = i32::MAX as usize & !(PAGE_SIZE - 1);
 
-    fn fill_bytes(&mut self, dest: &mut [u8]) {
-        let mut bytes = dest.chunks_exact_mut(4);
-        for chunk in &mut bytes {
-            let random = self.next_u32().to_ne_bytes();
-            chunk.copy_from_slice(&random);
+/// Fill `buf` with random bytes from the DRBG of the security monitor.
+pub fn fill_random(buf: &mut [u8]) -> Result<(), isize> {
+    for chunk in buf.chunks_mut(RANDOM_MAX) {
+        let (error, _) = sbi_random(chunk.as_mut_ptr() as usize, chunk.len());
+        if error != 0 {
+            return Err(error);
         }
-        let remainder = bytes.into_remainder();
-        let random = self.next_u32().to_ne_bytes();
-        remainder.copy_from_slice(&random[..remainder.len()]);
     }
-
-    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
-        self.fill_bytes(dest);
-        Ok(())
-    }
-}
-
-pub struct RandGenerator {
-    xorshift32: Xorshift32
+    Ok(())
 }
 
-impl RandGenerator {
-    pub fn new(seed: usize) -> Self {
-        let seed_array = [(seed & 0xff) as u8, (seed & 0xff00) as u8, (seed & 0xff0000) as u8, (seed & 0xff000000) as u8];
-        let rng = Xorshift32::from_seed(seed_array); 
-        Self {
-            xorshift32: rng,
-        }       
-    }
-
-    pub fn next_u8(&mut self) -> u8 {
-        self.xorshift32
-            .next_u32() as u8
+/// getrandom(2) backed by the DRBG of the security monitor.
+///
+/// The DRBG never runs out of entropy once seeded, so `GRND_RANDOM` and
+/// `GRND_INSECURE` behave the same as no flags, and it never blocks.
+pub fn sys_getrandom(buf: usize, len: usize, flags: u32) -> isize {
+    const CHUNK: usize = 256;
+
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+        || flags & (GRND_RANDOM | GRND_INSECURE) == (GRND_RANDOM | GRND_INSECURE)
+    {
+        return -EINVAL;
     }
 
-    pub fn next_u32(&mut self) -> u32 {
-        self.xorshift32
-            .next_u32()
-    }
-    
-    pub fn next_u64(&mut self) -> u64 {
-        self.xorshift32
-            .next_u64()
-    }
-
- }
-
-pub fn sys_getrandom(buf: usize, len: usize, flag: u32) -> isize {
-    let ulen = core::mem::size_of::<usize>();
-    if len % ulen != 0 {
-        return 0;
-    }
-    let mut rand = RandGenerator::new(buf);
-    // build buffer
-    let rt_buf = UsrBuf::new(buf, len, Buf_Policy::Write, None);
-    rt_buf.build_buf();
-
-    let p: UsrOutPtr<u8> = UsrPtr::from_usr_buf(&rt_buf);
-    for i in 0..len {
-        let rand_num = rand.next_u8();
-        let _ = p.add(i).write(rand_num);
+    let len = len.min(MAX_RW_COUNT);
+    let mut rand = [0u8; CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = CHUNK.min(len - done);
+        if fill_random(&mut rand[..n]).is_err() {
+            // the SM has no seed, and waiting would never get one
+            return if flags & GRND_NONBLOCK != 0 {
+                -EAGAIN
+            } else {
+                -EIO
+            };
+        }
+        unsafe {
+            copy_to_user(
+                UsrBuf::new(buf + done, n, Buf_Policy::Write, None),
+                rand.as_ptr() as usize,
+            );
+        }
+        done += n;
     }
+    rand.fill(0);
 
-    rt_buf.retract_buf();
-
-    len as isize
+    done as isize
 }
 
 pub fn sys_uname(buf: usize) -> isize {
@@ -1,12 +1,20 @@
+mod attest;
+mod counter;
 mod file;
 #[allow(unused)]
 pub mod linux_wrap;
 mod mem;
 mod misc;
+mod service;
 mod task;
 mod time;
 mod vm;
 
+pub use attest::{
+    sys_attest_enclave, sys_extend_rtmr, sys_get_sealing_key, sys_local_report,
+    sys_verify_local_report,
+};
+pub use counter::{sys_counter_create, sys_counter_increment, sys_counter_read};
 use file::{
     sys_close, sys_epoll_create1, sys_epoll_ctl, sys_epoll_pwait, sys_fcntl, sys_fstat, sys_fsync,
     sys_ftruncate, sys_getcwd, sys_ioctl, sys_lseek, sys_newfstatat, sys_openat, sys_pipe2,
@@ -20,11 +28,14 @@ use linux_wrap::{
     SYS_RT_SIGACTION, SYS_RT_SIGPROCMASK, SYS_SET_ROBUST_LIST, SYS_SET_TID_ADDRESS, SYS_SYNC,
     SYS_UNAME, SYS_UNLINKAT, SYS_WRITE, SYS_WRITEV,
 };
+pub use mem::pause_for_host;
 use mem::{sys_brk, sys_mmap, sys_mprotect, sys_munmap};
-pub use misc::RandGenerator;
+pub use misc::fill_random;
 use misc::{sys_getrandom, sys_uname};
+pub use service::sys_call_service;
 use sbi::ecall::{
-    sbi_call_1, sbi_unimp_1, sbi_unimp_2, sbi_unimp_3, SBISMEnclaveCall, SBI_EXT_TEE_ENCLAVE,
+    sbi_call_1, sbi_call_2, sbi_call_3, sbi_call_5, sbi_unimp_1, sbi_unimp_3, SBISMEnclaveCall,
+    SBI_EXT_TEE_ENCLAVE,
 };
 use task::sys_getpid;
 use time::{sys_clock_gettime, sys_gettimeofday};
@@ -47,12 +58,142 @@ pub fn sbi_stop_enclave(request: usize) -> (isize, isize) {
     )
 }
 
-pub fn sbi_open_channel(request1: usize, request2: usize) -> (isize, isize) {
-    sbi_unimp_2(
+/// Give the `count` frames linked from `head` back to the host.
+pub fn sbi_free_memory(head: usize, count: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMEFree as usize,
+        head,
+        count,
+    )
+}
+
+pub fn sbi_attest_enclave(report: usize, data: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSmAttestEnclave as usize,
+        report,
+        data,
+    )
+}
+
+pub fn sbi_local_report(report: usize, data: usize, target: usize) -> (isize, isize) {
+    sbi_call_3(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMLocalReport as usize,
+        report,
+        data,
+        target,
+    )
+}
+
+pub fn sbi_verify_local_report(report: usize) -> (isize, isize) {
+    sbi_call_1(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMVerifyLocalReport as usize,
+        report,
+    )
+}
+
+pub fn sbi_extend_rtmr(index: usize, digest: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMExtendRtmr as usize,
+        index,
+        digest,
+    )
+}
+
+pub fn sbi_counter_create(id: usize, policy: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMCounterCreate as usize,
+        id,
+        policy,
+    )
+}
+
+pub fn sbi_counter_increment(id: usize, policy: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMCounterIncrement as usize,
+        id,
+        policy,
+    )
+}
+
+pub fn sbi_counter_read(id: usize, policy: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMCounterRead as usize,
+        id,
+        policy,
+    )
+}
+
+/// Register `entry` as where the first thread resumed after a restore goes to
+/// relocate the runtime.
+pub fn sbi_register_restore(entry: usize) -> (isize, isize) {
+    sbi_call_1(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMRegisterRestore as usize,
+        entry,
+    )
+}
+
+/// Go back to where the thread was, once the runtime is relocated.
+pub fn sbi_finish_restore() -> (isize, isize) {
+    sbi_call_1(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMFinishRestore as usize,
+        0,
+    )
+}
+
+pub fn sbi_call_service(
+    eid: usize,
+    req: usize,
+    len: usize,
+    reply: usize,
+    cap: usize,
+) -> (isize, isize) {
+    sbi_call_5(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMCallService as usize,
+        eid,
+        req,
+        len,
+        reply,
+        cap,
+    )
+}
+
+pub fn sbi_random(buf: usize, len: usize) -> (isize, isize) {
+    sbi_call_2(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMRandom as usize,
+        buf,
+        len,
+    )
+}
+
+pub fn sbi_get_sealing_key(key: usize, key_id: usize, policy: usize) -> (isize, isize) {
+    sbi_call_3(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMGetSealingKey as usize,
+        key,
+        key_id,
+        policy,
+    )
+}
+
+pub fn sbi_open_channel(name: usize, head: usize, satp: usize) -> (isize, isize) {
+    sbi_unimp_3(
         SBI_EXT_TEE_ENCLAVE,
         SBISMEnclaveCall::SbiSMChannelOpen as usize,
-        request1,
-        request2,
+        name,
+        head,
+        satp,
     )
 }
 
@@ -102,6 +243,16 @@ pub fn sbi_copy_from_kernel(to: usize, from: usize, size: usize) -> (isize, isiz
     )
 }
 
+pub fn sbi_copy_to_kernel(to: usize, from: usize, size: usize) -> (isize, isize) {
+    sbi_unimp_3(
+        SBI_EXT_TEE_ENCLAVE,
+        SBISMEnclaveCall::SbiSMCopyToKernel as usize,
+        to,
+        from,
+        size,
+    )
+}
+
 pub fn linux_syscall(syscall_id: usize, args: [usize; 6]) -> isize {
     log::debug!("linux syscall: {:#x}", syscall_id);
     let [a0, a1, a2, a3, a4, a5] = args;
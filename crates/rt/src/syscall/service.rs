@@ -0,0 +1,52 @@
+use alloc::vec;
+use channel::service::SERVICE_MSG_MAX;
+
+use crate::usr::{copy_from_user, copy_to_user, Buf_Policy, UsrBuf};
+
+use super::sbi_call_service;
+
+/// Call the service enclave `eid` with `len` bytes of request at `req`.
+///
+/// At most `cap` bytes of reply are written to `reply`, and the length of the
+/// reply is returned.
+pub fn sys_call_service(eid: usize, req: usize, len: usize, reply: usize, cap: usize) -> isize {
+    if len > SERVICE_MSG_MAX {
+        return -1;
+    }
+
+    // the monitor only copies enclave memory, so the buffers go through the
+    // runtime rather than letting the user name runtime addresses
+    let mut request = vec![0u8; len];
+    let mut response = vec![0u8; cap.min(SERVICE_MSG_MAX)];
+    if len != 0 {
+        unsafe {
+            copy_from_user(
+                UsrBuf::new(req, len, Buf_Policy::Read, None),
+                request.as_mut_ptr() as usize,
+            );
+        }
+    }
+
+    let (error, reply_len) = sbi_call_service(
+        eid,
+        request.as_ptr() as usize,
+        len,
+        response.as_mut_ptr() as usize,
+        response.len(),
+    );
+    if error != 0 {
+        return -1;
+    }
+
+    let copied = (reply_len as usize).min(response.len());
+    if copied != 0 {
+        unsafe {
+            copy_to_user(
+                UsrBuf::new(reply, copied, Buf_Policy::Write, None),
+                response.as_ptr() as usize,
+            );
+        }
+    }
+
+    reply_len
+}
@@ -59,6 +59,11 @@ impl Task {
         self.ht_offset
     }
 
+    /// Set where the host maps the shared memory again, once it is restored.
+    pub fn set_ht_offset(&mut self, ht_offset: usize) {
+        self.ht_offset = ht_offset;
+    }
+
     pub fn set_break(&self, new_break: usize) {
         let mut lock = self.program_break.lock();
         *lock.deref_mut() = new_break.into();
@@ -19,7 +19,9 @@ use crate::{
     pt::RtPtWriter,
     scratch::Scratch,
     syscall::{
-        linux_syscall, sbi_copy_from_kernel, sbi_exit_enclave, sbi_recv_channel, sbi_stop_enclave,
+        linux_syscall, pause_for_host, sbi_copy_from_kernel, sbi_exit_enclave, sbi_recv_channel,
+        sys_attest_enclave, sys_call_service, sys_counter_create, sys_counter_increment,
+        sys_counter_read, sys_extend_rtmr, sys_get_sealing_key, sys_local_report, sys_verify_local_report,
     },
     trap_restore_a0_t0_smode, trap_restore_general_regs_except_a0_t0_smode,
     trap_restore_sepc_sstatus, trap_save_and_setup_sp_t0_smode,
@@ -80,8 +82,33 @@ fn lue_handle(regs: &mut TrapRegsSMode) {
                     Some(rt_call) => match rt_call {
                         RuntimeSbiCall::RuntimeSyscallOcall => todo!(),
                         RuntimeSbiCall::RuntimeSyscallSharedcopy => todo!(),
-                        RuntimeSbiCall::RuntimeSyscallAttestEnclave => todo!(),
-                        RuntimeSbiCall::RuntimeSyscallGetSealingKey => todo!(),
+                        RuntimeSbiCall::RuntimeSyscallAttestEnclave => {
+                            regs.a0 = sys_attest_enclave(arg0, arg1, arg2) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallGetSealingKey => {
+                            regs.a0 = sys_get_sealing_key(arg0, arg1, arg2) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallLocalReport => {
+                            regs.a0 = sys_local_report(arg0, arg1, arg2) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallVerifyLocalReport => {
+                            regs.a0 = sys_verify_local_report(arg0) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallExtendRtmr => {
+                            regs.a0 = sys_extend_rtmr(arg0, arg1) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallCounterCreate => {
+                            regs.a0 = sys_counter_create(arg0, arg1) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallCounterIncrement => {
+                            regs.a0 = sys_counter_increment(arg0, arg1, arg2) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallCounterRead => {
+                            regs.a0 = sys_counter_read(arg0, arg1, arg2) as usize;
+                        }
+                        RuntimeSbiCall::RuntimeSyscallCallService => {
+                            regs.a0 = sys_call_service(arg0, arg1, arg2, arg3, arg4) as usize;
+                        }
                         RuntimeSbiCall::RuntimeSyscallExit => {
                             sbi_exit_enclave(arg0);
                         }
@@ -138,17 +165,17 @@ fn lue_handle(regs: &mut TrapRegsSMode) {
             Interrupt::SupervisorTimer => {
                 // log::debug!("time interrupt.");
                 // log::debug!("sip: {:#x}", riscv::register::sip::read().bits());
-                sbi_stop_enclave(1);
+                pause_for_host(1);
             }
             Interrupt::SupervisorExternal => {
                 // log::debug!("sei.");
                 // log::debug!("sip: {:#x}", riscv::register::sip::read().bits());
-                sbi_stop_enclave(1);
+                pause_for_host(1);
             }
             Interrupt::SupervisorSoft => {
                 // println!("ssoft.");
                 // log::debug!("sip: {:#x}", riscv::register::sip::read().bits());
-                sbi_stop_enclave(1);
+                pause_for_host(1);
             }
             _ => {
                 log::error!("unsupported trap: {:#x}", cause.bits());
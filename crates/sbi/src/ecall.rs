@@ -29,6 +29,13 @@ pub enum RuntimeSbiCall {
     RuntimeSyscallSharedcopy = 1002,
     RuntimeSyscallAttestEnclave = 1003,
     RuntimeSyscallGetSealingKey = 1004,
+    RuntimeSyscallLocalReport = 1005,
+    RuntimeSyscallVerifyLocalReport = 1006,
+    RuntimeSyscallExtendRtmr = 1007,
+    RuntimeSyscallCounterCreate = 1008,
+    RuntimeSyscallCounterIncrement = 1009,
+    RuntimeSyscallCounterRead = 1010,
+    RuntimeSyscallCallService = 1011,
     RuntimeSyscallExit = 1101,
 }
 
@@ -44,6 +51,19 @@ pub enum SBISMEnclaveCall {
     SbiSMExitEnclave = 3006,
     SbiSMEneterLde = 3007,
     SbiSMExitLde = 3008,
+    SbiSMGetBootLog = 3009,
+    SbiSMLocalReport = 3010,
+    SbiSMVerifyLocalReport = 3011,
+    SbiSMExtendRtmr = 3012,
+    SbiSMCounterCreate = 3013,
+    SbiSMCounterIncrement = 3014,
+    SbiSMCounterRead = 3015,
+    SbiSMExportCounters = 3016,
+    SbiSMImportCounters = 3017,
+    SbiSMCallService = 3018,
+    SbiSMServiceReturn = 3019,
+    SbiSMRegisterRestore = 3020,
+    SbiSMFinishRestore = 3021,
     SbiSMCallPlugin = 4000,
     SbiSMELock = 5001,
     SbiSMEFree = 5002,
@@ -56,6 +76,8 @@ pub enum SBISMEnclaveCall {
     SbiSMCopyToLue = 5009,
     SbiSMCopyFromKernel = 5010,
     SbiSMCopyToKernel = 5011,
+    SbiSMSnapshotEnclave = 5012,
+    SbiSMRestoreEnclave = 5013,
 }
 
 pub mod pmu {
@@ -396,6 +418,13 @@ pub fn runtime_sbi_call_from_usize(n: usize) -> Option<RuntimeSbiCall> {
         1002 => Some(RuntimeSbiCall::RuntimeSyscallSharedcopy),
         1003 => Some(RuntimeSbiCall::RuntimeSyscallAttestEnclave),
         1004 => Some(RuntimeSbiCall::RuntimeSyscallGetSealingKey),
+        1005 => Some(RuntimeSbiCall::RuntimeSyscallLocalReport),
+        1006 => Some(RuntimeSbiCall::RuntimeSyscallVerifyLocalReport),
+        1007 => Some(RuntimeSbiCall::RuntimeSyscallExtendRtmr),
+        1008 => Some(RuntimeSbiCall::RuntimeSyscallCounterCreate),
+        1009 => Some(RuntimeSbiCall::RuntimeSyscallCounterIncrement),
+        1010 => Some(RuntimeSbiCall::RuntimeSyscallCounterRead),
+        1011 => Some(RuntimeSbiCall::RuntimeSyscallCallService),
         1101 => Some(RuntimeSbiCall::RuntimeSyscallExit),
         _ => None,
     }
@@ -412,6 +441,19 @@ pub fn enclave_sbi_call_from_usize(n: usize) -> Option<SBISMEnclaveCall> {
         3003 => Some(SBISMEnclaveCall::SbiSMGetSealingKey),
         3004 => Some(SBISMEnclaveCall::SbiSMStopEnclave),
         3006 => Some(SBISMEnclaveCall::SbiSMExitEnclave),
+        3009 => Some(SBISMEnclaveCall::SbiSMGetBootLog),
+        3010 => Some(SBISMEnclaveCall::SbiSMLocalReport),
+        3011 => Some(SBISMEnclaveCall::SbiSMVerifyLocalReport),
+        3012 => Some(SBISMEnclaveCall::SbiSMExtendRtmr),
+        3013 => Some(SBISMEnclaveCall::SbiSMCounterCreate),
+        3014 => Some(SBISMEnclaveCall::SbiSMCounterIncrement),
+        3015 => Some(SBISMEnclaveCall::SbiSMCounterRead),
+        3016 => Some(SBISMEnclaveCall::SbiSMExportCounters),
+        3017 => Some(SBISMEnclaveCall::SbiSMImportCounters),
+        3018 => Some(SBISMEnclaveCall::SbiSMCallService),
+        3019 => Some(SBISMEnclaveCall::SbiSMServiceReturn),
+        3020 => Some(SBISMEnclaveCall::SbiSMRegisterRestore),
+        3021 => Some(SBISMEnclaveCall::SbiSMFinishRestore),
         4000 => Some(SBISMEnclaveCall::SbiSMCallPlugin),
         _ => None,
     }
@@ -494,6 +536,63 @@ pub fn sbi_call_1(eid: usize, fid: usize, arg0: usize) -> (isize, isize) {
     (error, value)
 }
 
+#[inline(never)]
+pub fn sbi_call_2(eid: usize, fid: usize, arg0: usize, arg1: usize) -> (isize, isize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") eid,
+            in("a6") fid,
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+        );
+    }
+    (error, value)
+}
+
+#[inline(never)]
+pub fn sbi_call_3(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, isize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") eid,
+            in("a6") fid,
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+        );
+    }
+    (error, value)
+}
+
+#[inline(never)]
+pub fn sbi_call_5(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) -> (isize, isize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") eid,
+            in("a6") fid,
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a3") arg3,
+            in("a4") arg4,
+        );
+    }
+    (error, value)
+}
+
 #[inline(never)]
 pub fn sbi_unimp_1(eid: usize, fid: usize, arg0: usize) -> (isize, isize) {
     let (error, value);
@@ -0,0 +1,106 @@
+use channel::attest::{
+    EnclaveReport, LocalReport, PUBLIC_KEY_SIZE, REPORT_DATA_SIZE, SignedReport,
+};
+use ed25519_dalek::{Signer, SigningKey};
+use enclave::{Enclave, EnclaveData, EnclaveId, Measurement};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const REPORT_KEY_LABEL: &[u8] = b"lattice-report-key";
+
+/// The Ed25519 key of the device, which signs attestation reports.
+///
+/// The secret never leaves the security monitor.
+pub struct DeviceKey {
+    secret: [u8; 32],
+    public: [u8; PUBLIC_KEY_SIZE],
+}
+
+impl DeviceKey {
+    pub fn new(secret: [u8; 32]) -> Self {
+        let public = SigningKey::from_bytes(&secret).verifying_key().to_bytes();
+        Self { secret, public }
+    }
+
+    pub fn sign(&self, report: EnclaveReport) -> SignedReport {
+        let signature = SigningKey::from_bytes(&self.secret).sign(report.as_bytes());
+        SignedReport {
+            report,
+            public_key: self.public,
+            signature: signature.to_bytes(),
+        }
+    }
+}
+
+/// Per-boot secret from which the keys of local reports are derived.
+///
+/// The keys never leave the security monitor: a target enclave asks the
+/// monitor to verify a report instead of deriving the key itself.
+pub struct ReportSecret([u8; 32]);
+
+impl ReportSecret {
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self(secret)
+    }
+
+    /// The MAC of `report` for the enclave `target` with `measurement`.
+    ///
+    /// The measurement is mixed in, so a report stays bound to the enclave
+    /// it was made for even if its id is reused after destruction.
+    fn mac(
+        &self,
+        target: EnclaveId,
+        measurement: &Measurement,
+        report: &EnclaveReport,
+    ) -> Hmac<Sha256> {
+        let mut kdf = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        kdf.update(REPORT_KEY_LABEL);
+        kdf.update(&(target.0 as u64).to_le_bytes());
+        kdf.update(measurement);
+        let key = kdf.finalize().into_bytes();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(report.as_bytes());
+        mac.update(&(target.0 as u64).to_le_bytes());
+        mac
+    }
+
+    pub fn gen_local<D: EnclaveData>(
+        &self,
+        target: &Enclave<D>,
+        report: EnclaveReport,
+    ) -> LocalReport {
+        let mac = self.mac(target.id(), &target.measurement, &report);
+        LocalReport {
+            report,
+            target: target.id().0 as u64,
+            mac: mac.finalize().into_bytes().into(),
+        }
+    }
+
+    /// Whether `report` was made by [`Self::gen_local`] for `target`.
+    pub fn verify_local<D: EnclaveData>(&self, target: &Enclave<D>, report: &LocalReport) -> bool {
+        report.target == target.id().0 as u64
+            && self
+                .mac(target.id(), &target.measurement, &report.report)
+                .verify_slice(&report.mac)
+                .is_ok()
+    }
+}
+
+/// Build the report of `enc`, binding `report_data` supplied by the enclave.
+pub fn gen_report<D: EnclaveData>(
+    sm_measurement: &Measurement,
+    enc: &Enclave<D>,
+    report_data: [u8; REPORT_DATA_SIZE],
+) -> EnclaveReport {
+    EnclaveReport {
+        sm_measurement: *sm_measurement,
+        measurement: enc.measurement,
+        eid: enc.id().0 as u64,
+        enc_type: enc.get_type() as u64,
+        debug: (cfg!(debug_assertions) || enc.debug) as u64,
+        rtmrs: enc.rtmrs,
+        report_data,
+    }
+}
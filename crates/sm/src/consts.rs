@@ -1,3 +1,6 @@
+/// Version of the security monitor, checked against enclave manifests.
+pub const SM_VERSION: u64 = 1;
+
 pub const GUARD_PAGE_SIZE: usize = 0x1000;
 pub const MAX_HART_NUM: usize = 128;
 //pub const PMP_NUM: usize = 16;
@@ -10,6 +13,28 @@ pub const E_DEBUG_FLAG: usize = 0xf0f0f0f1;
 
 pub const FRAME_SIZE: usize = 0x1000;
 
+/// Ticks of the machine timer a user enclave thread runs for before the host
+/// gets the hart back, unless the enclave asks for another slice. 10ms with
+/// the 10MHz timer of QEMU virt.
+pub const DEFAULT_TIME_SLICE: u64 = 100_000;
+
+/// Ticks of the machine timer a call of a service enclave runs for at most,
+/// before it fails and the caller gets the hart back. 100ms with the 10MHz
+/// timer of QEMU virt.
+pub const SERVICE_CALL_TICKS: u64 = 1_000_000;
+
+/// Private memory a callable service enclave needs at least, for its page
+/// table, stack and message page.
+pub const SERVICE_MIN_MEM: usize = 16 * FRAME_SIZE;
+
+/// Page tables a user enclave may have to be snapshotted.
+pub const MAX_SNAPSHOT_TABLES: usize = 512;
+
+/// Pages a user enclave may own to be snapshotted, as many as the frames of
+/// a [`LueRestoreArgs`](channel::e2r::LueRestoreArgs) index.
+pub const MAX_SNAPSHOT_PAGES: usize =
+    channel::e2r::RESTORE_INDEX_FRAMES * FRAME_SIZE / size_of::<usize>();
+
 pub const RT_INIT_ELF_START: usize = 0x1_0000_0000;
 
 pub const RT_INIT_RT_START: usize = 0xFFFF_0000_0000;
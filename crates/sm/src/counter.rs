@@ -0,0 +1,267 @@
+use core::mem::offset_of;
+
+use channel::{
+    attest::{MAC_SIZE, SealPolicy},
+    counter::{COUNTER_ENTRY_SIZE, COUNTER_ID_SIZE, CounterBlob, MAX_COUNTERS},
+};
+use chacha20::{
+    ChaCha20,
+    cipher::{KeyIvInit, StreamCipher},
+};
+use enclave::{Error, MEASUREMENT_SIZE, Measurement};
+use hmac::{Hmac, Mac};
+use platform::CounterAnchor;
+use sha2::Sha256;
+
+use crate::{helper, seal::RootSecret};
+
+const MAC_LABEL: &[u8] = b"lattice-counter-mac";
+const ENC_LABEL: &[u8] = b"lattice-counter-enc";
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Counter {
+    policy: u64,
+    identity: Measurement,
+    id: [u8; COUNTER_ID_SIZE],
+    value: u64,
+}
+
+const _: () = assert!(size_of::<Counter>() == COUNTER_ENTRY_SIZE);
+
+impl Counter {
+    const EMPTY: Self = Self {
+        policy: 0,
+        identity: [0; MEASUREMENT_SIZE],
+        id: [0; COUNTER_ID_SIZE],
+        value: 0,
+    };
+}
+
+/// Keys of the [`CounterBlob`]s, derived from the root secret.
+pub struct BlobKeys {
+    mac: [u8; 32],
+    enc: [u8; 32],
+}
+
+impl BlobKeys {
+    pub fn new(root: &RootSecret) -> Self {
+        Self {
+            mac: root.derive_internal(MAC_LABEL),
+            enc: root.derive_internal(ENC_LABEL),
+        }
+    }
+
+    /// `HMAC(mac_key, version || num || counters)`
+    fn mac(&self, version: u64, num: u64, counters: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.mac).unwrap();
+        mac.update(&version.to_le_bytes());
+        mac.update(&num.to_le_bytes());
+        mac.update(counters);
+        mac
+    }
+
+    /// ChaCha20 keyed by `HMAC(enc_key, mac)`.
+    ///
+    /// The MAC doubles as a synthetic IV, so a key is never reused for
+    /// different tables and no nonce has to be kept.
+    fn cipher(&self, mac: &[u8; MAC_SIZE]) -> ChaCha20 {
+        let mut kdf = Hmac::<Sha256>::new_from_slice(&self.enc).unwrap();
+        kdf.update(mac);
+        let key: [u8; 32] = kdf.finalize().into_bytes().into();
+        ChaCha20::new(&key.into(), &[0; 12].into())
+    }
+}
+
+/// Monotonic counters of enclaves, keyed by the identity chosen by a
+/// [`SealPolicy`] and a counter id.
+///
+/// `version` is bumped on every change, and stored in the [`CounterAnchor`]
+/// before the change is used. An imported [`CounterBlob`] must match the
+/// version stored last, and a non-zero one from the previous boot locks the
+/// counters until the matching blob is imported, so the host can neither
+/// replay an older blob nor reset the counters by dropping it. Without an
+/// anchor, the counters are refused.
+pub struct CounterTable {
+    counters: [Counter; MAX_COUNTERS],
+    num: usize,
+    version: u64,
+    anchor: Option<&'static dyn CounterAnchor>,
+    /// Whether the counters can be used.
+    loaded: bool,
+}
+
+impl CounterTable {
+    pub fn new(anchor: Option<&'static dyn CounterAnchor>) -> Self {
+        let version = anchor.map_or(0, |anchor| anchor.load());
+        Self {
+            counters: [Counter::EMPTY; MAX_COUNTERS],
+            num: 0,
+            version,
+            anchor,
+            loaded: version == 0,
+        }
+    }
+
+    fn anchor(&self) -> Result<&'static dyn CounterAnchor, Error> {
+        self.anchor.ok_or(Error::NoCounterAnchor)
+    }
+
+    /// Bump the version, once the anchor stores it.
+    fn bump(&mut self) -> Result<(), Error> {
+        let version = self.version.checked_add(1).ok_or(Error::NoCounterSpace)?;
+        if !self.anchor()?.store(version) {
+            return Err(Error::InvalidCounters);
+        }
+        self.version = version;
+        Ok(())
+    }
+
+    fn find(
+        &self,
+        policy: SealPolicy,
+        identity: &Measurement,
+        id: &[u8; COUNTER_ID_SIZE],
+    ) -> Result<Option<usize>, Error> {
+        self.anchor()?;
+        if !self.loaded {
+            return Err(Error::InvalidCounters);
+        }
+
+        Ok(self.counters[..self.num].iter().position(|counter| {
+            counter.policy == policy as u64 && counter.identity == *identity && counter.id == *id
+        }))
+    }
+
+    pub fn create(
+        &mut self,
+        policy: SealPolicy,
+        identity: &Measurement,
+        id: &[u8; COUNTER_ID_SIZE],
+    ) -> Result<(), Error> {
+        if self.find(policy, identity, id)?.is_some() {
+            return Err(Error::CounterExists);
+        }
+        if self.num == MAX_COUNTERS {
+            return Err(Error::NoCounterSpace);
+        }
+
+        self.bump()?;
+        self.counters[self.num] = Counter {
+            policy: policy as u64,
+            identity: *identity,
+            id: *id,
+            value: 0,
+        };
+        self.num += 1;
+        Ok(())
+    }
+
+    /// Increment the counter and return its new value.
+    pub fn increment(
+        &mut self,
+        policy: SealPolicy,
+        identity: &Measurement,
+        id: &[u8; COUNTER_ID_SIZE],
+    ) -> Result<u64, Error> {
+        let idx = self.find(policy, identity, id)?.ok_or(Error::NoCounter)?;
+        // an exhausted counter stays at the maximum instead of wrapping
+        let value = self.counters[idx]
+            .value
+            .checked_add(1)
+            .ok_or(Error::NoCounterSpace)?;
+        self.bump()?;
+        self.counters[idx].value = value;
+        Ok(value)
+    }
+
+    pub fn read(
+        &self,
+        policy: SealPolicy,
+        identity: &Measurement,
+        id: &[u8; COUNTER_ID_SIZE],
+    ) -> Result<u64, Error> {
+        let idx = self.find(policy, identity, id)?.ok_or(Error::NoCounter)?;
+        Ok(self.counters[idx].value)
+    }
+
+    /// Seal the table into a [`CounterBlob`], written by `write` at offsets
+    /// of the blob.
+    pub fn export<E>(
+        &self,
+        keys: &BlobKeys,
+        mut write: impl FnMut(usize, &[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let num = self.num as u64;
+        // SAFETY: Counter is `repr(C)` without padding.
+        let counters = unsafe { helper::bytes_of(&self.counters) };
+        let mac: [u8; MAC_SIZE] = keys
+            .mac(self.version, num, counters)
+            .finalize()
+            .into_bytes()
+            .into();
+        let mut cipher = keys.cipher(&mac);
+
+        write(offset_of!(CounterBlob, version), &self.version.to_le_bytes())?;
+        write(offset_of!(CounterBlob, num), &num.to_le_bytes())?;
+        write(offset_of!(CounterBlob, mac), &mac)?;
+
+        let mut buf = [0; COUNTER_ENTRY_SIZE];
+        for (i, counter) in self.counters.iter().enumerate() {
+            // SAFETY: Counter is `repr(C)` without padding.
+            buf.copy_from_slice(unsafe { helper::bytes_of(counter) });
+            cipher.apply_keystream(&mut buf);
+            write(offset_of!(CounterBlob, entries) + i * COUNTER_ENTRY_SIZE, &buf)?;
+        }
+        buf.fill(0);
+
+        Ok(())
+    }
+
+    /// Replace the table by a [`CounterBlob`], read by `read` at offsets of
+    /// the blob.
+    ///
+    /// Only allowed before the counters are used, and the blob must match the
+    /// version the anchor stored last.
+    pub fn import(
+        &mut self,
+        keys: &BlobKeys,
+        mut read: impl FnMut(usize, &mut [u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let anchor = self.anchor()?.load();
+        if self.loaded && self.version != 0 {
+            return Err(Error::InvalidCounters);
+        }
+
+        let mut version = [0; 8];
+        let mut num = [0; 8];
+        let mut mac = [0; MAC_SIZE];
+        read(offset_of!(CounterBlob, version), &mut version)?;
+        read(offset_of!(CounterBlob, num), &mut num)?;
+        read(offset_of!(CounterBlob, mac), &mut mac)?;
+        let version = u64::from_le_bytes(version);
+        let num = u64::from_le_bytes(num);
+        if version != anchor || num > MAX_COUNTERS as u64 {
+            return Err(Error::InvalidCounters);
+        }
+
+        // the table is unused, so it can be decrypted in place
+        // SAFETY: Counter is `repr(C)` without padding, and valid for any bytes.
+        let counters = unsafe { helper::bytes_of_mut(&mut self.counters) };
+        let res = read(offset_of!(CounterBlob, entries), counters).and_then(|_| {
+            keys.cipher(&mac).apply_keystream(counters);
+            keys.mac(version, num, counters)
+                .verify_slice(&mac)
+                .map_err(|_| Error::InvalidCounters)
+        });
+        if let Err(e) = res {
+            self.counters = [Counter::EMPTY; MAX_COUNTERS];
+            return Err(e);
+        }
+
+        self.num = num as usize;
+        self.version = version;
+        self.loaded = true;
+        Ok(())
+    }
+}
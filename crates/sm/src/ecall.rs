@@ -34,6 +34,8 @@ pub struct EcallResult {
     pub proxy: ProxyResult,
     pub retval: usize,
     pub fixed_epc: bool,
+    /// The registers are of a context switched to, kept as they are.
+    pub switched: bool,
 }
 
 impl EcallResult {
@@ -44,6 +46,7 @@ impl EcallResult {
             proxy: ProxyResult::Continue,
             retval: 0,
             fixed_epc: false,
+            switched: false,
         }
     }
 
@@ -53,6 +56,7 @@ impl EcallResult {
             proxy: ProxyResult::Return,
             retval: 0,
             fixed_epc: false,
+            switched: false,
         }
     }
 
@@ -67,6 +71,14 @@ impl EcallResult {
         self.fixed_epc = true;
         self
     }
+
+    /// Return to a context switched to, whose registers are all live.
+    #[inline(always)]
+    pub fn switched(mut self) -> Self {
+        self.fixed_epc = true;
+        self.switched = true;
+        self
+    }
 }
 
 pub trait HandleEcall {
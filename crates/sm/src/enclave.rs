@@ -1,16 +1,17 @@
 use console::log;
-use core::{cell::RefCell, fmt::Display};
+use core::{cell::RefCell, fmt::Display, sync::atomic::Ordering};
 use enclave::{
-    EnclaveId, EnclaveIdGenerator, LinuxServiceEnclave, LinuxServiceEnclaveList, LinuxUserEnclave,
-    LinuxUserEnclaveList,
+    Enclave, EnclaveId, EnclaveIdGenerator, LinuxDriverEnclave, LinuxDriverEnclaveList,
+    LinuxServiceEnclave, LinuxServiceEnclaveList, LinuxUserEnclave, LinuxUserEnclaveList, Thread,
 };
-use riscv::register::satp;
-use spin::Mutex;
+use pma::{PhysMemArea, PhysMemAreaMgr, PmaProp};
+use riscv::register::{Permission, mhartid, satp};
+use spin::{Mutex, RwLock};
 use vm::{
     allocator::FrameAllocator,
+    mm::SV39,
     page_table::{BarePtWriter, PTEFlags},
     prelude::*,
-    trans_direct,
     vm::Sv39VmMgr,
     PAGE_SIZE,
 };
@@ -22,6 +23,35 @@ pub const LAUNCH_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSMRunEnclave as u
 pub const RESUME_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSMResumeEnclave as usize;
 pub const PAUSE_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSMStopEnclave as usize;
 pub const EXIT_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSMExitEnclave as usize;
+pub const ATTEST_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSmAttestEnclave as usize;
+pub const BOOT_LOG: usize = sbi::ecall::SBISMEnclaveCall::SbiSMGetBootLog as usize;
+pub const LOCAL_REPORT: usize = sbi::ecall::SBISMEnclaveCall::SbiSMLocalReport as usize;
+pub const VERIFY_LOCAL_REPORT: usize =
+    sbi::ecall::SBISMEnclaveCall::SbiSMVerifyLocalReport as usize;
+pub const EXTEND_RTMR: usize = sbi::ecall::SBISMEnclaveCall::SbiSMExtendRtmr as usize;
+pub const COUNTER_CREATE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCounterCreate as usize;
+pub const COUNTER_INCREMENT: usize =
+    sbi::ecall::SBISMEnclaveCall::SbiSMCounterIncrement as usize;
+pub const COUNTER_READ: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCounterRead as usize;
+pub const EXPORT_COUNTERS: usize = sbi::ecall::SBISMEnclaveCall::SbiSMExportCounters as usize;
+pub const IMPORT_COUNTERS: usize = sbi::ecall::SBISMEnclaveCall::SbiSMImportCounters as usize;
+pub const RANDOM: usize = sbi::ecall::SBISMEnclaveCall::SbiSMRandom as usize;
+pub const SEALING_KEY: usize = sbi::ecall::SBISMEnclaveCall::SbiSMGetSealingKey as usize;
+pub const CALL_SERVICE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCallService as usize;
+pub const SERVICE_RETURN: usize = sbi::ecall::SBISMEnclaveCall::SbiSMServiceReturn as usize;
+pub const REGISTER_RESTORE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMRegisterRestore as usize;
+pub const FINISH_RESTORE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMFinishRestore as usize;
+pub const CHANNEL_OPEN: usize = sbi::ecall::SBISMEnclaveCall::SbiSMChannelOpen as usize;
+pub const CHANNEL_CONNECT: usize = sbi::ecall::SBISMEnclaveCall::SbiSMChannelConnect as usize;
+pub const CHANNEL_CLOSE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMChannelClose as usize;
+pub const COPY_FROM_LUE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCopyFromLue as usize;
+pub const COPY_TO_LUE: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCopyToLue as usize;
+pub const COPY_FROM_KERNEL: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCopyFromKernel as usize;
+pub const COPY_TO_KERNEL: usize = sbi::ecall::SBISMEnclaveCall::SbiSMCopyToKernel as usize;
+pub const DONATE_MEM: usize = sbi::ecall::SBISMEnclaveCall::SbiSMELock as usize;
+pub const FREE_MEM: usize = sbi::ecall::SBISMEnclaveCall::SbiSMEFree as usize;
+pub const SNAPSHOT_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSMSnapshotEnclave as usize;
+pub const RESTORE_ENC: usize = sbi::ecall::SBISMEnclaveCall::SbiSMRestoreEnclave as usize;
 
 #[derive(Default)]
 pub struct UserArgs {
@@ -30,6 +60,20 @@ pub struct UserArgs {
     pub binary: VirtMemArea,
     pub share: VirtMemArea,
     pub unused: VirtMemArea,
+    /// Host virtual address of the manifest, 0 if the enclave is unsigned.
+    pub manifest: usize,
+    /// Id of the service enclave providing the runtime.
+    pub lse: usize,
+    /// Offset of the entry of a service enclave in its runtime.
+    pub entry: usize,
+    /// Where the host loaded the module a driver enclave takes over.
+    pub driver: VirtMemArea,
+    /// Number of threads of a user enclave.
+    pub threads: usize,
+    /// Time slice of a user enclave, 0 for the default.
+    pub time_slice: u64,
+    /// Time budget of a user enclave, 0 for no limit.
+    pub time_budget: u64,
 }
 
 impl Display for UserArgs {
@@ -39,16 +83,52 @@ impl Display for UserArgs {
 runtime: {}
 binary:  {}
 share:   {}
-unused:  {}",
-            self.mem, self.rt, self.binary, self.share, self.unused
+unused:  {}
+lse:     #{}",
+            self.mem, self.rt, self.binary, self.share, self.unused, self.lse
         ))
     }
 }
 
+impl UserArgs {
+    /// Check that the meta page at the start of `mem` and `parts` make up all
+    /// of `mem`, each part starting at a page and none overlapping another.
+    ///
+    /// Empty parts are left out, wherever they start.
+    fn check_layout(&self, parts: &[VirtMemArea]) -> Result<(), enclave::Error> {
+        let mem = self.mem;
+        let invalid = || {
+            log::error!("host memory is not laid out as expected:\n{self}");
+            enclave::Error::InvalidAddress
+        };
+        let mem_end = mem.start.checked_add(mem.size).ok_or_else(invalid)?;
+        if !aligned!(mem.start, PAGE_SIZE) || !aligned!(mem.size, PAGE_SIZE) || mem.size == 0 {
+            return Err(invalid());
+        }
+
+        let mut sorted: heapless::Vec<&VirtMemArea, 4> =
+            parts.iter().filter(|part| part.size != 0).collect();
+        sorted.sort_unstable_by_key(|part| part.start);
+        let mut end = mem.start + PAGE_SIZE;
+        for part in sorted {
+            if part.start != end || part.size > mem_end - end {
+                return Err(invalid());
+            }
+            end += align_up!(part.size, PAGE_SIZE);
+        }
+        if end != mem_end {
+            return Err(invalid());
+        }
+
+        Ok(())
+    }
+}
+
 pub struct EnclaveMgr {
     eid_gen: EnclaveIdGenerator,
     lue_list: Mutex<LinuxUserEnclaveList>,
     lse_list: Mutex<LinuxServiceEnclaveList>,
+    lde_list: Mutex<LinuxDriverEnclaveList>,
 }
 
 impl EnclaveMgr {
@@ -57,104 +137,365 @@ impl EnclaveMgr {
             eid_gen: EnclaveIdGenerator::new(),
             lue_list: Mutex::new(LinuxUserEnclaveList::new()),
             lse_list: Mutex::new(LinuxServiceEnclaveList::new()),
+            lde_list: Mutex::new(LinuxDriverEnclaveList::new()),
         }
     }
 
-    pub fn get_lse(&self, _: impl Into<EnclaveId>) -> Option<&'static mut LinuxServiceEnclave> {
-        self.lse_list.lock().first()
+    pub fn get_lse(&self, id: impl Into<EnclaveId>) -> Option<&'static mut LinuxServiceEnclave> {
+        self.lse_list.lock().get(id.into())
     }
 
     pub fn get_lue(&self, id: impl Into<EnclaveId>) -> Option<&'static mut LinuxUserEnclave> {
         self.lue_list.lock().get(id.into())
     }
 
+    pub fn get_lde(&self, id: impl Into<EnclaveId>) -> Option<&'static mut LinuxDriverEnclave> {
+        self.lde_list.lock().get(id.into())
+    }
+
+    /// Any enclave with `id`, regardless of its type.
+    pub fn get_enc(&self, id: impl Into<EnclaveId>) -> Option<&'static mut Enclave<()>> {
+        let id = id.into();
+        self.get_lue(id)
+            .map(|enc| enc.idx())
+            .or_else(|| self.lse_list.lock().get(id).map(|enc| enc.idx()))
+            .or_else(|| self.get_lde(id).map(|enc| enc.idx()))
+            .map(|idx| idx.as_enc())
+    }
+
     pub fn get_new_eid(&self) -> EnclaveId {
         self.eid_gen.fetch()
     }
 
-    pub fn push_lue(&self, lue: &'static mut LinuxUserEnclave) {
-        self.lue_list.lock().push(lue);
+    /// Push `lue` if the LSE it maps the runtime of is still there.
+    ///
+    /// Both lists are locked like [`Self::take_lse`] does, so the LSE is not
+    /// destroyed under a LUE built meanwhile.
+    pub fn push_lue(&self, lue: &'static mut LinuxUserEnclave) -> Result<(), enclave::Error> {
+        let mut list = self.lue_list.lock();
+        if self.lse_list.lock().get(lue.data.lse_id).is_none() {
+            log::error!("#{} runs on #{}, which is gone", lue.id(), lue.data.lse_id);
+            return Err(enclave::Error::NoServiceEnclave);
+        }
+        list.push(lue);
+        Ok(())
     }
 
     pub fn push_lse(&self, lse: &'static mut LinuxServiceEnclave) {
         self.lse_list.lock().push(lse);
     }
 
+    pub fn push_lde(&self, lde: &'static mut LinuxDriverEnclave) {
+        self.lde_list.lock().push(lde);
+    }
+
     pub fn rm_lue(&self, eid: EnclaveId) -> Option<&'static mut LinuxUserEnclave> {
         self.lue_list.lock().remove(eid)
     }
+
+    pub fn rm_lde(&self, eid: EnclaveId) -> Option<&'static mut LinuxDriverEnclave> {
+        self.lde_list.lock().remove(eid)
+    }
+
+    /// Get the thread `tid` of the LUE with `id` and mark it running on the
+    /// current hart.
+    ///
+    /// The list is locked meanwhile, so a LUE being removed is never entered.
+    pub fn enter_lue(
+        &self,
+        id: impl Into<EnclaveId>,
+        tid: usize,
+    ) -> Result<(&'static mut LinuxUserEnclave, &'static mut Thread), enclave::Error> {
+        let list = self.lue_list.lock();
+        let enc = list.get(id.into()).ok_or(enclave::Error::InvalidEnclaveId)?;
+        if enc.data.exiting.load(Ordering::SeqCst) {
+            return Err(enclave::Error::EnclaveExited);
+        }
+        if enc.data.out_of_budget() {
+            log::error!("#{} ran for its whole time budget", enc.id());
+            return Err(enclave::Error::TimeBudgetExceeded);
+        }
+        let thread = enc.data.thread(tid).ok_or_else(|| {
+            log::error!("#{} has no thread {tid}", enc.id());
+            enclave::Error::InvalidThread
+        })?;
+        enc.data.enter(thread, mhartid::read()).map_err(|hart| {
+            log::error!("thread {tid} of #{} is running on hart {hart}", enc.id());
+            enclave::Error::EnclaveRunning
+        })?;
+        Ok((enc, thread))
+    }
+
+    /// Get the callable LSE with `id` and mark it serving a call on the
+    /// current hart.
+    pub fn enter_lse(
+        &self,
+        id: impl Into<EnclaveId>,
+    ) -> Result<&'static mut LinuxServiceEnclave, enclave::Error> {
+        let enc = self.get_lse(id).ok_or(enclave::Error::NoServiceEnclave)?;
+        if !enc.data.is_callable() {
+            log::error!("#{} only provides a runtime", enc.id());
+            return Err(enclave::Error::ServiceUnavailable);
+        }
+        enc.enter(mhartid::read()).map_err(|hart| {
+            log::error!("#{} is serving a call on hart {hart}", enc.id());
+            enclave::Error::EnclaveRunning
+        })?;
+        Ok(enc)
+    }
+
+    /// Get the LDE with `id` and mark it running on the current hart.
+    ///
+    /// The list is locked meanwhile, so an LDE being removed is never entered.
+    pub fn enter_lde(
+        &self,
+        id: impl Into<EnclaveId>,
+    ) -> Result<&'static mut LinuxDriverEnclave, enclave::Error> {
+        let list = self.lde_list.lock();
+        let enc = list.get(id.into()).ok_or(enclave::Error::InvalidEnclaveId)?;
+        enc.enter(mhartid::read()).map_err(|hart| {
+            log::error!("#{} is running on hart {hart}", enc.id());
+            enclave::Error::EnclaveRunning
+        })?;
+        Ok(enc)
+    }
+
+    /// Mark `enc` exiting, so none of its threads is entered again.
+    ///
+    /// The threads running by now are the ones to stop.
+    pub fn exit_lue(&self, enc: &LinuxUserEnclave) {
+        let _list = self.lue_list.lock();
+        enc.data.exiting.store(true, Ordering::SeqCst);
+    }
+
+    /// Remove the LUE with `id` if none of its threads is running.
+    pub fn take_lue(
+        &self,
+        id: impl Into<EnclaveId>,
+    ) -> Result<&'static mut LinuxUserEnclave, enclave::Error> {
+        let mut list = self.lue_list.lock();
+        let eid = id.into();
+        let enc = list.get(eid).ok_or(enclave::Error::InvalidEnclaveId)?;
+        // threads are only entered with the list locked, so none can start
+        if enc.data.is_running() {
+            log::error!("#{eid} is running");
+            return Err(enclave::Error::EnclaveRunning);
+        }
+        Ok(list.remove(eid).unwrap())
+    }
+
+    /// Remove the LDE with `id` if it is not running on any hart.
+    pub fn take_lde(
+        &self,
+        id: impl Into<EnclaveId>,
+    ) -> Result<&'static mut LinuxDriverEnclave, enclave::Error> {
+        let mut list = self.lde_list.lock();
+        let eid = id.into();
+        let enc = list.get(eid).ok_or(enclave::Error::InvalidEnclaveId)?;
+        // never left, as the enclave is about to be scrubbed
+        enc.enter(mhartid::read()).map_err(|hart| {
+            log::error!("#{eid} is running on hart {hart}");
+            enclave::Error::EnclaveRunning
+        })?;
+        Ok(list.remove(eid).unwrap())
+    }
+
+    /// Remove the LSE with `id` if no LUE runs on its runtime and it is not
+    /// serving a call.
+    pub fn take_lse(
+        &self,
+        id: impl Into<EnclaveId>,
+    ) -> Result<&'static mut LinuxServiceEnclave, enclave::Error> {
+        let lues = self.lue_list.lock();
+        let mut list = self.lse_list.lock();
+        let eid = id.into();
+        let enc = list.get(eid).ok_or(enclave::Error::InvalidEnclaveId)?;
+        if let Some(lue) = lues.iter().find(|lue| lue.data.lse_id == enc.id()) {
+            log::error!("#{} runs on the runtime of #{eid}", lue.id());
+            return Err(enclave::Error::EnclaveRunning);
+        }
+        // never left, as the enclave is about to be scrubbed
+        enc.enter(mhartid::read()).map_err(|hart| {
+            log::error!("#{eid} is serving a call on hart {hart}");
+            enclave::Error::EnclaveRunning
+        })?;
+        Ok(list.remove(eid).unwrap())
+    }
+}
+
+/// Where a [`Builder`] takes the frames of the memory it builds an enclave in.
+pub trait FrameSource {
+    /// Take the frame at `vaddr` of the memory, which is only handed out
+    /// once.
+    fn take(&self, vaddr: usize) -> Result<PhysAddr, enclave::Error>;
 }
 
-pub struct Builder {
-    pub vmm: Sv39VmMgr<BarePtWriter, BuilderAllocator>,
+/// The memory of the host a creation claimed for `eid`, the frames of which
+/// are pending until they are taken.
+///
+/// The host may have remapped it since, so a frame is found again through
+/// the host page table, and only taken if it is still pending. A frame
+/// mapped twice is thus never handed out twice.
+pub struct Claim<'a, P> {
+    pma_mgr: &'a RwLock<PhysMemAreaMgr>,
+    eid: EnclaveId,
+    mem: VirtMemArea,
+    prop: P,
 }
 
-impl Builder {
+impl<'a, P: Fn(usize) -> Option<PmaProp>> Claim<'a, P> {
+    pub fn new(
+        pma_mgr: &'a RwLock<PhysMemAreaMgr>,
+        eid: EnclaveId,
+        mem: VirtMemArea,
+        prop: P,
+    ) -> Self {
+        Self {
+            pma_mgr,
+            eid,
+            mem,
+            prop,
+        }
+    }
+
+    /// Share the taken frames meant for everyone, and give the frames not
+    /// taken back to the host, untouched.
+    pub fn commit(self) {
+        let mut mgr = self.pma_mgr.write();
+        loop {
+            let pending = |pma: &PhysMemArea| {
+                pma.get_prop().get_owner() == self.eid && pma.get_prop().is_pending()
+            };
+            let Some(pma) = mgr.iter_pma().find(pending) else {
+                break;
+            };
+            let prop = if pma.get_prop().is_shared() {
+                PmaProp::empty()
+                    .owner(EnclaveId::EVERYONE)
+                    .permission(pma.get_prop().get_owner_perm())
+            } else {
+                PmaProp::empty()
+                    .owner(EnclaveId::HOST)
+                    .permission(Permission::RWX)
+            };
+            mgr.insert_pma(PhysMemArea {
+                region: pma.get_region(),
+                prop,
+            })
+            .unwrap();
+        }
+    }
+}
+
+impl<P: Fn(usize) -> Option<PmaProp>> FrameSource for Claim<'_, P> {
+    fn take(&self, vaddr: usize) -> Result<PhysAddr, enclave::Error> {
+        let pending = PmaProp::empty().owner(self.eid).pending();
+        let prop =
+            (self.prop)(vaddr).filter(|_| vaddr.wrapping_sub(self.mem.start) < self.mem.size);
+        let paddr =
+            VirtAddr(vaddr).translate(self.mem.satp.ppn(), self.mem.satp.mode(), &BarePtReader);
+        let mut mgr = self.pma_mgr.write();
+        match (paddr, prop) {
+            (Some(paddr), Some(prop))
+                if mgr
+                    .get_pma(paddr)
+                    .is_some_and(|pma| pma.get_prop() == pending) =>
+            {
+                // a shared frame stays the creation's until it succeeds
+                let prop = if prop.get_owner() == EnclaveId::EVERYONE {
+                    pending.permission(prop.get_owner_perm()).shared()
+                } else {
+                    prop
+                };
+                mgr.insert_page(paddr, prop);
+                Ok(paddr)
+            }
+            _ => {
+                log::error!("{vaddr:#x} is not a frame left to #{}", self.eid);
+                Err(enclave::Error::InvalidAddress)
+            }
+        }
+    }
+}
+
+pub struct Builder<'a> {
+    pub vmm: Sv39VmMgr<BarePtWriter, BuilderAllocator<'a>>,
+}
+
+impl<'a> Builder<'a> {
+    /// Build a page table in the frames `allocator` hands out.
+    pub fn new(allocator: BuilderAllocator<'a>) -> Result<Self, enclave::Error> {
+        let root = allocator.alloc().ok_or_else(no_frame)?;
+        Ok(Self {
+            vmm: Sv39VmMgr::new(root, BarePtWriter, allocator, satp::read().asid(), SV39),
+        })
+    }
+
     pub fn create_lue(
         &mut self,
         userargs: &UserArgs,
         eid: EnclaveId,
-    ) -> &'static mut LinuxUserEnclave {
+    ) -> Result<&'static mut LinuxUserEnclave, enclave::Error> {
         // create enclave at first page
-        let meta_page = VirtAddr(userargs.mem.start)
-            .translate(
-                userargs.mem.satp.ppn(),
-                userargs.mem.satp.mode(),
-                &BarePtReader,
-            )
-            .unwrap();
+        let meta_page = self.vmm.frame_allocator.take(userargs.mem.start)?;
         log::debug!("meta page: {:#x}", meta_page.0);
-        enclave::create_lue_at(meta_page.0, eid)
+        Ok(enclave::create_lue_at(meta_page.0, eid))
     }
 
     pub fn create_lse(
         &mut self,
         userargs: &UserArgs,
         eid: EnclaveId,
-    ) -> &'static mut LinuxServiceEnclave {
+    ) -> Result<&'static mut LinuxServiceEnclave, enclave::Error> {
         // alloc meta page and update meta page ownership
-        let meta_page = VirtAddr(userargs.mem.start)
-            .translate(
-                userargs.mem.satp.ppn(),
-                userargs.mem.satp.mode(),
-                &BarePtReader,
-            )
-            .unwrap();
+        let meta_page = self.vmm.frame_allocator.take(userargs.mem.start)?;
         log::debug!("meta page: {:#x}", meta_page.0);
-        enclave::create_lse_at(meta_page.0, eid)
+        Ok(enclave::create_lse_at(meta_page.0, eid))
     }
 
-    pub fn create_trampoline(&mut self, vma: VirtMemArea) -> VirtMemArea {
+    pub fn create_lde(
+        &mut self,
+        userargs: &UserArgs,
+        eid: EnclaveId,
+    ) -> Result<&'static mut LinuxDriverEnclave, enclave::Error> {
+        // create enclave at first page
+        let meta_page = self.vmm.frame_allocator.take(userargs.mem.start)?;
+        log::debug!("meta page: {:#x}", meta_page.0);
+        Ok(enclave::create_lde_at(meta_page.0, eid))
+    }
+
+    /// Map the frames of `vma` where they are, which the monitor has mapped
+    /// in a page table of its own.
+    pub fn create_trampoline(&mut self, vma: VirtMemArea) -> Result<VirtMemArea, enclave::Error> {
         let mut tp = VirtMemArea::default().satp(satp::Satp::from_bits(self.vmm.gen_satp()));
         debug_assert_ne!(tp.satp.bits(), vma.satp.bits());
         for vpn in vma.iter_vpn() {
             let paddr = vpn
                 .translate(vma.satp.ppn(), vma.satp.mode(), &BarePtReader)
-                .unwrap();
+                .ok_or(enclave::Error::InvalidAddress)?;
             let dst_vpn = VirtPageNum::from_vaddr(paddr.0);
             if tp.is_empty() {
                 tp = tp.start(dst_vpn);
             }
             tp = tp.size(tp.size + PAGE_SIZE);
-            self.vmm.map_frame(
+            self.map(
                 dst_vpn,
                 PhysPageNum::from_paddr(paddr),
                 PTEFlags::rx().accessed(),
-            );
+            )?;
         }
 
-        tp
+        Ok(tp)
     }
 
-    pub fn collect_unused(&mut self) -> (usize, usize) {
+    pub fn collect_unused(&mut self) -> Result<(usize, usize), enclave::Error> {
         let vma = self.vmm.frame_allocator.vma();
         // link_remain_frame(vma)
         log::debug!("remaining free size: {:#x}", vma.size);
 
         let mut ll_node = 0;
         for offset in (0..vma.size).step_by(0x1000).rev() {
-            let vaddr = VirtAddr(vma.start + offset);
-            let paddr = trans_direct(vaddr, vma.satp.ppn(), vma.satp.mode()).unwrap();
+            let paddr = self.vmm.frame_allocator.take(vma.start + offset)?;
             // clean content
             unsafe {
                 let slice = core::slice::from_raw_parts_mut(paddr.0 as *mut u8, 0x1000);
@@ -165,120 +506,214 @@ impl Builder {
             };
             ll_node = paddr.0;
         }
-        (ll_node, vma.size)
+        Ok((ll_node, vma.size))
     }
 
-    pub fn map_vma(&mut self, src: VirtMemArea, dst: VirtMemArea) -> Option<VirtMemArea> {
+    /// Map `src` of the memory the enclave is built in at `dst`.
+    pub fn map_vma(
+        &mut self,
+        src: VirtMemArea,
+        dst: VirtMemArea,
+    ) -> Result<VirtMemArea, enclave::Error> {
         let dst = dst.satp(satp::Satp::from_bits(self.vmm.gen_satp()));
         assert_eq!(src.size, dst.size);
         for (v_s, v_d) in src.iter_vpn().zip(dst.iter_vpn()) {
-            let ppn = v_s
-                .translate(src.satp.ppn(), src.satp.mode(), &BarePtReader)
-                .unwrap();
-            self.vmm.map_frame(v_d, ppn.into(), dst.flags);
+            let paddr = self.vmm.frame_allocator.take(VirtAddr::from(v_s).0)?;
+            self.map(v_d, paddr.into(), dst.flags)?;
+        }
+        Ok(dst)
+    }
+
+    /// Map the runtime `rt` a service enclave recorded at `dst`.
+    pub fn map_runtime(
+        &mut self,
+        rt: VirtMemArea,
+        dst: VirtMemArea,
+    ) -> Result<VirtMemArea, enclave::Error> {
+        let dst = dst.satp(satp::Satp::from_bits(self.vmm.gen_satp()));
+        assert_eq!(rt.size, dst.size);
+        for (v_s, v_d) in rt.iter_vpn().zip(dst.iter_vpn()) {
+            let paddr = v_s
+                .translate(rt.satp.ppn(), rt.satp.mode(), &BarePtReader)
+                .ok_or(enclave::Error::InvalidAddress)?;
+            self.map(v_d, paddr.into(), dst.flags)?;
         }
-        Some(dst)
+        Ok(dst)
     }
 
-    pub fn alloc_vma(&mut self, vma: VirtMemArea) -> Option<VirtMemArea> {
+    pub fn alloc_vma(&mut self, vma: VirtMemArea) -> Result<VirtMemArea, enclave::Error> {
         let vma = vma.satp(satp::Satp::from_bits(self.vmm.gen_satp()));
-        self.vmm.alloc_vma(vma)
+        self.vmm.alloc_vma(vma).ok_or_else(no_frame)
+    }
+
+    /// Allocate a page of the enclave that is not mapped, returning its
+    /// physical address.
+    pub fn alloc_page(&mut self) -> Result<usize, enclave::Error> {
+        self.vmm
+            .frame_allocator
+            .alloc()
+            .map(|ppn| PhysAddr::from_ppn(ppn).0)
+            .ok_or_else(no_frame)
     }
 
-    pub fn map_frames(&mut self, ppn: PhysPageNum, vma: VirtMemArea) -> VirtMemArea {
+    pub fn map_frames(
+        &mut self,
+        ppn: PhysPageNum,
+        vma: VirtMemArea,
+    ) -> Result<VirtMemArea, enclave::Error> {
         let vma = vma.satp(satp::Satp::from_bits(self.vmm.gen_satp()));
         for (i, vpn) in vma.iter_vpn().enumerate() {
             let ppn = ppn.add(i);
-            self.vmm.map_frame(vpn, ppn, vma.flags);
+            self.map(vpn, ppn, vma.flags)?;
         }
 
-        vma
+        Ok(vma)
+    }
+
+    fn map(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+    ) -> Result<(), enclave::Error> {
+        self.vmm.try_map_frame(vpn, ppn, flags).ok_or_else(|| {
+            log::error!("cannot map {:#x} of the enclave", VirtAddr::from(vpn).0);
+            enclave::Error::InvalidAddress
+        })
     }
 }
 
+fn no_frame() -> enclave::Error {
+    log::error!("no frame is left to build the enclave");
+    enclave::Error::InvalidAddress
+}
+
 pub mod lse {
     use channel::h2e::LseInfo;
-    use riscv::register::satp;
+    use console::log;
+    use pma::PhysMemAreaMgr;
     use vm::prelude::*;
 
     use super::UserArgs;
+    use crate::helper;
 
-    pub fn get_user_args(addr: usize) -> UserArgs {
-        let load_info = unsafe {
-            let paddr = VirtAddr(addr)
-                .translate(satp::read().ppn(), satp::read().mode(), &BarePtReader)
-                .unwrap();
+    /// Copy the [`LseInfo`] at `addr` of the host, and check the memory it
+    /// describes is laid out as the meta page, the runtime and the private
+    /// memory.
+    pub fn get_user_args(mgr: &PhysMemAreaMgr, addr: usize) -> Result<UserArgs, enclave::Error> {
+        // SAFETY: LseInfo is made of addresses and sizes, valid for any bytes
+        let load_info: LseInfo = unsafe { helper::read_from_host(mgr, addr) }.map_err(|e| {
+            log::error!("cannot read the service enclave info: {e}");
+            enclave::Error::InvalidAddress
+        })?;
 
-            &*(paddr.0 as *const LseInfo)
-        };
-
-        UserArgs {
+        let args = UserArgs {
             mem: VirtMemArea::default()
                 .start(load_info.mem.start as usize)
-                .size(load_info.mem.page_num * PAGE_SIZE),
+                .size(load_info.mem.page_num.saturating_mul(PAGE_SIZE)),
             rt: VirtMemArea::default()
                 .start(load_info.rt.ptr as usize)
                 .size(load_info.rt.size),
+            unused: VirtMemArea::default()
+                .start(load_info.unused.start as usize)
+                .size(load_info.unused.size),
+            entry: load_info.entry,
             ..Default::default()
+        };
+        // the trampoline is the first page of the runtime
+        if args.rt.size == 0 || args.rt.start != args.mem.start.wrapping_add(PAGE_SIZE) {
+            log::error!("runtime at {} is not after the meta page", args.rt);
+            return Err(enclave::Error::InvalidAddress);
         }
+        args.check_layout(&[args.rt, args.unused])?;
+
+        Ok(args)
     }
 }
 
 pub mod lue {
-    use channel::h2e::LueInfo;
+    use channel::{e2r::LueBootArgs, h2e::LueInfo, snapshot::RestoreInfo};
     use console::log;
     use context::SupervisorRegs;
     use device::device::Device;
-    use enclave::{Layout, LinuxServiceEnclave, LinuxUserEnclave};
+    use enclave::{Layout, LinuxServiceEnclave, LinuxUserEnclave, Thread};
+    use pma::PhysMemAreaMgr;
     use riscv::register::satp;
-    use sbi::TrapRegs;
+    use sbi::{TrapRegs, ecall::STOP_TIMER_INTERRUPT};
     use vm::prelude::*;
 
     use super::UserArgs;
+    use crate::helper;
 
-    pub fn prepare_launch(enc: &mut LinuxUserEnclave, regs: &mut TrapRegs) -> (usize, usize) {
+    /// Returns the entry arguments of `thread`, its id and the bootargs.
+    pub fn prepare_launch(thread: &mut Thread, regs: &mut TrapRegs) -> (usize, usize) {
         let sregs = SupervisorRegs::dump();
-        enc.nw_ctx.sregs = sregs;
-        enc.nw_ctx.tregs = regs.clone();
+        thread.nw_ctx.sregs = sregs;
+        thread.nw_ctx.tregs = regs.clone();
         // set mepc to the next instruction
-        enc.nw_ctx.tregs.mepc += 0x2;
+        thread.nw_ctx.tregs.mepc += 0x2;
 
         // prepare satp
-        debug_assert_ne!(enc.data.enc_ctx.sregs.satp, 0);
-        debug_assert_ne!(enc.data.enc_ctx.sregs.satp, satp::read().bits());
-        satp::write(enc.data.enc_ctx.sregs.satp);
+        debug_assert_ne!(thread.enc_ctx.sregs.satp, 0);
+        debug_assert_ne!(thread.enc_ctx.sregs.satp, satp::read().bits());
+        satp::write(thread.enc_ctx.sregs.satp);
 
-        enc.pmp_record.start();
+        thread.pmp_record.start();
+        thread.launched = true;
 
-        (enc.data.enc_ctx.tregs.a0, enc.data.enc_ctx.tregs.a1)
+        (thread.enc_ctx.tregs.a0, thread.enc_ctx.tregs.a1)
     }
 
-    pub fn pause(enc: &mut LinuxUserEnclave, regs: &mut TrapRegs) -> Result<(), enclave::Error> {
+    pub fn pause(
+        enc: &mut LinuxUserEnclave,
+        thread: &mut Thread,
+        regs: &mut TrapRegs,
+    ) -> Result<(), enclave::Error> {
         log::debug!("Pausing lue #{}", enc.id().0);
         regs.mepc += 0x4;
         let rc = regs.a0;
 
-        enc.data.enc_ctx.save(&regs);
+        thread.enc_ctx.save(&regs);
         log::debug!("saved enclave context:");
-        log::debug!("satp: {:#x}", enc.data.enc_ctx.sregs.satp);
-        log::debug!("sscratch: {:#x}", enc.data.enc_ctx.sregs.sscratch);
-        log::debug!("sstatus: {:#x}", enc.data.enc_ctx.sregs.sstatus);
-        log::debug!("stvec: {:#x}", enc.data.enc_ctx.sregs.stvec);
-        log::debug!("sepc: {:#x}", enc.data.enc_ctx.sregs.sepc);
-        log::debug!("scaues: {:#x}", enc.data.enc_ctx.sregs.scaues);
-        log::debug!("stval: {:#x}", enc.data.enc_ctx.sregs.stval);
+        log::debug!("satp: {:#x}", thread.enc_ctx.sregs.satp);
+        log::debug!("sscratch: {:#x}", thread.enc_ctx.sregs.sscratch);
+        log::debug!("sstatus: {:#x}", thread.enc_ctx.sregs.sstatus);
+        log::debug!("stvec: {:#x}", thread.enc_ctx.sregs.stvec);
+        log::debug!("sepc: {:#x}", thread.enc_ctx.sregs.sepc);
+        log::debug!("scaues: {:#x}", thread.enc_ctx.sregs.scaues);
+        log::debug!("stval: {:#x}", thread.enc_ctx.sregs.stval);
 
-        enc.data.pmp_cache.dump();
+        thread.pmp_cache.dump();
         log::debug!("dumpped pmp entires");
 
-        *regs = unsafe { enc.nw_ctx.restore() };
+        // the pause returns nothing, unless the host donates memory meanwhile
+        thread.enc_ctx.tregs.a0 = 0;
+        thread.enc_ctx.tregs.a1 = 0;
+
+        *regs = unsafe { thread.nw_ctx.restore() };
         regs.a0 = 0;
         regs.a1 = rc;
 
-        enc.data.switch_cycle.end();
+        thread.switch_cycle.end();
         Ok(())
     }
 
+    /// Save the context of `thread`, stopped as its time slice is over, and
+    /// return to the host that entered it.
+    ///
+    /// Unlike [`pause`], every register of the thread is kept as it was.
+    pub fn preempt(thread: &mut Thread, regs: &mut TrapRegs) {
+        thread.enc_ctx.save(&regs);
+        thread.pmp_cache.dump();
+        thread.preempted = true;
+
+        *regs = unsafe { thread.nw_ctx.restore() };
+        regs.a0 = 0;
+        regs.a1 = STOP_TIMER_INTERRUPT;
+
+        thread.switch_cycle.end();
+    }
+
     pub fn create_bootargs(
         bootargs_vma: VirtMemArea,
         mem: VirtMemArea,
@@ -287,7 +722,7 @@ pub mod lue {
         unused_head: usize,
         unused_size: usize,
         device: Device,
-    ) {
+    ) -> &'static LueBootArgs {
         use channel::enclave::runtime::*;
 
         let paddr = bootargs_vma
@@ -326,6 +761,8 @@ pub mod lue {
             },
             device,
         };
+
+        args
     }
 
     pub fn init_layout(args: &UserArgs, lse: &LinuxServiceEnclave) -> enclave::Layout {
@@ -338,6 +775,7 @@ pub mod lue {
         // layout.share.size = align_up!(args.share.size, PAGE_SIZE);
         layout.share.size = args.share.size;
         layout.bootargs.size = PAGE_SIZE;
+        layout.threads = args.threads;
 
         debug_assert_eq!(
             args.mem.size,
@@ -351,20 +789,21 @@ pub mod lue {
         layout
     }
 
-    pub fn get_args(addr: usize) -> UserArgs {
-        debug_assert_ne!(addr, 0);
-        let load_info = unsafe {
-            let paddr = VirtAddr(addr)
-                .translate(satp::read().ppn(), satp::read().mode(), &BarePtReader)
-                .unwrap();
-
-            &*(paddr.0 as *const LueInfo)
-        };
+    /// Copy the [`LueInfo`] at `addr` of the host, and check the memory it
+    /// describes is laid out as the meta page, the runtime, the binary, the
+    /// shared and the private memory.
+    pub fn get_args(mgr: &PhysMemAreaMgr, addr: usize) -> Result<UserArgs, enclave::Error> {
+        // SAFETY: LueInfo is made of addresses, sizes and counts, valid for
+        // any bytes
+        let load_info: LueInfo = unsafe { helper::read_from_host(mgr, addr) }.map_err(|e| {
+            log::error!("cannot read the user enclave info: {e}");
+            enclave::Error::InvalidAddress
+        })?;
 
-        UserArgs {
+        let args = UserArgs {
             mem: VirtMemArea::default()
                 .start(load_info.mem.start as usize)
-                .size(load_info.mem.page_num * PAGE_SIZE),
+                .size(load_info.mem.page_num.saturating_mul(PAGE_SIZE)),
             rt: VirtMemArea::default()
                 .start(load_info.rt.ptr as usize)
                 .size(load_info.rt.size),
@@ -377,24 +816,245 @@ pub mod lue {
             unused: VirtMemArea::default()
                 .start(load_info.unused.start as usize)
                 .size(load_info.unused.size),
+            manifest: load_info.manifest as usize,
+            lse: load_info.lse,
+            threads: load_info.threads.max(1),
+            time_slice: load_info.time_slice,
+            time_budget: load_info.time_budget,
+            ..Default::default()
+        };
+        if args.threads > enclave::MAX_THREADS {
+            log::error!("{} threads are asked for", args.threads);
+            return Err(enclave::Error::InvalidThread);
+        }
+        // mapped as they are, so they must be whole pages
+        if !aligned!(args.share.size, PAGE_SIZE) || !aligned!(args.unused.size, PAGE_SIZE) {
+            log::error!(
+                "shared {} or private {} memory is not in pages",
+                args.share,
+                args.unused
+            );
+            return Err(enclave::Error::InvalidAddress);
+        }
+        args.check_layout(&[args.rt, args.binary, args.share, args.unused])?;
+
+        Ok(args)
+    }
+
+    /// Private memory a user enclave running on a runtime of `rt_size` bytes
+    /// needs at least, for its page table, boot arguments and threads.
+    ///
+    /// Each part of the address space takes a table per 2MiB it spans, and
+    /// the parts fall in five regions of 1GiB at most, each with a table.
+    pub fn unused_needed(args: &UserArgs, rt_size: usize) -> usize {
+        let pages = |size: usize| size.div_ceil(PAGE_SIZE);
+        let tables = |pages: usize| pages.div_ceil(512) + 1;
+        let threads = 2 * args.threads;
+        // the root, tables of the trampoline, boot arguments and serial, and
+        // the page the allocator keeps
+        let fixed = 1 + 5 + 3 + 1;
+
+        (fixed
+            + tables(pages(rt_size))
+            + tables(threads)
+            + tables(pages(args.binary.size) + pages(args.share.size))
+            + 1
+            + threads)
+            * PAGE_SIZE
+    }
+
+    /// The memory, shared memory and service enclave of a restore, and where
+    /// the snapshot is in host memory.
+    pub fn get_restore_args(
+        mgr: &PhysMemAreaMgr,
+        addr: usize,
+    ) -> Result<(UserArgs, VirtMemArea), enclave::Error> {
+        // SAFETY: RestoreInfo is made of addresses and sizes, valid for any bytes
+        let info: RestoreInfo = unsafe { helper::read_from_host(mgr, addr) }.map_err(|e| {
+            log::error!("cannot read the restore info: {e}");
+            enclave::Error::InvalidAddress
+        })?;
+
+        let args = UserArgs {
+            mem: VirtMemArea::default()
+                .start(info.mem.start as usize)
+                .size(info.mem.page_num.saturating_mul(PAGE_SIZE)),
+            share: VirtMemArea::default()
+                .start(info.shared.ptr as usize)
+                .size(info.shared.size),
+            lse: info.lse,
+            ..Default::default()
+        };
+        if (info.snapshot as usize).checked_add(info.size).is_none() {
+            log::error!("the snapshot at {:p} overflows", info.snapshot);
+            return Err(enclave::Error::InvalidAddress);
         }
+        let snapshot = VirtMemArea::default()
+            .start(info.snapshot as usize)
+            .size(info.size);
+
+        Ok((args, snapshot))
     }
 }
 
-#[inline]
-pub fn measure_data(vma: VirtMemArea) -> md5::Digest {
-    let mut ctx = md5::Context::new();
+pub mod lde {
+    use channel::{
+        e2r::LdeBootArgs,
+        h2e::{LdeInfo, Sections},
+    };
+    use console::log;
+    use context::SupervisorRegs;
+    use device::device::Device;
+    use enclave::{Layout, LinuxDriverEnclave};
+    use pma::PhysMemAreaMgr;
+    use riscv::register::satp;
+    use sbi::TrapRegs;
+    use vm::prelude::*;
 
-    for vpn in vma.iter_vpn() {
-        let paddr = vpn
-            .translate(vma.satp.ppn(), vma.satp.mode(), &BarePtReader)
-            .unwrap();
+    use super::UserArgs;
+    use crate::helper;
+
+    /// The arguments of a driver enclave, and the sections of its module
+    /// in the host.
+    ///
+    /// The [`LdeInfo`] at `addr` of the host is copied, and the memory it
+    /// describes must be laid out as the meta page, the runtime, the binary
+    /// and the private memory.
+    pub fn get_args(
+        mgr: &PhysMemAreaMgr,
+        addr: usize,
+    ) -> Result<(UserArgs, Sections), enclave::Error> {
+        // SAFETY: LdeInfo is made of addresses, sizes and names, valid for
+        // any bytes
+        let load_info: LdeInfo = unsafe { helper::read_from_host(mgr, addr) }.map_err(|e| {
+            log::error!("cannot read the driver enclave info: {e}");
+            enclave::Error::InvalidAddress
+        })?;
+
+        let args = UserArgs {
+            mem: VirtMemArea::default()
+                .start(load_info.mem.start as usize)
+                .size(load_info.mem.page_num.saturating_mul(PAGE_SIZE)),
+            rt: VirtMemArea::default()
+                .start(load_info.rt.ptr as usize)
+                .size(load_info.rt.size),
+            binary: VirtMemArea::default()
+                .start(load_info.bin.ptr as usize)
+                .size(load_info.bin.size),
+            unused: VirtMemArea::default()
+                .start(load_info.unused.start as usize)
+                .size(load_info.unused.size),
+            driver: VirtMemArea::default()
+                .start(load_info.driver.ptr as usize)
+                .size(load_info.driver.size),
+            ..Default::default()
+        };
+        args.check_layout(&[args.rt, args.binary, args.unused])?;
+
+        Ok((args, load_info.driver.sections.clone()))
+    }
 
-        let bytes = unsafe { core::slice::from_raw_parts(paddr.0 as *const u8, 0x1000) };
-        ctx.consume(bytes);
+    /// Save the context of the host launching `enc`, and switch to the
+    /// address space of the driver.
+    ///
+    /// Returns the arguments of the entry of the runtime.
+    pub fn prepare_launch(enc: &mut LinuxDriverEnclave, regs: &TrapRegs) -> (usize, usize) {
+        enc.nw_ctx.sregs = SupervisorRegs::dump();
+        enc.nw_ctx.tregs = regs.clone();
+        // set mepc to the next instruction
+        enc.nw_ctx.tregs.mepc += 0x2;
+
+        debug_assert_ne!(enc.data.enc_ctx.sregs.satp, 0);
+        satp::write(enc.data.enc_ctx.sregs.satp);
+        enc.data.launched = true;
+
+        (enc.data.enc_ctx.tregs.a0, enc.data.enc_ctx.tregs.a1)
     }
 
-    ctx.compute()
+    /// Save the context of `enc`, which pauses with the result in `a0`, and
+    /// return it to the host that entered the driver.
+    pub fn pause(enc: &mut LinuxDriverEnclave, regs: &mut TrapRegs) {
+        log::debug!("Pausing lde #{}", enc.id());
+        regs.mepc += 0x4;
+        let rc = regs.a0;
+
+        enc.data.enc_ctx.save(regs);
+        enc.data.pmp_cache.dump();
+        enc.data.enc_ctx.tregs.a0 = 0;
+        enc.data.enc_ctx.tregs.a1 = 0;
+
+        *regs = unsafe { enc.nw_ctx.restore() };
+        regs.a0 = 0;
+        regs.a1 = rc;
+    }
+
+    pub fn init_layout(args: &UserArgs) -> Layout {
+        let mut layout = Layout::default();
+        layout.rt.size = args.rt.size;
+        layout.binary.size = args.binary.size;
+        layout.bootargs.size = PAGE_SIZE;
+        layout.kernel.size = PAGE_SIZE;
+
+        debug_assert_eq!(
+            args.mem.size,
+            align_up!(layout.rt.size, PAGE_SIZE)
+                + align_up!(layout.binary.size, PAGE_SIZE)
+                + align_up!(args.unused.size, PAGE_SIZE)
+                + 0x1000
+        );
+
+        layout
+    }
+
+    pub fn create_bootargs(
+        bootargs_vma: VirtMemArea,
+        mem: VirtMemArea,
+        layout: &Layout,
+        userargs: &UserArgs,
+        sections: Sections,
+        unused_head: usize,
+        unused_size: usize,
+        device: Device,
+    ) -> &'static LdeBootArgs {
+        use channel::enclave::runtime::*;
+
+        let paddr = bootargs_vma
+            .start
+            .translate(
+                bootargs_vma.satp.ppn(),
+                bootargs_vma.satp.mode(),
+                &BarePtReader,
+            )
+            .unwrap();
+        let args = unsafe { &mut *(paddr.0 as *mut LdeBootArgs) };
+
+        *args = LdeBootArgs {
+            mem: MemArg {
+                total_size: mem.size,
+            },
+            mods: ModArg {
+                start_vaddr: 0,
+                num: 0,
+            },
+            tp: TpArg {
+                addr: layout.trampoline.start,
+            },
+            bin: BinArg {
+                start: layout.binary.start,
+                size: layout.binary.size,
+            },
+            unmapped: UnmappedArg {
+                head: unused_head,
+                size: unused_size,
+            },
+            driver_start: userargs.driver.start,
+            driver_size: userargs.driver.size,
+            sections,
+            device,
+        };
+
+        args
+    }
 }
 
 struct InnerAllocator {
@@ -423,37 +1083,43 @@ impl InnerAllocator {
             )
         }
     }
-
-    pub fn alloc_frame(&mut self) -> Option<PhysPageNum> {
-        self.alloc_vpage()
-            // .map(|vma| VirtAddr::from(vma))
-            .and_then(|vma| {
-                vma.start
-                    .translate(vma.satp.ppn(), vma.satp.mode(), &BarePtReader)
-            })
-            .map(|paddr| PhysPageNum::from_paddr(paddr))
-    }
 }
 
-pub struct BuilderAllocator {
+/// Hands out the frames of `vma` of the memory of a [`FrameSource`] in
+/// order.
+pub struct BuilderAllocator<'a> {
     inner: RefCell<InnerAllocator>,
+    frames: &'a dyn FrameSource,
 }
 
-impl BuilderAllocator {
-    pub fn new(vma: VirtMemArea) -> Self {
+impl<'a> BuilderAllocator<'a> {
+    pub fn new(vma: VirtMemArea, frames: &'a dyn FrameSource) -> Self {
         Self {
             inner: RefCell::new(InnerAllocator::new(vma)),
+            frames,
         }
     }
 
     pub fn vma(&self) -> VirtMemArea {
         self.inner.borrow().vma
     }
+
+    /// Hand out `size` more bytes of the memory, past the end of the frames.
+    pub fn grow(&self, size: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.vma.size += size;
+    }
+
+    /// Take the frame at `vaddr` of the memory.
+    pub fn take(&self, vaddr: usize) -> Result<PhysAddr, enclave::Error> {
+        self.frames.take(vaddr)
+    }
 }
 
-impl FrameAllocator for BuilderAllocator {
+impl FrameAllocator for BuilderAllocator<'_> {
     fn alloc(&self) -> Option<PhysPageNum> {
-        let ppn = self.inner.borrow_mut().alloc_frame()?;
+        let vma = self.inner.borrow_mut().alloc_vpage()?;
+        let ppn = PhysPageNum::from_paddr(self.frames.take(vma.start).ok()?);
         let slice = unsafe { core::slice::from_raw_parts_mut((ppn.0 * 0x1000) as *mut u8, 4096) };
         for b in slice {
             *b = 0;
@@ -466,3 +1132,90 @@ impl FrameAllocator for BuilderAllocator {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use console::{Console, console::init_console};
+    use pma::{Owner, PhysMemArea, PhysMemAreaMgr, PmaProp};
+    use riscv::register::{Permission, satp};
+    use spin::RwLock;
+    use vm::{page_table::PTEFlags, prelude::*};
+
+    use super::{Claim, FrameSource};
+
+    const EID: Owner = Owner(2);
+    const MEM: usize = 0x8000_0000;
+
+    /// Drops what the refused takes log.
+    struct NoConsole;
+
+    impl Console for NoConsole {
+        fn put_char(&self, _: u8) {}
+    }
+
+    /// The memory a creation of [`EID`] claimed, mapped where it is.
+    fn claimed(pool: &mut [u8], pages: usize) -> (RwLock<PhysMemAreaMgr>, VirtMemArea) {
+        init_console(&NoConsole);
+        let mut mgr = PhysMemAreaMgr::new(pool);
+        mgr.insert_pma(PhysMemArea {
+            region: 0..usize::MAX,
+            prop: PmaProp::default(),
+        })
+        .unwrap();
+        mgr.insert_pma(PhysMemArea {
+            region: MEM..MEM + pages * PAGE_SIZE,
+            prop: PmaProp::empty().owner(EID).pending(),
+        })
+        .unwrap();
+        let mem = VirtMemArea {
+            start: MEM,
+            size: pages * PAGE_SIZE,
+            flags: PTEFlags::empty(),
+            satp: satp::Satp::from_bits(0),
+        };
+        (RwLock::new(mgr), mem)
+    }
+
+    fn prop_of(mgr: &RwLock<PhysMemAreaMgr>, paddr: usize) -> PmaProp {
+        mgr.read().get_pma(paddr).unwrap().get_prop()
+    }
+
+    #[test]
+    fn test_claim_takes_frame_once() {
+        let mut pool = [0_u8; 0x4000];
+        let (mgr, mem) = claimed(&mut pool, 2);
+        let prop = PmaProp::empty().owner(EID).permission(Permission::RWX);
+        let claim = Claim::new(&mgr, EID, mem, |_| Some(prop));
+
+        assert!(matches!(claim.take(MEM), Ok(PhysAddr(MEM))));
+        assert_eq!(prop_of(&mgr, MEM), prop);
+        assert!(claim.take(MEM).is_err());
+        // only the claimed memory is handed out
+        assert!(claim.take(MEM + 2 * PAGE_SIZE).is_err());
+        assert!(claim.take(MEM - PAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_claim_commit() {
+        let mut pool = [0_u8; 0x4000];
+        let (mgr, mem) = claimed(&mut pool, 3);
+        let shared = PmaProp::empty()
+            .owner(Owner::EVERYONE)
+            .permission(Permission::R);
+        let claim = Claim::new(&mgr, EID, mem, |vaddr| (vaddr == MEM).then_some(shared));
+
+        claim.take(MEM).ok().unwrap();
+        // a shared frame stays pending until the creation succeeds
+        assert!(prop_of(&mgr, MEM).is_pending());
+        // a frame without a property is never taken
+        assert!(claim.take(MEM + PAGE_SIZE).is_err());
+
+        claim.commit();
+        assert_eq!(prop_of(&mgr, MEM), shared);
+        let host = PmaProp::empty()
+            .owner(Owner::HOST)
+            .permission(Permission::RWX);
+        assert_eq!(prop_of(&mgr, MEM + PAGE_SIZE), host);
+        assert_eq!(prop_of(&mgr, MEM + 2 * PAGE_SIZE), host);
+    }
+}
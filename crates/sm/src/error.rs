@@ -1,3 +1,4 @@
+use enclave::EnclaveId;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,6 +7,8 @@ pub enum Error {
     Fdt(fdt::FdtError),
     #[error("Can't convert virtual address `{0:#x}` to physical address")]
     InvalidAddress(usize),
+    #[error("Enclave #{0} is not allowed to access `{1:#x}`")]
+    AccessDenied(EnclaveId, usize),
     #[error("{0}")]
     Other(&'static str),
 }
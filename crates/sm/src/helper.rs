@@ -1,16 +1,25 @@
 use heapless::Vec;
 use console::log;
+use riscv::register::satp::{self, Satp};
 use vm::{
     BarePtReader, PhysAddr, PhysPageNum, Translate, VAddrTranslator, mm::MemModel, vm::VirtPageNum,
 };
 
 use crate::{Error, PMP_COUNT, PmpStatus};
-use pma::{PhysMemArea, PhysMemAreaMgr};
+use pma::{Owner, PhysMemArea, PhysMemAreaMgr};
 use pmp::{MAX_PMP_COUNT, PmpHelper, calc_napot_area};
+use vm::prelude::PAGE_SIZE;
 
+/// Push the pmas the walk of `mepc` and `mtval` in the page table at
+/// `root_ppn` touches to `buf`.
+///
+/// The tables of an enclave, its root too, must be pages it owns, or the host
+/// could point them at its own memory and remap the enclave. Leaf pages are
+/// checked by the caller.
 #[inline]
 pub fn pmas_req_vaddr<M: MemModel>(
     mgr: &PhysMemAreaMgr,
+    eid: Owner,
     mepc: usize,
     mtval: usize,
     root_ppn: usize,
@@ -20,10 +29,7 @@ pub fn pmas_req_vaddr<M: MemModel>(
     let root_ppn = PhysPageNum(root_ppn);
     let root_paddr = PhysAddr::from_ppn(root_ppn).0;
     log::trace!("pt_root: {:#x}", root_paddr);
-    let root_pma = mgr.get_pma(root_paddr).ok_or_else(|| {
-        log::error!("pma for {:#x} not found", root_paddr);
-        Error::InvalidAddress(root_paddr)
-    })?;
+    let root_pma = table_pma(mgr, eid, root_paddr)?;
 
     buf.push(PmpHelper {
         pma: root_pma,
@@ -32,29 +38,40 @@ pub fn pmas_req_vaddr<M: MemModel>(
     })
     .unwrap();
 
-    let p_mepc = vm::VirtAddr(mepc)
+    pmas_walk(mgr, eid, mepc, root_ppn, mm, buf)?;
+    if mtval != mepc {
+        pmas_walk(mgr, eid, mtval, root_ppn, mm, buf)?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn pmas_walk<M: MemModel>(
+    mgr: &PhysMemAreaMgr,
+    eid: Owner,
+    vaddr: usize,
+    root_ppn: PhysPageNum,
+    mm: M,
+    buf: &mut Vec<PmpHelper, MAX_PMP_COUNT>,
+) -> Result<(), Error> {
+    let paddr = vm::VirtAddr(vaddr)
         .translate(root_ppn, M::mode(), &BarePtReader)
-        .ok_or_else(|| {
-            // log::error!(
-            //     "mepc: {:#x} can't be translated in pt_root: {:#x}",
-            //     mepc,
-            //     root_paddr
-            // );
-            Error::InvalidAddress(mepc)
-        })?;
-    log::trace!("walk mepc: {:#x} => {:#x}", mepc, p_mepc.0);
+        .ok_or(Error::InvalidAddress(vaddr))?;
+    log::trace!("walk {:#x} => {:#x}", vaddr, paddr.0);
     for pte in
-        VAddrTranslator::new(VirtPageNum::from_vaddr(mepc), root_ppn, &BarePtReader, mm).iter_pte()
+        VAddrTranslator::new(VirtPageNum::from_vaddr(vaddr), root_ppn, &BarePtReader, mm).iter_pte()
     {
-        let addr = if pte.is_leaf() {
-            p_mepc.0
+        let (addr, pma) = if pte.is_leaf() {
+            (paddr.0, mgr.get_pma(paddr.0))
         } else {
-            PhysAddr::from_ppn(pte.get_ppn()).0
+            let addr = PhysAddr::from_ppn(pte.get_ppn()).0;
+            (addr, Some(table_pma(mgr, eid, addr)?))
         };
         if let Some(p) = buf.iter_mut().find(|p| p.pma.get_region().contains(&addr)) {
             p.is_tor = true;
         } else {
-            let pma = mgr.get_pma(addr).ok_or_else(|| {
+            let pma = pma.ok_or_else(|| {
                 log::error!("pma for {:#x} not found", addr);
                 Error::InvalidAddress(addr)
             })?;
@@ -67,46 +84,27 @@ pub fn pmas_req_vaddr<M: MemModel>(
         }
     }
 
-    if mtval != mepc {
-        let p_mtval = vm::VirtAddr(mtval)
-            .translate(root_ppn, M::mode(), &BarePtReader)
-            .ok_or_else(|| {
-                // log::error!(
-                //     "mtval: {:#x} can't be translated in pt_root: {:#x}",
-                //     mtval,
-                //     root_paddr
-                // );
-                Error::InvalidAddress(mtval)
-            })?;
-        log::trace!("walk mtval: {:#x} => {:#x}", mtval, p_mtval.0);
-        for pte in VAddrTranslator::new(VirtPageNum::from_vaddr(mtval), root_ppn, &BarePtReader, mm)
-            .iter_pte()
-        {
-            let addr = if pte.is_leaf() {
-                p_mtval.0
-            } else {
-                PhysAddr::from_ppn(pte.get_ppn()).0
-            };
-            if let Some(p) = buf.iter_mut().find(|p| p.pma.get_region().contains(&addr)) {
-                p.is_tor = true;
-            } else {
-                let pma = mgr.get_pma(addr).ok_or_else(|| {
-                    log::error!("pma for {:#x} not found", addr);
-                    Error::InvalidAddress(addr)
-                })?;
-                buf.push(PmpHelper {
-                    pma,
-                    addr,
-                    is_tor: false,
-                })
-                .unwrap();
-            }
-        }
-    }
-
     Ok(())
 }
 
+/// The pma of the page table at `paddr`, which must be owned by `eid` if it
+/// is an enclave.
+#[inline]
+fn table_pma(mgr: &PhysMemAreaMgr, eid: Owner, paddr: usize) -> Result<PhysMemArea, Error> {
+    let pma = mgr.get_pma(paddr);
+    let owned = pma
+        .as_ref()
+        .is_some_and(|pma| pma.check_owner(|owner| owner == eid));
+    if eid != Owner::HOST && !owned {
+        log::error!("Enclave #{eid} has a page table at {paddr:#x} it does not own");
+        return Err(Error::AccessDenied(eid, paddr));
+    }
+    pma.ok_or_else(|| {
+        log::error!("pma for {:#x} not found", paddr);
+        Error::InvalidAddress(paddr)
+    })
+}
+
 #[inline(always)]
 pub fn update_pmp_by_pmas(
     helpers: &mut Vec<PmpHelper, MAX_PMP_COUNT>,
@@ -231,3 +229,199 @@ pub fn pmas_on_paddr(
 
     Ok(())
 }
+
+/// Walk `len` bytes at `vaddr` of the current address space page by page.
+///
+/// Every page must be owned by `eid`, so an enclave cannot make the security
+/// monitor read or write memory of others on its behalf.
+fn for_each_enclave_page(
+    mgr: &PhysMemAreaMgr,
+    eid: Owner,
+    vaddr: usize,
+    len: usize,
+    mut f: impl FnMut(usize, usize, usize),
+) -> Result<(), Error> {
+    vaddr.checked_add(len).ok_or(Error::InvalidAddress(vaddr))?;
+    let satp = satp::read();
+    let mut off = 0;
+    while off < len {
+        let va = vaddr + off;
+        let size = (PAGE_SIZE - va % PAGE_SIZE).min(len - off);
+        let paddr = vm::VirtAddr(va)
+            .translate(PhysPageNum(satp.ppn()), satp.mode(), &BarePtReader)
+            .ok_or(Error::InvalidAddress(va))?;
+        let pma = mgr.get_pma(paddr.0).ok_or(Error::InvalidAddress(paddr.0))?;
+        if !pma.check_owner(|owner| owner == eid) {
+            return Err(Error::AccessDenied(eid, va));
+        }
+        f(paddr.0, off, size);
+        off += size;
+    }
+
+    Ok(())
+}
+
+/// Copy `src` to `vaddr` of enclave `eid`, translated by the current satp.
+pub fn copy_to_enclave(
+    mgr: &PhysMemAreaMgr,
+    eid: Owner,
+    vaddr: usize,
+    src: &[u8],
+) -> Result<(), Error> {
+    for_each_enclave_page(mgr, eid, vaddr, src.len(), |paddr, off, size| unsafe {
+        core::ptr::copy_nonoverlapping(src[off..].as_ptr(), paddr as *mut u8, size)
+    })
+}
+
+/// Copy from `vaddr` of enclave `eid` to `dst`, translated by the current satp.
+pub fn copy_from_enclave(
+    mgr: &PhysMemAreaMgr,
+    eid: Owner,
+    vaddr: usize,
+    dst: &mut [u8],
+) -> Result<(), Error> {
+    for_each_enclave_page(mgr, eid, vaddr, dst.len(), |paddr, off, size| unsafe {
+        core::ptr::copy_nonoverlapping(paddr as *const u8, dst[off..].as_mut_ptr(), size)
+    })
+}
+
+/// Check that `len` bytes at `vaddr` of enclave `eid` can be copied, translated
+/// by the current satp.
+pub fn check_enclave_range(
+    mgr: &PhysMemAreaMgr,
+    eid: Owner,
+    vaddr: usize,
+    len: usize,
+) -> Result<(), Error> {
+    for_each_enclave_page(mgr, eid, vaddr, len, |_, _, _| {})
+}
+
+/// The physical address of the page at `vaddr` of enclave `eid`, translated by
+/// the current satp.
+pub fn enclave_page(mgr: &PhysMemAreaMgr, eid: Owner, vaddr: usize) -> Result<usize, Error> {
+    if vaddr % PAGE_SIZE != 0 {
+        return Err(Error::InvalidAddress(vaddr));
+    }
+    let mut page = 0;
+    for_each_enclave_page(mgr, eid, vaddr, PAGE_SIZE, |paddr, _, _| page = paddr)?;
+
+    Ok(page)
+}
+
+/// Translate `vaddr` through the page table of `satp`, which may be of
+/// another address space than the current one.
+///
+/// The page tables walked and the page must all be owned by `eid`, so a page
+/// table forged by others cannot lead to memory that is not theirs.
+pub fn translate_owned(
+    mgr: &PhysMemAreaMgr,
+    satp: Satp,
+    eid: Owner,
+    vaddr: usize,
+) -> Result<usize, Error> {
+    fn walk<M: MemModel>(
+        mgr: &PhysMemAreaMgr,
+        root: PhysPageNum,
+        eid: Owner,
+        vaddr: usize,
+        mm: M,
+    ) -> Result<usize, Error> {
+        let check = |paddr: usize| {
+            let pma = mgr.get_pma(paddr).ok_or(Error::InvalidAddress(paddr))?;
+            if !pma.check_owner(|owner| owner == eid) {
+                return Err(Error::AccessDenied(eid, vaddr));
+            }
+            Ok(())
+        };
+
+        check(PhysAddr::from_ppn(root).0)?;
+        for pte in VAddrTranslator::new(VirtPageNum::from_vaddr(vaddr), root, &BarePtReader, mm)
+            .iter_pte()
+            .filter(|pte| !pte.is_leaf())
+        {
+            check(PhysAddr::from_ppn(pte.get_ppn()).0)?;
+        }
+        let paddr = vm::VirtAddr(vaddr)
+            .trans_2_pm(root, &BarePtReader, mm)
+            .ok_or(Error::InvalidAddress(vaddr))?;
+        check(paddr.0)?;
+
+        Ok(paddr.0)
+    }
+
+    let root = PhysPageNum(satp.ppn());
+    match satp.mode() {
+        satp::Mode::Sv39 => walk(mgr, root, eid, vaddr, vm::mm::SV39),
+        satp::Mode::Sv48 => walk(mgr, root, eid, vaddr, vm::mm::SV48),
+        _ => Err(Error::InvalidAddress(vaddr)),
+    }
+}
+
+/// Copy `len` bytes from `from` of enclave `src` to `to` of enclave `dst`,
+/// each translated by its own satp with [`translate_owned`].
+///
+/// The copy stops at the first page that cannot be translated, and the
+/// number of bytes copied is returned.
+pub fn copy_between(
+    mgr: &PhysMemAreaMgr,
+    (dst, dst_satp, to): (Owner, Satp, usize),
+    (src, src_satp, from): (Owner, Satp, usize),
+    len: usize,
+) -> usize {
+    if to.checked_add(len).is_none() || from.checked_add(len).is_none() {
+        log::error!("cannot copy {len:#x} bytes from #{src} to #{dst}");
+        return 0;
+    }
+    let mut off = 0;
+    while off < len {
+        let size = (len - off)
+            .min(PAGE_SIZE - (to + off) % PAGE_SIZE)
+            .min(PAGE_SIZE - (from + off) % PAGE_SIZE);
+        let pages = translate_owned(mgr, src_satp, src, from + off)
+            .and_then(|s| Ok((s, translate_owned(mgr, dst_satp, dst, to + off)?)));
+        let (src_paddr, dst_paddr) = match pages {
+            Ok(pages) => pages,
+            Err(e) => {
+                log::error!("cannot copy from #{src} to #{dst}: {e}");
+                break;
+            }
+        };
+        unsafe { core::ptr::copy(src_paddr as *const u8, dst_paddr as *mut u8, size) };
+        off += size;
+    }
+
+    off
+}
+
+/// The bytes of `value`.
+///
+/// # Safety
+///
+/// `T` must be `repr(C)` without padding.
+pub unsafe fn bytes_of<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// The mutable bytes of `value`.
+///
+/// # Safety
+///
+/// `T` must be `repr(C)` without padding, and valid for any bytes.
+pub unsafe fn bytes_of_mut<T: Copy>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// Copy a `T` from `vaddr` of the host into the monitor, so the host cannot
+/// change it once it is checked.
+///
+/// # Safety
+///
+/// `T` must be valid for any bytes.
+pub unsafe fn read_from_host<T>(mgr: &PhysMemAreaMgr, vaddr: usize) -> Result<T, Error> {
+    let mut value = core::mem::MaybeUninit::<T>::zeroed();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_enclave(mgr, Owner::HOST, vaddr, bytes)?;
+
+    Ok(unsafe { value.assume_init() })
+}
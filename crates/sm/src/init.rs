@@ -11,11 +11,21 @@ use device::device::{Device, DeviceInfo};
 use pma::{Owner, PhysMemArea, PhysMemAreaMgr, PmaProp};
 use pmp::PmpStatus;
 use riscv::register::{Permission, mepc, mhartid, mstatus, mtvec, stvec};
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 use trap_proxy::TrapProxy;
 use vm::aligned;
 
-use crate::{Error, Platform, SecMonitor, enclave::EnclaveMgr, trap::TrapHandler};
+use crate::{
+    Error, Platform, SecMonitor,
+    attest::{DeviceKey, ReportSecret},
+    counter::{BlobKeys, CounterTable},
+    enclave::EnclaveMgr,
+    ipc::ChannelTable,
+    measure::{self, MeasureLog},
+    rng::{self, Drbg},
+    seal::RootSecret,
+    trap::TrapHandler,
+};
 
 pub unsafe fn init<P: Platform>(platform: &P, next_addr: usize, arg1: usize) -> ! {
     static IS_COLD: AtomicBool = AtomicBool::new(true);
@@ -44,7 +54,7 @@ unsafe fn boothart_init<P: Platform>(platform: &P, fdt: usize) {
     #[allow(static_mut_refs)]
     let sm = unsafe { crate::SM.assume_init_mut() };
 
-    let device = DeviceInfo::new(fdt as *const u8).unwrap();
+    let mut device = DeviceInfo::new(fdt as *const u8).unwrap();
 
     init_console_uart(device.get_uart().unwrap());
 
@@ -61,6 +71,10 @@ unsafe fn boothart_init<P: Platform>(platform: &P, fdt: usize) {
     //     log::debug!("dma: {:#x}", dma as usize);
     // }
 
+    // measure before anything is taken from the fdt
+    init_measure(sm, platform, &device);
+    log::debug!("Inited boot measurement");
+
     // init hsm
     init_hsm(sm, platform);
     log::debug!("Inited hsm. Hart num: {}", sm.hsm.num());
@@ -82,6 +96,21 @@ unsafe fn boothart_init<P: Platform>(platform: &P, fdt: usize) {
     init_device(sm, &device);
     log::debug!("Inited device");
 
+    init_attest(sm, platform, &mut device);
+    log::debug!("Inited attestation");
+
+    init_seal(sm, platform, &mut device);
+    log::debug!("Inited sealing");
+
+    init_counters(sm, platform, &mut device);
+    log::debug!("Inited counters");
+
+    init_rng(sm, platform, &mut device);
+    log::debug!("Inited rng");
+
+    init_local_attest(sm);
+    log::debug!("Inited local attestation");
+
     // initialize the memory region, and update the reserved memory area
     // create_sm(device, rw_start);
 
@@ -180,6 +209,20 @@ fn init_pma<P: Platform>(sm: &mut SecMonitor, platform: &P) {
     sm.pma_mgr = RwLock::new(mgr);
 }
 
+fn init_measure<P: Platform>(sm: &mut SecMonitor, platform: &P, device: &DeviceInfo) {
+    use channel::attest::BootEventKind;
+
+    let text = platform.get_sbi_text_region();
+    log::debug!("sbi text region: {:#x?}", text);
+    let image = measure::measure_image(text);
+    let config = measure::measure_config(platform, device);
+    let mut log = MeasureLog::new();
+    log.append(BootEventKind::Image, image);
+    log.append(BootEventKind::Config, config);
+    log::info!("SM measurement: {:02x?}", log.measurement());
+    sm.boot_log = log;
+}
+
 fn init_hsm<P: Platform>(sm: &mut SecMonitor, platform: &P) {
     let hsm = hsm::Hsm::new(platform.get_hart_num());
     sm.hsm = hsm;
@@ -187,6 +230,7 @@ fn init_hsm<P: Platform>(sm: &mut SecMonitor, platform: &P) {
 
 fn init_enclave(sm: &mut SecMonitor) {
     sm.enc_mgr = EnclaveMgr::new();
+    sm.channels = Mutex::new(ChannelTable::new());
 }
 
 fn init_clint(sm: &mut SecMonitor, device: &DeviceInfo) -> Result<(), Error> {
@@ -204,3 +248,54 @@ fn init_clint(sm: &mut SecMonitor, device: &DeviceInfo) -> Result<(), Error> {
 fn init_device(sm: &mut SecMonitor, device: &DeviceInfo) {
     sm.device = Device::from_device_info(device).unwrap();
 }
+
+fn init_attest<P: Platform>(sm: &mut SecMonitor, platform: &P, device: &mut DeviceInfo) {
+    sm.device_key = platform.get_device_key(device).map(DeviceKey::new);
+    if sm.device_key.is_none() {
+        log::warn!("No device key, attestation is disabled");
+    }
+}
+
+fn init_seal<P: Platform>(sm: &mut SecMonitor, platform: &P, device: &mut DeviceInfo) {
+    sm.root_secret = platform.get_root_secret(device).map(RootSecret::new);
+    if sm.root_secret.is_none() {
+        log::warn!("No root secret, sealing is disabled");
+    }
+}
+
+fn init_counters<P: Platform>(sm: &mut SecMonitor, platform: &P, device: &mut DeviceInfo) {
+    let anchor = platform.get_counter_anchor(device);
+    if anchor.is_none() {
+        log::warn!("No counter anchor, counters are disabled");
+    }
+    sm.blob_keys = sm.root_secret.as_ref().map(BlobKeys::new);
+    sm.counters = Mutex::new(CounterTable::new(anchor));
+}
+
+fn init_rng<P: Platform>(sm: &mut SecMonitor, platform: &P, device: &mut DeviceInfo) {
+    // the fdt seed is always taken, so it is wiped even if Zkr is used
+    let fdt_seed = platform.get_rng_seed(device);
+    let seed = if device.has_isa_ext("zkr") {
+        rng::zkr_seed().or_else(|| {
+            log::warn!("Zkr entropy source failed");
+            fdt_seed
+        })
+    } else {
+        fdt_seed
+    };
+    if seed.is_none() {
+        log::warn!("No rng seed, random numbers are disabled");
+    }
+    sm.rng = Mutex::new(seed.map(Drbg::new));
+}
+
+fn init_local_attest(sm: &mut SecMonitor) {
+    sm.report_secret = sm.rng.lock().as_mut().map(|rng| {
+        let mut secret = [0; 32];
+        rng.fill(&mut secret);
+        ReportSecret::new(secret)
+    });
+    if sm.report_secret.is_none() {
+        log::warn!("No rng seed, local attestation is disabled");
+    }
+}
@@ -0,0 +1,384 @@
+use channel::{
+    channel::{Channel, ChannelStatus, MAX_CHANNEL_PAGES, MAX_CHANNELS},
+    op::Head,
+};
+use enclave::{EnclaveId, Error};
+use heapless::Vec;
+use pma::{PhysMemAreaMgr, PmaProp};
+use riscv::register::Permission;
+use vm::prelude::PAGE_SIZE;
+
+struct Slot {
+    channel: Channel<EnclaveId>,
+    /// The head page and the pages it lists, in the order they were lent.
+    pages: Vec<usize, MAX_CHANNEL_PAGES>,
+    /// The properties the pages had before the peer got them, given back
+    /// when the channel is closed.
+    props: Vec<PmaProp, MAX_CHANNEL_PAGES>,
+    /// The address space of the opener its peer copies from and to, 0 if
+    /// there is none.
+    satp: usize,
+}
+
+impl Slot {
+    const EMPTY: Self = Self {
+        channel: Channel::EMPTY,
+        pages: Vec::new(),
+        props: Vec::new(),
+        satp: 0,
+    };
+
+    fn is_open(&self) -> bool {
+        self.channel.status == ChannelStatus::Using
+    }
+
+    fn has_end(&self, eid: EnclaveId) -> bool {
+        self.channel.lue == Some(eid) || self.channel.lde == Some(eid)
+    }
+}
+
+/// Channels moving pages between enclaves, without the host in between.
+///
+/// The opener keeps its pages until a peer connects, then they belong to the
+/// peer until either end closes the channel and they go back to the opener as
+/// they were. Only pages the opener may use in full can be lent, and a page is
+/// lent by one channel at most, so it is always the opener's or the peer's.
+pub struct ChannelTable {
+    slots: [Slot; MAX_CHANNELS],
+}
+
+impl ChannelTable {
+    pub const fn new() -> Self {
+        Self {
+            slots: [Slot::EMPTY; MAX_CHANNELS],
+        }
+    }
+
+    fn get_open(&mut self, id: usize) -> Result<&mut Slot, Error> {
+        self.slots
+            .get_mut(id)
+            .filter(|slot| slot.is_open())
+            .ok_or(Error::NoChannel)
+    }
+
+    /// Open a channel of `eid` named `name`, returning its id.
+    ///
+    /// `head` is the physical address of a [`Head`] page of `eid`, or 0, and
+    /// the pages it lists are lent along with it. The peer may copy from and
+    /// to the address space `satp` of `eid`, if it is not 0.
+    pub fn open(
+        &mut self,
+        mgr: &PhysMemAreaMgr,
+        eid: EnclaveId,
+        name: u64,
+        head: usize,
+        satp: usize,
+    ) -> Result<usize, Error> {
+        if self
+            .slots
+            .iter()
+            .any(|slot| slot.is_open() && slot.channel.arg0 == name)
+        {
+            return Err(Error::ChannelExists);
+        }
+
+        let mut pages = Vec::new();
+        if head != 0 {
+            pages.push(head).unwrap();
+            let head = Head::from_ptr(head as *const Head);
+            // read once, the opener may change the page on another hart
+            let len = head.len as usize;
+            if len >= MAX_CHANNEL_PAGES {
+                return Err(Error::InvalidAddress);
+            }
+            for i in 0..len {
+                let paddr = head.paddr[i] as usize;
+                if paddr % PAGE_SIZE != 0 || pages.contains(&paddr) {
+                    return Err(Error::InvalidAddress);
+                }
+                pages.push(paddr).unwrap();
+            }
+        }
+
+        if pages.iter().any(|&paddr| self.lends(paddr)) || !owns_pages(mgr, eid, &pages) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let (id, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| !slot.is_open())
+            .ok_or(Error::NoChannelSpace)?;
+        slot.channel = Channel {
+            lue: Some(eid),
+            lde: None,
+            arg0: name,
+            arg1: head as u64,
+            status: ChannelStatus::Using,
+        };
+        slot.pages = pages;
+        slot.satp = satp;
+
+        Ok(id)
+    }
+
+    /// Whether the page at `paddr` is lent by an open channel.
+    pub fn lends(&self, paddr: usize) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.is_open() && slot.pages.contains(&paddr))
+    }
+
+    /// Whether `eid` is an end of an open channel.
+    pub fn has_channels(&self, eid: EnclaveId) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.is_open() && slot.has_end(eid))
+    }
+
+    /// Connect `eid` to the channel `id`, which gets the lent pages.
+    ///
+    /// Returns the physical address of the head page, 0 if there is none, and
+    /// the opener the pages are taken from.
+    pub fn connect(
+        &mut self,
+        mgr: &mut PhysMemAreaMgr,
+        eid: EnclaveId,
+        id: usize,
+    ) -> Result<(u64, EnclaveId), Error> {
+        let slot = self.get_open(id)?;
+        if slot.channel.lde.is_some() {
+            return Err(Error::ChannelConnected);
+        }
+        let opener = slot.channel.lue.unwrap();
+        if opener == eid {
+            return Err(Error::InvalidCaller);
+        }
+        if !owns_pages(mgr, opener, &slot.pages) {
+            return Err(Error::InvalidAddress);
+        }
+
+        slot.props = slot
+            .pages
+            .iter()
+            .map(|&paddr| mgr.get_pma(paddr).unwrap().get_prop())
+            .collect();
+        give_pages(mgr, eid, &slot.pages);
+        slot.channel.lde = Some(eid);
+
+        Ok((slot.channel.arg1, opener))
+    }
+
+    /// The opener of the channel `eid` is connected to and the address space it
+    /// copies from and to, which must be the only one.
+    pub fn client_of(&self, eid: EnclaveId) -> Result<(EnclaveId, usize), Error> {
+        let mut connected = self
+            .slots
+            .iter()
+            .filter(|slot| slot.is_open() && slot.channel.lde == Some(eid));
+        match (connected.next(), connected.next()) {
+            (Some(slot), None) if slot.satp != 0 => Ok((slot.channel.lue.unwrap(), slot.satp)),
+            _ => Err(Error::NoChannel),
+        }
+    }
+
+    /// Close the channel `id` from either end, giving the lent pages back to
+    /// the opener.
+    ///
+    /// Returns the peer the pages are taken from, if it was connected.
+    pub fn close(
+        &mut self,
+        mgr: &mut PhysMemAreaMgr,
+        eid: EnclaveId,
+        id: usize,
+    ) -> Result<Option<EnclaveId>, Error> {
+        let slot = self.get_open(id)?;
+        if !slot.has_end(eid) {
+            return Err(Error::InvalidCaller);
+        }
+
+        Ok(Self::revoke(mgr, slot))
+    }
+
+    /// Close every channel `eid` is an end of, before it is destroyed.
+    ///
+    /// Returns the peers pages are taken from, other than `eid`.
+    pub fn close_all(
+        &mut self,
+        mgr: &mut PhysMemAreaMgr,
+        eid: EnclaveId,
+    ) -> Vec<EnclaveId, MAX_CHANNELS> {
+        let mut peers = Vec::new();
+        for slot in self
+            .slots
+            .iter_mut()
+            .filter(|slot| slot.is_open() && slot.has_end(eid))
+        {
+            if let Some(peer) = Self::revoke(mgr, slot).filter(|&peer| peer != eid) {
+                peers.push(peer).unwrap();
+            }
+        }
+
+        peers
+    }
+
+    fn revoke(mgr: &mut PhysMemAreaMgr, slot: &mut Slot) -> Option<EnclaveId> {
+        let peer = slot.channel.lde;
+        if peer.is_some() {
+            for (&paddr, &prop) in slot.pages.iter().zip(&slot.props) {
+                mgr.insert_page(paddr, prop);
+            }
+        }
+        *slot = Slot::EMPTY;
+
+        peer
+    }
+}
+
+fn owns_pages(mgr: &PhysMemAreaMgr, eid: EnclaveId, pages: &[usize]) -> bool {
+    pages.iter().all(|&paddr| {
+        mgr.get_pma(paddr).is_some_and(|pma| {
+            // the pages the monitor keeps from the enclave are not its to lend
+            pma.check_owner(|owner| owner == eid)
+                && pma.get_prop().get_owner_perm() == Permission::RWX
+        })
+    })
+}
+
+fn give_pages(mgr: &mut PhysMemAreaMgr, eid: EnclaveId, pages: &[usize]) {
+    for &paddr in pages {
+        mgr.insert_page(
+            paddr,
+            PmaProp::empty().owner(eid).permission(Permission::RWX),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pma::{Owner, PhysMemArea};
+
+    use super::*;
+
+    const OPENER: EnclaveId = Owner(2);
+    const PEER: EnclaveId = Owner(3);
+    const NAME: u64 = 0x6368;
+
+    #[repr(C, align(0x1000))]
+    struct Pages([[u8; PAGE_SIZE]; 3]);
+
+    /// A manager with the pages at `base` owned by `owner` with `perm`.
+    fn mgr_of(pool: &mut [u8], base: usize, owner: EnclaveId, perm: Permission) -> PhysMemAreaMgr {
+        let mut mgr = PhysMemAreaMgr::new(pool);
+        mgr.insert_pma(PhysMemArea {
+            region: 0..usize::MAX,
+            prop: PmaProp::default(),
+        })
+        .unwrap();
+        for i in 0..3 {
+            mgr.insert_page(
+                base + i * PAGE_SIZE,
+                PmaProp::empty().owner(owner).permission(perm),
+            );
+        }
+        mgr
+    }
+
+    #[test]
+    fn test_channel_lifecycle() {
+        let mut pool = [0_u8; 0x4000];
+        let mut mgr = mgr_of(&mut pool, 0x8000_0000, OPENER, Permission::RWX);
+        let mut table = ChannelTable::new();
+
+        let id = table.open(&mgr, OPENER, NAME, 0, 0).ok().unwrap();
+        assert!(table.has_channels(OPENER));
+        assert!(matches!(
+            table.open(&mgr, PEER, NAME, 0, 0),
+            Err(Error::ChannelExists)
+        ));
+        assert!(matches!(
+            table.connect(&mut mgr, OPENER, id),
+            Err(Error::InvalidCaller)
+        ));
+
+        assert!(matches!(
+            table.connect(&mut mgr, PEER, id),
+            Ok((0, opener)) if opener == OPENER
+        ));
+        assert!(matches!(
+            table.connect(&mut mgr, Owner(4), id),
+            Err(Error::ChannelConnected)
+        ));
+        assert!(matches!(
+            table.close(&mut mgr, Owner(4), id),
+            Err(Error::InvalidCaller)
+        ));
+
+        assert!(matches!(
+            table.close(&mut mgr, PEER, id),
+            Ok(Some(peer)) if peer == PEER
+        ));
+        assert!(!table.has_channels(OPENER) && !table.has_channels(PEER));
+        assert!(matches!(
+            table.connect(&mut mgr, PEER, id),
+            Err(Error::NoChannel)
+        ));
+        // the name is free again
+        assert!(table.open(&mgr, OPENER, NAME, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn test_channel_lends_pages() {
+        let mut pages = Pages([[0; PAGE_SIZE]; 3]);
+        let base = pages.0.as_mut_ptr() as usize;
+        let head = Head::from_ptr(base as *const Head);
+        head.len = 2;
+        head.paddr[0] = (base + PAGE_SIZE) as u64;
+        head.paddr[1] = (base + 2 * PAGE_SIZE) as u64;
+
+        let mut pool = [0_u8; 0x4000];
+        let mut mgr = mgr_of(&mut pool, base, OPENER, Permission::RWX);
+        let prop = mgr.get_pma(base).unwrap().get_prop();
+        let mut table = ChannelTable::new();
+
+        let id = table.open(&mgr, OPENER, NAME, base, 0).ok().unwrap();
+        assert!((0..3).all(|i| table.lends(base + i * PAGE_SIZE)));
+        // a page is lent by one channel at most
+        assert!(matches!(
+            table.open(&mgr, OPENER, NAME + 1, base, 0),
+            Err(Error::InvalidAddress)
+        ));
+
+        table.connect(&mut mgr, PEER, id).ok().unwrap();
+        for i in 0..3 {
+            let pma = mgr.get_pma(base + i * PAGE_SIZE).unwrap();
+            assert!(pma.check_owner(|owner| owner == PEER));
+        }
+
+        assert_eq!(table.close_all(&mut mgr, OPENER).as_slice(), [PEER]);
+        for i in 0..3 {
+            assert_eq!(mgr.get_pma(base + i * PAGE_SIZE).unwrap().get_prop(), prop);
+        }
+        assert!(!table.lends(base));
+    }
+
+    #[test]
+    fn test_channel_refuses_kept_pages() {
+        let mut pages = Pages([[0; PAGE_SIZE]; 3]);
+        let base = pages.0.as_mut_ptr() as usize;
+
+        let mut pool = [0_u8; 0x4000];
+        let mgr = mgr_of(&mut pool, base, OPENER, Permission::RX);
+        let mut table = ChannelTable::new();
+
+        assert!(matches!(
+            table.open(&mgr, OPENER, NAME, base, 0),
+            Err(Error::InvalidAddress)
+        ));
+        assert!(matches!(
+            table.open(&mgr, PEER, NAME, base, 0),
+            Err(Error::InvalidAddress)
+        ));
+    }
+}
@@ -8,7 +8,7 @@ use console::println;
 use riscv::{asm::wfi, register::mscratch};
 
 pub use init::init;
-pub use platform::Platform;
+pub use platform::{CounterAnchor, Platform};
 pub use pma::{Owner, PhysMemArea, PhysMemAreaMgr, PmaProp};
 pub use pmp::{PMP_COUNT, PmpStatus};
 pub use sm::SecMonitor;
@@ -17,13 +17,21 @@ pub use trap_proxy::{TrapProxy, TrapRegs};
 pub use device::DeviceInfo;
 pub use error::Error;
 
+mod attest;
 pub mod consts;
+mod counter;
 mod device;
 mod ecall;
 mod error;
 mod helper;
 mod init;
+mod ipc;
+mod manifest;
+mod measure;
+mod rng;
+mod seal;
 mod sm;
+mod snapshot;
 mod trap;
 
 mod enclave;
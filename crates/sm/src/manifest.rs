@@ -0,0 +1,33 @@
+use channel::manifest::Manifest;
+use ed25519_dalek::{Signature, VerifyingKey};
+use enclave::Measurement;
+use sha2::{Digest, Sha256};
+
+use crate::{Error, consts::SM_VERSION, enclave::UserArgs};
+
+/// Check `manifest` against the enclave to be created from `args`.
+///
+/// The measurement can only be checked once the enclave is built.
+/// Returns the signer identity, the SHA-256 of the signer public key.
+pub fn verify(manifest: &Manifest, args: &UserArgs) -> Result<Measurement, Error> {
+    let body = &manifest.body;
+    let key = VerifyingKey::from_bytes(&manifest.signer)
+        .map_err(|_| Error::Other("invalid signer key"))?;
+    key.verify_strict(body.as_bytes(), &Signature::from_bytes(&manifest.signature))
+        .map_err(|_| Error::Other("invalid manifest signature"))?;
+
+    if cfg!(debug_assertions) && body.debug == 0 {
+        return Err(Error::Other("production enclaves cannot run on a debug SM"));
+    }
+    if body.min_sm_version > SM_VERSION {
+        return Err(Error::Other("SM is older than the manifest requires"));
+    }
+    if args.mem.size as u64 > body.max_mem_size {
+        return Err(Error::Other("memory exceeds the manifest limit"));
+    }
+    if args.share.size as u64 > body.max_shared_size {
+        return Err(Error::Other("shared memory exceeds the manifest limit"));
+    }
+
+    Ok(Sha256::digest(manifest.signer).into())
+}
@@ -0,0 +1,278 @@
+use core::ops::Range;
+
+use channel::{
+    attest::{BootEvent, BootEventKind, BootLog, MAX_BOOT_EVENTS},
+    enclave::runtime::{LdeBootArgs, LueBootArgs},
+};
+use device::device::DeviceInfo;
+use enclave::{Layout, MEASUREMENT_SIZE, Measurement};
+use platform::Platform;
+use sha2::{Digest, Sha256};
+use vm::prelude::*;
+
+/// The components of an enclave measurement.
+///
+/// They are extended into the chain in the order of their values.
+#[derive(Clone, Copy)]
+#[repr(u64)]
+pub enum Component {
+    Runtime = 1,
+    Binary = 2,
+    Layout = 3,
+    Shared = 4,
+    BootArgs = 5,
+    Entry = 6,
+}
+
+/// The regions of a [`Layout`], tagging their records in the measurement.
+#[derive(Clone, Copy)]
+#[repr(u64)]
+enum Region {
+    Runtime = 1,
+    Stack = 2,
+    Binary = 3,
+    Shared = 4,
+    BootArgs = 5,
+    Trampoline = 6,
+    Kernel = 7,
+    Threads = 8,
+}
+
+/// SHA-256 measurement chain.
+///
+/// Each component is hashed on its own, and then extended into the chain by
+/// `value = SHA256(value || component || SHA256(data))`.
+pub struct MeasureChain {
+    value: Measurement,
+}
+
+impl MeasureChain {
+    pub fn new() -> Self {
+        Self {
+            value: [0; MEASUREMENT_SIZE],
+        }
+    }
+
+    pub fn extend(&mut self, component: Component, digest: &[u8]) {
+        let mut ctx = Sha256::new();
+        ctx.update(self.value);
+        ctx.update((component as u64).to_le_bytes());
+        ctx.update(digest);
+        self.value = ctx.finalize().into();
+    }
+
+    /// Extend the chain with the content of `vma`, read through `vma.satp`.
+    pub fn extend_pages(&mut self, component: Component, vma: VirtMemArea) {
+        let digest = measure_data(vma);
+        self.extend(component, &digest);
+    }
+
+    /// Extend the chain with the virtual addresses and permissions of `layout`.
+    ///
+    /// Each region is hashed as a record tagged with the region and prefixed
+    /// with its length, so no two layouts hash the same bytes. The trampoline
+    /// is mapped at the physical address of the runtime, so only its size and
+    /// permissions are measured.
+    pub fn extend_layout(&mut self, layout: &Layout) {
+        let mut ctx = Sha256::new();
+        for (region, vma) in [
+            (Region::Runtime, &layout.rt),
+            (Region::Stack, &layout.stack),
+            (Region::Binary, &layout.binary),
+            (Region::Shared, &layout.share),
+            (Region::BootArgs, &layout.bootargs),
+        ] {
+            update_record(&mut ctx, region, &vma_record(vma));
+        }
+        update_record(
+            &mut ctx,
+            Region::Trampoline,
+            &vma_record(&layout.trampoline)[size_of::<usize>()..],
+        );
+        // only driver enclaves have a kernel page, the others an empty record
+        let kernel = vma_record(&layout.kernel);
+        let len = if layout.kernel.is_empty() {
+            0
+        } else {
+            kernel.len()
+        };
+        update_record(&mut ctx, Region::Kernel, &kernel[..len]);
+        update_record(&mut ctx, Region::Threads, &layout.threads.to_le_bytes());
+        self.extend(Component::Layout, &ctx.finalize());
+    }
+
+    pub fn extend_shared(&mut self, size: usize) {
+        self.extend(Component::Shared, &Sha256::digest(size.to_le_bytes()));
+    }
+
+    /// Extend the chain with the offset of the service entry in the runtime.
+    pub fn extend_entry(&mut self, entry: usize) {
+        self.extend(Component::Entry, &Sha256::digest(entry.to_le_bytes()));
+    }
+
+    /// Extend the chain with the boot arguments.
+    ///
+    /// Host virtual addresses, physical addresses and the device information
+    /// depend on the host and the platform rather than on the enclave, so they
+    /// are left out.
+    pub fn extend_bootargs(&mut self, args: &LueBootArgs) {
+        let mut ctx = Sha256::new();
+        for val in [
+            args.mem.total_size,
+            args.mods.start_vaddr,
+            args.mods.num,
+            args.bin.start,
+            args.bin.size,
+            args.shared.enc_vaddr,
+            args.shared.size,
+            args.unmapped.size,
+        ] {
+            ctx.update(val.to_le_bytes());
+        }
+        self.extend(Component::BootArgs, &ctx.finalize());
+    }
+
+    /// Extend the chain with the boot arguments of a driver enclave.
+    ///
+    /// The driver is placed at the address the host loaded it at, so only
+    /// its size is measured, as in [`Self::extend_bootargs`].
+    pub fn extend_driver_bootargs(&mut self, args: &LdeBootArgs) {
+        let mut ctx = Sha256::new();
+        for val in [
+            args.mem.total_size,
+            args.mods.start_vaddr,
+            args.mods.num,
+            args.bin.start,
+            args.bin.size,
+            args.driver_size,
+            args.unmapped.size,
+        ] {
+            ctx.update(val.to_le_bytes());
+        }
+        self.extend(Component::BootArgs, &ctx.finalize());
+    }
+
+    pub fn finish(self) -> Measurement {
+        self.value
+    }
+}
+
+/// Hash a record of `region`: its tag, the length of `data`, then `data`.
+fn update_record(ctx: &mut Sha256, region: Region, data: &[u8]) {
+    ctx.update((region as u64).to_le_bytes());
+    ctx.update((data.len() as u64).to_le_bytes());
+    ctx.update(data);
+}
+
+/// The start, size and permissions of `vma`, as measured.
+fn vma_record(vma: &VirtMemArea) -> [u8; 2 * size_of::<usize>() + 1] {
+    let mut record = [0; 2 * size_of::<usize>() + 1];
+    record[..size_of::<usize>()].copy_from_slice(&vma.start.to_le_bytes());
+    record[size_of::<usize>()..2 * size_of::<usize>()].copy_from_slice(&vma.size.to_le_bytes());
+    record[2 * size_of::<usize>()] = vma.flags.bits();
+    record
+}
+
+/// Extend a runtime measurement register, `rtmr = SHA256(rtmr || digest)`.
+pub fn extend_rtmr(rtmr: &mut Measurement, digest: &Measurement) {
+    let mut ctx = Sha256::new();
+    ctx.update(*rtmr);
+    ctx.update(digest);
+    *rtmr = ctx.finalize().into();
+}
+
+/// SHA-256 of the first `vma.size` bytes of `vma`.
+pub fn measure_data(vma: VirtMemArea) -> Measurement {
+    let mut ctx = Sha256::new();
+    let mut remain = vma.size;
+
+    for vpn in vma.iter_vpn() {
+        let paddr = vpn
+            .translate(vma.satp.ppn(), vma.satp.mode(), &BarePtReader)
+            .unwrap();
+
+        let len = remain.min(PAGE_SIZE);
+        let bytes = unsafe { core::slice::from_raw_parts(paddr.0 as *const u8, len) };
+        ctx.update(bytes);
+        remain -= len;
+    }
+
+    ctx.finalize().into()
+}
+
+/// Append-only log of what the security monitor measured at boot.
+pub struct MeasureLog {
+    log: BootLog,
+}
+
+impl MeasureLog {
+    pub const fn new() -> Self {
+        Self {
+            log: BootLog::empty(),
+        }
+    }
+
+    /// Append an event and extend the measurement with it.
+    ///
+    /// Panics if the log is full, since a measurement left out of the log
+    /// could not be verified.
+    pub fn append(&mut self, kind: BootEventKind, digest: Measurement) {
+        let num = self.log.num as usize;
+        assert!(num < MAX_BOOT_EVENTS, "boot log is full");
+
+        let mut ctx = Sha256::new();
+        ctx.update(self.log.measurement);
+        ctx.update((kind as u64).to_le_bytes());
+        ctx.update(digest);
+        self.log.measurement = ctx.finalize().into();
+        self.log.events[num] = BootEvent {
+            kind: kind as u64,
+            digest,
+        };
+        self.log.num += 1;
+    }
+
+    pub fn measurement(&self) -> &Measurement {
+        &self.log.measurement
+    }
+
+    pub fn log(&self) -> &BootLog {
+        &self.log
+    }
+}
+
+/// SHA-256 of the firmware text and read-only data at `region`.
+pub fn measure_image(region: Range<usize>) -> Measurement {
+    let bytes = unsafe { core::slice::from_raw_parts(region.start as *const u8, region.len()) };
+    Sha256::digest(bytes).into()
+}
+
+/// SHA-256 of the platform configuration the security monitor relies on.
+///
+/// It covers the memory layout, the regions reserved for the firmware and
+/// the security monitor, the harts and the PMP count. Secrets in `/chosen`
+/// are left out, and must be taken before the fdt is handed to the host.
+pub fn measure_config<P: Platform>(platform: &P, device: &DeviceInfo) -> Measurement {
+    let mut ctx = Sha256::new();
+    let mut update_region = |region: Range<usize>| {
+        ctx.update(region.start.to_le_bytes());
+        ctx.update(region.end.to_le_bytes());
+    };
+
+    update_region(platform.get_sbi_region());
+    update_region(platform.get_heap_region());
+    update_region(platform.get_pma_region());
+    for mem in device.get_mem_regions() {
+        update_region(mem.start as usize..mem.end() as usize);
+    }
+    for mem in device.get_mem_region_reserved() {
+        update_region(mem.start as usize..mem.end() as usize);
+    }
+    if let Some(clint) = device.get_clint_region() {
+        update_region(clint.start as usize..clint.end() as usize);
+    }
+
+    ctx.update(platform.get_hart_num().to_le_bytes());
+    ctx.update(platform.get_pmp_count().to_le_bytes());
+    ctx.finalize().into()
+}
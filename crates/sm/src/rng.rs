@@ -0,0 +1,63 @@
+use chacha20::{
+    ChaCha20,
+    cipher::{KeyIvInit, StreamCipher},
+};
+use riscv::register::seed::{self, Opst};
+use sha2::{Digest, Sha256};
+
+/// Number of 16-bit samples taken from the `seed` CSR for one seed.
+///
+/// The samples are not full entropy, so 4 times more bits than the seed
+/// size are collected and conditioned by SHA-256.
+const ZKR_SAMPLES: usize = 64;
+/// Polls of the `seed` CSR before giving up on a stuck entropy source.
+const ZKR_MAX_POLLS: usize = 1 << 20;
+
+/// ChaCha20 DRBG with fast key erasure.
+///
+/// Every request generates the next key before the output, so the state
+/// after a request reveals nothing about the bytes already handed out.
+pub struct Drbg {
+    key: [u8; 32],
+}
+
+impl Drbg {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self { key: seed }
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let mut cipher = ChaCha20::new(&self.key.into(), &[0; 12].into());
+        let mut next = [0; 32];
+        cipher.apply_keystream(&mut next);
+        self.key = next;
+        buf.fill(0);
+        cipher.apply_keystream(buf);
+    }
+}
+
+/// Collect a seed from the Zkr entropy source.
+///
+/// Returns `None` if the entropy source is dead or never becomes ready.
+pub fn zkr_seed() -> Option<[u8; 32]> {
+    let mut ctx = Sha256::new();
+    let mut samples = 0;
+
+    for _ in 0..ZKR_MAX_POLLS {
+        // SAFETY: the caller checked that the harts implement Zkr
+        let seed = unsafe { seed::read() };
+        match seed.opst() {
+            Opst::Es16 => {
+                ctx.update(seed.entropy().to_le_bytes());
+                samples += 1;
+                if samples == ZKR_SAMPLES {
+                    return Some(ctx.finalize().into());
+                }
+            }
+            Opst::Bist | Opst::Wait => continue,
+            Opst::Dead => return None,
+        }
+    }
+
+    None
+}
@@ -0,0 +1,43 @@
+use channel::attest::{KEY_ID_SIZE, SEALING_KEY_SIZE, SealPolicy};
+use enclave::Measurement;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const SEAL_LABEL: &[u8] = b"lattice-sealing-key";
+
+/// The platform root secret, from which sealing keys are derived.
+pub struct RootSecret([u8; 32]);
+
+impl RootSecret {
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self(secret)
+    }
+
+    /// `HMAC-SHA256(root, label || policy || debug || identity || key_id)`
+    ///
+    /// `identity` is the measurement or the signer, as chosen by `policy`.
+    /// Debug builds of the security monitor derive different keys, so data
+    /// sealed in production cannot be unsealed with a debug monitor.
+    pub fn derive(
+        &self,
+        policy: SealPolicy,
+        identity: &Measurement,
+        key_id: &[u8; KEY_ID_SIZE],
+    ) -> [u8; SEALING_KEY_SIZE] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(SEAL_LABEL);
+        mac.update(&(policy as u64).to_le_bytes());
+        mac.update(&[cfg!(debug_assertions) as u8]);
+        mac.update(identity);
+        mac.update(key_id);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// A key for the security monitor itself, `HMAC-SHA256(root, label || debug)`.
+    pub fn derive_internal(&self, label: &[u8]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(label);
+        mac.update(&[cfg!(debug_assertions) as u8]);
+        mac.finalize().into_bytes().into()
+    }
+}
@@ -1,28 +1,55 @@
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use enclave::{Enclave, EnclaveId, EnclaveIdx, EnclaveType};
+use channel::attest::{
+    KEY_ID_SIZE, LocalReport, MAC_SIZE, MEASUREMENT_SIZE, RANDOM_MAX, REPORT_DATA_SIZE, SealPolicy,
+};
+use channel::{
+    counter::COUNTER_ID_SIZE,
+    e2r::{LueRestoreArgs, RESTORE_INDEX_FRAMES, RESTORE_SPARE_FRAMES, SharedArg},
+    h2e::Sections,
+    info::LseInfo,
+    manifest::Manifest,
+    mem::MAX_DONATED_PAGES,
+    service::{SERVICE_LAUNCH, SERVICE_MSG_MAX, SERVICE_MSG_VADDR, ServiceMsg},
+    snapshot::{SNAPSHOT_MAGIC, SnapshotHeader, SnapshotMapping},
+};
+use context::HartContext;
+use enclave::{
+    Enclave, EnclaveId, EnclaveIdx, EnclaveType, Layout, LinuxDriverEnclave, LinuxServiceEnclave,
+    LinuxUserEnclave, Measurement, Thread,
+};
 use heapless::Vec;
-use hsm::Hsm;
+use hsm::{Hsm, MSTATUS_MPP, MSTATUS_MPP_SHIFT, MSTATUS_SIE};
 use console::{log, println};
 use device::device::Device;
 use pmp::{MAX_PMP_COUNT, PmpHelper};
 use riscv::{asm::fence, register::*};
-use sbi::TrapRegs;
+use sbi::{TrapRegs, ecall::STOP_EXIT_ENCLAVE};
 use spin::{Mutex, RwLock};
 use trap_proxy::ProxyResult;
 use vm::{
-    allocator::FrameAllocator,
-    mm::SV39,
-    page_table::{BarePtReader, BarePtWriter, PTEFlags},
+    page_table::{BarePtReader, PTEFlags},
     prelude::*,
-    vm::Sv39VmMgr,
 };
 
 use crate::{
-    Error, check_stack_overflow,
+    Error,
+    attest::{self, DeviceKey, ReportSecret},
+    check_stack_overflow,
+    consts::{
+        DEFAULT_TIME_SLICE, MAX_SNAPSHOT_PAGES, MAX_SNAPSHOT_TABLES, SERVICE_CALL_TICKS,
+        SERVICE_MIN_MEM,
+    },
+    counter::{BlobKeys, CounterTable},
     ecall::{EcallError, EcallResult},
-    enclave::{Builder, BuilderAllocator, EnclaveMgr, lse, lue, measure_data},
+    enclave::{Builder, BuilderAllocator, Claim, EnclaveMgr, FrameSource, UserArgs, lde, lse, lue},
     helper,
+    ipc::ChannelTable,
+    manifest,
+    measure::{self, Component, MeasureChain, MeasureLog},
+    rng::Drbg,
+    seal::RootSecret,
+    snapshot::{self, EnclaveState, Sealer, SnapshotKeys, SnapshotPages, TableCount, ThreadState},
 };
 use clint::ClintClient;
 use pma::{Owner, PhysMemArea, PhysMemAreaMgr, PmaProp};
@@ -30,12 +57,21 @@ use pma::{Owner, PhysMemArea, PhysMemAreaMgr, PmaProp};
 pub struct SecMonitor {
     pub pma_mgr: RwLock<PhysMemAreaMgr>,
     pub enc_mgr: EnclaveMgr,
+    pub channels: Mutex<ChannelTable>,
     pub clint: ClintClient,
     pub hsm: Hsm,
     pub nw_cache: Mutex<pmp::NwCache>,
     pub nw_fault_num: AtomicUsize,
 
     pub device: Device,
+
+    pub device_key: Option<DeviceKey>,
+    pub report_secret: Option<ReportSecret>,
+    pub root_secret: Option<RootSecret>,
+    pub blob_keys: Option<BlobKeys>,
+    pub counters: Mutex<CounterTable>,
+    pub rng: Mutex<Option<Drbg>>,
+    pub boot_log: MeasureLog,
 }
 
 impl SecMonitor {
@@ -61,25 +97,280 @@ impl SecMonitor {
     ) -> Result<ProxyResult, Error> {
         let res = match interrupt {
             mcause::Interrupt::MachineSoft => self.handle_msoft_trap(regs),
+            mcause::Interrupt::MachineTimer => self.handle_mtimer_trap(regs),
             _ => ProxyResult::Continue,
         };
 
         Ok(res)
     }
 
-    pub fn handle_msoft_trap(&self, _: &mut TrapRegs) -> ProxyResult {
+    pub fn handle_msoft_trap(&self, regs: &mut TrapRegs) -> ProxyResult {
         let hsm = &self.hsm;
         let op = hsm.take_op();
-        if op.clean_pmp {
-            if hsm.current().get_priv::<EnclaveIdx>().is_none() {
-                // in normal world
-                hsm.current().clean_pmp();
-            }
+        if op.clean_pmp || op.revoke_pmp || op.stop_enclave {
             self.clint.reset_msip();
         }
+        if op.clean_pmp || op.revoke_pmp {
+            if op.revoke_pmp || hsm.current().get_priv::<EnclaveIdx>().is_none() {
+                // in normal world, or pages were taken from an enclave
+                hsm.clean_pmp();
+            }
+        }
+        if op.stop_enclave {
+            return self.stop_thread(regs);
+        }
         ProxyResult::Continue
     }
 
+    /// Take the hart back from the user enclave thread whose time slice is
+    /// over, and return to the host with [`sbi::ecall::STOP_TIMER_INTERRUPT`].
+    ///
+    /// If the enclave ran for its whole time budget, all of its threads are
+    /// stopped and the host gets [`enclave::Error::TimeBudgetExceeded`]
+    /// instead. A service enclave has the timer while it serves a call, see
+    /// [`Self::preempt_service`]. Timers of the host are left to the SBI.
+    pub fn handle_mtimer_trap(&self, regs: &mut TrapRegs) -> ProxyResult {
+        let hart = mhartid::read();
+        let Some(enc) = self
+            .hsm
+            .current()
+            .get_priv::<EnclaveIdx>()
+            .map(|idx| idx.as_enc())
+        else {
+            return ProxyResult::Continue;
+        };
+        if let Some(lse) = enc.as_lse() {
+            return self.preempt_service(lse, regs);
+        }
+        let Some((enc, thread)) = enc
+            .as_lue()
+            .and_then(|enc| enc.data.thread_on(hart).map(|thread| (enc, thread)))
+        else {
+            return ProxyResult::Continue;
+        };
+        log::debug!("preempting #{} on hart {hart}", enc.id());
+
+        lue::preempt(thread, regs);
+        self.hsm.current().clear_priv();
+        self.hsm.clean_pmp();
+        riscv::asm::sfence_vma_all();
+        self.disarm_timer(enc, thread);
+        if enc.data.out_of_budget() {
+            log::info!("#{} ran for its whole time budget", enc.id());
+            regs.a0 = enclave::Error::TimeBudgetExceeded as usize;
+            self.stop_lue(enc);
+        }
+        self.leave_thread(enc, thread);
+
+        ProxyResult::Return
+    }
+
+    /// Charge the user enclave thread `lse` serves for the time of the call,
+    /// one time slice at a time.
+    ///
+    /// The call is cut short once it ran for [`SERVICE_CALL_TICKS`], and the
+    /// caller gets [`enclave::Error::ServiceUnavailable`], or once the calling
+    /// enclave ran for its whole time budget, and the thread gets
+    /// [`enclave::Error::TimeBudgetExceeded`] and is stopped right away.
+    fn preempt_service(&self, lse: &mut LinuxServiceEnclave, regs: &mut TrapRegs) -> ProxyResult {
+        let hart = mhartid::read();
+        let now = self.clint.mtime();
+        let caller = self
+            .enc_mgr
+            .get_lue(lse.data.caller)
+            .and_then(|enc| enc.data.thread_on(hart).map(|thread| (enc, thread)));
+        let out_of_budget = caller.is_some_and(|(enc, thread)| {
+            enc.data.charge(now.saturating_sub(thread.entered_at));
+            thread.entered_at = now;
+            enc.data.out_of_budget()
+        });
+        if now < lse.data.deadline && !out_of_budget {
+            self.clint.set_mtimecmp(hart, self.service_timer(lse, now));
+            return ProxyResult::Return;
+        }
+
+        let err = if out_of_budget {
+            log::info!("#{} ran for its whole time budget", lse.data.caller);
+            // the timer goes off as the thread is back, which stops it
+            lse.data.caller_timer = now;
+            lse.data.caller_timer_enabled = true;
+            enclave::Error::TimeBudgetExceeded
+        } else {
+            log::error!(
+                "#{} served #{} for too long on hart {hart}",
+                lse.id(),
+                lse.data.caller
+            );
+            enclave::Error::ServiceUnavailable
+        };
+        self.return_to_caller(lse, regs);
+        lse.leave();
+        regs.a0 = err as usize;
+        regs.a1 = 0;
+
+        ProxyResult::Return
+    }
+
+    /// When the timer of this hart goes off next as `lse` serves a call: at
+    /// the end of a time slice of the calling user enclave, or at the deadline
+    /// of the call.
+    fn service_timer(&self, lse: &LinuxServiceEnclave, now: u64) -> u64 {
+        let slice = match self.enc_mgr.get_lue(lse.data.caller) {
+            None => return lse.data.deadline,
+            Some(enc) if enc.data.time_slice == 0 => DEFAULT_TIME_SLICE,
+            Some(enc) => enc.data.time_slice,
+        };
+        now.saturating_add(slice).min(lse.data.deadline)
+    }
+
+    /// Hand the timer of this hart to `thread` as the host enters it, so the
+    /// host gets the hart back once the time slice of `enc` is over, or its
+    /// own timer is due.
+    fn arm_timer(&self, enc: &LinuxUserEnclave, thread: &mut Thread) {
+        let hart = mhartid::read();
+        let now = self.clint.mtime();
+        thread.nw_timer = self.clint.mtimecmp(hart);
+        thread.nw_timer_enabled = mie::read().mtimer();
+        thread.entered_at = now;
+
+        let slice = match enc.data.time_slice {
+            0 => DEFAULT_TIME_SLICE,
+            slice => slice,
+        };
+        let mut deadline = now.saturating_add(slice);
+        if thread.nw_timer_enabled {
+            deadline = deadline.min(thread.nw_timer);
+        }
+        self.clint.set_mtimecmp(hart, deadline);
+        unsafe { mie::set_mtimer() };
+    }
+
+    /// Give the timer of this hart back to the host as `thread` leaves, and
+    /// charge `enc` for the time the thread ran.
+    fn disarm_timer(&self, enc: &LinuxUserEnclave, thread: &Thread) {
+        let hart = mhartid::read();
+        enc.data
+            .charge(self.clint.mtime().saturating_sub(thread.entered_at));
+        self.clint.set_mtimecmp(hart, thread.nw_timer);
+        unsafe {
+            if thread.nw_timer_enabled {
+                mie::set_mtimer();
+            } else {
+                mie::clear_mtimer();
+            }
+        }
+    }
+
+    /// Stop the thread running on this hart if its enclave is exiting, and
+    /// return to the host that entered it with [`enclave::Error::EnclaveExited`].
+    fn stop_thread(&self, regs: &mut TrapRegs) -> ProxyResult {
+        let hart = mhartid::read();
+        let Some((enc, thread)) = self
+            .hsm
+            .current()
+            .get_priv::<EnclaveIdx>()
+            .and_then(|idx| idx.as_enc().as_lue())
+            .filter(|enc| enc.data.exiting.load(Ordering::SeqCst))
+            .and_then(|enc| enc.data.thread_on(hart).map(|thread| (enc, thread)))
+        else {
+            // the thread left by itself meanwhile
+            return ProxyResult::Continue;
+        };
+        log::debug!("stopping #{} on hart {hart}", enc.id());
+
+        // SAFETY: It is ready to switch context
+        *regs = unsafe { thread.nw_ctx.restore() };
+        regs.a0 = enclave::Error::EnclaveExited as usize;
+        regs.a1 = 0;
+        self.hsm.current().clear_priv();
+        self.hsm.clean_pmp();
+        riscv::asm::sfence_vma_all();
+        self.disarm_timer(enc, thread);
+        self.leave_thread(enc, thread);
+
+        ProxyResult::Return
+    }
+
+    /// Mark `thread` of `enc` not running, and clean the enclave up if it is
+    /// the last thread to leave an exiting enclave.
+    fn leave_thread(&self, enc: &'static mut LinuxUserEnclave, thread: &Thread) {
+        if !enc.data.leave(thread) || !enc.data.exiting.load(Ordering::SeqCst) {
+            return;
+        }
+        // the host may be destroying it too, only one of us gets it
+        let Ok(enc) = self.enc_mgr.take_lue(enc.id()) else {
+            return;
+        };
+        let (owner, nw_vma) = (enc.id(), enc.nw_vma);
+        // enclave will be cleaned
+        let _ = enc;
+        self.reclaim_memory(owner, nw_vma);
+        log::info!("[SM] Enclave {} cleaned", owner);
+    }
+
+    /// Abort the enclave running on this hart for a trap it cannot go on
+    /// from, rather than halting the machine.
+    ///
+    /// The host that entered it gets [`enclave::Error::EnclaveFault`] and the
+    /// mcause, and its memory is scrubbed and given back once all of its
+    /// threads are stopped. A service enclave is shared by the user enclaves
+    /// running on its runtime, so only the faulting call fails. The address
+    /// of the fault is only logged, as it tells where the enclave was.
+    fn abort_enclave(&self, regs: &mut TrapRegs) -> ProxyResult {
+        let hart = mhartid::read();
+        let Some(enc) = self
+            .hsm
+            .current()
+            .get_priv::<EnclaveIdx>()
+            .map(|idx| idx.as_enc())
+        else {
+            return ProxyResult::Continue;
+        };
+        let mcause = mcause::read().bits();
+        log::error!(
+            "#{} faulted on hart {hart}, mcause: {mcause:#x}, mepc: {:#x}, mtval: {:#x}",
+            enc.id(),
+            regs.mepc,
+            mtval::read()
+        );
+        enc.print_records();
+
+        if let Some(lse) = enc.as_lse() {
+            self.return_to_caller(lse, regs);
+            lse.leave();
+        } else if let Some(enc) = enc.as_lue() {
+            let Some(thread) = enc.data.thread_on(hart) else {
+                return ProxyResult::Continue;
+            };
+            self.stop_lue(enc);
+            // SAFETY: It is ready to switch context
+            *regs = unsafe { thread.nw_ctx.restore() };
+            self.hsm.current().clear_priv();
+            self.hsm.clean_pmp();
+            riscv::asm::sfence_vma_all();
+            self.disarm_timer(enc, thread);
+            self.leave_thread(enc, thread);
+        } else {
+            let owner = enc.id();
+            // SAFETY: It is ready to switch context
+            *regs = unsafe { enc.nw_ctx.restore() };
+            self.hsm.current().clear_priv();
+            self.hsm.clean_pmp();
+            riscv::asm::sfence_vma_all();
+            // running here, so the host cannot be destroying it meanwhile
+            self.enc_mgr.rm_lde(owner);
+            let nw_vma = enc.nw_vma;
+            // enclave will be cleaned
+            let _ = enc;
+            self.reclaim_memory(owner, nw_vma);
+            log::info!("[SM] Enclave {} cleaned", owner);
+        }
+        regs.a0 = enclave::Error::EnclaveFault as usize;
+        regs.a1 = mcause;
+
+        ProxyResult::Return
+    }
+
     pub fn handle_exception(
         &self,
         exception: mcause::Exception,
@@ -100,6 +391,9 @@ impl SecMonitor {
             | mcause::Exception::StoreFault
             | mcause::Exception::InstructionFault => match self.handle_pmp_fault(regs) {
                 Ok(_) => ProxyResult::Return,
+                Err(Error::AccessDenied(eid, _)) if eid != EnclaveId::HOST => {
+                    self.abort_enclave(regs)
+                }
                 Err(e) => {
                     log::trace!("[SM] {e}");
                     // unsafe { regs.redirect_to_smode() };
@@ -138,7 +432,8 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
                     regs.ra,
                     regs.sp,
                 );
-                panic!()
+                // the page faults of enclaves are delegated to them
+                self.abort_enclave(regs)
             }
             _ => ProxyResult::Continue,
         };
@@ -146,50 +441,94 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
         Ok(res)
     }
 
+    /// Clean the pmp of the harts in the normal world whose entries cover the
+    /// areas changed since the last shootdown, all of which it batches.
     fn reset_harts_pmp(&self) {
-        fence();
-        for i in 0..self.hsm.num() {
-            if i == mhartid::read() {
-                continue;
+        self.shootdown_pmp(hsm::HartStateOps {
+            clean_pmp: true,
+            ..hsm::HartStateOps::empty()
+        });
+        log::debug!("cleaned harts pmp");
+    }
+
+    /// Like [`Self::reset_harts_pmp`], but running enclaves are cleaned too,
+    /// and the pmp entries cached by the paused ones of `from` are dropped,
+    /// as pages were taken from them.
+    fn revoke_harts_pmp(&self, from: &[EnclaveId]) {
+        for &eid in from {
+            if let Some(lue) = self.enc_mgr.get_lue(eid) {
+                lue.data.threads().for_each(|thread| thread.pmp_cache.clear());
+            } else if let Some(lde) = self.enc_mgr.get_lde(eid) {
+                lde.data.pmp_cache.clear();
             }
-            self.hsm.send_ops(i, hsm::HartStateOps {
-                clean_pmp: true,
-                ..self.hsm.recv_op(i)
-            });
         }
-        self.hsm.current().clean_pmp();
-        self.clint.send_ipi_other_harts();
-        log::debug!("cleaned harts pmp");
+
+        self.shootdown_pmp(hsm::HartStateOps {
+            revoke_pmp: true,
+            ..hsm::HartStateOps::empty()
+        });
+        log::debug!("revoked harts pmp");
+    }
+
+    /// Send `ops` to the other harts whose pmp entries may cover an area
+    /// changed since the last shootdown, and clean the pmp of this one.
+    fn shootdown_pmp(&self, ops: hsm::HartStateOps) {
+        // taken after the change, so a hart either recorded its entries
+        // already, or reads the new pmas
+        let dirty = self.pma_mgr.write().take_dirty();
+        fence();
+        for i in self.hsm.harts_covering(&dirty) {
+            self.hsm.or_ops(i, ops.clone());
+            self.clint.send_ipi(i);
+            log::trace!("hart {i} may cover changed pmas");
+        }
+        self.hsm.clean_pmp();
     }
 
     fn create_lue(&self, arg0: usize) -> Result<EcallResult, EcallError> {
-        debug_assert_ne!(arg0, 0);
         let eid = self.enc_mgr.get_new_eid();
         log::debug!("eid: {eid}");
-        let userargs = lue::get_args(arg0);
+        let userargs = lue::get_args(&self.pma_mgr.read(), arg0).map_err(|e| {
+            log::error!("host cannot create #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
         log::debug!("user args:\n{userargs}");
 
-        // the entire memory
-        self.pma_mgr.write().update_pma_by_vma(
-            userargs.mem,
-            PmaProp::empty().owner(eid).permission(Permission::RWX),
-        );
+        let lse = self.enc_mgr.get_lse(userargs.lse).ok_or_else(|| {
+            log::error!("service enclave #{} does not exist", userargs.lse);
+            EcallError::code(enclave::Error::NoServiceEnclave as usize)
+        })?;
+        let needed = lue::unused_needed(&userargs, lse.data.rt.size);
+        if userargs.unused.size < needed {
+            log::error!(
+                "#{eid} needs {needed:#x} bytes of private memory, not {:#x}",
+                userargs.unused.size
+            );
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        }
 
-        // the first page is the meta page
-        self.pma_mgr.write().update_pma_by_vma(
-            VirtMemArea::default()
-                .start(userargs.mem.start as usize)
-                .size(PAGE_SIZE),
-            PmaProp::empty().owner(eid).permission(Permission::NONE),
-        );
+        let manifest = if userargs.manifest != 0 {
+            Some(self.read_manifest(&userargs)?)
+        } else {
+            None
+        };
 
-        // share area
-        self.pma_mgr.write().update_pma_by_vma(
-            userargs.share,
-            PmaProp::empty()
-                .owner(EnclaveId::EVERYONE)
-                .permission(Permission::RWX),
-        );
+        // the entire memory, the first page of which is the meta page, and
+        // the share area
+        let (meta, share) = (userargs.mem.start, userargs.share);
+        let claim = self
+            .claim_host_memory(eid, userargs.mem, |vaddr| {
+                Some(if vaddr == meta {
+                    PmaProp::empty().owner(eid).permission(Permission::NONE)
+                } else if (share.start..share.start + share.size).contains(&vaddr) {
+                    PmaProp::empty()
+                        .owner(EnclaveId::EVERYONE)
+                        .permission(Permission::RWX)
+                } else {
+                    PmaProp::empty().owner(eid).permission(Permission::RWX)
+                })
+            })
+            .map_err(|e| EcallError::code(e as usize))?;
 
         self.reset_harts_pmp();
 
@@ -205,10 +544,46 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
             })
             .for_each(|pma| println!("{pma}"));
 
-        let md5 = measure_data(userargs.binary);
-        log::debug!("md5: {:#x}", md5);
+        let mem = userargs.mem;
+        let enc = self.build_lue(eid, userargs, lse, &claim).map_err(|e| {
+            self.reclaim_memory(eid, mem);
+            self.reset_harts_pmp();
+            EcallError::code(e as usize)
+        })?;
+        claim.commit();
+        self.reset_harts_pmp();
+
+        if let Some((manifest, signer)) = manifest {
+            if manifest.body.measurement != enc.measurement {
+                log::error!("#{eid} does not match the measurement of its manifest");
+                let nw_vma = enc.nw_vma;
+                self.reclaim_memory(eid, nw_vma);
+                self.reset_harts_pmp();
+                return Err(EcallError::code(enclave::Error::InvalidManifest as usize));
+            }
+            enc.signer = Some(signer);
+            enc.debug = manifest.body.debug != 0;
+            enc.data.max_mem_size = manifest.body.max_mem_size as usize;
+        }
+
+        let nw_vma = enc.nw_vma;
+        if let Err(e) = self.enc_mgr.push_lue(enc) {
+            self.reclaim_memory(eid, nw_vma);
+            self.reset_harts_pmp();
+            return Err(EcallError::code(e as usize));
+        }
+
+        Ok(EcallResult::ret().retval(eid.0))
+    }
 
-        let lse = self.enc_mgr.get_lse(0).unwrap();
+    /// Build user enclave `eid` in the memory `claim` took from the host.
+    fn build_lue(
+        &self,
+        eid: EnclaveId,
+        userargs: UserArgs,
+        lse: &LinuxServiceEnclave,
+        claim: &dyn FrameSource,
+    ) -> Result<&'static mut LinuxUserEnclave, enclave::Error> {
         let mut layout = lue::init_layout(&userargs, lse);
         log::debug!("#{eid} layout:\n{layout}");
 
@@ -216,336 +591,2489 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
             VirtMemArea::default()
                 .start(userargs.unused.start as usize)
                 .size(userargs.unused.size - 0x1000),
+            claim,
+        );
+        let mut builder = Builder::new(allocator)?;
+
+        let enc = builder.create_lue(&userargs, eid)?;
+        enc.nw_vma = userargs.mem;
+
+        // the trampoline and the runtime are the frames the service recorded
+        // when it was created, not what the host maps now
+        let trampoline = builder.create_trampoline(lse.data.trampoline)?;
+        layout.trampoline = trampoline;
+        builder.map_runtime(lse.data.rt, layout.rt)?;
+
+        // map args
+        let bootargs_vma = builder.alloc_vma(layout.bootargs)?;
+
+        // each thread gets a page out of reach of the enclave and a stack
+        let satp = builder.vmm.gen_satp();
+        for tid in 0..layout.threads {
+            let thread = enc.data.push_thread(builder.alloc_page()?);
+            let stack = builder.alloc_vma(layout.thread_stack(tid))?;
+            thread.enc_ctx.sregs.satp = satp;
+            thread.enc_ctx.tregs.a0 = tid;
+            thread.enc_ctx.tregs.a1 = bootargs_vma.start;
+            thread.enc_ctx.tregs.sp = stack.start + stack.size;
+        }
+        enc.data.time_slice = userargs.time_slice;
+        enc.data.time_budget = userargs.time_budget;
+        enc.data.set_mem_size(userargs.mem.size);
+        enc.data.lse = lse.measurement;
+        enc.data.lse_id = lse.id();
+        enc.data.rt = layout.rt;
+        enc.data.share = layout.share;
+
+        // map share
+        builder.map_vma(userargs.share, layout.share)?;
+        // map binary, which is measured where the enclave maps it
+        let binary = builder.map_vma(userargs.binary, layout.binary)?;
+        // map serial
+        builder.map_frames(
+            PhysPageNum::from_paddr(self.device.uart.get_reg().start),
+            VirtMemArea::default()
+                .start(self.device.uart.get_reg().start)
+                .size(align_up!(self.device.uart.get_reg().len(), PAGE_SIZE))
+                .flags(PTEFlags::rw().dirty().accessed()),
+        )?;
+
+        let (head, free_size) = builder.collect_unused()?;
+
+        // like the meta page, the threads are only accessed by the monitor
+        {
+            let mut pma_mgr = self.pma_mgr.write();
+            for thread in enc.data.threads() {
+                pma_mgr.insert_page(
+                    thread as *const Thread as usize,
+                    PmaProp::empty().owner(eid).permission(Permission::NONE),
+                );
+            }
+        }
+        self.reset_harts_pmp();
+
+        let mut chain = MeasureChain::new();
+        chain.extend_pages(Component::Runtime, lse.data.rt);
+        chain.extend_pages(Component::Binary, binary);
+        chain.extend_layout(&layout);
+        chain.extend_shared(userargs.share.size);
+
+        let bootargs = lue::create_bootargs(
+            bootargs_vma,
+            userargs.mem.size(userargs.mem.size - 0x1000),
+            layout,
+            userargs,
+            head,
+            free_size,
+            self.device.clone(),
         );
+        chain.extend_bootargs(bootargs);
 
-        let mut builder = Builder {
-            vmm: Sv39VmMgr::new(
-                allocator.alloc().unwrap(),
-                BarePtWriter,
-                allocator,
-                satp::read().asid(),
-                SV39,
-            ),
+        enc.measurement = chain.finish();
+        log::debug!("#{eid} measurement: {:02x?}", enc.measurement);
+
+        Ok(enc)
+    }
+
+    /// Copy the manifest of a LUE from the host and check it against `userargs`.
+    ///
+    /// Returns the manifest and the identity of its signer.
+    fn read_manifest(&self, userargs: &UserArgs) -> Result<(Manifest, Measurement), EcallError> {
+        let invalid_manifest = |e: Error| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidManifest as usize)
         };
 
-        let enc = builder.create_lue(&userargs, eid);
+        // SAFETY: Manifest is `repr(C)` without padding, and valid for any bytes.
+        let mut manifest: Manifest = unsafe { core::mem::zeroed() };
+        let bytes = unsafe { helper::bytes_of_mut(&mut manifest) };
+        helper::copy_from_enclave(&self.pma_mgr.read(), EnclaveId::HOST, userargs.manifest, bytes)
+            .map_err(invalid_manifest)?;
+        let signer = manifest::verify(&manifest, userargs).map_err(invalid_manifest)?;
+
+        Ok((manifest, signer))
+    }
+
+    fn create_lse(&self, arg0: usize) -> Result<EcallResult, EcallError> {
+        let eid = self.enc_mgr.get_new_eid();
+        log::debug!("eid: {eid}");
+        let userargs = lse::get_user_args(&self.pma_mgr.read(), arg0).map_err(|e| {
+            log::error!("host cannot create #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
+
+        // the runtime is recorded in a page table at the start of the private
+        // memory, and a callable service keeps the rest
+        let callable = userargs.entry != 0;
+        let tables = LseInfo::table_pages(userargs.rt.size) * PAGE_SIZE;
+        let private = userargs.unused.size.saturating_sub(tables);
+        if userargs.unused.size < tables
+            || callable && (userargs.entry >= userargs.rt.size || private < SERVICE_MIN_MEM)
+        {
+            log::error!(
+                "#{eid} cannot record its runtime and serve at {:#x} with {:#x} bytes of memory",
+                userargs.entry,
+                userargs.unused.size
+            );
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        }
+
+        // the meta page and the runtime are shared, the page table of the
+        // runtime is only written by the monitor, and the rest is the private
+        // memory of a callable service
+        let (meta, rt) = (userargs.mem.start, userargs.rt);
+        let table = userargs.unused.size(tables);
+        let claim = self
+            .claim_host_memory(eid, userargs.mem, |vaddr| {
+                if vaddr == meta {
+                    Some(
+                        PmaProp::empty()
+                            .owner(Owner::EVERYONE)
+                            .permission(Permission::NONE),
+                    )
+                } else if (rt.start..rt.start + rt.size).contains(&vaddr) {
+                    Some(
+                        PmaProp::empty()
+                            .owner(Owner::EVERYONE)
+                            .permission(Permission::RX),
+                    )
+                } else if (table.start..table.start + table.size).contains(&vaddr) {
+                    Some(PmaProp::empty().owner(eid).permission(Permission::NONE))
+                } else if callable {
+                    Some(PmaProp::empty().owner(eid).permission(Permission::RWX))
+                } else {
+                    None
+                }
+            })
+            .map_err(|e| EcallError::code(e as usize))?;
+        self.reset_harts_pmp();
+
+        let enc = self
+            .build_lse(eid, &userargs, private, &claim)
+            .map_err(|e| {
+                self.reclaim_memory(eid, userargs.mem);
+                self.reset_harts_pmp();
+                EcallError::code(e as usize)
+            })?;
+        claim.commit();
+        self.reset_harts_pmp();
+
+        self.enc_mgr.push_lse(enc);
+
+        Ok(EcallResult::ret().retval(eid.0))
+    }
+
+    /// Build service enclave `eid` in the memory `claim` took from the host,
+    /// `private` bytes of which are left to a callable service.
+    fn build_lse(
+        &self,
+        eid: EnclaveId,
+        userargs: &UserArgs,
+        private: usize,
+        claim: &dyn FrameSource,
+    ) -> Result<&'static mut LinuxServiceEnclave, enclave::Error> {
+        let tables = userargs.unused.size - private;
+        let allocator = BuilderAllocator::new(userargs.unused.size(tables), claim);
+        let mut rt_builder = Builder::new(allocator)?;
+
+        let enc = rt_builder.create_lse(userargs, eid)?;
+        enc.nw_vma = userargs.mem;
+        // user enclaves map the runtime from the frames recorded here, which
+        // the host cannot remap
+        let mut layout = Layout::default();
+        layout.rt.size = userargs.rt.size;
+        enc.data.rt = rt_builder.map_vma(userargs.rt, layout.rt)?;
+        enc.data.trampoline = enc.data.rt.size(PAGE_SIZE);
+        enc.data.entry = userargs.entry;
+        enc.data.launched = false;
+        enc.data.caller = EnclaveId::HOST;
+        enc.data.reply = (0, 0);
+
+        let mut chain = MeasureChain::new();
+        chain.extend_pages(Component::Runtime, enc.data.rt);
+
+        if userargs.entry != 0 {
+            layout.bootargs.size = PAGE_SIZE;
+
+            let allocator = BuilderAllocator::new(
+                userargs
+                    .unused
+                    .start(userargs.unused.start + tables)
+                    .size(private),
+                claim,
+            );
+            let mut builder = Builder::new(allocator)?;
+
+            builder.map_runtime(enc.data.rt, layout.rt)?;
+            builder.alloc_vma(layout.stack)?;
+            enc.data.stack_top = layout.stack.start + layout.stack.size;
+
+            // the message page takes the place of the boot arguments
+            let msg = builder.alloc_vma(layout.bootargs)?;
+            debug_assert_eq!(msg.start, SERVICE_MSG_VADDR);
+            enc.data.msg = msg
+                .start
+                .translate(msg.satp.ppn(), msg.satp.mode(), &BarePtReader)
+                .ok_or(enclave::Error::InvalidAddress)?
+                .0;
+
+            // map serial
+            builder.map_frames(
+                PhysPageNum::from_paddr(self.device.uart.get_reg().start),
+                VirtMemArea::default()
+                    .start(self.device.uart.get_reg().start)
+                    .size(align_up!(self.device.uart.get_reg().len(), PAGE_SIZE))
+                    .flags(PTEFlags::rw().dirty().accessed()),
+            )?;
+            enc.data.satp = builder.vmm.gen_satp();
+            // scrub the rest, which is left to the service
+            builder.collect_unused()?;
+
+            chain.extend_layout(&layout);
+            chain.extend_entry(userargs.entry);
+            log::debug!("#{eid} serves at {:#x}", userargs.entry);
+        }
+
+        enc.measurement = chain.finish();
+        log::debug!("#{eid} measurement: {:02x?}", enc.measurement);
+
+        Ok(enc)
+    }
+
+    fn create_lde(&self, arg0: usize) -> Result<EcallResult, EcallError> {
+        let eid = self.enc_mgr.get_new_eid();
+        log::debug!("eid: {eid}");
+        let (userargs, sections) = lde::get_args(&self.pma_mgr.read(), arg0).map_err(|e| {
+            log::error!("host cannot create #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
+        log::debug!("user args:\n{userargs}");
+        log::debug!("driver: {}", userargs.driver);
+
+        // the entire memory, including the runtime and the module to load,
+        // the first page of which is the meta page
+        let meta = userargs.mem.start;
+        let claim = self
+            .claim_host_memory(eid, userargs.mem, |vaddr| {
+                let perm = if vaddr == meta {
+                    Permission::NONE
+                } else {
+                    Permission::RWX
+                };
+                Some(PmaProp::empty().owner(eid).permission(perm))
+            })
+            .map_err(|e| EcallError::code(e as usize))?;
+
+        self.reset_harts_pmp();
+
+        let mem = userargs.mem;
+        let enc = self
+            .build_lde(eid, &userargs, sections, &claim)
+            .map_err(|e| {
+                self.reclaim_memory(eid, mem);
+                self.reset_harts_pmp();
+                EcallError::code(e as usize)
+            })?;
+        claim.commit();
+        self.reset_harts_pmp();
+
+        self.enc_mgr.push_lde(enc);
+
+        Ok(EcallResult::ret().retval(eid.0))
+    }
+
+    /// Build driver enclave `eid` in the memory `claim` took from the host.
+    fn build_lde(
+        &self,
+        eid: EnclaveId,
+        userargs: &UserArgs,
+        sections: Sections,
+        claim: &dyn FrameSource,
+    ) -> Result<&'static mut LinuxDriverEnclave, enclave::Error> {
+        let mut layout = lde::init_layout(userargs);
+
+        let allocator = BuilderAllocator::new(
+            VirtMemArea::default()
+                .start(userargs.unused.start as usize)
+                .size(userargs.unused.size),
+            claim,
+        );
+        let mut builder = Builder::new(allocator)?;
+
+        let enc = builder.create_lde(userargs, eid)?;
         enc.nw_vma = userargs.mem;
         enc.data.enc_ctx.sregs.satp = builder.vmm.gen_satp();
         enc.data.enc_ctx.tregs.a0 = 0;
 
-        // map trampoline
-        let trampoline = builder.create_trampoline(lse.data.trampoline);
-        layout.trampoline = trampoline;
+        // map runtime
+        let rt = builder.map_vma(userargs.rt, layout.rt)?;
+
+        // map trampoline, the first page of the runtime, from the frames
+        // just mapped
+        layout.trampoline = builder.create_trampoline(rt.size(PAGE_SIZE))?;
+
+        // map stack
+        builder.alloc_vma(layout.stack)?;
+        enc.data.enc_ctx.tregs.sp = layout.stack.start + layout.stack.size;
+
+        // map the page of the driver kernel
+        builder.alloc_vma(layout.kernel)?;
+
+        // map args
+        let bootargs_vma = builder.alloc_vma(layout.bootargs)?;
+        enc.data.enc_ctx.tregs.a1 = bootargs_vma.start;
+
+        // map the module, which the runtime relocates to the driver region
+        let binary = builder.map_vma(userargs.binary, layout.binary)?;
+        // map serial
+        builder.map_frames(
+            PhysPageNum::from_paddr(self.device.uart.get_reg().start),
+            VirtMemArea::default()
+                .start(self.device.uart.get_reg().start)
+                .size(align_up!(self.device.uart.get_reg().len(), PAGE_SIZE))
+                .flags(PTEFlags::rw().dirty().accessed()),
+        )?;
+
+        let (head, free_size) = builder.collect_unused()?;
+        log::debug!("#{eid} layout:\n{layout}");
+
+        let mut chain = MeasureChain::new();
+        chain.extend_pages(Component::Runtime, rt);
+        chain.extend_pages(Component::Binary, binary);
+        chain.extend_layout(&layout);
+
+        let bootargs = lde::create_bootargs(
+            bootargs_vma,
+            userargs.mem.size(userargs.mem.size - 0x1000),
+            &layout,
+            userargs,
+            sections,
+            head,
+            free_size,
+            self.device.clone(),
+        );
+        chain.extend_driver_bootargs(bootargs);
+
+        enc.measurement = chain.finish();
+        log::debug!("#{eid} measurement: {:02x?}", enc.measurement);
+
+        Ok(enc)
+    }
+
+    fn create_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        const USER_ENC: usize = EnclaveType::User as usize;
+        const DRV_ENC: usize = EnclaveType::Driver as usize;
+        const SER_ENC: usize = EnclaveType::Service as usize;
+
+        match regs.a1 {
+            USER_ENC => self.create_lue(regs.a0),
+            DRV_ENC => self.create_lde(regs.a0),
+            SER_ENC => self.create_lse(regs.a0),
+            ty => {
+                log::error!("unknown enclave type {ty}");
+                Err(EcallError::code(
+                    enclave::Error::InvalidEnclaveType as usize,
+                ))
+            }
+        }
+    }
+
+    fn destory_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        // SAFETY: Enclave<()> is safe
+        let enc = self
+            .hsm
+            .current()
+            .get_priv::<EnclaveIdx>()
+            .unwrap()
+            .as_enc();
+        let owner = enc.id();
+
+        if enc.get_type() == EnclaveType::Service {
+            log::error!("service enclave #{owner} returns from calls instead of exiting");
+            return Err(EcallError::code(enclave::Error::InvalidCaller as usize));
+        }
+
+        enc.print_records();
+
+        if let Some(enc) = enc.as_lue() {
+            log::info!("enclave pause num: {}", enc.data.pause_num);
+            return self.exit_lue(enc, regs);
+        }
+
+        log::info!("Cleaning enclave {}", owner);
+
+        self.enc_mgr.rm_lde(owner);
+        *regs = unsafe { enc.nw_ctx.restore() };
+
+        let nw_vma = enc.nw_vma;
+        // enclave will be cleaned
+        let _ = enc;
+        self.reclaim_memory(owner, nw_vma);
+
+        self.hsm.current().clear_priv();
+        log::info!("[SM] Enclave {} cleaned", owner);
+
+        Ok(EcallResult::ret().retval(0).fixed_epc())
+    }
+
+    /// Exit the LUE a thread of which calls on this hart.
+    ///
+    /// The threads running on other harts are stopped, and the last thread to
+    /// leave cleans the enclave up.
+    fn exit_lue(
+        &self,
+        enc: &'static mut LinuxUserEnclave,
+        regs: &mut TrapRegs,
+    ) -> Result<EcallResult, EcallError> {
+        let hart = mhartid::read();
+        let thread = enc.data.thread_on(hart).unwrap();
+        log::info!("#{} exits on hart {hart}", enc.id());
+        self.stop_lue(enc);
+
+        // SAFETY: It is ready to switch context
+        *regs = unsafe { thread.nw_ctx.restore() };
+        self.hsm.current().clear_priv();
+        self.hsm.clean_pmp();
+        self.disarm_timer(enc, thread);
+        self.leave_thread(enc, thread);
+
+        Ok(EcallResult::ret().retval(STOP_EXIT_ENCLAVE).fixed_epc())
+    }
+
+    /// Mark `enc` exiting and stop its threads running on other harts.
+    fn stop_lue(&self, enc: &LinuxUserEnclave) {
+        let hart = mhartid::read();
+        self.enc_mgr.exit_lue(enc);
+        for other in enc.data.threads().filter_map(|thread| thread.hart()) {
+            if other == hart {
+                continue;
+            }
+            self.hsm.or_ops(other, hsm::HartStateOps {
+                stop_enclave: true,
+                ..hsm::HartStateOps::empty()
+            });
+            self.clint.send_ipi(other);
+        }
+    }
+
+    /// Destroy an enclave, either the calling one or, on behalf of the host,
+    /// one that is not running.
+    ///
+    /// a0: id of the enclave if the caller is the host.
+    fn destroy_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        if self.hsm.current().get_priv::<EnclaveIdx>().is_some() {
+            return self.destory_enclave(regs);
+        }
+
+        let eid = EnclaveId::from(regs.a0);
+        if self.enc_mgr.get_lse(eid).is_some() {
+            return self.destroy_service(eid);
+        }
+        let enc = match self.enc_mgr.get_lde(eid) {
+            Some(_) => self.enc_mgr.take_lde(eid).map(|enc| enc.idx().as_enc()),
+            None => self.enc_mgr.take_lue(eid).map(|enc| enc.idx().as_enc()),
+        }
+        .map_err(|e| {
+            log::error!("host cannot destroy #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
+        enc.print_records();
+
+        let nw_vma = enc.nw_vma;
+        // enclave will be cleaned
+        let _ = enc;
+        self.reclaim_memory(eid, nw_vma);
+        log::info!("[SM] Enclave {} destroyed by the host", eid);
+
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    /// Destroy service enclave `eid` on behalf of the host, once no user
+    /// enclave runs on its runtime.
+    ///
+    /// Besides its own pages, the runtime and the meta page, which are shared
+    /// with everyone, go back to the host.
+    fn destroy_service(&self, eid: EnclaveId) -> Result<EcallResult, EcallError> {
+        let enc = self.enc_mgr.take_lse(eid).map_err(|e| {
+            log::error!("host cannot destroy #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
+        enc.print_records();
+
+        let (rt, nw_vma) = (enc.data.rt, enc.nw_vma);
+        let meta = enc as *const LinuxServiceEnclave as usize;
+        let host = PmaProp::empty()
+            .owner(EnclaveId::HOST)
+            .permission(Permission::RWX);
+        // the runtime is found by the page table of the monitor, which goes
+        // with the owned pages
+        let mut mgr = self.pma_mgr.write();
+        for vpn in rt.iter_vpn() {
+            if let Some(paddr) = vpn.translate(rt.satp.ppn(), rt.satp.mode(), &BarePtReader) {
+                mgr.insert_page(paddr, host);
+            }
+        }
+        drop(mgr);
+        self.reclaim_memory(eid, nw_vma);
+        // SAFETY: the enclave is out of the list and never runs again, and
+        // its meta page keeps the context of the last caller
+        unsafe { clean_page_content(meta) };
+        self.pma_mgr.write().insert_page(meta, host);
+        log::info!("[SM] Enclave {} destroyed by the host", eid);
+
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    /// Take the pages of the host at `mem` for `eid`, each of which is given
+    /// the property `prop` returns for its address when the builder takes it
+    /// from the returned claim.
+    ///
+    /// The frames are marked pending for `eid`, which makes them the frame
+    /// set of the creation: a frame mapped twice in `mem` is found pending
+    /// already, and a frame another creation is taking is not the host's.
+    /// A pending frame is taken once, and the ones left pending go back to
+    /// the host when the claim is committed, or by their owner alone when the
+    /// creation fails.
+    fn claim_host_memory<P: Fn(usize) -> Option<PmaProp>>(
+        &self,
+        eid: EnclaveId,
+        mem: VirtMemArea,
+        prop: P,
+    ) -> Result<Claim<'_, P>, enclave::Error> {
+        let host = PmaProp::empty()
+            .owner(EnclaveId::HOST)
+            .permission(Permission::RWX);
+        let pending = PmaProp::empty().owner(eid).pending();
+        let give_back = |mgr: &mut PhysMemAreaMgr| loop {
+            let owned = |pma: &PhysMemArea| pma.get_prop().get_owner() == eid;
+            let Some(pma) = mgr.iter_pma().find(owned) else {
+                break;
+            };
+            mgr.insert_pma(PhysMemArea {
+                region: pma.get_region(),
+                prop: host,
+            })
+            .unwrap();
+        };
+
+        let mut mgr = self.pma_mgr.write();
+        for (i, vpn) in mem.iter_vpn().enumerate() {
+            let vaddr = mem.start + i * PAGE_SIZE;
+            let page = vpn
+                .translate(mem.satp.ppn(), mem.satp.mode(), &BarePtReader)
+                .and_then(|paddr| Some((paddr, mgr.get_pma(paddr)?)));
+            match page {
+                Some((paddr, pma)) if pma.get_prop().get_owner() == EnclaveId::HOST => {
+                    mgr.insert_page(paddr, pending)
+                }
+                Some((paddr, pma)) if pma.get_prop() == pending => {
+                    log::error!("{:#x} is mapped twice, at {vaddr:#x}", paddr.0);
+                    give_back(&mut mgr);
+                    return Err(enclave::Error::InvalidAddress);
+                }
+                _ => {
+                    log::error!("{vaddr:#x} is not a page of the host");
+                    give_back(&mut mgr);
+                    return Err(enclave::Error::InvalidAddress);
+                }
+            }
+        }
+
+        Ok(Claim::new(&self.pma_mgr, eid, mem, prop))
+    }
+
+    /// Scrub the pages of `owner` and give them back to the host, together
+    /// with the pages `nw_vma` shares with it.
+    fn reclaim_memory(&self, owner: EnclaveId, nw_vma: VirtMemArea) {
+        // lent pages go back to their opener before the owned ones are found
+        let peers = self
+            .channels
+            .lock()
+            .close_all(&mut self.pma_mgr.write(), owner);
+        if !peers.is_empty() {
+            self.revoke_harts_pmp(&peers);
+        }
+
+        let mut pma_mgr = self.pma_mgr.write();
+
+        // owned pages are found by their pma rather than the host page table,
+        // so a page the host unmapped is still given back
+        loop {
+            let owned = |pma: &PhysMemArea| pma.get_prop().get_owner() == owner;
+            let Some(pma) = pma_mgr.iter_pma().find(owned) else {
+                break;
+            };
+            for page in pma.get_region().step_by(PAGE_SIZE) {
+                // SAFETY: the page is owned by the enclave, which will never run again.
+                unsafe { clean_page_content(page) };
+            }
+            pma_mgr
+                .insert_pma(PhysMemArea {
+                    region: pma.get_region(),
+                    prop: PmaProp::empty()
+                        .owner(EnclaveId::HOST)
+                        .permission(Permission::RWX),
+                })
+                .unwrap();
+        }
+
+        // only writable pages, the runtime of service enclaves is shared too
+        for vpn in nw_vma.iter_vpn() {
+            let Some(paddr) = vpn.translate(nw_vma.satp.ppn(), nw_vma.satp.mode(), &BarePtReader)
+            else {
+                continue;
+            };
+            let shared = pma_mgr.get_pma(paddr).is_some_and(|pma| {
+                pma.get_prop().get_owner() == EnclaveId::EVERYONE
+                    && pma.get_prop().get_owner_perm() == Permission::RWX
+            });
+            if shared {
+                pma_mgr.insert_page(
+                    paddr,
+                    PmaProp::empty()
+                        .owner(EnclaveId::HOST)
+                        .permission(Permission::RWX),
+                );
+            }
+        }
+    }
+
+    /// Launch an enclave, or one thread of a user enclave.
+    ///
+    /// a0: id of the enclave, a1: id of the thread of a user enclave. The
+    /// main thread, 0, is launched before the others, and each only once.
+    fn launch_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let eid = EnclaveId::from(regs.a0);
+        let tid = regs.a1;
+        log::debug!("Launch enclave. Id: #{}, thread: {tid}", eid);
+        #[allow(unused_assignments)]
+        let mut args = (0, 0);
+        #[allow(unused_assignments)]
+        let mut addr = 0;
+        #[allow(unused_assignments)]
+        let mut sp = 0;
+
+        if let Some(_) = self.enc_mgr.get_lue(eid) {
+            let (enc, thread) = self.enc_mgr.enter_lue(eid, tid).map_err(|e| {
+                log::error!("{e}");
+                EcallError::code(e as usize)
+            })?;
+            let main_launched = enc.data.thread(0).is_some_and(|main| main.launched);
+            // a restored runtime relocates itself before any thread goes on
+            if thread.launched || (tid != 0 && !main_launched) || enc.data.is_relocating() {
+                log::error!("thread {tid} of #{eid} cannot be launched");
+                enc.data.leave(thread);
+                return Err(EcallError::code(enclave::Error::InvalidThread as usize));
+            }
+            args = lue::prepare_launch(thread, regs);
+            debug_assert_eq!(args.0, tid);
+            self.arm_timer(enc, thread);
+            addr = enclave::DEFAULT_RT_START;
+            sp = thread.enc_ctx.tregs.sp;
+            self.hsm.current().set_priv(enc.idx());
+            log::debug!("Set enclave idx #{}", enc.idx());
+        } else if let Some(_) = self.enc_mgr.get_lse(eid) {
+            return self.launch_service(regs);
+        } else if let Some(_) = self.enc_mgr.get_lde(eid) {
+            let enc = self.enc_mgr.enter_lde(eid).map_err(|e| {
+                log::error!("{e}");
+                EcallError::code(e as usize)
+            })?;
+            if enc.data.launched {
+                log::error!("#{eid} is launched already");
+                enc.leave();
+                return Err(EcallError::code(enclave::Error::EnclaveRunning as usize));
+            }
+            args = lde::prepare_launch(enc, regs);
+            addr = enclave::DEFAULT_RT_START;
+            sp = enc.data.enc_ctx.tregs.sp;
+            self.hsm.current().set_priv(enc.idx());
+            log::debug!("Set enclave idx #{}", enc.idx());
+        } else {
+            log::error!("enclave #{eid} not found");
+            return Err(EcallError::code(enclave::Error::InvalidEnclaveId as usize));
+        }
+
+        self.hsm.clean_pmp();
+        unsafe { stvec::write(0, stvec::TrapMode::Direct) };
+
+        log::debug!("lue arg0: {:#x}, arg1: {:#x}", args.0, args.1);
+        log::debug!("enclave entry: {addr:#x}");
+        log::debug!("entry satp: {:#x}", satp::read().bits());
+
+        unsafe {
+            self.hsm.mret(
+                addr,
+                mstatus::MPP::Supervisor,
+                args.0,
+                args.1,
+                sp,
+                satp::read().bits(),
+            )
+        }
+    }
+
+    /// Launch a service enclave by calling its entry once, with no request.
+    ///
+    /// a0: id of the service enclave. Returns when the service returns.
+    fn launch_service(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let lse = self.enc_mgr.enter_lse(regs.a0).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(e as usize)
+        })?;
+        if lse.data.launched {
+            log::error!("#{} is launched already", lse.id());
+            lse.leave();
+            return Err(EcallError::code(enclave::Error::EnclaveRunning as usize));
+        }
+
+        // SAFETY: the message page belongs to the service, which is entered
+        let msg = unsafe { &mut *(lse.data.msg as *mut ServiceMsg) };
+        msg.caller = SERVICE_LAUNCH;
+        msg.len = 0;
+        lse.data.reply = (0, 0);
+        // unimp length
+        regs.mepc += 0x2;
+
+        self.switch_to_service(lse, EnclaveId::HOST, regs)
+    }
+
+    /// Call a service enclave, which runs until it returns a reply.
+    ///
+    /// a0: id of the service enclave, a1: address of the request, a2: its
+    /// length, a3: address of the reply buffer, a4: its size. Returns the
+    /// length of the reply, which is cut to the size of the buffer.
+    fn call_service(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let caller = self
+            .hsm
+            .current()
+            .get_priv::<EnclaveIdx>()
+            .map(|idx| idx.as_enc().id())
+            .unwrap_or(EnclaveId::HOST);
+        let invalid_address = |e: Error| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
+        };
+
+        if regs.a2 > SERVICE_MSG_MAX {
+            log::error!("#{caller} sent {:#x} bytes of request", regs.a2);
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        }
+        let pma_mgr = self.pma_mgr.read();
+        // checked now, as a failed reply cannot be reported once the service ran
+        helper::check_enclave_range(&pma_mgr, caller, regs.a3, regs.a4)
+            .map_err(invalid_address)?;
+
+        let lse = self.enc_mgr.enter_lse(regs.a0).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(e as usize)
+        })?;
+        if !lse.data.launched {
+            log::error!("#{} is not launched", lse.id());
+            lse.leave();
+            return Err(EcallError::code(enclave::Error::ServiceUnavailable as usize));
+        }
+
+        // SAFETY: the message page belongs to the service, which is entered
+        let msg = unsafe { &mut *(lse.data.msg as *mut ServiceMsg) };
+        if let Err(e) =
+            helper::copy_from_enclave(&pma_mgr, caller, regs.a1, &mut msg.data[..regs.a2])
+        {
+            lse.leave();
+            return Err(invalid_address(e));
+        }
+        drop(pma_mgr);
+        msg.caller = caller.0 as u64;
+        msg.len = regs.a2 as u64;
+        lse.data.reply = (regs.a3, regs.a4);
+        // skip the unimp of the host or the ecall of an enclave
+        regs.mepc += if caller == EnclaveId::HOST { 0x2 } else { 0x4 };
+
+        self.switch_to_service(lse, caller, regs)
+    }
+
+    /// Save the context of `caller` and enter `lse` at its entry.
+    fn switch_to_service(
+        &self,
+        lse: &'static mut LinuxServiceEnclave,
+        caller: EnclaveId,
+        regs: &TrapRegs,
+    ) -> ! {
+        lse.data.caller = caller;
+        lse.data.caller_ctx.save(regs);
+        // the service has the timer of this hart, so a call never keeps it
+        let hart = mhartid::read();
+        let now = self.clint.mtime();
+        lse.data.caller_timer = self.clint.mtimecmp(hart);
+        lse.data.caller_timer_enabled = mie::read().mtimer();
+        lse.data.deadline = now.saturating_add(SERVICE_CALL_TICKS);
+        self.clint.set_mtimecmp(hart, self.service_timer(lse, now));
+        unsafe { mie::set_mtimer() };
+        if let Some(thread) = self
+            .enc_mgr
+            .get_lue(caller)
+            .and_then(|enc| enc.data.thread_on(mhartid::read()))
+        {
+            thread.pmp_cache.dump();
+        }
+
+        self.hsm.clean_pmp();
+        self.hsm.current().set_priv(lse.idx());
+        satp::write(lse.data.satp);
+        unsafe { stvec::write(0, stvec::TrapMode::Direct) };
+
+        // SAFETY: the message page belongs to the service, which is entered
+        let len = unsafe { (*(lse.data.msg as *const ServiceMsg)).len as usize };
+        log::debug!("#{caller} calls #{} with {len:#x} bytes", lse.id());
+
+        unsafe {
+            self.hsm.mret(
+                enclave::DEFAULT_RT_START + lse.data.entry,
+                mstatus::MPP::Supervisor,
+                SERVICE_MSG_VADDR,
+                len,
+                lse.data.stack_top,
+                lse.data.satp,
+            )
+        }
+    }
+
+    /// Return to the caller of the service enclave serving on this hart.
+    ///
+    /// a0: length of the reply in the message page.
+    fn service_return(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let lse = self.current_enclave()?.as_lse().ok_or_else(|| {
+            log::error!("only service enclaves return from calls");
+            EcallError::code(enclave::Error::InvalidCaller as usize)
+        })?;
+        let caller = lse.data.caller;
+        let len = regs.a0.min(SERVICE_MSG_MAX);
+        self.return_to_caller(lse, regs);
+
+        // SAFETY: the message page belongs to the service, which is entered
+        let msg = unsafe { &*(lse.data.msg as *const ServiceMsg) };
+        let (addr, size) = lse.data.reply;
+        let reply = &msg.data[..len.min(size)];
+        // the host may have remapped the buffer during the call
+        let len = match helper::copy_to_enclave(&self.pma_mgr.read(), caller, addr, reply) {
+            Ok(_) => len,
+            Err(e) => {
+                log::error!("#{} cannot reply to #{caller}: {e}", lse.id());
+                0
+            }
+        };
+
+        lse.data.launched = true;
+        lse.leave();
+        log::debug!("#{} replied {len:#x} bytes to #{caller}", lse.id());
+
+        Ok(EcallResult::ret().retval(len).fixed_epc())
+    }
+
+    /// Switch this hart back from `lse` to the caller it serves.
+    fn return_to_caller(&self, lse: &LinuxServiceEnclave, regs: &mut TrapRegs) {
+        let caller = lse.data.caller;
+        // SAFETY: It is ready to switch context
+        *regs = unsafe { lse.data.caller_ctx.restore() };
+        // a timer of the caller due during the call goes off right away, so a
+        // calling thread whose slice is over is stopped
+        self.clint.set_mtimecmp(mhartid::read(), lse.data.caller_timer);
+        unsafe {
+            if lse.data.caller_timer_enabled {
+                mie::set_mtimer();
+            } else {
+                mie::clear_mtimer();
+            }
+        }
+        self.hsm.clean_pmp();
+        match self.enc_mgr.get_enc(caller) {
+            Some(enc) => {
+                self.hsm.current().set_priv(enc.idx());
+                if let Some(enc) = enc.as_lue() {
+                    let hart = mhartid::read();
+                    if let Some(thread) = enc.data.thread_on(hart) {
+                        self.hsm.update_pmp(|| thread.pmp_cache.restore());
+                    }
+                    if enc.data.exiting.load(Ordering::SeqCst) {
+                        // stopped right after the return, as the other threads
+                        self.hsm.or_ops(hart, hsm::HartStateOps {
+                            stop_enclave: true,
+                            ..hsm::HartStateOps::empty()
+                        });
+                        self.clint.send_ipi(hart);
+                    }
+                }
+            }
+            None => self.hsm.current().clear_priv(),
+        }
+        riscv::asm::sfence_vma_all();
+    }
+
+    /// Resume a paused thread of a user enclave, or a paused driver enclave.
+    ///
+    /// a0: id of the enclave, a1: id of the thread, ignored for a driver.
+    fn resume_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        // todo!()
+        let eid = EnclaveId::from(regs.a0);
+        let tid = regs.a1;
+        if self.enc_mgr.get_lde(eid).is_some() {
+            return self.resume_driver(regs);
+        }
+
+        log::debug!("hart {} resuming thread {tid} of #{eid}", mhartid::read());
+        let (enc, thread) = self.enc_mgr.enter_lue(eid, tid).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(e as usize)
+        })?;
+        if !thread.launched {
+            log::error!("thread {tid} of #{eid} is not launched");
+            enc.data.leave(thread);
+            return Err(EcallError::code(enclave::Error::InvalidThread as usize));
+        }
+        // a restored runtime relocates itself before any thread goes on
+        let relocation = enc.data.claim_relocation(thread).map_err(|e| {
+            log::error!("thread {tid} of #{eid} waits for the runtime to relocate itself");
+            enc.data.leave(thread);
+            EcallError::code(e as usize)
+        })?;
+        // unimp length
+        regs.mepc += 0x2;
+
+        // set current enclave
+        log::debug!("Set current enclave to #{eid}, idx: {}", enc.idx());
+
+        self.hsm.clean_pmp();
+        self.hsm.current().set_priv(enc.idx());
+        // });
+
+        // restore the pmp status
+        self.hsm.update_pmp(|| thread.pmp_cache.restore());
+        log::debug!("restore pmp entires");
+
+        // save new context
+        thread.nw_ctx.save(&regs);
+        self.arm_timer(enc, thread);
+
+        debug_assert_ne!(thread.enc_ctx.sregs.satp, 0);
+        debug_assert_ne!(thread.enc_ctx.sregs.satp, satp::read().bits());
+
+        log::debug!("restore enclave context:");
+        log::debug!("satp: {:#x}", thread.enc_ctx.sregs.satp);
+        log::debug!("sscratch: {:#x}", thread.enc_ctx.sregs.sscratch);
+        log::debug!("sstatus: {:#x}", thread.enc_ctx.sregs.sstatus);
+        log::debug!("stvec: {:#x}", thread.enc_ctx.sregs.stvec);
+        log::debug!("sepc: {:#x}", thread.enc_ctx.sregs.sepc);
+        log::debug!("scaues: {:#x}", thread.enc_ctx.sregs.scaues);
+        log::debug!("stval: {:#x}", thread.enc_ctx.sregs.stval);
+        // SAFETY: It is ready to switch context
+        *regs = unsafe { thread.enc_ctx.restore() };
+        thread.preempted = false;
+        if let Some(args) = relocation {
+            log::info!("thread {tid} of #{eid} relocates the runtime");
+            self.enter_restore_hook(enc, tid, thread, args, regs);
+        }
+        riscv::asm::sfence_vma_all();
+
+        thread.switch_cycle.end();
+
+        // let cycle_finish = riscv::register::cycle::read();
+        // log::info!("cycle in resume enclave: {:#x}", cycle_finish - cycle_start);
+        // unsafe { riscv::register::mcountinhibit::set_cy() };
+
+        Ok(EcallResult::ret().switched())
+    }
+
+    /// Resume the paused driver enclave `a0` where it was.
+    fn resume_driver(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.enc_mgr.enter_lde(regs.a0).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(e as usize)
+        })?;
+        if !enc.data.launched {
+            log::error!("#{} is not launched", enc.id());
+            enc.leave();
+            return Err(EcallError::code(enclave::Error::InvalidThread as usize));
+        }
+        // unimp length
+        regs.mepc += 0x2;
+
+        self.hsm.clean_pmp();
+        self.hsm.current().set_priv(enc.idx());
+        self.hsm.update_pmp(|| enc.data.pmp_cache.restore());
+
+        enc.nw_ctx.save(regs);
+        debug_assert_ne!(enc.data.enc_ctx.sregs.satp, 0);
+        // SAFETY: It is ready to switch context
+        *regs = unsafe { enc.data.enc_ctx.restore() };
+        riscv::asm::sfence_vma_all();
+
+        Ok(EcallResult::ret().switched())
+    }
+
+    /// Have `thread`, resumed into `regs`, enter the restore hook of the
+    /// runtime with the [`LueRestoreArgs`] at `args`, its id and the new
+    /// address of the trampoline. The thread goes on where it was once the
+    /// hook finishes.
+    fn enter_restore_hook(
+        &self,
+        enc: &LinuxUserEnclave,
+        tid: usize,
+        thread: &mut Thread,
+        args: usize,
+        regs: &mut TrapRegs,
+    ) {
+        thread.restored_ctx = thread.enc_ctx.clone();
+        let ctx = &thread.restored_ctx;
+        // the hook runs in the supervisor mode on the stack of the runtime,
+        // which a thread in the user mode has at its scratch
+        let mpp = (ctx.tregs.mstatus >> MSTATUS_MPP_SHIFT) & 0x3;
+        regs.sp = if mpp == mstatus::MPP::Supervisor as usize {
+            ctx.tregs.sp
+        } else {
+            ctx.sregs.sscratch
+        };
+        regs.mepc = enc.data.restore_entry;
+        regs.a0 = tid;
+        // SAFETY: the arguments are written by the restore in a page of the
+        // enclave
+        regs.a1 = unsafe { (*(args as *const LueRestoreArgs)).tp };
+        regs.a2 = args;
+        regs.mstatus &= !(MSTATUS_MPP | MSTATUS_SIE);
+        regs.mstatus |= (mstatus::MPP::Supervisor as usize) << MSTATUS_MPP_SHIFT;
+    }
+
+    /// Finish the restore hook, so the thread running it goes on where it
+    /// was paused.
+    fn finish_restore(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?.as_lue().ok_or_else(|| {
+            log::error!("only a user enclave finishes a restore");
+            EcallError::code(enclave::Error::InvalidCaller as usize)
+        })?;
+        let thread = enc.data.thread_on(mhartid::read()).unwrap();
+        if !enc.data.finish_relocation(thread) {
+            log::error!("#{} is not relocating its runtime", enc.id());
+            return Err(EcallError::code(enclave::Error::InvalidCaller as usize));
+        }
+        log::info!("#{} relocated its runtime", enc.id());
+
+        thread.enc_ctx = thread.restored_ctx.clone();
+        // SAFETY: the context is where the thread was paused
+        *regs = unsafe { thread.enc_ctx.restore() };
+        riscv::asm::sfence_vma_all();
+        Ok(EcallResult::ret().switched())
+    }
+
+    /// Register the restore hook of the runtime of the calling user enclave,
+    /// without which it is not snapshotted.
+    ///
+    /// a0: address of the hook.
+    fn register_restore(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?.as_lue().ok_or_else(|| {
+            log::error!("only a user enclave registers a restore hook");
+            EcallError::code(enclave::Error::InvalidCaller as usize)
+        })?;
+        enc.data.restore_entry = regs.a0;
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    fn pause_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        // unsafe { riscv::register::mcountinhibit::clear_cy() };
+        // let cycle_start = riscv::register::cycle::read();
+
+        log::debug!("hart {} pausing enclave", mhartid::read());
+        let enc = self.current_enclave()?;
+
+        match enc.get_type() {
+            EnclaveType::User => {
+                let enc = enc.as_lue().unwrap();
+                let thread = enc.data.thread_on(mhartid::read()).unwrap();
+                self.hsm.current().clear_priv();
+                let res = lue::pause(enc, thread, regs);
+                self.disarm_timer(enc, thread);
+                // the context is saved, so the thread can be resumed anywhere
+                self.leave_thread(enc, thread);
+                res.map(|_| {
+                    log::debug!("pause enclave, return to {:#x}", regs.mepc);
+                    EcallResult::ret().retval(regs.a1).fixed_epc()
+                })
+                .map_err(|e| {
+                    log::error!("pause enclave failed: {}", e);
+                    EcallError::code(e as usize)
+                })
+            }
+            EnclaveType::Driver => {
+                let enc = enc.as_lde().unwrap();
+                self.hsm.current().clear_priv();
+                lde::pause(enc, regs);
+                // the context is saved, so the driver can be resumed anywhere
+                enc.leave();
+                Ok(EcallResult::ret().retval(regs.a1).fixed_epc())
+            }
+            EnclaveType::Service => {
+                // calls run to completion, so the service just goes on
+                log::debug!("service enclave #{} is not paused", enc.id());
+                Ok(EcallResult::ret().retval(0))
+            }
+            _ => {
+                log::error!("unsupported enclave type");
+                Err(EcallError::code(0x1))
+            }
+        }
+    }
+
+    /// Donate host memory to a paused thread of a user enclave.
+    ///
+    /// a0: id of the enclave, a1: id of the thread, a2: host address of the
+    /// pages, a3: their size, at most [`MAX_DONATED_PAGES`] pages.
+    ///
+    /// The pages must be owned by the host, each mapped once in the range, and
+    /// the enclave may hold no more memory than its manifest allows. They are
+    /// scrubbed and linked like the free list of the runtime, which gets the
+    /// head in `a1` when the thread returns from its pause.
+    fn donate_memory(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        if self.hsm.current().get_priv::<EnclaveIdx>().is_some() {
+            log::error!("only the host donates memory");
+            return Err(EcallError::code(enclave::Error::InvalidCaller as usize));
+        }
+        let (eid, tid, vaddr, size) = (EnclaveId::from(regs.a0), regs.a1, regs.a2, regs.a3);
+        if size == 0
+            || size % PAGE_SIZE != 0
+            || size / PAGE_SIZE > MAX_DONATED_PAGES
+            || vaddr.checked_add(size).is_none()
+        {
+            log::error!("cannot donate {size:#x} bytes");
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        }
+
+        // entered, so the thread is not resumed meanwhile
+        let (enc, thread) = self.enc_mgr.enter_lue(eid, tid).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(e as usize)
+        })?;
+        // a preempted thread has no registers to spare for the result
+        if !thread.launched || thread.preempted {
+            log::error!("thread {tid} of #{eid} is not paused");
+            self.leave_thread(enc, thread);
+            return Err(EcallError::code(enclave::Error::InvalidThread as usize));
+        }
+        // counted first, so donations on other threads see the pages
+        if let Err(e) = enc.data.donate(size / PAGE_SIZE) {
+            log::error!(
+                "#{eid} cannot hold {size:#x} more bytes, the manifest allows {:#x}",
+                enc.data.max_mem_size
+            );
+            self.leave_thread(enc, thread);
+            return Err(EcallError::code(e as usize));
+        }
+
+        // translated once, as the host may remap the range on other harts
+        let mut pma_mgr = self.pma_mgr.write();
+        let pages = {
+            let mut pages: Vec<usize, MAX_DONATED_PAGES> = Vec::new();
+            (vaddr..vaddr + size)
+                .step_by(PAGE_SIZE)
+                .try_for_each(|vaddr| {
+                    let paddr = helper::enclave_page(&pma_mgr, EnclaveId::HOST, vaddr)?;
+                    if pages.contains(&paddr) {
+                        return Err(Error::InvalidAddress(vaddr));
+                    }
+                    pages.push(paddr).unwrap();
+                    Ok(())
+                })
+                .map(|_| pages)
+        };
+        let pages = match pages {
+            Ok(pages) => pages,
+            Err(e) => {
+                log::error!("host cannot donate memory to #{eid}: {e}");
+                drop(pma_mgr);
+                enc.data.undo_donate(size / PAGE_SIZE);
+                self.leave_thread(enc, thread);
+                return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+            }
+        };
+
+        for &paddr in &pages {
+            pma_mgr.insert_page(
+                paddr,
+                PmaProp::empty().owner(eid).permission(Permission::RWX),
+            );
+        }
+        drop(pma_mgr);
+        // the host may have the pages in its pmp on any hart
+        self.reset_harts_pmp();
+
+        let mut head = thread.enc_ctx.tregs.a1;
+        for &paddr in &pages {
+            // SAFETY: the page is the enclave's now, and out of reach of the host
+            unsafe {
+                let page = paddr as *mut usize;
+                page.write_bytes(0, PAGE_SIZE / size_of::<usize>());
+                page.write(head);
+            }
+            head = paddr;
+        }
+        thread.enc_ctx.tregs.a1 = head;
+        log::debug!("host donated {} pages to #{eid}", pages.len());
+        self.leave_thread(enc, thread);
+
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    /// Give memory of a user enclave back to the host, either by the enclave
+    /// itself or at the request of the host.
+    fn free_memory(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        if self.hsm.current().get_priv::<EnclaveIdx>().is_some() {
+            self.return_memory(regs)
+        } else {
+            self.reclaim_donated(regs)
+        }
+    }
+
+    /// Give free pages of the calling user enclave back to the host.
+    ///
+    /// a0: physical address of the first page, a1: number of pages, at most
+    /// [`MAX_DONATED_PAGES`]. The pages are linked like the free list of the
+    /// runtime. Returns the number of pages given back, which are scrubbed.
+    fn return_memory(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        let Some(lue) = enc.as_lue().filter(|_| regs.a1 <= MAX_DONATED_PAGES) else {
+            log::error!("#{eid} cannot give {} pages back", regs.a1);
+            return Err(EcallError::code(enclave::Error::InvalidCaller as usize));
+        };
+
+        let channels = self.channels.lock();
+        let mut pma_mgr = self.pma_mgr.write();
+        let mut pages: Vec<usize, MAX_DONATED_PAGES> = Vec::new();
+        let mut paddr = regs.a0;
+        for _ in 0..regs.a1 {
+            // not the meta page or a thread, which the enclave cannot access
+            let owned = paddr % PAGE_SIZE == 0
+                && pma_mgr.get_pma(paddr).is_some_and(|pma| {
+                    pma.check_owner(|owner| owner == eid)
+                        && pma.get_prop().get_owner_perm() == Permission::RWX
+                });
+            if !owned || pages.contains(&paddr) || channels.lends(paddr) {
+                log::error!("#{eid} cannot give {paddr:#x} back");
+                return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+            }
+            pages.push(paddr).unwrap();
+            // SAFETY: the page is owned by the enclave
+            paddr = unsafe { *(paddr as *const usize) };
+        }
+        drop(channels);
+
+        for &paddr in &pages {
+            // SAFETY: the page is owned by the enclave, which gives it up
+            unsafe { (paddr as *mut u8).write_bytes(0, PAGE_SIZE) };
+            pma_mgr.insert_page(
+                paddr,
+                PmaProp::empty()
+                    .owner(EnclaveId::HOST)
+                    .permission(Permission::RWX),
+            );
+        }
+        drop(pma_mgr);
+        lue.data.give_back(pages.len());
+        // the threads may have the pages in their pmp
+        self.revoke_harts_pmp(&[eid]);
+        log::debug!("#{eid} gave {} pages back", pages.len());
+
+        Ok(EcallResult::ret().retval(pages.len()))
+    }
+
+    /// Check whether memory the host donated is back with it.
+    ///
+    /// a0: id of the user enclave, a1: id of a paused thread, a2: host address
+    /// of the memory, a3: its size, at most what the host donated. Returns how
+    /// many of the pages the enclave still holds, and the thread finds as many
+    /// in `a0` when it returns from its pause, so it gives pages back.
+    fn reclaim_donated(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let (eid, tid, vaddr, size) = (EnclaveId::from(regs.a0), regs.a1, regs.a2, regs.a3);
+        let end = vaddr
+            .checked_add(size)
+            .filter(|_| vaddr % PAGE_SIZE == 0 && size % PAGE_SIZE == 0);
+        let Some(end) = end else {
+            log::error!("cannot reclaim {size:#x} bytes at {vaddr:#x}");
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        };
+
+        let (enc, thread) = self.enc_mgr.enter_lue(eid, tid).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(e as usize)
+        })?;
+        // a preempted thread has no registers to spare for the result
+        if !thread.launched || thread.preempted {
+            log::error!("thread {tid} of #{eid} is not paused");
+            self.leave_thread(enc, thread);
+            return Err(EcallError::code(enclave::Error::InvalidThread as usize));
+        }
+        // the host only looks for what it donated, so the range is bounded
+        if size / PAGE_SIZE > enc.data.donated() {
+            log::error!(
+                "host reclaims {} pages of #{eid}, which got {} donated",
+                size / PAGE_SIZE,
+                enc.data.donated()
+            );
+            self.leave_thread(enc, thread);
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        }
+
+        let satp = satp::read();
+        let pma_mgr = self.pma_mgr.read();
+        let held = (vaddr..end).step_by(PAGE_SIZE).try_fold(0, |held, vaddr| {
+            let paddr = vaddr
+                .translate(satp.ppn(), satp.mode(), &BarePtReader)
+                .ok_or(Error::InvalidAddress(vaddr))?;
+            match pma_mgr.get_pma(paddr).map(|pma| pma.get_prop().get_owner()) {
+                Some(EnclaveId::HOST) => Ok(held),
+                Some(owner) if owner == eid => Ok(held + 1),
+                _ => Err(Error::InvalidAddress(vaddr)),
+            }
+        });
+        drop(pma_mgr);
+
+        let res = match held {
+            Ok(held) => {
+                thread.enc_ctx.tregs.a0 = held;
+                Ok(EcallResult::ret().retval(held))
+            }
+            Err(e) => {
+                log::error!("host cannot reclaim memory of #{eid}: {e}");
+                Err(EcallError::code(enclave::Error::InvalidAddress as usize))
+            }
+        };
+        self.leave_thread(enc, thread);
+
+        res
+    }
+
+    /// The root secret, if the caller is the host.
+    fn host_root_secret(&self) -> Result<&RootSecret, EcallError> {
+        if self.hsm.current().get_priv::<EnclaveIdx>().is_some() {
+            log::error!("snapshots are only handled by the host");
+            return Err(EcallError::code(enclave::Error::InvalidCaller as usize));
+        }
+        self.root_secret.as_ref().ok_or_else(|| {
+            log::error!("snapshots are unavailable without a root secret");
+            EcallError::code(enclave::Error::NoRootSecret as usize)
+        })
+    }
+
+    /// Snapshot a paused user enclave on behalf of the host.
+    ///
+    /// a0: id of the enclave, a1: slot of the counter the snapshot is fresh
+    /// for, a2: host address of the buffer, 0 for the size only, a3: its size.
+    ///
+    /// Returns the size of the snapshot. Once the snapshot is written, the
+    /// enclave is destroyed and lives on in it alone, so a restored enclave
+    /// never runs along with the one it was taken from. Only the latest
+    /// snapshot of the slot is restored.
+    fn snapshot_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let root = self.host_root_secret()?;
+        let eid = EnclaveId::from(regs.a0);
+        // none of its threads is entered while it is out of the list
+        let enc = self.enc_mgr.take_lue(eid).map_err(|e| {
+            log::error!("cannot snapshot #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
+        let res = self.write_snapshot(root, enc, regs.a1 as u64, regs.a2, regs.a3);
+        let nw_vma = enc.nw_vma;
+        if res.is_ok() && regs.a2 != 0 {
+            self.reclaim_memory(eid, nw_vma);
+            log::info!("#{eid} destroyed once snapshotted");
+        } else if self.enc_mgr.push_lue(enc).is_err() {
+            // the service was destroyed meanwhile, which the host asked for
+            self.reclaim_memory(eid, nw_vma);
+        }
+
+        let len = res.map_err(|e| {
+            log::error!("cannot snapshot #{eid}: {e}");
+            EcallError::code(e as usize)
+        })?;
+        Ok(EcallResult::ret().retval(len))
+    }
+
+    /// Seal `enc` into `size` bytes at `buf` of the host, see
+    /// [`SnapshotHeader`] for the layout.
+    fn write_snapshot(
+        &self,
+        root: &RootSecret,
+        enc: &mut LinuxUserEnclave,
+        slot: u64,
+        buf: usize,
+        size: usize,
+    ) -> Result<usize, enclave::Error> {
+        let eid = enc.id();
+        if enc.data.exiting.load(Ordering::SeqCst) {
+            return Err(enclave::Error::EnclaveExited);
+        }
+        if self.channels.lock().has_channels(eid) {
+            log::error!("#{eid} has open channels");
+            return Err(enclave::Error::ChannelConnected);
+        }
+        // the runtime relocates itself once restored, which takes it a hook
+        if enc.data.restore_entry == 0 || enc.data.is_relocating() {
+            log::error!("the runtime of #{eid} cannot be restored yet");
+            return Err(enclave::Error::InvalidSnapshot);
+        }
+
+        // only the address space the threads run in is rebuilt on restore
+        let satp = enc.data.thread(0).unwrap().enc_ctx.sregs.satp;
+        if enc.data.threads().any(|thread| thread.enc_ctx.sregs.satp != satp)
+            || !matches!(satp::Satp::from_bits(satp).mode(), satp::Mode::Sv39)
+        {
+            log::error!("#{eid} runs in more than one address space");
+            return Err(enclave::Error::InvalidSnapshot);
+        }
+        let root_table = satp::Satp::from_bits(satp).ppn() * PAGE_SIZE;
+
+        let mgr = self.pma_mgr.read();
+        let owned = |paddr: usize| {
+            mgr.get_pma(paddr)
+                .is_some_and(|pma| pma.get_prop().get_owner() == eid)
+        };
+
+        // the runtime, the shared memory and the serial are mapped again on
+        // restore where they were, and the trampoline where it is then
+        let (rt, share) = (enc.data.rt, enc.data.share);
+        let uart = self.device.uart.get_reg();
+        let ranges = [
+            rt.start..rt.start + rt.size,
+            share.start..share.start + share.size,
+            uart.start..uart.start + align_up!(uart.len(), PAGE_SIZE),
+        ];
+        let mut needed = TableCount::new(&ranges);
+        let mut tables = Vec::new();
+        let mut mappings = 0;
+        snapshot::walk(&mgr, eid, root_table, &mut tables, &mut |vaddr, pte| {
+            if owned(pte.get_addr()) {
+                needed.add(vaddr);
+                mappings += 1;
+            }
+            Ok(())
+        })?;
+
+        let mut excluded: Vec<usize, { MAX_SNAPSHOT_TABLES + enclave::MAX_THREADS + 1 }> =
+            Vec::new();
+        excluded.push(enc as *const LinuxUserEnclave as usize).unwrap();
+        for thread in enc.data.threads() {
+            excluded.push(thread as *const Thread as usize).unwrap();
+        }
+        excluded.extend_from_slice(&tables).unwrap();
+        let pages = SnapshotPages::new(&mgr, eid, &mut excluded);
+
+        // the enclave cannot map its own meta page, threads or page tables
+        snapshot::walk(&mgr, eid, root_table, &mut tables, &mut |vaddr, pte| {
+            if owned(pte.get_addr()) && pages.index(pte.get_addr()).is_none() {
+                log::error!("#{eid} maps a page of the monitor at {vaddr:#x}");
+                return Err(enclave::Error::InvalidSnapshot);
+            }
+            Ok(())
+        })?;
+
+        if pages.count() > MAX_SNAPSHOT_PAGES {
+            log::error!("#{eid} owns more than {MAX_SNAPSHOT_PAGES} pages");
+            return Err(enclave::Error::InvalidSnapshot);
+        }
+
+        let mut header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            slot,
+            version: 0,
+            measurement: enc.measurement,
+            threads: enc.data.threads().count() as u64,
+            tables: needed.count() as u64,
+            mappings,
+            pages: pages.count() as u64,
+            mac: [0; MAC_SIZE],
+        };
+        let len = snapshot::snapshot_size(&header).unwrap();
+        if buf == 0 {
+            return Ok(len);
+        }
+        if size < len {
+            log::error!("#{eid} needs {len:#x} bytes to be snapshotted");
+            return Err(enclave::Error::InvalidAddress);
+        }
+        helper::check_enclave_range(&mgr, EnclaveId::HOST, buf, len).map_err(|e| {
+            log::error!("{e}");
+            enclave::Error::InvalidAddress
+        })?;
+
+        let id = snapshot::counter_id(slot);
+        header.version = {
+            let mut counters = self.counters.lock();
+            match counters.increment(SealPolicy::Measurement, &enc.measurement, &id) {
+                Err(enclave::Error::NoCounter) => {
+                    counters.create(SealPolicy::Measurement, &enc.measurement, &id)?;
+                    counters.increment(SealPolicy::Measurement, &enc.measurement, &id)
+                }
+                res => res,
+            }?
+        };
+
+        let keys = SnapshotKeys::new(root, &enc.measurement);
+        let mut sealer = Sealer::new(&keys, &header);
+        let mut off = size_of::<SnapshotHeader>();
+        let mut write = |bytes: &mut [u8]| {
+            sealer.seal(bytes);
+            helper::copy_to_enclave(&mgr, EnclaveId::HOST, buf + off, bytes).map_err(|e| {
+                log::error!("{e}");
+                enclave::Error::InvalidAddress
+            })?;
+            off += bytes.len();
+            Ok(())
+        };
+
+        let mut state = EnclaveState {
+            signer: enc.signer.unwrap_or_default(),
+            signed: enc.signer.is_some() as u64,
+            debug: enc.debug as u64,
+            rtmrs: enc.rtmrs,
+            lse: enc.data.lse,
+            share_start: share.start as u64,
+            share_size: share.size as u64,
+            time_slice: enc.data.time_slice,
+            time_budget: enc.data.time_budget,
+            cpu_time: enc.data.cpu_time(),
+            max_mem_size: enc.data.max_mem_size as u64,
+            restore_entry: enc.data.restore_entry as u64,
+        };
+        // SAFETY: EnclaveState is `repr(C)` without padding, and valid for any bytes.
+        write(unsafe { helper::bytes_of_mut(&mut state) })?;
+
+        for thread in enc.data.threads() {
+            let mut ctx = [0; size_of::<HartContext>()];
+            ctx.copy_from_slice(snapshot::context_bytes(&mut thread.enc_ctx));
+            write(&mut ctx)?;
+            let mut flags = ThreadState {
+                launched: thread.launched as u64,
+                preempted: thread.preempted as u64,
+            };
+            // SAFETY: as above
+            write(unsafe { helper::bytes_of_mut(&mut flags) })?;
+        }
+
+        let mut chunk = [0; 256];
+        for page in pages.iter() {
+            for at in (page..page + PAGE_SIZE).step_by(chunk.len()) {
+                // SAFETY: the page is owned by the enclave, which is not running
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        at as *const u8,
+                        chunk.as_mut_ptr(),
+                        chunk.len(),
+                    )
+                };
+                write(&mut chunk)?;
+            }
+        }
+        chunk.fill(0);
+        // the runtime finds where its pages went by where they were
+        let mut addrs = [0u64; 32];
+        let mut len = 0;
+        for page in pages.iter() {
+            addrs[len] = page as u64;
+            len += 1;
+            if len == addrs.len() {
+                // SAFETY: u64 is valid for any bytes
+                write(unsafe { helper::bytes_of_mut(&mut addrs) })?;
+                len = 0;
+            }
+        }
+        if len != 0 {
+            // SAFETY: as above
+            let rest = unsafe { helper::bytes_of_mut(&mut addrs) };
+            write(&mut rest[..len * size_of::<u64>()])?;
+        }
+
+        snapshot::walk(&mgr, eid, root_table, &mut tables, &mut |vaddr, pte| {
+            if !owned(pte.get_addr()) {
+                return Ok(());
+            }
+            let mut mapping = SnapshotMapping {
+                vaddr: vaddr as u64,
+                flags: pte.get_flags().bits() as u64,
+                page: pages.index(pte.get_addr()).unwrap() as u64,
+            };
+            // SAFETY: SnapshotMapping is `repr(C)` without padding.
+            write(unsafe { helper::bytes_of_mut(&mut mapping) })
+        })?;
+
+        header.mac = sealer.finish();
+        // SAFETY: SnapshotHeader is `repr(C)` without padding.
+        let bytes = unsafe { helper::bytes_of(&header) };
+        helper::copy_to_enclave(&mgr, EnclaveId::HOST, buf, bytes).map_err(|e| {
+            log::error!("{e}");
+            enclave::Error::InvalidAddress
+        })?;
+        log::info!("#{eid} snapshotted to slot {slot}, version {}", header.version);
+
+        Ok(len)
+    }
+
+    /// Restore a user enclave from a snapshot on behalf of the host.
+    ///
+    /// a0: host address of the [`RestoreInfo`](channel::snapshot::RestoreInfo).
+    /// Returns the id of the enclave, whose threads are resumed where they
+    /// were paused.
+    ///
+    /// The pages are restored into other frames and the page table is rebuilt
+    /// for them. The first thread resumed enters the restore hook of the
+    /// runtime to relocate the physical addresses it keeps by itself, and goes
+    /// on where it was paused once the hook finishes.
+    fn restore_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let root = self.host_root_secret()?;
+        let refused = |e: enclave::Error| {
+            log::error!("snapshot refused: {e}");
+            EcallError::code(e as usize)
+        };
+        let (args, blob) = lue::get_restore_args(&self.pma_mgr.read(), regs.a0).map_err(refused)?;
+
+        let mut header = SnapshotHeader::EMPTY;
+        // SAFETY: SnapshotHeader is `repr(C)` without padding, and valid for any bytes.
+        let bytes = unsafe { helper::bytes_of_mut(&mut header) };
+        helper::copy_from_enclave(&self.pma_mgr.read(), EnclaveId::HOST, blob.start, bytes)
+            .map_err(|e| {
+                log::error!("{e}");
+                refused(enclave::Error::InvalidAddress)
+            })?;
+        self.check_snapshot(&header, &args, blob).map_err(refused)?;
+        let lse = self
+            .enc_mgr
+            .get_lse(args.lse)
+            .ok_or(enclave::Error::NoServiceEnclave)
+            .map_err(refused)?;
+
+        let eid = self.enc_mgr.get_new_eid();
+        match self.restore_lue(root, eid, &header, &args, blob, lse) {
+            Ok(enc) => match self.enc_mgr.push_lue(enc) {
+                Ok(()) => {
+                    log::info!("#{eid} restored from slot {}", header.slot);
+                    Ok(EcallResult::ret().retval(eid.0))
+                }
+                Err(e) => {
+                    self.reclaim_memory(eid, args.mem);
+                    self.reset_harts_pmp();
+                    Err(refused(e))
+                }
+            },
+            Err(e) => {
+                self.reclaim_memory(eid, args.mem);
+                self.reset_harts_pmp();
+                Err(refused(e))
+            }
+        }
+    }
+
+    /// Check a snapshot before any memory is taken from the host.
+    fn check_snapshot(
+        &self,
+        header: &SnapshotHeader,
+        args: &UserArgs,
+        blob: VirtMemArea,
+    ) -> Result<(), enclave::Error> {
+        if header.magic != SNAPSHOT_MAGIC
+            || header.threads == 0
+            || header.threads > enclave::MAX_THREADS as u64
+            || header.tables == 0
+            || header.tables > MAX_SNAPSHOT_TABLES as u64
+            || header.pages > MAX_SNAPSHOT_PAGES as u64
+            || snapshot::snapshot_size(header) != Some(blob.size)
+        {
+            return Err(enclave::Error::InvalidSnapshot);
+        }
+
+        // the shared memory is the end of the memory, past the restored pages
+        let (mem, share) = (args.mem, args.share);
+        let restored = mem.start.checked_add(header.restore_pages() * PAGE_SIZE);
+        if !aligned!(mem.start, PAGE_SIZE)
+            || !aligned!(share.start, PAGE_SIZE)
+            || !aligned!(share.size, PAGE_SIZE)
+            || restored.is_none_or(|end| end > share.start)
+            || share.start.checked_add(share.size) != mem.start.checked_add(mem.size)
+        {
+            log::error!("cannot restore into {mem} shared at {share}");
+            return Err(enclave::Error::InvalidAddress);
+        }
+
+        let version = self.counters.lock().read(
+            SealPolicy::Measurement,
+            &header.measurement,
+            &snapshot::counter_id(header.slot),
+        )?;
+        if version != header.version {
+            log::error!("slot {} is at version {version}, not {}", header.slot, header.version);
+            return Err(enclave::Error::InvalidSnapshot);
+        }
+
+        Ok(())
+    }
+
+    /// Restore the snapshot of `header` as `eid` into the memory of `args`.
+    ///
+    /// The memory is laid out as the meta page, the threads, the pages, the
+    /// index the pages are recorded in as they are taken, the index of where
+    /// they were, the [`LueRestoreArgs`] and its spare frames, and the frames
+    /// of the new page table. The mappings are decrypted and mapped from the
+    /// index before the snapshot is checked. On failure, the memory is left
+    /// to be reclaimed.
+    fn restore_lue(
+        &self,
+        root: &RootSecret,
+        eid: EnclaveId,
+        header: &SnapshotHeader,
+        args: &UserArgs,
+        blob: VirtMemArea,
+        lse: &LinuxServiceEnclave,
+    ) -> Result<&'static mut LinuxUserEnclave, enclave::Error> {
+        const PER_INDEX: usize = PAGE_SIZE / size_of::<usize>();
+        let mem = args.mem;
+        let threads = header.threads as usize;
+        let pages = header.pages as usize;
+        let tables = header.tables as usize;
+        let mappings = header.mappings as usize;
+        let indexes = pages.div_ceil(PER_INDEX);
+        let share = args.share.start;
+        let claim = self.claim_host_memory(eid, mem, |vaddr| {
+            Some(if vaddr == mem.start {
+                PmaProp::empty().owner(eid).permission(Permission::NONE)
+            } else if vaddr >= share {
+                PmaProp::empty()
+                    .owner(EnclaveId::EVERYONE)
+                    .permission(Permission::RWX)
+            } else {
+                PmaProp::empty().owner(eid).permission(Permission::RWX)
+            })
+        })?;
+        self.reset_harts_pmp();
 
-        // map lse
-        builder.map_vma(lse.data.rt, layout.rt);
+        let take = |i: usize| claim.take(mem.start + i * PAGE_SIZE).map(|paddr| paddr.0);
+        let enc = enclave::create_lue_at(take(0)?, eid);
+        enc.nw_vma = mem;
+        for tid in 0..threads {
+            enc.data.push_thread(take(1 + tid)?);
+        }
+        // the host may remap the pages once they are taken, so the mappings
+        // find them in the index, and the runtime where they were
+        let mut index: Vec<usize, RESTORE_INDEX_FRAMES> = Vec::new();
+        let mut old_index: Vec<usize, RESTORE_INDEX_FRAMES> = Vec::new();
+        for i in 0..indexes {
+            let frame = take(1 + threads + pages + i)?;
+            let old_frame = take(1 + threads + pages + indexes + i)?;
+            index
+                .push(frame)
+                .and_then(|_| old_index.push(old_frame))
+                .map_err(|_| enclave::Error::InvalidSnapshot)?;
+        }
+        let entry = |index: &[usize], i: usize| {
+            (index[i / PER_INDEX] as *mut usize).wrapping_add(i % PER_INDEX)
+        };
+        let args_page = take(1 + threads + pages + 2 * indexes)?;
+        let mut spare = [0; RESTORE_SPARE_FRAMES];
+        for (i, frame) in spare.iter_mut().enumerate() {
+            *frame = take(1 + threads + pages + 2 * indexes + 1 + i)?;
+        }
 
-        // map stack
-        builder.alloc_vma(layout.stack).unwrap();
-        enc.data.enc_ctx.tregs.sp = layout.stack.start + layout.stack.size;
+        let keys = SnapshotKeys::new(root, &header.measurement);
+        let mut sealer = Sealer::new(&keys, header);
+        let mut off = size_of::<SnapshotHeader>();
+        let mut read = |bytes: &mut [u8]| {
+            helper::copy_from_enclave(
+                &self.pma_mgr.read(),
+                EnclaveId::HOST,
+                blob.start + off,
+                bytes,
+            )
+            .map_err(|e| {
+                log::error!("{e}");
+                enclave::Error::InvalidAddress
+            })?;
+            sealer.open(bytes);
+            off += bytes.len();
+            Ok::<_, enclave::Error>(())
+        };
 
-        // map args
-        let bootargs_vma = builder.alloc_vma(layout.bootargs).unwrap();
-        enc.data.enc_ctx.tregs.a1 = bootargs_vma.start;
+        let mut state = EnclaveState::EMPTY;
+        // SAFETY: EnclaveState is `repr(C)` without padding, and valid for any bytes.
+        read(unsafe { helper::bytes_of_mut(&mut state) })?;
+        for thread in enc.data.threads() {
+            read(snapshot::context_bytes(&mut thread.enc_ctx))?;
+            let mut flags = ThreadState {
+                launched: 0,
+                preempted: 0,
+            };
+            // SAFETY: as above
+            read(unsafe { helper::bytes_of_mut(&mut flags) })?;
+            thread.launched = flags.launched != 0;
+            thread.preempted = flags.preempted != 0;
+        }
+        for i in 0..pages {
+            let page = take(1 + threads + i)?;
+            // SAFETY: the page and the index are owned by the enclave, which
+            // is not running yet
+            unsafe { entry(&index, i).write(page) };
+            read(unsafe { core::slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE) })?;
+        }
+        for (i, &frame) in old_index.iter().enumerate() {
+            let len = (pages - i * PER_INDEX).min(PER_INDEX) * size_of::<usize>();
+            // SAFETY: as above
+            read(unsafe { core::slice::from_raw_parts_mut(frame as *mut u8, len) })?;
+        }
+        // the runtime looks the pages up by where they were
+        let mut prev = None;
+        for i in 0..pages {
+            // SAFETY: the entries of all the pages are written above
+            let old = unsafe { entry(&old_index, i).read() };
+            if !aligned!(old, PAGE_SIZE) || prev.is_some_and(|prev| old <= prev) {
+                log::error!("#{eid} had its pages out of order");
+                return Err(enclave::Error::InvalidSnapshot);
+            }
+            prev = Some(old);
+        }
 
-        // map share
-        builder.map_vma(userargs.share, layout.share);
-        // map binary
-        builder.map_vma(userargs.binary, layout.binary);
-        // map serial
+        // the tables are just enough but for those of the trampoline, which
+        // is mapped last where the service is now
+        let tables_at = 1 + threads + pages + 2 * indexes + 1 + RESTORE_SPARE_FRAMES;
+        let allocator = BuilderAllocator::new(
+            mem.start(mem.start + tables_at * PAGE_SIZE)
+                .size(tables * PAGE_SIZE),
+            &claim,
+        );
+        let mut builder = Builder::new(allocator)?;
+
+        let mut layout = Layout::default();
+        layout.rt.size = lse.data.rt.size;
+        layout.share = layout
+            .share
+            .start(state.share_start as usize)
+            .size(args.share.size);
+        builder.map_runtime(lse.data.rt, layout.rt)?;
+        if !args.share.is_empty() {
+            builder.map_vma(args.share, layout.share)?;
+        }
         builder.map_frames(
             PhysPageNum::from_paddr(self.device.uart.get_reg().start),
             VirtMemArea::default()
                 .start(self.device.uart.get_reg().start)
                 .size(align_up!(self.device.uart.get_reg().len(), PAGE_SIZE))
                 .flags(PTEFlags::rw().dirty().accessed()),
-        );
-
-        let (head, free_size) = builder.collect_unused();
-
-        lue::create_bootargs(
-            bootargs_vma,
-            userargs.mem.size(userargs.mem.size - 0x1000),
-            layout,
-            userargs,
-            head,
-            free_size,
-            self.device.clone(),
-        );
+        )?;
+        for _ in 0..mappings {
+            let mut mapping = SnapshotMapping {
+                vaddr: 0,
+                flags: 0,
+                page: 0,
+            };
+            // SAFETY: SnapshotMapping is `repr(C)` without padding, and valid for any bytes.
+            read(unsafe { helper::bytes_of_mut(&mut mapping) })?;
+            let vpn = VirtPageNum::from_vaddr(mapping.vaddr as usize);
+            if mapping.page >= pages as u64 || builder.vmm.get_pte(vpn).is_some() {
+                log::error!("#{eid} cannot map {:#x} again", mapping.vaddr);
+                return Err(enclave::Error::InvalidSnapshot);
+            }
+            // SAFETY: the entries of all the pages are written above
+            let page = unsafe { entry(&index, mapping.page as usize).read() };
+            builder
+                .vmm
+                .try_map_frame(
+                    vpn,
+                    PhysPageNum::from_paddr(page),
+                    PTEFlags::from_bits_truncate(mapping.flags as u8),
+                )
+                .ok_or_else(|| {
+                    log::error!("#{eid} has no frame left to map {:#x}", mapping.vaddr);
+                    enclave::Error::InvalidSnapshot
+                })?;
+        }
+        sealer.verify(&header.mac)?;
+
+        let tramp = lse.data.trampoline;
+        let tp = VirtAddr(tramp.start)
+            .translate(tramp.satp.ppn(), tramp.satp.mode(), &BarePtReader)
+            .ok_or(enclave::Error::InvalidAddress)?;
+        let missing = snapshot::missing_tables(builder.vmm.root_ppn.0 * PAGE_SIZE, tp.0);
+        builder.vmm.frame_allocator.grow(missing * PAGE_SIZE);
+        // fails if the trampoline moved onto an address of the enclave
+        builder.create_trampoline(tramp)?;
+
+        if state.lse != lse.measurement {
+            log::error!("#{eid} ran on another runtime");
+            return Err(enclave::Error::InvalidSnapshot);
+        }
+        if state.share_size != args.share.size as u64 {
+            log::error!("#{eid} shared {:#x} bytes", state.share_size);
+            return Err(enclave::Error::InvalidAddress);
+        }
+        // the check above may race with another restore of the snapshot
+        let version = self.counters.lock().increment(
+            SealPolicy::Measurement,
+            &header.measurement,
+            &snapshot::counter_id(header.slot),
+        )?;
+        if version != header.version + 1 {
+            log::error!("slot {} moved on during the restore", header.slot);
+            return Err(enclave::Error::InvalidSnapshot);
+        }
 
-        self.enc_mgr.push_lue(enc);
+        // the threads keep the address space id the runtime gave them
+        let asid = satp::Satp::from_bits(enc.data.thread(0).unwrap().enc_ctx.sregs.satp).asid();
+        builder.vmm.asid = asid;
+        let satp = builder.vmm.gen_satp();
+        for thread in enc.data.threads() {
+            thread.enc_ctx.sregs.satp = satp;
+        }
+        enc.measurement = header.measurement;
+        enc.signer = (state.signed != 0).then_some(state.signer);
+        enc.debug = state.debug != 0;
+        enc.rtmrs = state.rtmrs;
+        enc.data.lse = state.lse;
+        enc.data.lse_id = lse.id();
+        enc.data.time_slice = state.time_slice;
+        enc.data.time_budget = state.time_budget;
+        enc.data.charge(state.cpu_time);
+        // the donations are in the snapshot, and what the host restores into
+        // is the memory the enclave holds now
+        enc.data.max_mem_size = state.max_mem_size as usize;
+        enc.data.set_mem_size(mem.size);
+        enc.data.rt = layout.rt;
+        enc.data.share = layout.share;
+
+        // the runtime relocates itself by the indexes before any thread goes
+        // on, and takes them with the arguments once it is done
+        let mut relocation = LueRestoreArgs {
+            tp: tp.0,
+            shared: SharedArg {
+                enc_vaddr: layout.share.start,
+                host_vaddr: args.share.start,
+                size: args.share.size,
+            },
+            pages,
+            old: [0; RESTORE_INDEX_FRAMES],
+            new: [0; RESTORE_INDEX_FRAMES],
+            spare,
+        };
+        relocation.old[..indexes].copy_from_slice(&old_index);
+        relocation.new[..indexes].copy_from_slice(&index);
+        // SAFETY: the page is owned by the enclave, which is not running yet
+        unsafe { (args_page as *mut LueRestoreArgs).write(relocation) };
+        enc.data.restore_entry = state.restore_entry as usize;
+        enc.data.set_relocation(args_page);
+
+        // the frames the page table did not take go back to the host
+        claim.commit();
+        let mut mgr = self.pma_mgr.write();
+        // like the meta page, the threads are only accessed by the monitor
+        for thread in enc.data.threads() {
+            mgr.insert_page(
+                thread as *const Thread as usize,
+                PmaProp::empty().owner(eid).permission(Permission::NONE),
+            );
+        }
+        drop(mgr);
+        self.reset_harts_pmp();
 
-        Ok(EcallResult::ret().retval(eid.0))
+        Ok(enc)
     }
 
-    fn create_lse(&self, arg0: usize) -> Result<EcallResult, EcallError> {
-        debug_assert_ne!(arg0, 0);
-        let eid = self.enc_mgr.get_new_eid();
-        log::debug!("eid: {eid}");
-        let userargs = lse::get_user_args(arg0);
-
-        debug_assert!(aligned!(userargs.rt.start, PAGE_SIZE));
-        debug_assert_eq!(userargs.mem.start, userargs.rt.start - 0x1000);
-        debug_assert_eq!(
-            userargs.mem.size,
-            align_up!(userargs.rt.size, PAGE_SIZE) + 0x1000
-        );
+    /// The enclave running on the current hart.
+    fn current_enclave(&self) -> Result<&'static mut Enclave<()>, EcallError> {
+        // SAFETY: It is safe to convert EnclaveIdx to Enclave<()>
+        self.hsm
+            .current()
+            .get_priv::<EnclaveIdx>()
+            .map(|idx| idx.as_enc())
+            .ok_or_else(|| {
+                log::error!("ecall is only allowed in enclaves");
+                EcallError::code(enclave::Error::InvalidCaller as usize)
+            })
+    }
 
-        self.pma_mgr.write().update_pma_by_vma(
-            userargs.rt,
-            PmaProp::empty()
-                .owner(Owner::EVERYONE)
-                .permission(Permission::RX),
-        );
+    /// Sign a report of the calling enclave.
+    ///
+    /// a0: address of the [`SignedReport`](channel::attest::SignedReport) to write,
+    /// a1: address of the [`REPORT_DATA_SIZE`] bytes of report data.
+    fn attest_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        let key = self.device_key.as_ref().ok_or_else(|| {
+            log::error!("attestation is unavailable without a device key");
+            EcallError::code(enclave::Error::NoDeviceKey as usize)
+        })?;
+        let invalid_address = |e: Error| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
+        };
 
-        self.pma_mgr.write().update_pma_by_vma(
-            userargs.mem.size(PAGE_SIZE),
-            PmaProp::empty()
-                .owner(Owner::EVERYONE)
-                .permission(Permission::NONE),
-        );
-        self.reset_harts_pmp();
+        let mut report_data = [0; REPORT_DATA_SIZE];
+        helper::copy_from_enclave(&self.pma_mgr.read(), eid, regs.a1, &mut report_data)
+            .map_err(invalid_address)?;
 
-        let md5 = measure_data(userargs.rt);
-        log::debug!("md5: {:#x}", md5);
+        let report = attest::gen_report(self.boot_log.measurement(), enc, report_data);
+        let report = key.sign(report);
+        // SAFETY: SignedReport is `repr(C)` without padding.
+        let bytes = unsafe { helper::bytes_of(&report) };
+        helper::copy_to_enclave(&self.pma_mgr.read(), eid, regs.a0, bytes)
+            .map_err(invalid_address)?;
+        log::debug!("#{eid} attested");
 
-        let allocator = BuilderAllocator::new(
-            VirtMemArea::default()
-                .start(userargs.unused.start as usize)
-                .size(userargs.unused.size),
-        );
+        Ok(EcallResult::ret().retval(0))
+    }
 
-        let mut builder = Builder {
-            vmm: Sv39VmMgr::new(
-                PhysPageNum(0),
-                BarePtWriter,
-                allocator,
-                satp::read().asid(),
-                SV39,
-            ),
+    /// Make a report of the calling enclave for another enclave.
+    ///
+    /// a0: address of the [`LocalReport`] to write, a1: address of the
+    /// [`REPORT_DATA_SIZE`] bytes of report data, a2: id of the target enclave.
+    fn local_report(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        let secret = self.report_secret.as_ref().ok_or_else(|| {
+            log::error!("local attestation is unavailable without a report secret");
+            EcallError::code(enclave::Error::NoEntropy as usize)
+        })?;
+        let target = self.enc_mgr.get_enc(regs.a2).ok_or_else(|| {
+            log::error!("#{eid} requested a report for unknown enclave #{}", regs.a2);
+            EcallError::code(enclave::Error::InvalidEnclaveId as usize)
+        })?;
+        let invalid_address = |e: Error| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
         };
 
-        let enc = builder.create_lse(&userargs, eid);
-        enc.nw_vma = userargs.mem;
-        enc.data.rt = userargs.rt;
-        enc.data.trampoline = userargs.rt.size(PAGE_SIZE);
-        self.enc_mgr.push_lse(enc);
+        let mut report_data = [0; REPORT_DATA_SIZE];
+        helper::copy_from_enclave(&self.pma_mgr.read(), eid, regs.a1, &mut report_data)
+            .map_err(invalid_address)?;
 
-        Ok(EcallResult::ret().retval(eid.0))
+        let report = attest::gen_report(self.boot_log.measurement(), enc, report_data);
+        let report = secret.gen_local(target, report);
+        // SAFETY: LocalReport is `repr(C)` without padding.
+        let bytes = unsafe { helper::bytes_of(&report) };
+        helper::copy_to_enclave(&self.pma_mgr.read(), eid, regs.a0, bytes)
+            .map_err(invalid_address)?;
+        log::debug!("#{eid} made a local report for #{}", target.id());
+
+        Ok(EcallResult::ret().retval(0))
     }
 
-    fn create_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
-        const USER_ENC: usize = EnclaveType::User as usize;
-        const SER_ENC: usize = EnclaveType::Service as usize;
+    /// Verify a [`LocalReport`] made for the calling enclave.
+    ///
+    /// a0: address of the [`LocalReport`].
+    fn verify_local_report(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        let secret = self.report_secret.as_ref().ok_or_else(|| {
+            log::error!("local attestation is unavailable without a report secret");
+            EcallError::code(enclave::Error::NoEntropy as usize)
+        })?;
+
+        // SAFETY: LocalReport is `repr(C)` without padding, and valid for any bytes.
+        let mut report: LocalReport = unsafe { core::mem::zeroed() };
+        helper::copy_from_enclave(&self.pma_mgr.read(), eid, regs.a0, unsafe {
+            helper::bytes_of_mut(&mut report)
+        })
+        .map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
+        })?;
 
-        match regs.a1 {
-            USER_ENC => self.create_lue(regs.a0),
-            SER_ENC => self.create_lse(regs.a0),
-            _ => panic!("unknown enclave type"),
+        if !secret.verify_local(enc, &report) {
+            log::warn!("#{eid} got an invalid local report");
+            return Err(EcallError::code(enclave::Error::InvalidReport as usize));
         }
+
+        Ok(EcallResult::ret().retval(0))
     }
 
-    fn destory_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
-        // SAFETY: Enclave<()> is safe
-        let enc = self
+    /// Copy the boot log of the security monitor to the caller, either an
+    /// enclave or the host.
+    ///
+    /// a0: address of the [`BootLog`](channel::attest::BootLog) to write.
+    fn get_boot_log(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let caller = self
             .hsm
             .current()
             .get_priv::<EnclaveIdx>()
-            .unwrap()
-            .as_enc();
-        let owner = enc.id();
+            .map(|idx| idx.as_enc().id())
+            .unwrap_or(EnclaveId::HOST);
+        let bytes = self.boot_log.log().as_bytes();
+        helper::copy_to_enclave(&self.pma_mgr.read(), caller, regs.a0, bytes).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
+        })?;
 
-        enc.print_records();
+        Ok(EcallResult::ret().retval(0))
+    }
 
-        if let Some(enc) = enc.as_lue() {
-            log::info!("enclave pause num: {}", enc.data.pause_num);
+    /// Extend a runtime measurement register of the calling enclave.
+    ///
+    /// a0: index of the register, below [`channel::attest::NUM_RTMRS`], a1:
+    /// address of the [`MEASUREMENT_SIZE`] bytes of digest.
+    fn extend_rtmr(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        let rtmr = enc.rtmrs.get_mut(regs.a0).ok_or_else(|| {
+            log::error!("#{eid} extended unknown register {}", regs.a0);
+            EcallError::code(enclave::Error::InvalidRegister as usize)
+        })?;
+
+        let mut digest = [0; MEASUREMENT_SIZE];
+        helper::copy_from_enclave(&self.pma_mgr.read(), eid, regs.a1, &mut digest).map_err(
+            |e| {
+                log::error!("{e}");
+                EcallError::code(enclave::Error::InvalidAddress as usize)
+            },
+        )?;
+        measure::extend_rtmr(rtmr, &digest);
+        log::debug!("#{eid} rtmr[{}]: {:02x?}", regs.a0, rtmr);
+
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    /// The counter of the calling enclave named by a0: address of the
+    /// [`COUNTER_ID_SIZE`] bytes of counter id, a1: [`SealPolicy`].
+    fn counter_of(
+        &self,
+        regs: &TrapRegs,
+    ) -> Result<(SealPolicy, Measurement, [u8; COUNTER_ID_SIZE]), EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        if self.blob_keys.is_none() {
+            log::error!("counters are unavailable without a root secret");
+            return Err(EcallError::code(enclave::Error::NoRootSecret as usize));
         }
+        let invalid_policy = || {
+            log::error!("#{eid} requested invalid counter policy {}", regs.a1);
+            EcallError::code(enclave::Error::InvalidPolicy as usize)
+        };
+        let policy = SealPolicy::try_from(regs.a1).map_err(|_| invalid_policy())?;
+        let identity = match policy {
+            SealPolicy::Measurement => enc.measurement,
+            SealPolicy::Signer => enc.signer.ok_or_else(invalid_policy)?,
+        };
 
-        log::info!("Cleaning enclave {}", owner);
+        let mut id = [0; COUNTER_ID_SIZE];
+        helper::copy_from_enclave(&self.pma_mgr.read(), eid, regs.a0, &mut id).map_err(|e| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
+        })?;
 
-        self.enc_mgr.rm_lue(owner);
-        *regs = unsafe { enc.nw_ctx.restore() };
+        Ok((policy, identity, id))
+    }
 
-        let nw_vma = enc.nw_vma;
-        // enclave will be cleaned
-        let _ = enc;
-        // clean memory content
-        // SAFETY: it is safe to clean the enclave memory content by using host satp.
-        // 因为，如果操作系统去掉了某个页的映射，那SM就不会复原这个页的所有者，这会导致这个页永远也无法被访问。
-        for vpn in nw_vma.iter_vpn() {
-            let paddr = vpn
-                .translate(nw_vma.satp.ppn(), nw_vma.satp.mode(), &BarePtReader)
-                .unwrap();
-            let pma = self.pma_mgr.read().get_pma(paddr).unwrap();
-            let pma_owner = pma.get_prop().get_owner();
-            // we still need to check the owner of the page, avoiding cleaning the page that is not owned by the enclave
-            if pma_owner == owner {
-                unsafe { clean_page_content(paddr.0 as usize) };
-                self.pma_mgr.write().insert_page(
-                    paddr,
-                    PmaProp::empty()
-                        .owner(EnclaveId::HOST.0)
-                        .permission(Permission::RWX),
-                );
-            } else if pma_owner == EnclaveId::EVERYONE {
-                self.pma_mgr.write().insert_page(
-                    paddr,
-                    PmaProp::empty()
-                        .owner(EnclaveId::HOST.0)
-                        .permission(Permission::RWX),
-                );
-            } else {
-                log::error!("cleaning pma {pma} owned by {}", pma_owner);
-                panic!(
-                    "[SM] Invalid pma owner in cleaning enclave. The correct owner should be {owner} or {}, but got {}.",
-                    EnclaveId::EVERYONE,
-                    pma_owner
-                );
-            }
+    /// Create a monotonic counter of the calling enclave, starting from 0.
+    ///
+    /// a0: address of the counter id, a1: [`SealPolicy`].
+    fn counter_create(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let (policy, identity, id) = self.counter_of(regs)?;
+        self.counters
+            .lock()
+            .create(policy, &identity, &id)
+            .map_err(|e| EcallError::code(e as usize))?;
+
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    /// Increment a monotonic counter of the calling enclave.
+    ///
+    /// a0: address of the counter id, a1: [`SealPolicy`]. Returns the new value.
+    fn counter_increment(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let (policy, identity, id) = self.counter_of(regs)?;
+        let value = self
+            .counters
+            .lock()
+            .increment(policy, &identity, &id)
+            .map_err(|e| EcallError::code(e as usize))?;
+
+        Ok(EcallResult::ret().retval(value as usize))
+    }
+
+    /// Read a monotonic counter of the calling enclave.
+    ///
+    /// a0: address of the counter id, a1: [`SealPolicy`]. Returns the value.
+    fn counter_read(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let (policy, identity, id) = self.counter_of(regs)?;
+        let value = self
+            .counters
+            .lock()
+            .read(policy, &identity, &id)
+            .map_err(|e| EcallError::code(e as usize))?;
+
+        Ok(EcallResult::ret().retval(value as usize))
+    }
+
+    /// The keys of the counter blobs, if the caller is the host.
+    fn host_blob_keys(&self) -> Result<&BlobKeys, EcallError> {
+        if self.hsm.current().get_priv::<EnclaveIdx>().is_some() {
+            log::error!("counter blobs are only handled by the host");
+            return Err(EcallError::code(enclave::Error::InvalidCaller as usize));
         }
+        self.blob_keys.as_ref().ok_or_else(|| {
+            log::error!("counters are unavailable without a root secret");
+            EcallError::code(enclave::Error::NoRootSecret as usize)
+        })
+    }
 
-        self.hsm.current().clear_priv();
-        log::info!("[SM] Enclave {} cleaned", owner);
+    /// Seal the counter table for the host to store.
+    ///
+    /// a0: address of the [`CounterBlob`](channel::counter::CounterBlob) to write.
+    fn export_counters(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let keys = self.host_blob_keys()?;
+        let mgr = self.pma_mgr.read();
+        self.counters
+            .lock()
+            .export(keys, |off, bytes| {
+                let vaddr = regs
+                    .a0
+                    .checked_add(off)
+                    .ok_or(Error::InvalidAddress(regs.a0))?;
+                helper::copy_to_enclave(&mgr, EnclaveId::HOST, vaddr, bytes)
+            })
+            .map_err(|e| {
+                log::error!("{e}");
+                EcallError::code(enclave::Error::InvalidAddress as usize)
+            })?;
 
-        Ok(EcallResult::ret().retval(0).fixed_epc())
+        Ok(EcallResult::ret().retval(0))
     }
 
-    fn launch_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
-        let eid = EnclaveId::from(regs.a0);
-        log::debug!("Launch enclave. Id: #{}", eid);
-        #[allow(unused_assignments)]
-        let mut args = (0, 0);
-        #[allow(unused_assignments)]
-        let mut addr = 0;
-        #[allow(unused_assignments)]
-        let mut sp = 0;
+    /// Restore the counter table from the blob exported before the restart.
+    ///
+    /// a0: address of the [`CounterBlob`](channel::counter::CounterBlob).
+    fn import_counters(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let keys = self.host_blob_keys()?;
+        let mgr = self.pma_mgr.read();
+        self.counters
+            .lock()
+            .import(keys, |off, bytes| {
+                regs.a0
+                    .checked_add(off)
+                    .ok_or(Error::InvalidAddress(regs.a0))
+                    .and_then(|vaddr| {
+                        helper::copy_from_enclave(&mgr, EnclaveId::HOST, vaddr, bytes)
+                    })
+                    .map_err(|e| {
+                        log::error!("{e}");
+                        enclave::Error::InvalidAddress
+                    })
+            })
+            .map_err(|e| {
+                log::error!("counter blob refused: {e}");
+                EcallError::code(e as usize)
+            })?;
+        log::info!("counters restored");
 
-        if let Some(enc) = self.enc_mgr.get_lue(eid) {
-            args = lue::prepare_launch(enc, regs);
-            debug_assert_eq!(args.0, 0);
-            addr = enclave::DEFAULT_RT_START;
-            sp = enc.data.enc_ctx.tregs.sp;
-            self.hsm.current().set_priv(enc.idx());
-            log::debug!("Set enclave idx #{}", enc.idx());
-        } else if let Some(_) = self.enc_mgr.get_lse(eid) {
-            panic!("Unsupported yet")
-        } else {
-            panic!("Enclave not found")
-        }
+        Ok(EcallResult::ret().retval(0))
+    }
 
-        self.hsm.current().clean_pmp();
-        unsafe { stvec::write(0, stvec::TrapMode::Direct) };
+    /// Fill enclave memory with random bytes.
+    ///
+    /// a0: address of the buffer, a1: length, at most [`RANDOM_MAX`] bytes.
+    fn random(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        const CHUNK: usize = 256;
 
-        log::debug!("lue arg0: {:#x}, arg1: {:#x}", args.0, args.1);
-        log::debug!("enclave entry: {addr:#x}");
-        log::debug!("entry satp: {:#x}", satp::read().bits());
+        let eid = self.current_enclave()?.id();
+        if regs.a1 > RANDOM_MAX || regs.a0.checked_add(regs.a1).is_none() {
+            log::error!("#{eid} requested {:#x} random bytes", regs.a1);
+            return Err(EcallError::code(enclave::Error::InvalidAddress as usize));
+        }
 
-        unsafe {
-            self.hsm.mret(
-                addr,
-                mstatus::MPP::Supervisor,
-                args.0,
-                args.1,
-                sp,
-                satp::read().bits(),
-            )
+        let mut rng = self.rng.lock();
+        let rng = rng.as_mut().ok_or_else(|| {
+            log::error!("random numbers are unavailable without a seed");
+            EcallError::code(enclave::Error::NoEntropy as usize)
+        })?;
+
+        let mut buf = [0; CHUNK];
+        let mut off = 0;
+        while off < regs.a1 {
+            let len = CHUNK.min(regs.a1 - off);
+            rng.fill(&mut buf[..len]);
+            let res =
+                helper::copy_to_enclave(&self.pma_mgr.read(), eid, regs.a0 + off, &buf[..len]);
+            buf.fill(0);
+            res.map_err(|e| {
+                log::error!("{e}");
+                EcallError::code(enclave::Error::InvalidAddress as usize)
+            })?;
+            off += len;
         }
+
+        Ok(EcallResult::ret().retval(regs.a1))
     }
 
-    fn resume_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
-        // todo!()
-        let eid = EnclaveId::from(regs.a0);
-        // unimp length
-        regs.mepc += 0x2;
+    /// Derive a sealing key for the calling enclave.
+    ///
+    /// a0: address of the key to write, a1: address of the
+    /// [`KEY_ID_SIZE`] bytes of key id, a2: [`SealPolicy`].
+    fn get_sealing_key(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let enc = self.current_enclave()?;
+        let eid = enc.id();
+        let root = self.root_secret.as_ref().ok_or_else(|| {
+            log::error!("sealing is unavailable without a root secret");
+            EcallError::code(enclave::Error::NoRootSecret as usize)
+        })?;
+        let invalid_policy = || {
+            log::error!("#{eid} requested invalid sealing policy {}", regs.a2);
+            EcallError::code(enclave::Error::InvalidPolicy as usize)
+        };
+        let policy = SealPolicy::try_from(regs.a2).map_err(|_| invalid_policy())?;
+        let identity = match policy {
+            SealPolicy::Measurement => enc.measurement,
+            // unsigned enclaves have no signer to bind to
+            SealPolicy::Signer => enc.signer.ok_or_else(invalid_policy)?,
+        };
+        let invalid_address = |e: Error| {
+            log::error!("{e}");
+            EcallError::code(enclave::Error::InvalidAddress as usize)
+        };
 
-        log::debug!("hart {} resuming enclave #{eid}", mhartid::read());
-        let enc = self
-            .enc_mgr
-            .get_lue(eid)
-            .ok_or(enclave::Error::InvalidEnclaveId)
-            .map_err(|e| {
+        let mut key_id = [0; KEY_ID_SIZE];
+        helper::copy_from_enclave(&self.pma_mgr.read(), eid, regs.a1, &mut key_id)
+            .map_err(invalid_address)?;
+
+        let mut key = root.derive(policy, &identity, &key_id);
+        let res = helper::copy_to_enclave(&self.pma_mgr.read(), eid, regs.a0, &key);
+        key.fill(0);
+        res.map_err(invalid_address)?;
+
+        Ok(EcallResult::ret().retval(0))
+    }
+
+    /// Open a channel to lend pages to another enclave.
+    ///
+    /// a0: name of the channel, a1: address of the [`Head`](channel::op::Head)
+    /// page listing the pages, or 0, a2: satp of the memory the peer may copy
+    /// from and to, or 0. Returns the id of the channel.
+    fn channel_open(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let eid = self.current_enclave()?.id();
+        let mut channels = self.channels.lock();
+        let pma_mgr = self.pma_mgr.read();
+        let head = match regs.a1 {
+            0 => 0,
+            vaddr => helper::enclave_page(&pma_mgr, eid, vaddr).map_err(|e| {
                 log::error!("{e}");
+                EcallError::code(enclave::Error::InvalidAddress as usize)
+            })?,
+        };
+        let id = channels
+            .open(&pma_mgr, eid, regs.a0 as u64, head, regs.a2)
+            .map_err(|e| {
+                log::error!("#{eid} cannot open channel {:#x}: {e}", regs.a0);
                 EcallError::code(e as usize)
             })?;
 
-        // set current enclave
-        log::debug!("Set current enclave to #{eid}, idx: {}", enc.idx());
+        Ok(EcallResult::ret().retval(id))
+    }
 
-        self.hsm.current().clean_pmp();
-        self.hsm.current().set_priv(enc.idx());
-        // });
+    /// Connect to a channel opened by another enclave, taking its pages.
+    ///
+    /// a0: id of the channel. Returns the physical address of the head page,
+    /// 0 if there is none.
+    fn channel_connect(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let eid = self.current_enclave()?.id();
+        let (head, opener) = self
+            .channels
+            .lock()
+            .connect(&mut self.pma_mgr.write(), eid, regs.a0)
+            .map_err(|e| {
+                log::error!("#{eid} cannot connect to channel {}: {e}", regs.a0);
+                EcallError::code(e as usize)
+            })?;
+        self.revoke_harts_pmp(&[opener]);
 
-        // restore the pmp status
-        enc.data.pmp_cache.restore();
-        log::debug!("restore pmp entires");
+        Ok(EcallResult::ret().retval(head as usize))
+    }
 
-        // save new context
-        enc.nw_ctx.save(&regs);
+    /// Close a channel from either end, and give its pages back to the
+    /// enclave that opened it.
+    ///
+    /// a0: id of the channel.
+    fn channel_close(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let eid = self.current_enclave()?.id();
+        let peer = self
+            .channels
+            .lock()
+            .close(&mut self.pma_mgr.write(), eid, regs.a0)
+            .map_err(|e| {
+                log::error!("#{eid} cannot close channel {}: {e}", regs.a0);
+                EcallError::code(e as usize)
+            })?;
+        if let Some(peer) = peer {
+            self.revoke_harts_pmp(&[peer]);
+        }
 
-        debug_assert_ne!(enc.data.enc_ctx.sregs.satp, 0);
-        debug_assert_ne!(enc.data.enc_ctx.sregs.satp, satp::read().bits());
+        Ok(EcallResult::ret().retval(0))
+    }
 
-        log::debug!("restore enclave context:");
-        log::debug!("satp: {:#x}", enc.data.enc_ctx.sregs.satp);
-        log::debug!("sscratch: {:#x}", enc.data.enc_ctx.sregs.sscratch);
-        log::debug!("sstatus: {:#x}", enc.data.enc_ctx.sregs.sstatus);
-        log::debug!("stvec: {:#x}", enc.data.enc_ctx.sregs.stvec);
-        log::debug!("sepc: {:#x}", enc.data.enc_ctx.sregs.sepc);
-        log::debug!("scaues: {:#x}", enc.data.enc_ctx.sregs.scaues);
-        log::debug!("stval: {:#x}", enc.data.enc_ctx.sregs.stval);
-        // SAFETY: It is ready to switch context
-        *regs = unsafe { enc.data.enc_ctx.restore() };
-        riscv::asm::sfence_vma_all();
+    /// Copy `a2` bytes from `a1` to `a0` between the calling driver enclave and
+    /// `peer`, whose address space is `satp`.
+    ///
+    /// Returns the number of bytes not copied, like `copy_from_user` of Linux.
+    fn lde_copy(
+        &self,
+        regs: &mut TrapRegs,
+        peer: Option<(EnclaveId, usize)>,
+        to_peer: bool,
+    ) -> Result<EcallResult, EcallError> {
+        let lde = self.current_enclave()?.as_lde().ok_or_else(|| {
+            log::error!("only driver enclaves copy from and to others");
+            EcallError::code(enclave::Error::InvalidCaller as usize)
+        })?;
+        let (peer, satp) = match peer {
+            Some(peer) => peer,
+            // kernel addresses are the same in every address space of the host
+            None => (EnclaveId::HOST, lde.nw_vma.satp.bits()),
+        };
+        let ours = (lde.id(), satp::read());
+        let theirs = (peer, satp::Satp::from_bits(satp));
+        let (dst, src) = if to_peer { (theirs, ours) } else { (ours, theirs) };
+
+        let len = regs.a2;
+        let copied = helper::copy_between(
+            &self.pma_mgr.read(),
+            (dst.0, dst.1, regs.a0),
+            (src.0, src.1, regs.a1),
+            len,
+        );
 
-        enc.data.switch_cycle.end();
+        Ok(EcallResult::ret().retval(len - copied))
+    }
 
-        // let cycle_finish = riscv::register::cycle::read();
-        // log::info!("cycle in resume enclave: {:#x}", cycle_finish - cycle_start);
-        // unsafe { riscv::register::mcountinhibit::set_cy() };
+    /// The user enclave the calling driver enclave serves through a channel.
+    fn lde_client(&self) -> Result<(EnclaveId, usize), EcallError> {
+        let eid = self.current_enclave()?.id();
+        self.channels.lock().client_of(eid).map_err(|e| {
+            log::error!("#{eid} has no user enclave to copy from and to: {e}");
+            EcallError::code(e as usize)
+        })
+    }
 
-        Ok(EcallResult::ret().fixed_epc())
+    /// a0: address in the driver enclave, a1: address in the user enclave,
+    /// a2: length.
+    fn copy_from_lue(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let client = self.lde_client()?;
+        self.lde_copy(regs, Some(client), false)
     }
 
-    fn pause_enclave(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
-        // unsafe { riscv::register::mcountinhibit::clear_cy() };
-        // let cycle_start = riscv::register::cycle::read();
+    /// a0: address in the user enclave, a1: address in the driver enclave,
+    /// a2: length.
+    fn copy_to_lue(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        let client = self.lde_client()?;
+        self.lde_copy(regs, Some(client), true)
+    }
 
-        log::debug!("hart {} pausing enclave", mhartid::read());
-        // SAFETY: It is safe to convert EnclaveIdx to Enclave<()>
-        let enc = self
-            .hsm
-            .current()
-            .get_priv::<EnclaveIdx>()
-            .map(|idx| {
-                log::debug!("current enclave idx: {}", idx);
-                idx
-            })
-            .unwrap()
-            .as_enc();
+    /// a0: address in the driver enclave, a1: kernel address of the host,
+    /// a2: length.
+    fn copy_from_kernel(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        self.lde_copy(regs, None, false)
+    }
 
-        match enc.get_type() {
-            EnclaveType::User => {
-                let enc = enc.as_lue().unwrap();
-                self.hsm.current().clear_priv();
-                lue::pause(enc, regs)
-                    .map(|_| {
-                        log::debug!("pause enclave, return to {:#x}", regs.mepc);
-                        EcallResult::ret().retval(regs.a1).fixed_epc()
-                    })
-                    .map_err(|e| {
-                        log::error!("pause enclave failed: {}", e);
-                        EcallError::code(e as usize)
-                    })
-            }
-            EnclaveType::Service => {
-                log::error!("service enclave cannot be paused");
-                Err(EcallError::code(0x1))
-            }
-            _ => {
-                log::error!("unsupported enclave type");
-                Err(EcallError::code(0x1))
-            }
-        }
+    /// a0: kernel address of the host, a1: address in the driver enclave,
+    /// a2: length.
+    fn copy_to_kernel(&self, regs: &mut TrapRegs) -> Result<EcallResult, EcallError> {
+        self.lde_copy(regs, None, true)
     }
 
     #[inline]
@@ -562,12 +3090,40 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
         let res = EcallHandler::new(CREATE_ENC, EXT_ID, SecMonitor::create_enclave)
             .add_ecall(LAUNCH_ENC, EXT_ID, SecMonitor::launch_enclave)
             .add_ecall(EXIT_ENC, EXT_ID, SecMonitor::destory_enclave)
-            .add_ecall(DESTROY_ENC, EXT_ID, SecMonitor::destory_enclave)
+            .add_ecall(DESTROY_ENC, EXT_ID, SecMonitor::destroy_enclave)
             .add_ecall(RESUME_ENC, EXT_ID, SecMonitor::resume_enclave)
             .add_ecall(PAUSE_ENC, EXT_ID, SecMonitor::pause_enclave)
+            .add_ecall(ATTEST_ENC, EXT_ID, SecMonitor::attest_enclave)
+            .add_ecall(SEALING_KEY, EXT_ID, SecMonitor::get_sealing_key)
+            .add_ecall(RANDOM, EXT_ID, SecMonitor::random)
+            .add_ecall(BOOT_LOG, EXT_ID, SecMonitor::get_boot_log)
+            .add_ecall(LOCAL_REPORT, EXT_ID, SecMonitor::local_report)
+            .add_ecall(VERIFY_LOCAL_REPORT, EXT_ID, SecMonitor::verify_local_report)
+            .add_ecall(EXTEND_RTMR, EXT_ID, SecMonitor::extend_rtmr)
+            .add_ecall(COUNTER_CREATE, EXT_ID, SecMonitor::counter_create)
+            .add_ecall(COUNTER_INCREMENT, EXT_ID, SecMonitor::counter_increment)
+            .add_ecall(COUNTER_READ, EXT_ID, SecMonitor::counter_read)
+            .add_ecall(EXPORT_COUNTERS, EXT_ID, SecMonitor::export_counters)
+            .add_ecall(IMPORT_COUNTERS, EXT_ID, SecMonitor::import_counters)
+            .add_ecall(CALL_SERVICE, EXT_ID, SecMonitor::call_service)
+            .add_ecall(SERVICE_RETURN, EXT_ID, SecMonitor::service_return)
+            .add_ecall(REGISTER_RESTORE, EXT_ID, SecMonitor::register_restore)
+            .add_ecall(FINISH_RESTORE, EXT_ID, SecMonitor::finish_restore)
+            .add_ecall(CHANNEL_OPEN, EXT_ID, SecMonitor::channel_open)
+            .add_ecall(CHANNEL_CONNECT, EXT_ID, SecMonitor::channel_connect)
+            .add_ecall(CHANNEL_CLOSE, EXT_ID, SecMonitor::channel_close)
+            .add_ecall(COPY_FROM_LUE, EXT_ID, SecMonitor::copy_from_lue)
+            .add_ecall(COPY_TO_LUE, EXT_ID, SecMonitor::copy_to_lue)
+            .add_ecall(COPY_FROM_KERNEL, EXT_ID, SecMonitor::copy_from_kernel)
+            .add_ecall(COPY_TO_KERNEL, EXT_ID, SecMonitor::copy_to_kernel)
+            .add_ecall(DONATE_MEM, EXT_ID, SecMonitor::donate_memory)
+            .add_ecall(FREE_MEM, EXT_ID, SecMonitor::free_memory)
+            .add_ecall(SNAPSHOT_ENC, EXT_ID, SecMonitor::snapshot_enclave)
+            .add_ecall(RESTORE_ENC, EXT_ID, SecMonitor::restore_enclave)
             .call(self, regs);
 
         let res = match res {
+            Ok(r) if r.switched => r.proxy,
             Ok(r) => {
                 if !r.fixed_epc {
                     unsafe {
@@ -695,21 +3251,25 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
             panic!();
         }
 
+        // held until the entries are recorded, so the pmas they come from are
+        // not changed before a shootdown can see them
+        let pma_mgr = self.pma_mgr.read();
         match satp.mode() {
             satp::Mode::Bare => {
                 log::trace!("Bare mode");
-                pmas_on_paddr(&self.pma_mgr.read(), mepc, mtval, buf).unwrap()
+                pmas_on_paddr(&pma_mgr, mepc, mtval, buf)?
             }
             satp::Mode::Sv39 => {
                 log::trace!("SV39 mode");
-                pmas_req_vaddr(&self.pma_mgr.read(), mepc, mtval, satp.ppn(), SV39, buf)?
+                pmas_req_vaddr(&pma_mgr, eid, mepc, mtval, satp.ppn(), SV39, buf)?
             }
             satp::Mode::Sv48 => {
                 log::trace!("SV48 mode");
-                pmas_req_vaddr(&self.pma_mgr.read(), mepc, mtval, satp.ppn(), SV48, buf)?
+                pmas_req_vaddr(&pma_mgr, eid, mepc, mtval, satp.ppn(), SV48, buf)?
+            }
+            satp::Mode::Sv57 | satp::Mode::Sv64 => {
+                return Err(Error::other("unsupported paging mode"));
             }
-            satp::Mode::Sv57 => todo!(),
-            satp::Mode::Sv64 => todo!(),
         };
 
         log::trace!("required pma:");
@@ -726,11 +3286,13 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
                 log::error!("mepc: {mepc:#x}");
                 log::error!("mtval: {mtval:#x}");
                 log::error!("hart id: {}", mhartid::read());
-                panic!();
+                return Err(Error::AccessDenied(eid, mtval));
             }
         }
 
-        update_pmp_by_pmas(buf, self.iter_ctx_pma());
+        self.hsm
+            .update_pmp(|| update_pmp_by_pmas(buf, self.iter_ctx_pma(&pma_mgr)));
+        drop(pma_mgr);
 
         log::trace!("Updated pmp registers");
 
@@ -741,7 +3303,16 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
 
         let _ = idx
             .map(|idx| unsafe { Enclave::<()>::from_ptr(idx) })
-            .map(|enc| enc.pmp_record.finish_handle());
+            .map(|enc| {
+                // a user enclave records the faults of each thread
+                match enc
+                    .as_lue()
+                    .and_then(|lue| lue.data.thread_on(mhartid::read()))
+                {
+                    Some(thread) => thread.pmp_record.finish_handle(),
+                    None => enc.pmp_record.finish_handle(),
+                }
+            });
 
         // let cycle_finish = riscv::register::cycle::read();
         // log::info!(
@@ -762,13 +3333,16 @@ a0: {:#x}, a1: {:#x}, a2: {:#x}, ra:{:#x}, sp:{:#x}",
     }
 
     #[inline]
-    pub fn iter_ctx_pma(&self) -> impl Iterator<Item = PhysMemArea> {
+    pub fn iter_ctx_pma<'a>(
+        &self,
+        pma_mgr: &'a PhysMemAreaMgr,
+    ) -> impl Iterator<Item = PhysMemArea> + 'a {
         use pmp::iter_hps;
 
         iter_hps()
             .filter(|p| !p.is_off())
             .map(|p| p.get_region())
-            .map(|r| self.pma_mgr.read().get_pma(r.start).unwrap())
+            .map(|r| pma_mgr.get_pma(r.start).unwrap())
     }
 
     // #[inline]
@@ -0,0 +1,462 @@
+use core::{mem::offset_of, ops::Range};
+
+use chacha20::{
+    ChaCha20,
+    cipher::{KeyIvInit, StreamCipher},
+};
+use channel::{
+    attest::{KEY_ID_SIZE, MAC_SIZE, NUM_RTMRS, SealPolicy},
+    counter::COUNTER_ID_SIZE,
+    snapshot::{SnapshotHeader, SnapshotMapping},
+};
+use console::log;
+use context::HartContext;
+use enclave::{EnclaveId, Error, MEASUREMENT_SIZE, Measurement};
+use heapless::Vec;
+use hmac::{Hmac, Mac};
+use pma::PhysMemAreaMgr;
+use riscv::register::Permission;
+use sha2::Sha256;
+use vm::{
+    PAGE_SIZE,
+    page_table::{PageTable, PageTableEntry},
+};
+
+use crate::{consts::MAX_SNAPSHOT_TABLES, helper, seal::RootSecret};
+
+const MAC_LABEL: &[u8] = b"lattice-snapshot-mac";
+const ENC_LABEL: &[u8] = b"lattice-snapshot-enc";
+const KEY_ID: [u8; KEY_ID_SIZE] = *b"lattice-snapshot-sealing-key-v01";
+
+/// What a snapshot keeps of a user enclave besides its threads and pages.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct EnclaveState {
+    pub signer: Measurement,
+    pub signed: u64,
+    pub debug: u64,
+    pub rtmrs: [Measurement; NUM_RTMRS],
+    /// Measurement of the service enclave providing the runtime.
+    pub lse: Measurement,
+    pub share_start: u64,
+    pub share_size: u64,
+    pub time_slice: u64,
+    pub time_budget: u64,
+    pub cpu_time: u64,
+    /// Bytes of memory the manifest lets the enclave hold, 0 for no limit.
+    pub max_mem_size: u64,
+    /// Entry of the runtime to relocate itself once restored.
+    pub restore_entry: u64,
+}
+
+impl EnclaveState {
+    pub const EMPTY: Self = Self {
+        signer: [0; MEASUREMENT_SIZE],
+        signed: 0,
+        debug: 0,
+        rtmrs: [[0; MEASUREMENT_SIZE]; NUM_RTMRS],
+        lse: [0; MEASUREMENT_SIZE],
+        share_start: 0,
+        share_size: 0,
+        time_slice: 0,
+        time_budget: 0,
+        cpu_time: 0,
+        max_mem_size: 0,
+        restore_entry: 0,
+    };
+}
+
+/// What a snapshot keeps of a thread, after its context.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ThreadState {
+    pub launched: u64,
+    pub preempted: u64,
+}
+
+/// The bytes of a saved context.
+pub fn context_bytes(ctx: &mut HartContext) -> &mut [u8] {
+    // SAFETY: HartContext is `repr(C)` of registers only, so it has no
+    // padding and is valid for any bytes.
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            ctx as *mut HartContext as *mut u8,
+            size_of::<HartContext>(),
+        )
+    }
+}
+
+/// Size of the snapshot `header` is the header of, `None` if it cannot be.
+pub fn snapshot_size(header: &SnapshotHeader) -> Option<usize> {
+    let thread = size_of::<HartContext>() + size_of::<ThreadState>();
+    let threads = (header.threads as usize).checked_mul(thread)?;
+    let mappings = (header.mappings as usize).checked_mul(size_of::<SnapshotMapping>())?;
+    // each page is followed by the address it was at
+    let pages = (header.pages as usize).checked_mul(PAGE_SIZE + size_of::<u64>())?;
+
+    (size_of::<SnapshotHeader>() + size_of::<EnclaveState>())
+        .checked_add(threads)?
+        .checked_add(mappings)?
+        .checked_add(pages)
+}
+
+/// Id of the counter the snapshots of `slot` are fresh for, kept with the
+/// measurement of the enclave as its identity.
+pub fn counter_id(slot: u64) -> [u8; COUNTER_ID_SIZE] {
+    let mut id = [0; COUNTER_ID_SIZE];
+    id[..16].copy_from_slice(b"lattice-snapshot");
+    id[16..24].copy_from_slice(&slot.to_le_bytes());
+    id
+}
+
+/// Keys of the snapshots of an enclave, derived from the root secret and
+/// bound to its measurement, so only a snapshot of the same enclave opens.
+pub struct SnapshotKeys {
+    mac: [u8; 32],
+    enc: [u8; 32],
+}
+
+impl SnapshotKeys {
+    pub fn new(root: &RootSecret, measurement: &Measurement) -> Self {
+        let key = root.derive(SealPolicy::Measurement, measurement, &KEY_ID);
+        let derive = |label: &[u8]| -> [u8; 32] {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+            mac.update(label);
+            mac.finalize().into_bytes().into()
+        };
+
+        Self {
+            mac: derive(MAC_LABEL),
+            enc: derive(ENC_LABEL),
+        }
+    }
+}
+
+/// Encrypts or decrypts the body of a snapshot, MACing its ciphertext after
+/// the header.
+///
+/// The body is encrypted by ChaCha20 keyed by `HMAC(enc_key, slot || version)`.
+/// The counter of the slot moves on with every snapshot and restore, so a key
+/// never encrypts two bodies and no nonce has to be kept.
+pub struct Sealer {
+    cipher: ChaCha20,
+    mac: Hmac<Sha256>,
+}
+
+impl Sealer {
+    pub fn new(keys: &SnapshotKeys, header: &SnapshotHeader) -> Self {
+        let mut kdf = Hmac::<Sha256>::new_from_slice(&keys.enc).unwrap();
+        kdf.update(&header.slot.to_le_bytes());
+        kdf.update(&header.version.to_le_bytes());
+        let key: [u8; 32] = kdf.finalize().into_bytes().into();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&keys.mac).unwrap();
+        // SAFETY: SnapshotHeader is `repr(C)` without padding.
+        let bytes = unsafe { helper::bytes_of(header) };
+        mac.update(&bytes[..offset_of!(SnapshotHeader, mac)]);
+
+        Self {
+            cipher: ChaCha20::new(&key.into(), &[0; 12].into()),
+            mac,
+        }
+    }
+
+    /// Encrypt the next bytes of the body in place.
+    pub fn seal(&mut self, buf: &mut [u8]) {
+        self.cipher.apply_keystream(buf);
+        self.mac.update(buf);
+    }
+
+    /// Decrypt the next bytes of the body in place, which are not to be
+    /// trusted before [`Self::verify`].
+    pub fn open(&mut self, buf: &mut [u8]) {
+        self.mac.update(buf);
+        self.cipher.apply_keystream(buf);
+    }
+
+    pub fn finish(self) -> [u8; MAC_SIZE] {
+        self.mac.finalize().into_bytes().into()
+    }
+
+    pub fn verify(self, mac: &[u8; MAC_SIZE]) -> Result<(), Error> {
+        self.mac
+            .verify_slice(mac)
+            .map_err(|_| Error::InvalidSnapshot)
+    }
+}
+
+/// Walk the Sv39 page table of `eid` at `root`, adding its tables to
+/// `tables` and calling `leaf` with the address and the entry of each page
+/// it maps.
+///
+/// Tables must be pages `eid` owns and accesses, so its meta page and
+/// threads or the pages of others are never read as tables. Superpages are
+/// refused, as the monitor only maps pages.
+pub fn walk(
+    mgr: &PhysMemAreaMgr,
+    eid: EnclaveId,
+    root: usize,
+    tables: &mut Vec<usize, MAX_SNAPSHOT_TABLES>,
+    leaf: &mut dyn FnMut(usize, PageTableEntry) -> Result<(), Error>,
+) -> Result<(), Error> {
+    fn walk_table(
+        mgr: &PhysMemAreaMgr,
+        eid: EnclaveId,
+        table: usize,
+        level: usize,
+        base: usize,
+        tables: &mut Vec<usize, MAX_SNAPSHOT_TABLES>,
+        leaf: &mut dyn FnMut(usize, PageTableEntry) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let owned = mgr.get_pma(table).is_some_and(|pma| {
+            pma.get_prop().get_owner() == eid && pma.get_prop().get_owner_perm() == Permission::RWX
+        });
+        if !owned {
+            log::error!("#{eid} has a page table at {table:#x} it does not own");
+            return Err(Error::InvalidSnapshot);
+        }
+        if !tables.contains(&table) {
+            tables.push(table).map_err(|_| {
+                log::error!("#{eid} has more than {MAX_SNAPSHOT_TABLES} page tables");
+                Error::InvalidSnapshot
+            })?;
+        }
+
+        let entries = PageTable::from_addr(table);
+        for i in 0..512 {
+            let pte = entries.get_pte(i);
+            if !pte.is_valid() {
+                continue;
+            }
+            let mut vaddr = base | i << (12 + 9 * level);
+            // Sv39 addresses are sign-extended from bit 38
+            if level == 2 && i >= 256 {
+                vaddr |= !((1 << 39) - 1);
+            }
+            if !pte.is_leaf() {
+                if level == 0 {
+                    log::error!("#{eid} has a table below the last level at {vaddr:#x}");
+                    return Err(Error::InvalidSnapshot);
+                }
+                walk_table(mgr, eid, pte.get_addr(), level - 1, vaddr, tables, leaf)?;
+            } else if level != 0 {
+                log::error!("#{eid} maps a superpage at {vaddr:#x}");
+                return Err(Error::InvalidSnapshot);
+            } else {
+                leaf(vaddr, pte)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    walk_table(mgr, eid, root, 2, 0, tables, leaf)
+}
+
+/// Counts the page tables an Sv39 page table needs to map some pages: the
+/// root, and a table for each 1 GiB and each 2 MiB region they are in.
+///
+/// The pages are added in ascending order, as [`walk`] finds them, besides
+/// `ranges` mapped as a whole.
+pub struct TableCount<'a> {
+    ranges: &'a [Range<usize>],
+    /// The last region of each size a page is added in.
+    last: [Option<usize>; 2],
+    tables: usize,
+}
+
+impl<'a> TableCount<'a> {
+    /// Shifts of the regions a table of the second and the last level maps.
+    const SHIFTS: [usize; 2] = [30, 21];
+
+    pub fn new(ranges: &'a [Range<usize>]) -> Self {
+        Self {
+            ranges,
+            last: [None; 2],
+            tables: 0,
+        }
+    }
+
+    /// The regions of `range` by `shift`, `None` if it is empty.
+    fn regions(range: &Range<usize>, shift: usize) -> Option<(usize, usize)> {
+        (!range.is_empty()).then(|| (range.start >> shift, (range.end - 1) >> shift))
+    }
+
+    pub fn add(&mut self, vaddr: usize) {
+        for (last, shift) in self.last.iter_mut().zip(Self::SHIFTS) {
+            let region = vaddr >> shift;
+            if *last == Some(region) {
+                continue;
+            }
+            *last = Some(region);
+            let in_range = self.ranges.iter().any(|range| {
+                Self::regions(range, shift)
+                    .is_some_and(|(start, end)| (start..=end).contains(&region))
+            });
+            if !in_range {
+                self.tables += 1;
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        let mut tables = 1 + self.tables;
+        for shift in Self::SHIFTS {
+            // the regions of the ranges, counted once where they overlap
+            let mut regions: Vec<(usize, usize), 8> = self
+                .ranges
+                .iter()
+                .filter_map(|range| Self::regions(range, shift))
+                .collect();
+            regions.sort_unstable();
+            let mut next = 0;
+            for (start, end) in regions {
+                let start = start.max(next);
+                if start <= end {
+                    tables += end - start + 1;
+                    next = end + 1;
+                }
+            }
+        }
+        tables
+    }
+}
+
+/// How many tables the Sv39 page table at `root` lacks to map `vaddr`.
+pub fn missing_tables(root: usize, vaddr: usize) -> usize {
+    let mut table = root;
+    for (level, missing) in [(2, 2), (1, 1)] {
+        let pte = PageTable::from_addr(table).get_pte((vaddr >> (12 + 9 * level)) & 0x1ff);
+        if !pte.is_valid() {
+            return missing;
+        }
+        table = pte.get_addr();
+    }
+    0
+}
+
+/// The pages of an enclave a snapshot keeps, in the order of their pmas.
+///
+/// The `excluded` pages, its meta page, threads and page tables, are left
+/// out, as the monitor rebuilds them.
+pub struct SnapshotPages<'a> {
+    mgr: &'a PhysMemAreaMgr,
+    eid: EnclaveId,
+    /// Sorted, and all owned by `eid`.
+    excluded: &'a [usize],
+}
+
+impl<'a> SnapshotPages<'a> {
+    pub fn new(mgr: &'a PhysMemAreaMgr, eid: EnclaveId, excluded: &'a mut [usize]) -> Self {
+        excluded.sort_unstable();
+        Self { mgr, eid, excluded }
+    }
+
+    fn regions(&self) -> impl Iterator<Item = core::ops::Range<usize>> + '_ {
+        self.mgr
+            .iter_pma()
+            .filter(|pma| pma.get_prop().get_owner() == self.eid)
+            .map(|pma| pma.get_region())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.regions()
+            .flat_map(|region| region.step_by(PAGE_SIZE))
+            .filter(|page| self.excluded.binary_search(page).is_err())
+    }
+
+    pub fn count(&self) -> usize {
+        let owned: usize = self.regions().map(|region| region.len() / PAGE_SIZE).sum();
+        owned - self.excluded.len()
+    }
+
+    /// The index of the page at `paddr` in the snapshot.
+    pub fn index(&self, paddr: usize) -> Option<usize> {
+        if self.excluded.binary_search(&paddr).is_ok() {
+            return None;
+        }
+        let mut before = 0;
+        for region in self.regions() {
+            if region.contains(&paddr) {
+                let excluded = self.excluded.partition_point(|&page| page < paddr);
+                return Some(before + (paddr - region.start) / PAGE_SIZE - excluded);
+            }
+            before += region.len() / PAGE_SIZE;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use channel::snapshot::{SnapshotHeader, SnapshotMapping};
+    use context::HartContext;
+    use vm::PAGE_SIZE;
+
+    use super::{EnclaveState, TableCount, ThreadState, snapshot_size};
+
+    #[test]
+    fn test_snapshot_size() {
+        let fixed = size_of::<SnapshotHeader>() + size_of::<EnclaveState>();
+        assert_eq!(snapshot_size(&SnapshotHeader::EMPTY), Some(fixed));
+
+        let header = SnapshotHeader {
+            threads: 2,
+            mappings: 3,
+            pages: 4,
+            ..SnapshotHeader::EMPTY
+        };
+        let thread = size_of::<HartContext>() + size_of::<ThreadState>();
+        let size = fixed + 2 * thread + 3 * size_of::<SnapshotMapping>() + 4 * (PAGE_SIZE + 8);
+        assert_eq!(snapshot_size(&header), Some(size));
+
+        for header in [
+            SnapshotHeader {
+                pages: u64::MAX,
+                ..SnapshotHeader::EMPTY
+            },
+            SnapshotHeader {
+                threads: u64::MAX,
+                ..SnapshotHeader::EMPTY
+            },
+            // each part fits, but not all of them
+            SnapshotHeader {
+                threads: (usize::MAX / thread) as u64,
+                pages: 1,
+                ..SnapshotHeader::EMPTY
+            },
+        ] {
+            assert_eq!(snapshot_size(&header), None);
+        }
+    }
+
+    #[test]
+    fn test_table_count() {
+        let mut count = TableCount::new(&[]);
+        assert_eq!(count.count(), 1);
+        count.add(0x1000);
+        count.add(0x2000);
+        assert_eq!(count.count(), 3);
+        // another 2 MiB region, and then another 1 GiB one
+        count.add(0x20_0000);
+        assert_eq!(count.count(), 4);
+        count.add(0x4000_0000);
+        assert_eq!(count.count(), 6);
+    }
+
+    #[test]
+    fn test_table_count_ranges() {
+        // overlapping ranges count their regions once
+        let ranges = [0x8000_0000..0x8040_0000, 0x8020_0000..0x8060_0000];
+        let mut count = TableCount::new(&ranges);
+        assert_eq!(count.count(), 1 + 1 + 3);
+
+        count.add(0x8000_1000);
+        count.add(0x805f_f000);
+        assert_eq!(count.count(), 5);
+        count.add(0x8060_0000);
+        assert_eq!(count.count(), 6);
+        // an empty range maps nothing
+        assert_eq!(TableCount::new(&[0x1000..0x1000]).count(), 1);
+    }
+}
@@ -214,6 +214,18 @@ impl<W: PageTableWriter, A: FrameAllocator, M: MemModel> VirtMemMgr<W, A, M> {
     }
 
     pub fn map_frame(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        self.try_map_frame(vpn, ppn, flags)
+            .expect("Mapping conflict or no frame for the page table");
+    }
+
+    /// Map `vpn` to `ppn` like [`Self::map_frame`], but return `None` if a
+    /// frame of the page table cannot be allocated or `vpn` is mapped already.
+    pub fn try_map_frame(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+    ) -> Option<()> {
         let mut pt = self.root_ppn.0;
         let idxs = M::split_vpn(vpn);
         let mut level = M::LEVEL;
@@ -222,7 +234,7 @@ impl<W: PageTableWriter, A: FrameAllocator, M: MemModel> VirtMemMgr<W, A, M> {
             let pte = self.writer.read(pt, idxs[level]);
             if !pte.is_valid() {
                 // 如果中间节点的页表项不存在,分配一个新的页表
-                let new_pt = self.frame_allocator.alloc().unwrap();
+                let new_pt = self.frame_allocator.alloc()?;
                 let new_pte = PageTableEntry::new(new_pt, PTEFlags::V);
                 self.writer.write::<M>(pt, idxs[level], new_pte);
                 pt = new_pt.0; // 更新 pt 变量
@@ -231,7 +243,7 @@ impl<W: PageTableWriter, A: FrameAllocator, M: MemModel> VirtMemMgr<W, A, M> {
                 pt = pte.get_ppn().0;
             } else {
                 // 如果中间节点的页表项已经存在且是叶子节点,说明出现了映射冲突
-                panic!("Mapping conflict: PTE is already a leaf node");
+                return None;
             }
         }
 
@@ -239,8 +251,9 @@ impl<W: PageTableWriter, A: FrameAllocator, M: MemModel> VirtMemMgr<W, A, M> {
         if !pte.is_valid() {
             let new_pte = PageTableEntry::new(ppn, flags);
             self.writer.write::<M>(pt, idxs[0], new_pte);
+            Some(())
         } else {
-            panic!("Mapping conflict: PTE is already a leaf node");
+            None
         }
     }
 
@@ -255,7 +268,7 @@ impl<W: PageTableWriter, A: FrameAllocator, M: MemModel> VirtMemMgr<W, A, M> {
         vma.satp = satp::Satp::from_bits(self.gen_satp());
         for vpn in vma.iter_vpn() {
             let ppn = self.frame_allocator.alloc()?;
-            self.map_frame(vpn, ppn, vma.flags);
+            self.try_map_frame(vpn, ppn, vma.flags)?;
         }
         Some(vma)
     }
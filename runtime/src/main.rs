@@ -125,6 +125,9 @@ unsafe extern "C" fn entry(hartid: usize, args_addr: usize) -> ! {
         kernel.vmm.lock().dealloc_vma(elf_start, elf_size);
     }
 
+    // a restored enclave comes back through `restore` first
+    rt::syscall::sbi_register_restore(restore as usize);
+
     // // enable clock interrupt
     // enable_timer_interrupt();
 
@@ -149,6 +152,14 @@ unsafe extern "C" fn entry(hartid: usize, args_addr: usize) -> ! {
     kernel.start_task();
 }
 
+/// Where the first thread resumed after a restore goes, with the new address
+/// of the trampoline and the physical address of the
+/// [`LueRestoreArgs`](channel::enclave::runtime::LueRestoreArgs).
+#[no_mangle]
+unsafe extern "C" fn restore(_tid: usize, tp: usize, args_addr: usize) -> ! {
+    rt::restore::relocate(tp, args_addr)
+}
+
 #[panic_handler]
 fn panic(_panic: &core::panic::PanicInfo<'_>) -> ! {
     log::error!("{}", _panic);